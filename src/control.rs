@@ -0,0 +1,406 @@
+//! An optional local control socket that lets external scripts configure
+//! translation for channels. The socket only listens on the loopback
+//! interface and is off until the user turns it on with `/TRANSCTL`.
+//!
+//! Any local process can connect to a loopback port, so each time the socket
+//! is started a random token is made and printed to the user, and commands
+//! without it are refused.
+//!
+//! Clients send one JSON command per line and receive one JSON reply per
+//! line. The supported commands are:
+//! ```text
+//! {"token":"<tok>","cmd":"setlang","network":"<net>","channel":"<chan>",
+//!  "src":"en","tgt":"es"}
+//! {"token":"<tok>","cmd":"offlang","network":"<net>","channel":"<chan>"}
+//! ```
+//! Replies are either `{"ok":true}` or `{"ok":false,"error":"<reason>"}`.
+//!
+
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::format as fm;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use hexchat_api::*;
+
+use crate::find_lang;
+
+/// The port the control socket listens on if none is given.
+///
+pub(crate) const DEFAULT_CONTROL_PORT: u16 = 46717;
+
+/// How often the listener thread checks whether it's been asked to stop.
+///
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The longest command line a client can send, in bytes, including its 
+/// newline. Commands are far shorter, so a longer line is refused rather
+/// than buffered without end.
+///
+const MAX_LINE_BYTES: usize = 4096;
+
+/// The running control server, if there is one. It owns a thread that runs
+/// the plugin's code, so it's kept in a static rather than the addon state:
+/// `plugin_deinit()` gets no user data to reach the state with, and has to
+/// stop the thread before the plugin's library is unloaded.
+///
+static SERVER: Mutex<Option<ControlServer>> = Mutex::new(None);
+
+/// A running control socket listener.
+///
+struct ControlServer {
+    port   : u16,
+    token  : String,
+    stop   : Arc<AtomicBool>,
+    thread : Option<JoinHandle<()>>,
+}
+
+impl ControlServer {
+    /// Stops the listener thread and waits for it to finish.
+    ///
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Starts the control socket on the loopback interface. Any server that's
+/// already running is stopped first.
+/// # Arguments
+/// * `port` - The port to listen on.
+/// # Returns
+/// * The token clients must send with each command, or an error if the 
+///   socket couldn't be bound.
+///
+pub(crate) fn start(port: u16) -> io::Result<String> {
+    stop();
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    listener.set_nonblocking(true)?;
+
+    let token  = new_token();
+    let stop   = Arc::new(AtomicBool::new(false));
+    let flag   = stop.clone();
+    let tok    = token.clone();
+    let thread = thread::spawn(move || listen(listener, flag, &tok));
+
+    *SERVER.lock().unwrap() = Some(ControlServer {
+        port, token: token.clone(), stop, thread: Some(thread)
+    });
+    Ok(token)
+}
+
+/// Makes a random token of 32 hex digits for clients to authenticate with.
+/// It's read from the system's random source where there is one, and 
+/// otherwise made from the randomly seeded keys of the standard hasher.
+///
+fn new_token() -> String {
+    let mut bytes = [0u8; 16];
+    let from_os   = File::open("/dev/urandom")
+                         .and_then(|mut f| f.read_exact(&mut bytes))
+                         .is_ok();
+    if !from_os {
+        for chunk in bytes.chunks_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+            if let Ok(t) = SystemTime::now().duration_since(
+                                                SystemTime::UNIX_EPOCH) {
+                hasher.write_u128(t.as_nanos());
+            }
+            chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+    }
+    bytes.iter().map(|b| fm!("{:02x}", b)).collect()
+}
+
+/// Stops the control socket if it's running. Called by `/TRANSCTL off` and
+/// when the addon is unloaded.
+///
+pub(crate) fn stop() {
+    let server = SERVER.lock().unwrap().take();
+    if let Some(mut server) = server {
+        server.shutdown();
+    }
+}
+
+/// Returns the port the control socket is listening on, and the token
+/// clients must send, if it's running.
+///
+pub(crate) fn running() -> Option<(u16, String)> {
+    SERVER.lock().unwrap().as_ref().map(|s| (s.port, s.token.clone()))
+}
+
+/// Accepts connections until the stop flag is set. Clients are served one at
+/// a time.
+///
+fn listen(listener: TcpListener, stop: Arc<AtomicBool>, token: &str) {
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let _ = serve_client(stream, &stop, token);
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+            },
+            Err(_) => {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Reads commands from a client line by line and writes back a reply for
+/// each. A client that sends a line over `MAX_LINE_BYTES` gets an error 
+/// reply and is disconnected, since the rest of its line can't be told 
+/// apart from the next command.
+///
+fn serve_client(stream : TcpStream, 
+                stop   : &AtomicBool, 
+                token  : &str
+               ) -> io::Result<()> 
+{
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line   = String::new();
+
+    while !stop.load(Ordering::SeqCst) {
+        // The line never holds more than `MAX_LINE_BYTES` here, so there's 
+        // always room to read at least one more byte.
+        let room = (MAX_LINE_BYTES + 1 - line.len()) as u64;
+        match (&mut reader).take(room).read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) if line.len() > MAX_LINE_BYTES => {
+                let err = fm!("The line is over {} bytes long.", 
+                              MAX_LINE_BYTES);
+                writeln!(writer, "{}", json!({ "ok": false, "error": err }))?;
+                break;
+            },
+            Ok(_) => {
+                let reply = match parse_command(&line, token)
+                                 .and_then(|cmd| dispatch(cmd, stop)) {
+                    Ok(())   => json!({ "ok": true }),
+                    Err(err) => json!({ "ok": false, "error": err }),
+                };
+                writeln!(writer, "{}", reply)?;
+                line.clear();
+            },
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock
+                                           | ErrorKind::TimedOut) => {
+                // Keep any partial line and check the stop flag.
+            },
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// The commands accepted on the control socket.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ControlCommand {
+    SetLang { network: String, channel: String, src: String, tgt: String },
+    OffLang { network: String, channel: String },
+}
+
+/// Parses and validates a line of JSON received on the control socket.
+/// Language fields are resolved with `find_lang()`, so the command holds the
+/// language codes.
+/// # Arguments
+/// * `line`  - The line received.
+/// * `token` - The token the line must carry in its "token" field.
+/// # Returns
+/// * The parsed command, or a description of what's wrong with it.
+///
+pub(crate) fn parse_command(line  : &str, 
+                            token : &str
+                           ) -> Result<ControlCommand, String> 
+{
+    let json = serde_json::from_str::<Value>(line)
+                          .map_err(|_| "Invalid JSON.".to_string())?;
+
+    if !json["token"].as_str().is_some_and(|t| tokens_match(t, token)) {
+        return Err("Missing or wrong token.".to_string());
+    }
+
+    let field = |name: &str| -> Result<String, String> {
+        match json[name].as_str() {
+            Some(s) if !s.trim().is_empty() => Ok(s.to_string()),
+            _ => Err(fm!("Missing or empty field: {}.", name)),
+        }
+    };
+    let lang = |name: &str| -> Result<String, String> {
+        let value = field(name)?;
+        find_lang(&value).map(|info| info.1.to_string())
+                         .ok_or_else(|| fm!("Unsupported language: {}.",
+                                            value))
+    };
+    match field("cmd")?.to_lowercase().as_str() {
+        "setlang" => {
+            let src = lang("src")?;
+            let tgt = lang("tgt")?;
+            if src == tgt {
+                return Err("Source and target languages are the same."
+                           .to_string());
+            }
+            Ok(ControlCommand::SetLang {
+                network : field("network")?,
+                channel : field("channel")?,
+                src,
+                tgt
+            })
+        },
+        "offlang" => {
+            Ok(ControlCommand::OffLang {
+                network : field("network")?,
+                channel : field("channel")?,
+            })
+        },
+        other => Err(fm!("Unknown command: {}.", other)),
+    }
+}
+
+/// Compares a token sent by a client with the real one, taking the same 
+/// time however much of it matches, so it can't be guessed a character at
+/// a time.
+///
+fn tokens_match(sent: &str, token: &str) -> bool {
+    sent.len() == token.len()
+        && sent.bytes().zip(token.bytes())
+                       .fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Carries out a command by issuing the equivalent addon command in the
+/// requested context on the main thread.
+///
+fn dispatch(cmd: ControlCommand, stop: &AtomicBool) -> Result<(), String> {
+    let (network, channel, command) = match cmd {
+        ControlCommand::SetLang { network, channel, src, tgt } => {
+            (network, channel, fm!("SETLANG {} {}", src, tgt))
+        },
+        ControlCommand::OffLang { network, channel } => {
+            (network, channel, "OFFLANG".to_string())
+        },
+    };
+    let result = main_thread(move |hc| -> Result<(), String> {
+        let ctx = hc.find_context(&network, &channel)
+                    .ok_or_else(|| fm!("No such context: {}/{}.",
+                                       network, channel))?;
+        ctx.command(&command).map_err(|err| err.to_string())
+    });
+    // Poll rather than block on `get()` so an unload can't leave this
+    // thread waiting on a task queue that's been shut down.
+    while !result.is_done() {
+        if stop.load(Ordering::SeqCst) {
+            return Err("Shutting down.".to_string());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    result.get().map_err(|err| err.to_string())?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn commands_need_the_token() {
+        let line = r##"{"cmd":"offlang","network":"net","channel":"#c"}"##;
+        assert!(parse_command(line, TOKEN).is_err());
+
+        let line = r##"{"token":"nope","cmd":"offlang","network":"net",
+                       "channel":"#c"}"##;
+        assert_eq!(parse_command(line, TOKEN), 
+                   Err("Missing or wrong token.".to_string()));
+
+        let line = fm!(r##"{{"token":"{}","cmd":"offlang","network":"net",
+                            "channel":"#c"}}"##, TOKEN);
+        assert_eq!(parse_command(&line, TOKEN), 
+                   Ok(ControlCommand::OffLang { network : "net".into(), 
+                                                channel : "#c".into() }));
+    }
+
+    #[test]
+    fn setlang_resolves_and_checks_languages() {
+        let cmd = |body: &str| {
+            parse_command(&fm!(r##"{{"token":"{}",{}}}"##, TOKEN, body), TOKEN)
+        };
+        assert_eq!(cmd(r##""cmd":"SETLANG","network":"net","channel":"#c",
+                          "src":"English","tgt":"ES""##),
+                   Ok(ControlCommand::SetLang { network : "net".into(),
+                                                channel : "#c".into(),
+                                                src     : "en".into(),
+                                                tgt     : "es".into() }));
+        assert_eq!(cmd(r##""cmd":"setlang","network":"net","channel":"#c",
+                          "src":"en","tgt":"xx""##),
+                   Err("Unsupported language: xx.".to_string()));
+        assert_eq!(cmd(r##""cmd":"setlang","network":"net","channel":"#c",
+                          "src":"en","tgt":"english""##),
+                   Err("Source and target languages are the same."
+                       .to_string()));
+        assert_eq!(cmd(r##""cmd":"setlang","network":"net","channel":" ",
+                          "src":"en","tgt":"es""##),
+                   Err("Missing or empty field: channel.".to_string()));
+        assert_eq!(cmd(r##""cmd":"reload""##),
+                   Err("Unknown command: reload.".to_string()));
+        assert_eq!(parse_command("not json", TOKEN), 
+                   Err("Invalid JSON.".to_string()));
+    }
+
+    #[test]
+    fn tokens_are_random_hex() {
+        let (a, b) = (new_token(), new_token());
+        assert_eq!(a.len(), 32);
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+        assert!(tokens_match(&a, &a.clone()));
+        assert!(!tokens_match(&a, &b));
+        assert!(!tokens_match(&a[..31], &a));
+    }
+
+    #[test]
+    fn long_lines_are_refused() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port     = listener.local_addr().unwrap().port();
+        let server   = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_client(stream, &AtomicBool::new(false), TOKEN)
+        });
+        let client     = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+                                   .unwrap();
+        let mut writer = client.try_clone().unwrap();
+        let mut reader = BufReader::new(client);
+        let mut reply  = String::new();
+
+        writeln!(writer, r##"{{"cmd":"offlang"}}"##).unwrap();
+        reader.read_line(&mut reply).unwrap();
+        assert!(reply.contains(r##""ok":false"##));
+
+        reply.clear();
+        writeln!(writer, "{}", "x".repeat(MAX_LINE_BYTES)).unwrap();
+        reader.read_line(&mut reply).unwrap();
+        assert!(reply.contains("over 4096 bytes"));
+
+        // The client is disconnected.
+        server.join().unwrap().unwrap();
+        reply.clear();
+        assert_eq!(reader.read_line(&mut reply).unwrap(), 0);
+    }
+}
@@ -1,4 +1,5 @@
-#![allow(clippy::blocks_in_if_conditions)]
+#![allow(clippy::blocks_in_conditions)]
+#![allow(clippy::doc_overindented_list_items)]
 
 //! This Hexchat addon provides commands that can turn on language translation
 //! in any chat window of Hexhat. The user's text is translated to the target
@@ -14,23 +15,102 @@
 //!                 translation with `/SETLANG`.
 //! * `/SETLANG`  - Sets the source language (of the user) and the target 
 //!                 language to translate to/from for the user.
+//! * `/SETNETLANG` - Sets the languages used in every channel of a network
+//!                   that doesn't have its own set with `/SETLANG`.
+//! * `/SETLANGLIST` - Rotates outgoing translations through several target
+//!                    languages.
+//! * `/SETLANGRELAY` - Translates incoming messages between two languages
+//!                     other than the user's, for relaying.
+//! * `/SETUSERLANG` - Sets the language a nick's messages are translated 
+//!                    from, overriding the channel's.
+//! * `/SETDISPLAYLANG` - Sets the language incoming messages are shown in,
+//!                       if it isn't the one the user types in.
 //! * `/LSAY`     - Like `/SAY`, but performs translation. Required for
 //!                 outgoing translations. Without using this command, the 
 //!                 user's messages are sent normally. With the command they're
 //!                 translated and sent to the channel.
 //! * `/LME`      - A translator version of the `/ME` command.
-//! * `/OFFLANG`  - Turns translation off in the current window.
+//! * `/LTRANS`   - Translates and sends one message in any channel.
+//! * `/LTRANSLAST` - Translates the last message received in the window.
+//! * `/LDEF` - Prints the alternative translations of a word.
+//! * `/LTOPIC`   - Translates and sets the channel topic.
+//! * `/LEDIT`    - Translates a message into the input box for editing.
+//! * `/LPREVIEW` - Translates a message and prints it without sending it.
+//! * `/TRANSWELCOME` - Sends a translated welcome message to a newcomer.
+//! * `/LSAYBLOCK` - Sends a multi-line block, translating only the lines that
+//!                  aren't ASCII art.
+//! * `/OFFLANG`  - Turns translation off in the current window, or all of them.
+//! * `/TRANSAUTOPROMPT` - Makes `/LSAY` prompt for languages in windows 
+//!                        without translation.
+//! * `/TRANSRETRY` - Sends the message held back by a prompting `/LSAY`.
+//! * `/TRANSUNDO` - Resends the original of the last translated message as a
+//!                  correction.
+//! * `/TRANSEXPANDWARN` - Warns when outgoing translations grow too long.
+//! * `/TRANSFORMAT` - Keeps formatting at the edges of translated messages.
+//! * `/TRANSSKIPSAME` - Leaves incoming messages already in the user's 
+//!                      language untranslated.
+//! * `/TRANSMENICK` - Translates `/LME` actions with the user's nick as their
+//!                    subject.
+//! * `/TRANSVERIFY` - Shows outgoing translations translated back, to check
+//!                    them.
+//! * `/TRANSAWAYPAUSE` - Sends `/LSAY` messages untranslated while away.
+//! * `/TRANSCONFIDENCE` - Flags auto-detected languages of low confidence.
+//! * `/TRANSCOLOR` - Sets the colors of originals and of notices and errors.
+//! * `/TRANSORIG` - Shows or hides the originals of translated messages.
+//! * `/TRANSINLINE` - Shows incoming translations in place of the original.
+//! * `/TRANSERRRATE` - Sets how often over-limit errors are shown.
+//! * `/TRANSRETRIES` - Sets how many times failed requests are retried.
+//! * `/TRANSMAXCHARS` - Sets the longest text that will be translated.
+//! * `/TRANSRATE` - Limits how many requests are sent to Google a minute.
+//! * `/TRANSCOOLDOWN` - Pauses translation for a while when over the limit.
+//! * `/TRANSPAUSE` - Pauses translation in all channels.
+//! * `/TRANSRESUME` - Resumes translation paused with `/TRANSPAUSE`.
+//! * `/TRANSAUTOOFF` - Turns translation off after repeated errors.
+//! * `/TRANSDIR` - Sets whether incoming, outgoing, or both directions are
+//!                 translated in the current window.
+//! * `/TRANSSYSTEM` - Turns translation of part, quit, and kick reasons on
+//!                    or off.
+//! * `/TRANSDETECT` - Detects the language of some text without translating.
+//! * `/TRANSTEST` - Checks that Google's server can be reached, and how fast.
+//! * `/TRANSPROVIDER` - Switches between Google, DeepL, and a LibreTranslate
+//!                      server, or chains them so one falls back on another.
+//! * `/TRANSWEIGHT` - Spreads requests across the chained services by weight.
+//! * `/TRANSPIVOT` - Translates through English when a service can't 
+//!                   translate between two languages.
+//! * `/TRANSLANGSYNC` - Asks the translation service which languages it has.
+//! * `/TRANSKEY` - Sets the API key for DeepL.
+//! * `/TRANSPROXY` - Sends requests to the translation server through a 
+//!                   proxy.
+//! * `/TRANSREQ` - Prints the server requests a translation would make.
+//! * `/TRANSMUTE` - Stops translating messages from specific nicks.
+//! * `/TRANSWATCH` - Translates only the messages from specific nicks.
+//! * `/TRANSCMDSIGIL` - Sets the characters that start untranslated bot
+//!                      commands.
+//! * `/TRANSBLOCK` - Holds outgoing translations that contain blocked words.
+//! * `/TRANSSTATS` - Prints counts of translations and errors since loading.
+//! * `/TRANSCACHE` - Clears cached translations, or just the channel's.
+//! * `/TRANSHISTORY` - Records and reviews recent incoming translations.
+//! * `/TRANSCTL` - Starts or stops a local control socket for scripts.
+//! * `/TRANSEXPORT` - Writes the channel and network languages to a file.
+//! * `/TRANSIMPORT` - Merges the languages from an exported file.
 //!
 
+mod control;
+mod pool;
+
 use regex::Regex;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::From;
 use std::error::Error;
 use std::fmt;
+use std::io::Read;
 use std::format as fm;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use hexchat_api::*;
 use StripFlags::*;
@@ -41,13 +121,72 @@ use UserData::*;
 ///
 const TRANSLATION_SERVER_TIMEOUT: u64 = 5;
 
+/// How long an outgoing translation can take before a notice that it's 
+/// still being worked on is printed. The unit is milliseconds.
+///
+const SLOW_TRANSLATION_NOTICE: i64 = 1500;
+
+/// The color the originals of translated messages are printed in, set with
+/// `/TRANSCOLOR orig`. Cyan by default.
+///
+static ORIG_COLOR: ColorSetting = ColorSetting::new(11);
+
+/// The color the addon's notices and errors are printed in, set with 
+/// `/TRANSCOLOR error`. Magenta by default.
+///
+static ERROR_COLOR: ColorSetting = ColorSetting::new(13);
+
+/// The largest mIRC color number `/TRANSCOLOR` accepts.
+///
+const MAX_IRC_COLOR: u8 = 15;
+
+/// A configurable mIRC color. It's displayed as the color code, so it can be
+/// put in format strings like the `IRC_*` constants. The color is kept in a
+/// static rather than the addon state, since nearly every message the addon
+/// prints uses it. Statics outlive an unload, so `plugin_deinit()` resets it
+/// to its default, like the other statics.
+///
+struct ColorSetting {
+    color   : AtomicU8,
+    default : u8,
+}
+
+impl ColorSetting {
+    const fn new(color: u8) -> Self {
+        ColorSetting { color: AtomicU8::new(color), default: color }
+    }
+
+    fn get(&self) -> u8 {
+        self.color.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, color: u8) {
+        self.color.store(color, Ordering::Relaxed);
+    }
+
+    /// Sets the color back to its default.
+    ///
+    fn reset(&self) {
+        self.set(self.default);
+    }
+}
+
+impl fmt::Display for ColorSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Two digits, so text starting with a digit isn't read as part of 
+        // the code.
+        write!(f, "\x03{:02}", self.get())
+    }
+}
+
 // Register the entry points of the plugin.
 //
 dll_entry_points!(plugin_info, plugin_init, plugin_deinit);
 
 /// Channel data, a tuple of two strings. Used as keys in the channel map, 
 /// the fields hold the `network` and `channel` strings for contexts that
-/// have been enabled for translation. Used as the value in the channel map,
+/// have been enabled for translation. Keys are built with `chan_key()` so the
+/// channel name is case-folded. Used as the value in the channel map,
 /// the fields hold the `source_language` and `target_language` to translate
 /// between.
 ///
@@ -55,669 +194,8490 @@ type ChanData = (String, String);
 
 /// Maps the channels that have been activated for translation to the source
 /// and target language to translate between. The keys are instances of
-/// `ChanData`, as are the values.
+/// `ChanData`, as are the values. A network's default languages, set with
+/// `/SETNETLANG`, are kept under the key built by `net_key()`.
 ///
 type ChanMap  = HashMap<ChanData, ChanData>;
 
-/// Called when the plugin is loaded to register it with Hexchat.
+/// The channel name used in the channel map key of a network's default 
+/// languages. No channel can have an empty name, so it can't collide with 
+/// one.
 ///
-fn plugin_info() -> PluginInfo {
-    PluginInfo::new(
-        "Language Translator",
-        env!("CARGO_PKG_VERSION"),
-        "Instantly translated conversation in over 100 languages.")
-}
+const NET_DEFAULT_CHANNEL: &str = "";
 
-/// Called when the plugin is loaded.
+/// The directions translation is performed in for a context.
+/// # Variants
+/// * `Both` - Incoming and outgoing messages are translated. The default.
+/// * `In`   - Only incoming messages are translated. `/LSAY` and `/LME` send
+///            messages untranslated.
+/// * `Out`  - Only outgoing messages are translated. Incoming messages are
+///            displayed as they are.
 ///
-fn plugin_init(hc: &Hexchat) -> i32 {
-
-    hc.print("Language Translator loaded");
-    
-    // `map_udata` holds a `HashMap` that maps contexts, `(network, channel)`, 
-    // to chosen translation, `(source_lang, target_lang)`. 
-    let map_udata  = UserData::shared(HashMap::<ChanData, ChanData>::new());
-    
-    let lsay_udata = UserData::boxed(("SAY", map_udata.clone()));
-    let lme_udata  = UserData::boxed(("ME", map_udata.clone()));
-    
-    // Register the commands.
-    
-    hc.hook_command(
-        "LISTLANG", Priority::Norm, on_cmd_listlang, LISTLANG_HELP, NoData);
-        
-    hc.hook_command(
-        "SETLANG", Priority::Norm, on_cmd_setlang,   SETLANG_HELP, map_udata
-                                                                   .clone());
-    hc.hook_command(
-        "OFFLANG", Priority::Norm, on_cmd_offlang,   OFFLANG_HELP, map_udata
-                                                                   .clone());
-    hc.hook_command(
-        "LSAY",    Priority::Norm, on_cmd_lsay,      LSAY_HELP,    lsay_udata);
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Direction {
+    #[default]
+    Both,
+    In,
+    Out,
+}
 
-    hc.hook_command(
-        "LME",     Priority::Norm, on_cmd_lsay,      LME_HELP,     lme_udata);
+impl Direction {
+    /// Parses the direction names accepted by `/TRANSDIR`.
+    ///
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "both" => Some(Direction::Both),
+            "in"   => Some(Direction::In),
+            "out"  => Some(Direction::Out),
+            _      => None,
+        }
+    }
 
+    /// Indicates whether incoming messages get translated.
+    ///
+    fn incoming(self) -> bool {
+        self != Direction::Out
+    }
 
-    // Register the handler for all the interesting text events.
-    
-    for event in &["Channel Message", "Channel Msg Hilight", 
-                   "Channel Action",  "Channel Action Hilight", 
-                   "Private Message", "Private Message to Dialog",
-                   "Private Action",  "Private Action to Dialog", 
-                   "You Part",        "You Part with Reason", 
-                   "Disconnected"] 
-    {
-        let event_udata = UserData::boxed((*event, map_udata.clone()));
-        
-        hc.hook_print(event, Priority::Norm, on_recv_message, event_udata);
+    /// Indicates whether outgoing messages get translated.
+    ///
+    fn outgoing(self) -> bool {
+        self != Direction::In
     }
+}
 
-    1
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Both => write!(f, "both"),
+            Direction::In   => write!(f, "in"),
+            Direction::Out  => write!(f, "out"),
+        }
+    }
 }
 
-/// Called when the plugin is unloaded.
+/// The translation services that can be used.
+/// # Variants
+/// * `Google`         - Google's free translation endpoint. The default.
+/// * `LibreTranslate` - A LibreTranslate server. Holds its base URL.
+/// * `DeepL`          - DeepL's API, with the key set with `/TRANSKEY`.
 ///
-fn plugin_deinit(hc: &Hexchat) -> i32 {
-    hc.print("Language Translator unloaded");
-    1
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+enum Provider {
+    #[default]
+    Google,
+    LibreTranslate(String),
+    DeepL,
 }
 
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Provider::Google              => write!(f, "google"),
+            Provider::LibreTranslate(url) => write!(f, "libretranslate {}", url),
+            Provider::DeepL               => write!(f, "deepl"),
+        }
+    }
+}
 
-/// Returns Option((sourcelang, targetlang)) for the window receiving
-/// an event. If there's no entry in the map, or there's a problem accessing it,
-/// `None` is returned.
-/// # Arguments
-/// * `hc`        - The Hexchat interface.
-/// * `map_udata` - The user data of the invoking command.
-/// # Returns
-/// * Returns the channel data for the current context. This is obtained from
-///   the `HashMap` that maps contexts to the source and dest languages.
-///   If a context hasn't been set up for transation, `None` is returned.
+/// Returns the primary translation service, the first in the chain. It's 
+/// the one used for language detection.
 ///
-fn get_channel_langs(hc        : &Hexchat, 
-                     map_udata : &UserData) -> Option<ChanData> 
-{
-    let network = hc.get_info("network")?;
-    let channel = hc.get_info("channel")?;
-    map_udata.apply(
-        |chan_map: &ChanMap| {
-            chan_map.get(&(network, channel)).cloned()
-        })
+fn get_provider(state: &State) -> Provider {
+    state.lock().unwrap().providers.first().cloned().unwrap_or_default()
 }
 
-/// Activates the current context for language translation. A `HashMap` is
-/// maintained that maps contexts (network/channel) to the desired translation
-/// (source_lang, dest_lang).
-/// # Arguments
-/// * `hc`        - The Hexchat interface.
-/// * `map_udata` - The user data of the invoking command.
-/// * `source`    - The source language to translate from.
-/// * `dest`      - The destination language to translate to.
+/// Returns the chain of translation services, in the order they're tried.
 ///
-fn activate(hc        : &Hexchat, 
-            map_udata : &UserData, 
-            source    : &str, 
-            dest      : &str) 
-{
-    if {||{
-        let network = hc.get_info("network")?;
-        let channel = hc.get_info("channel")?;
-        map_udata.apply_mut(
-            |chan_map: &mut ChanMap| {
-                chan_map.insert((network, channel), 
-                                (source.to_string(), dest.to_string()));
-            });
-        Some(())
-    }}().is_none() {
-        hc.print(&fm!("{IRC_MAGENTA}\
-                 Failed to get channel information during activation."));
+fn get_providers(state: &State) -> Vec<Provider> {
+    let providers = &state.lock().unwrap().providers;
+    if providers.is_empty() {
+        vec![Provider::default()]
+    } else {
+        providers.clone()
     }
 }
 
-/// Removes the current context's key and value from the `HashMap` that maps
-/// active contexts to translation information (source-lang, dest-lang). This
-/// effectively disables language translation in that window if it was 
-/// on before. It has no effect if not.
+/// Formats a chain of translation services for printing.
 ///
-fn deactivate(hc        : &Hexchat, 
-              map_udata : &UserData) 
-{
-    if {||{
-        let network = hc.get_info("network")?;
-        let channel = hc.get_info("channel")?;
-        map_udata.apply_mut(
-            |chan_map: &mut ChanMap| {
-                chan_map.remove(&(network, channel))
-            });
-        Some(())
-    }}().is_none() {
-        hc.print(&fm!("{IRC_MAGENTA}\
-                 Failed to get channel information during deactivation."));
-    }
+fn providers_str(providers: &[Provider]) -> String {
+    providers.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", then ")
 }
 
-/// Implements the /SETLANG command. Use /SETLANG to set the source and
-/// target language for translation. Issuing this command activates 
-/// the channel for translation.
+/// The weight of a service in the chain that hasn't been given one with
+/// `/TRANSWEIGHT`, once another has.
 ///
-fn on_cmd_setlang(hc        : &Hexchat, 
-                  word      : &[String], 
-                  _word_eol : &[String], 
-                  map_udata : &UserData
-                 ) -> Eat 
-{
-    if word.len() == 3 {
-        let mut src_lang = word[1].as_str();
-        let mut tgt_lang = word[2].as_str();
-        
-        let mut params_good = false;
-        
-        // Verify each lang is in the list below.
-        if let Some(src_lang_info) = find_lang(src_lang) /* && */ {
-        if let Some(tgt_lang_info) = find_lang(tgt_lang) {
-        
-            if src_lang_info !=  tgt_lang_info {
-                params_good = true;
-                    
-                // Make sure the language names are the abbreviation.
-                src_lang  =  src_lang_info.1;
-                tgt_lang  =  tgt_lang_info.1;
+const DEFAULT_WEIGHT: u32 = 1;
 
-                // Activate the channel.
-                activate(hc, map_udata, src_lang, tgt_lang);
-                
-                hc.print(&fm!("{IRC_MAGENTA}\
-                         TRANSLATION IS ON FOR THIS CHANNEL! \
-                         {} (you) to {} (them).", src_lang_info.0, 
-                                                  tgt_lang_info.0));
-            } 
-        }}
-        if !params_good {
-            hc.print(&fm!("{IRC_MAGENTA}\
-                     BAD LANGUAGE PARAMETERS. Use /LISTLANG to \
-                     get a list of supported languages. And don't \
-                     set translation source and target languages the \
-                     same."));
-        }
-    } else {
-        hc.print(&fm!("USAGE: {}", SETLANG_HELP));
+/// What a service's weight is divided by after it reports being over the 
+/// limit, until `LOWERED_WEIGHT_PERIOD` has passed.
+///
+const LOWERED_WEIGHT_DIVISOR: u32 = 4;
+
+/// How long a service's weight stays lowered after it reports being over 
+/// the limit.
+///
+const LOWERED_WEIGHT_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// A small xorshift random number generator for picking services by weight.
+/// It doesn't need to be good, only cheap and repeatable from a seed.
+///
+#[derive(Debug)]
+struct WeightRng(u64);
+
+impl WeightRng {
+    /// Makes a generator that gives the same numbers for the same seed.
+    ///
+    fn new(seed: u64) -> Self {
+        // Xorshift never leaves zero.
+        WeightRng(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
     }
-    Eat::All
 }
 
-/// Implements the /OFFLANG command. Turns translation off in the 
-/// open window/channel.
-///
-fn on_cmd_offlang(hc        : &Hexchat, 
-                  word      : &[String], 
-                  _word_eol : &[String], 
-                  map_udata : &UserData
-                 ) -> Eat 
-{
-    if word.len() == 1 {
-        deactivate(hc, map_udata);
-        hc.print(&fm!("{IRC_MAGENTA}Translation turned OFF for this channel."));
-    } else {
-        hc.print(&fm!("USAGE: {}", OFFLANG_HELP));
+impl Default for WeightRng {
+    /// Seeds the generator from the randomly seeded keys of the standard 
+    /// hasher.
+    ///
+    fn default() -> Self {
+        use std::hash::{BuildHasher, Hasher};
+        let hasher = std::collections::hash_map::RandomState::new()
+                                                            .build_hasher();
+        WeightRng::new(hasher.finish())
     }
-    Eat::All
 }
 
-/// Implements the /LSAY and /LME commands. Use /LSAY or /LME followed 
-/// by whatever text you want. The text will be translated and posted to 
-/// the channel. Other users will only see the translated message.
+/// Picks an index at random, with each index as likely as its share of the
+/// total weight.
+/// # Returns
+/// * The index picked, or `None` if every weight is zero.
 ///
-fn on_cmd_lsay(hc        : &Hexchat, 
-               _word     : &[String], 
-               word_eol  : &[String], 
-               user_data : &UserData
-              ) -> Eat 
-{
-    // Unpackage the user data to get which command this is for (LSAY/LME),
-    // and get the `UserData` with the `HashMap` in it.
-    let (cmd, ref map_udata) = user_data.apply(
-                                    |ud: &(&str, UserData)| {
-                                        (ud.0, ud.1.clone())
-                                    });
-
-    if let Some(chan_langs) = get_channel_langs(hc, map_udata) {
-        if {||{
-            let src_lang  = chan_langs.0;
-            let tgt_lang  = chan_langs.1;
-            let message   = word_eol[1].clone();
-            
-            let strip_msg = hc.strip(&message, StripBoth)?;
-            let network   = hc.get_info("network")?;                              
-            let channel   = hc.get_info("channel")?;
-
-            thread::spawn(move || {
-                let msg;
-                let mut emsg = None;
-                let mut is_over_limit = false;
-               
-                match google_translate_free(&strip_msg, &src_lang, &tgt_lang) {
-                    Ok(trans) => { 
-                        msg  = trans;
-                    },
-                    Err(err)  => { 
-                        msg  = err.get_partial_trans().to_string();
-                        emsg = Some(fm!("{IRC_MAGENTA}{}", err));
-                        is_over_limit = err.is_over_limit();
-                    }
-                }
-                if let Err(err) = main_thread(
-                    move |hc| -> Result<(), HexchatError> {
-                        if let Some(ctx) = hc.find_context(&network, &channel) {
-                            ctx.command(&fm!("{} {}", cmd, msg))?;
-                            ctx.print(&fm!("{IRC_CYAN}{}", message))?;
-                               
-                            if let Some(emsg) = &emsg {
-                                ctx.print(emsg)?;
-                                if is_over_limit {
-                                    ctx.command("OFFLANG")?;
-                                }
-                            }
-                        } else {
-                            hc.print(&fm!("{IRC_MAGENTA}\
-                                     Failed to get context."));
-                        }
-                        Ok(())
-                    }
-                ).get() {
-                    hc_print_th!("{IRC_MAGENTA}{}", err);
-                }
-            });
-            Some(())
-        }}().is_none() {
-            // If we get here, either `strip()` or `get_info()` returned None.
-            hc.print(&fm!("{IRC_MAGENTA}\
-                     Translator Error: Basic failure retrieving channel \
-                     information, or unable to strip original message."));
+fn pick_weighted(weights: &[u32], rng: &mut WeightRng) -> Option<usize> {
+    let total = weights.iter().map(|&w| w as u64).sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+    let mut pick = rng.next() % total;
+    for (i, &weight) in weights.iter().enumerate() {
+        if pick < weight as u64 {
+            return Some(i);
         }
-        Eat::All
-    } else {
-        Eat::None
+        pick -= weight as u64;
     }
+    None
 }
 
-/// Callback invoked when channel events like 'Channel Message' occur. 
-/// If translation is on for the channel, this callback will have it 
-/// translated and update the context window with translated message text.
+/// Gives the order the services are tried in for one request. If weights 
+/// have been set with `/TRANSWEIGHT`, the first service is picked by weight
+/// and the others follow in chain order, so load is spread across them 
+/// while each still falls back on the rest. Otherwise the chain is tried in
+/// order. Services recently over the limit have their weights lowered.
 ///
-fn on_recv_message(hc        : &Hexchat, 
-                   word      : &[String], 
-                   user_data : &UserData
-                  ) -> Eat 
-{
-    if word.len() < 2  || word.last().unwrap() == "~" {
-        // To avoid recursion, this handler appends the "~" to the end of
-        // each `emit_print()` it generates so it can be caught here.
-        return Eat::None;
+fn weighted_providers(providers: &[Provider], state: &State) -> Vec<Provider> {
+    let mut guard = state.lock().unwrap();
+    let state     = &mut *guard;
+    if state.weights.is_empty() {
+        return providers.to_vec();
     }
-    let (event, ref map_udata) = user_data.apply(
-                                    |ud: &(&str, UserData)| {
-                                        (ud.0, ud.1.clone())
-                                    });
-    if let Some(chan_langs) = get_channel_langs(hc, map_udata) {
-        if {||{ // "try"
-            let sender    = word[0].clone();
-            let message   = word[1].clone();
-            let msg_type  = event;
-            let mode_char = if word.len() > 2 
-                                 { word[2].clone() } 
-                            else { "".to_string()  };
-            let src_lang  = chan_langs.0;
-            let tgt_lang  = chan_langs.1;
-            
-            let strip_msg = hc.strip(&message, StripBoth)?; // "throw"
-            let network   = hc.get_info("network")?;
-            let channel   = hc.get_info("channel")?;
-            
-            thread::spawn(move || {
-                let msg;
-                let mut emsg = None;
-                let mut is_over_limit = false;
-                
-                match google_translate_free(&strip_msg, &tgt_lang, &src_lang) {
-                    Ok(trans) => { 
-                        msg = trans;
-                    },
-                    Err(err)  => { 
-                        msg  = err.get_partial_trans().to_string();
-                        emsg = Some(fm!("{IRC_MAGENTA}{}", err));
-                        is_over_limit = err.is_over_limit();
-                    }
-                }
-                if let Err(err) = main_thread(
-                    move |hc| -> Result<(), HexchatError> {
-                        if let Some(ctx) = hc.find_context(&network, &channel) {
-                            if !mode_char.is_empty() {
-                                ctx.emit_print(
-                                    msg_type, 
-                                    &[&sender, &msg, &mode_char, "~"])?;
-                            } else {
-                                ctx.emit_print(msg_type, 
-                                               &[&sender, &msg, "~"])?;
-                            }
-                            ctx.print(&fm!("{IRC_CYAN}{}", message))?;
-                            if let Some(emsg) = &emsg { 
-                                ctx.print(emsg)?;
-                                if is_over_limit {
-                                    ctx.command("OFFLANG")?;
-                                }
-                            }
-                        } else {
-                            hc.print("Failed to get context.");
-                        }
-                        Ok(())
-                    }
-                ).get() {
-                    hc_print_th!("{IRC_MAGENTA}{}", err);
-                }
-            });
-            Some(())
-        }}().is_none() { // "catch"
-            // If we get here, either `strip()` or `get_info()` returned None.
-            hc.print(&fm!("{IRC_MAGENTA}\
-                     Translator Error: Basic failure retrieving channel \
-                     information, or unable to strip original message."));
-        }
-        Eat::Hexchat
-    } else {
-        Eat::None
+    let now     = Instant::now();
+    state.lowered_weights.retain(|_, until| *until > now);
+    let weights = providers.iter().map(|p| {
+                      let weight = state.weights.get(p).copied()
+                                        .unwrap_or(DEFAULT_WEIGHT);
+                      if state.lowered_weights.contains_key(p) {
+                          weight / LOWERED_WEIGHT_DIVISOR
+                      } else {
+                          weight
+                      }
+                  }).collect::<Vec<_>>();
+    let mut order = providers.to_vec();
+    if let Some(first) = pick_weighted(&weights, &mut state.weight_rng) {
+        let provider = order.remove(first);
+        order.insert(0, provider);
     }
+    order
 }
 
-/// Uses the free translation web service provided by Google to translate
-/// a chat text message to the desired target language.
-/// # Arguments
-/// * `text`    - The text to translate.
-/// * `source`  - The source language of the text.
-/// * `target`  - The language to translate the text to.
-/// # Returns
-/// * A result where `Ok()` contains the translated text, and `Err()` indicates
-///   the translation failed. The error will contain an aggregate of 
-///   descriptions for each problem encountered during translation.
+/// Lowers a service's weight for `LOWERED_WEIGHT_PERIOD` after it reports
+/// being over the limit, so requests go to the others while it recovers.
 ///
-fn google_translate_free(text   : &str, 
-                         source : &str, 
-                         target : &str
-                        ) -> Result<String, TranslationError> 
-{
-    // Optimizing the regex and agent using lazy_static wouldn't noticeably
-    // improve performance for the user. Plus, static resources are very hard to
-    // thoroughly clean up for when the plugin is being unloaded/reloaded.
-    let expr  = Regex::new(r".+?(?:[.?!;|]+\s+|$)").unwrap();
-    let agent = ureq::AgentBuilder::new()
-                      .timeout_read(
-                           Duration::from_secs(TRANSLATION_SERVER_TIMEOUT)
-                      ).build(); 
-                     
-    let mut translated = String::new();
-    let mut errors     = vec![];
-    let mut over_limit = false;
+fn lower_weight(provider: &Provider, state: &State) {
+    state.lock().unwrap()
+         .lowered_weights.insert(provider.clone(), 
+                                 Instant::now() + LOWERED_WEIGHT_PERIOD);
+}
 
-    // The translation service won't translate past certain punctuation, so we
-    // break the message up into parts terminated by such punctuation and
-    // treat each one as a separate translation while piecing the results 
-    // together.
-    for m in expr.find_iter(text) {
-        let sentence = m.as_str();
+/// Addon settings and per-context options that aren't part of the channel
+/// map. Unlike the channel map, this is shared with the translation threads,
+/// so it's accessed through the `State` handle.
+///
+#[derive(Debug, Default)]
+struct AddonState {
+    /// The translation direction of contexts that have one set. Contexts
+    /// not in the map translate in both directions.
+    directions: HashMap<ChanData, Direction>,
 
-        match translate_single(sentence, &agent, source, target) {
-            Ok(trans) => {
-                translated.push_str(&trans);
-            },
-            Err(err)  => {
-                use SingleTranslationError as STE;
+    /// The relay languages of contexts set with `/SETLANGRELAY`. Incoming
+    /// messages in these contexts are translated from the first language to
+    /// the second, rather than to the user's language.
+    relays: HashMap<ChanData, ChanData>,
 
-                let emsg = match err {
-                    STE::StaticError(s) => {
-                        s.to_string()
-                    },
-                    STE::DynamicError(s) => {
-                        s
-                    },
-                    STE::OverLimit(s) => {
-                        over_limit = true;
-                        s.to_string()
-                    }
-                };
-                errors.push(emsg);
-                translated.push_str(sentence);
-            },
-        }
-    }
-    if !errors.is_empty() {
-        // Error will contain the partially translated text, deduplicated
-        // error messages, and indicate if the translation limit was reached.
-        errors.sort_unstable();
-        errors.dedup();
-        Err( TranslationError::new(translated, errors.join(" "), over_limit) )
-        
-    } else {
-        // Each sentence translated went successfully.
-        Ok( translated )
-    }
+    /// Whether the reason text of part, quit, and kick messages is 
+    /// translated.
+    translate_system: bool,
+
+    /// The target languages of contexts set with `/SETLANGLIST`, with the
+    /// index of the one the next outgoing message is translated to.
+    lang_lists: HashMap<ChanData, (Vec<String>, usize)>,
+
+    /// The lowercase nicks, per context, whose messages aren't translated.
+    mutes: HashMap<ChanData, HashSet<String>>,
+
+    /// The lowercase nicks, per context, whose messages are the only ones
+    /// translated, set with `/TRANSWATCH`. A context without any translates
+    /// everyone's.
+    watches: HashMap<ChanData, HashSet<String>>,
+
+    /// The languages set with `/SETUSERLANG` that incoming messages from
+    /// particular nicks are translated from, per context, keyed by 
+    /// case-folded nick.
+    user_langs: HashMap<ChanData, HashMap<String, String>>,
+
+    /// The languages set with `/SETDISPLAYLANG` that incoming messages are
+    /// translated to, per context, instead of the source language of the 
+    /// channel.
+    display_langs: HashMap<ChanData, String>,
+
+    /// The lowercase words outgoing translations must not contain. A 
+    /// translation with one of them in it isn't sent.
+    blocked_words: HashSet<String>,
+
+    /// The language last detected in the user's messages in contexts whose
+    /// source language is `auto`. Incoming messages are translated to it.
+    detected_langs: HashMap<ChanData, String>,
+
+    /// The translation services, set with `/TRANSPROVIDER`. The first is 
+    /// used unless it fails, then each of the others in turn. Empty until 
+    /// set, which means Google only.
+    providers: Vec<Provider>,
+
+    /// The weights of services, set with `/TRANSWEIGHT`, that the first 
+    /// service tried for each request is picked by. Empty if the chain is
+    /// tried in order.
+    weights: HashMap<Provider, u32>,
+
+    /// When the lowered weights of services recently over the limit go 
+    /// back up.
+    lowered_weights: HashMap<Provider, Instant>,
+
+    /// Picks services by weight.
+    weight_rng: WeightRng,
+
+    /// Whether text is translated through English when a service can't 
+    /// translate between its languages directly, set with `/TRANSPIVOT`.
+    pivot: bool,
+
+    /// The languages a service said it supports when asked with 
+    /// `/TRANSLANGSYNC`, by our codes. They're kept for the session.
+    synced_langs: Option<(Provider, HashSet<String>)>,
+
+    /// Recent translations, so repeated messages don't need to be sent to
+    /// the translation service again.
+    cache: TranslationCache,
+
+    /// The DeepL API key, set with `/TRANSKEY`. It's only kept in memory.
+    deepl_key: Option<String>,
+
+    /// Counts of the translations made since the addon was loaded, for
+    /// `/TRANSSTATS`.
+    stats: HashMap<(String, String), TranslationStats>,
+
+    /// The characters that mark a message as a bot command, which isn't
+    /// translated. `None` if `DEFAULT_CMD_SIGILS` is used.
+    cmd_sigils: Option<String>,
+
+    /// Whether `/LSAY` prompts for languages in channels without 
+    /// translation, rather than passing the command through.
+    autoprompt: bool,
+
+    /// Messages held back by `/LSAY` or `/LME` per context, waiting for
+    /// `/TRANSRETRY`. Each holds the command, "SAY" or "ME", and the message.
+    pending: HashMap<ChanData, (&'static str, String)>,
+
+    /// The greeting sent by `/TRANSWELCOME`, set with `/TRANSWELCOME set`.
+    welcome_template: Option<String>,
+
+    /// The ratio of translated to original length above which outgoing
+    /// translations are warned about. `None` if the warning is off.
+    expand_warn_ratio: Option<f64>,
+
+    /// Whether an over-limit error is shown only once per context within
+    /// `OVER_LIMIT_ERR_WINDOW`, rather than for every message.
+    over_limit_once: bool,
+
+    /// When an over-limit error was last shown in each context.
+    over_limit_shown: HashMap<ChanData, Instant>,
+
+    /// The number of consecutive failed translations and the period they 
+    /// must happen within for a context to be turned off automatically. 
+    /// `None` if that's turned off.
+    auto_off: Option<(u32, Duration)>,
+
+    /// The run of consecutive failed translations in each context, with the
+    /// number of failures and when the run started.
+    error_runs: HashMap<ChanData, (u32, Instant)>,
+
+    /// How long translation is paused in a context after an over-limit 
+    /// error, set with `/TRANSCOOLDOWN`.
+    cooldown: Cooldown,
+
+    /// When translation resumes in each context paused after an over-limit 
+    /// error.
+    paused: HashMap<ChanData, Instant>,
+
+    /// Whether formatting at the start and end of messages, like colors and
+    /// bold, is put back around their translations.
+    keep_format: bool,
+
+    /// Whether outgoing translations are translated back to the user's 
+    /// language and shown, so their quality can be judged.
+    verify: bool,
+
+    /// Whether `/LSAY` and `/LME` send messages untranslated while the user
+    /// is marked away, set with `/TRANSAWAYPAUSE`.
+    away_pause: bool,
+
+    /// The confidence, from 0 to 1, below which the language detected for
+    /// an outgoing message is flagged, set with `/TRANSCONFIDENCE`. `None`
+    /// if detections aren't flagged.
+    min_confidence: Option<f64>,
+
+    /// Whether `/LME` actions are translated with the user's nick in front
+    /// of them, so they're conjugated for the right subject.
+    me_nick: bool,
+
+    /// Whether the language of incoming messages is detected first, so 
+    /// ones already in the user's language aren't translated.
+    skip_same: bool,
+
+    /// Whether incoming translations are shown in place of the original, 
+    /// rather than above it.
+    inline_replace: bool,
+
+    /// The contexts where the original of each translated message isn't
+    /// printed, set with `/TRANSORIG off`.
+    hidden_origs: HashSet<ChanData>,
+
+    /// Whether incoming translations are recorded in the history.
+    history_on: bool,
+
+    /// The recent incoming translations of each context.
+    history: HashMap<ChanData, VecDeque<HistoryEntry>>,
+
+    /// The languages of channels the user left or was disconnected from, 
+    /// taken out of the channel map until the user joins them again.
+    suspended: HashMap<ChanData, ChanData>,
+
+    /// The contexts translation was turned off in while their network had
+    /// default languages set with `/SETNETLANG`, so the defaults don't 
+    /// apply to them.
+    net_opt_outs: HashSet<ChanData>,
+
+    /// The sender and text of the last message received in each context, 
+    /// whether translation is on or not, for `/LTRANSLAST`.
+    last_received: HashMap<ChanData, (String, String)>,
+
+    /// The originals of the last messages translated and sent by `/LSAY`
+    /// or `/LME` in each context, most recent last, for `/TRANSUNDO`.
+    sent_origs: HashMap<ChanData, VecDeque<String>>,
+
+    /// How many times a failed request is retried, set with 
+    /// `/TRANSRETRIES`. `None` if it hasn't been set.
+    max_retries: Option<u32>,
+
+    /// The most characters a text can have to be translated, set with
+    /// `/TRANSMAXCHARS`. `None` if it hasn't been set.
+    max_chars: Option<usize>,
+
+    /// Limits the rate requests are sent to Google at, set with 
+    /// `/TRANSRATE`.
+    rate_limiter: RateLimiter,
+
+    /// The URL of the proxy requests are sent through, set with 
+    /// `/TRANSPROXY`. `None` if they're sent directly.
+    proxy: Option<String>,
+
+    /// The network agent shared by all requests, built by `get_agent()`. 
+    /// It's dropped when the proxy changes, to be built again with the new 
+    /// one.
+    agent: Option<ureq::Agent>,
 }
 
-/// Represents errors encountered when doing a single translation. This
-/// error is generated by `translate_single()`.
-/// # Variants
-/// * `StaticError`  - A predicted error with a static error message.
-/// * `DynamicError` - A freeform text error for unexpected errors.
-/// * `OverLimit`    - Indicates that the translation server sent a response
-///                    saying the user has used up all their translations
-///                    in some amount of time.
+/// The most entries kept in each context's translation history.
 ///
-#[derive(Debug, Clone)]
-enum SingleTranslationError {
-    StaticError  (&'static str),
-    DynamicError (String),
-    OverLimit    (&'static str),
-}
-impl From<&SingleTranslationError> for SingleTranslationError {
-    fn from(item: &SingleTranslationError) -> Self {
-        item.clone()
-    }
-}
+const HISTORY_SIZE: usize = 100;
 
-/// Translates a single phrase, or sentence - one without multiple clauses 
-/// separated by stop punctuation like a period.
-/// # Arguments
-/// * `sentence`    - The phrase to translate.
-/// * `agent`       - The network agent that will send the HTTPS GET.
-/// * `source`      - The source language to translate from.
-/// * `target`      - The target language to translate to.
-/// # Returns
-/// * A `Result` with either a `String` if the translation was successful; or
-///   a `SingleTranslationError` if not.
+/// The most originals of sent translations kept in each context for
+/// `/TRANSUNDO`.
 ///
-fn translate_single(sentence : &str, 
-                    agent    : &ureq::Agent,
-                    source   : &str,
-                    target   : &str
-                   ) -> Result<String, SingleTranslationError>
-{
-    use SingleTranslationError::*;
-    use serde_json::Result as SResult;
-    #[inline]
-    fn parse_json(s: &str) -> SResult<Value> {
-        serde_json::from_str::<Value>(s)
-    }
-    static ERRORS: [SingleTranslationError; 4] = [
-        StaticError("URL message escaping failed."),
-        StaticError("Failed to get response from translation server."),
-        StaticError("Failed to get text for HTTP response body."),
-        StaticError("Received invalid response format from server."),
-    ];
+const SENT_ORIGS_SIZE: usize = 5;
 
-    let escaped = urlparse::quote(sentence, b"").map_err(|_| &ERRORS[0])?;
-    let url     = fm!("https://translate.googleapis.com/\
-                      translate_a/single\
-                      ?client=gtx\
-                      &sl={source_lang}\
-                      &tl={target_lang}\
-                      &dt=t&q={source_text}",
-                      source_lang = source,
-                      target_lang = target,
-                      source_text = escaped);
-                                    
-    let tr_rsp = agent.get(&url).call()         .map_err(|_| &ERRORS[1])?;
-    
-    if tr_rsp.status_text() == "OK" {
-    
-        let rsp_txt = tr_rsp.into_string()      .map_err(|_| &ERRORS[2])?;
-        let tr_json = parse_json(&rsp_txt)      .map_err(|_| &ERRORS[3])?;
-        let trans   = tr_json[0][0][0].as_str() .ok_or  (    &ERRORS[3])?;
-        
-        let mut trans = trans.to_string();
-        
-        if sentence.ends_with(' ') {
-            trans.push(' ');
-        }
-        Ok(trans)
-        
-    } else if tr_rsp.status() == 403 {
-        Err( OverLimit("Server translation limit reached.") )
-        
-    } else {
-        Err( DynamicError(tr_rsp.status_text().to_string()) )
-    }
+/// An incoming message and its translation recorded in the translation
+/// history of a context.
+///
+#[derive(Debug, Clone)]
+struct HistoryEntry {
+    sender      : String,
+    original    : String,
+    translation : String,
+    lang        : String,
 }
 
-/// Implements the /LISTLANG command - prints out a list of all languages 
-/// that the translation web services support.
+/// Gives the language a history entry is recorded with: the one detected
+/// with the translation if there is one, or else the one the message was
+/// translated from.
+/// # Arguments
+/// * `source`    - The language the message was translated from, which may
+///                 be "auto".
+/// * `detection` - The detection returned with the translation, if any.
 ///
-#[allow(clippy::many_single_char_names)]     
-fn on_cmd_listlang(hc        : &Hexchat, 
-                   word      : &[String], 
-                   _word_eol : &[String], 
-                   _userdata : &UserData
-                  ) -> Eat 
-{
-    if word.len() == 1 {
-        hc.print("");
-        hc.print(&fm!("{IRC_CYAN}\
-                  ------------------------ Supported Languages \
-                  ------------------------"));
-        let langs = &SUPPORTED_LANGUAGES;
-        
-        for i in (0..langs.len()).step_by(3) {
-            let (a, b) = langs[i];
-            let (c, d) = langs[i + 1];
-            let (e, f) = langs[i + 2];
-            hc.print(
-                &fm!("{IRC_CYAN}{:-15}{:3}        {:-15}{:3}        {:-15}{:3}", 
-                         a, b, c, d, e, f));
-        }
-        hc.print("");
-    } else {
-        hc.print("USAGE: ");
-    }
-    Eat::All
+fn history_lang(source: &str, detection: Option<Detection>) -> String {
+    detection.map_or_else(|| source.to_string(), |(lang, _)| lang)
 }
 
-/// Finds and gives back a tuple (<long-name>, <abbrev>) from the supported 
-/// languages list. This can be used to verify the languages the user requested
-/// to see if they exist and can be used to interact with translation services.
-/// # Arguments
-/// * `lang` - This can be the name of the langauge, or the two character code
-///            for the language.
-/// # Returns
-/// * If a match is found, a tuple is returned from the `SUPPORTED_LANGUAGES`
-///   array. It will have the long name for the language and its two character
-///   code. 
+/// Records an entry in a context's translation history, evicting the oldest
+/// entry if the history is full.
 ///
-fn find_lang(lang: &str) -> Option<&(&str, &str)> {
-    let lang = lang.to_lowercase();
-    #[allow(clippy::manual_find)]
-    for lang_info in &SUPPORTED_LANGUAGES {
-        if lang == lang_info.0.to_lowercase() || lang == lang_info.1 {
-            return Some(lang_info);
-        }
+fn push_history(history: &mut VecDeque<HistoryEntry>, entry: HistoryEntry) {
+    if history.len() >= HISTORY_SIZE {
+        history.pop_front();
     }
-    None
+    history.push_back(entry);
 }
 
-/// Translation error. The error object will contain either a mix of translated
-/// and untranslated messages - if some succeeded and some didn't. Or, just
-/// untranslated text accessible from `get_partial_trans()`. The display
-/// of the error will be an accumulated set of each unique error that occurred
-/// during the translation. If the server indicated the user is over their
-/// translation limit, `is_over-limit()` will reflect that.
+/// Returns up to the last `n` entries of a context's history, oldest first.
 ///
-#[derive(Debug)]
-struct TranslationError {
-    partial_trans : String,
-    error_msg     : String,
-    over_limit    : bool,
+fn recent_history(history: &VecDeque<HistoryEntry>, 
+                  n       : usize
+                 ) -> impl Iterator<Item = &HistoryEntry> 
+{
+    history.iter().skip(history.len().saturating_sub(n))
 }
 
-impl TranslationError {
-    /// Constructs the translation error.
-    /// # Arguments
-    /// * `partial_trans`   - Translated and untranslated portions of the 
-    ///                       original text.
-    /// * `error_msg`       - The aggregate of error messages that occurred
-    ///                       during the translation.
-    /// * `over_limit`      - A bool indicating whether the server responded
-    ///                       with a 403 error.
-    ///
-    fn new(partial_trans: String, error_msg: String, over_limit: bool) -> Self {
-        TranslationError { partial_trans, error_msg, over_limit }
-    }
-    
-    /// Returns the parts of translated and untranslated text - in the same
-    /// order as the original text.
+impl AddonState {
+    /// Drops everything kept for a context when its translation is turned
+    /// off, so toggling translation doesn't leave stale settings behind. A
+    /// message held for `/TRANSRETRY` is kept, since it's only held while
+    /// the context is off.
     ///
-    fn get_partial_trans(&self) -> &str {
-        &self.partial_trans
+    fn forget_context(&mut self, key: &ChanData) {
+        self.directions.remove(key);
+        self.relays.remove(key);
+        self.lang_lists.remove(key);
+        self.detected_langs.remove(key);
+        self.mutes.remove(key);
+        self.watches.remove(key);
+        self.user_langs.remove(key);
+        self.display_langs.remove(key);
+        self.hidden_origs.remove(key);
+        self.over_limit_shown.remove(key);
+        self.error_runs.remove(key);
+        self.paused.remove(key);
+        self.history.remove(key);
+        self.sent_origs.remove(key);
+        self.suspended.remove(key);
     }
-    
-    /// Indicates whether the translator server responded with a 403 error
-    /// which means the number of translations per given span of time has 
-    /// been exceeded.
+
+    /// Drops everything kept for every context, as `forget_context()` does
+    /// for one.
     ///
-    fn is_over_limit(&self) -> bool {
-        self.over_limit
+    fn forget_all_contexts(&mut self) {
+        self.net_opt_outs.clear();
+        self.directions.clear();
+        self.relays.clear();
+        self.lang_lists.clear();
+        self.detected_langs.clear();
+        self.mutes.clear();
+        self.watches.clear();
+        self.user_langs.clear();
+        self.display_langs.clear();
+        self.hidden_origs.clear();
+        self.over_limit_shown.clear();
+        self.error_runs.clear();
+        self.paused.clear();
+        self.history.clear();
+        self.sent_origs.clear();
+        self.suspended.clear();
     }
 }
 
-impl Error for TranslationError {
-    /*
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        if let Some(err) = &self.source_error {
-            Some(err.as_ref())
-        } else { None }
+/// How long translation is paused in a context after an over-limit error,
+/// or `None` if translation is turned off instead. Pausing is on by default,
+/// for `DEFAULT_COOLDOWN`, since the server's limit clears up by itself.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cooldown(Option<Duration>);
+
+impl Default for Cooldown {
+    fn default() -> Self {
+        Cooldown(Some(DEFAULT_COOLDOWN))
     }
-    */
 }
 
-impl fmt::Display for TranslationError {
+/// How long translation is paused after an over-limit error unless another
+/// time is set with `/TRANSCOOLDOWN`.
+///
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(60);
 
-    /// Displays the aggregate of error messages that occurred during the 
-    /// translation.
-    ///
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Translation Error: {}", self.error_msg)
-    }
+/// The characters that mark a message as a bot command unless others are
+/// set with `/TRANSCMDSIGIL`. Only "!", since "@nick" and ".5" often start
+/// ordinary messages.
+///
+const DEFAULT_CMD_SIGILS: &str = "!";
+
+/// Returns the characters that mark a message as a bot command.
+///
+fn get_cmd_sigils(state: &State) -> String {
+    state.lock().unwrap().cmd_sigils.clone()
+         .unwrap_or_else(|| DEFAULT_CMD_SIGILS.to_string())
 }
 
+/// How many times a request that failed with a network error or a server
+/// error is retried unless another count is set with `/TRANSRETRIES`.
+///
+const DEFAULT_MAX_RETRIES: u32 = 3;
 
-/// Help strings printed when the user requests /HELP on any of the commands 
-/// this addon provides.
+/// The most retries `/TRANSRETRIES` accepts. With the backoff doubling each
+/// time, more would hold up a message for too long.
+///
+const MAX_RETRIES_LIMIT: u32 = 6;
 
-const LISTLANG_HELP: &str = "/LISTLANG - Lists languages supported and \
-                             their abbrevations. This command takes no \
-                             parameters.";
-                             
-const SETLANG_HELP : &str = "/SETLANG <src> <tgt> - Sets source and target \
-                             languages for the channel.";
-                             
-const OFFLANG_HELP : &str = "/OFFLANG - Deactivates translation on the \
-                             channel. This command takes no paramters.";
+/// The delay before the first retry of a failed request. It doubles with
+/// each retry after that.
+///
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// Returns how many times a failed request is retried.
+///
+fn get_max_retries(state: &State) -> u32 {
+    state.lock().unwrap().max_retries.unwrap_or(DEFAULT_MAX_RETRIES)
+}
+
+/// The most characters a text can have to be translated unless another
+/// limit is set with `/TRANSMAXCHARS`. Longer texts, like a huge paste, 
+/// would make requests too long for the server and use up translations.
+///
+const DEFAULT_MAX_CHARS: usize = 2000;
+
+/// The highest limit `/TRANSMAXCHARS` accepts.
+///
+const MAX_CHARS_LIMIT: usize = 20000;
+
+/// Returns the most characters a text can have to be translated.
+///
+fn get_max_chars(state: &State) -> usize {
+    state.lock().unwrap().max_chars.unwrap_or(DEFAULT_MAX_CHARS)
+}
+
+/// How many requests a minute are sent to Google unless another rate is set
+/// with `/TRANSRATE`.
+///
+const DEFAULT_RATE_LIMIT: u32 = 60;
+
+/// A token bucket that limits the requests sent to Google. It holds up to a
+/// minute's worth of tokens, refilled continuously, so short bursts go out
+/// right away, but a long run of them fails with an error rather than 
+/// getting the user over-limited.
+///
+#[derive(Debug)]
+struct RateLimiter {
+    per_minute : Option<u32>,
+    tokens     : f64,
+    refilled   : Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter {
+            per_minute : Some(DEFAULT_RATE_LIMIT),
+            tokens     : DEFAULT_RATE_LIMIT as f64,
+            refilled   : Instant::now(),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Sets the number of requests allowed a minute, or `None` for no 
+    /// limit. The bucket starts out full.
+    ///
+    fn set_rate(&mut self, per_minute: Option<u32>) {
+        self.per_minute = per_minute;
+        self.tokens     = per_minute.unwrap_or(0) as f64;
+        self.refilled   = Instant::now();
+    }
+
+    /// Adds the tokens earned since the last refill, up to the bucket's
+    /// capacity.
+    ///
+    fn refill(&mut self) {
+        if let Some(rate) = self.per_minute {
+            let now     = Instant::now();
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64 / 60.)
+                              .min(rate as f64);
+            self.refilled = now;
+        }
+    }
+
+    /// Takes a token for a request.
+    /// # Returns
+    /// * Whether a token was taken, and the request can be sent. With the
+    ///   bucket empty, it can't, and nothing is taken. Requests don't wait 
+    ///   for a token, since they're sent from the translation threads, and
+    ///   waiting would hold up every translation behind them.
+    ///
+    fn acquire(&mut self) -> bool {
+        if self.per_minute.is_none() {
+            return true;
+        }
+        self.refill();
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns how long until the next token comes in, or zero if there's
+    /// one now or no limit.
+    ///
+    fn next_token(&mut self) -> Duration {
+        let Some(rate) = self.per_minute else {
+            return Duration::ZERO;
+        };
+        self.refill();
+        let missing = (1. - self.tokens).max(0.);
+        Duration::from_secs_f64(missing * 60. / rate as f64)
+    }
+
+    /// Returns the number of requests that can be sent right away, or 
+    /// `None` if there's no limit.
+    ///
+    fn remaining(&mut self) -> Option<u32> {
+        self.per_minute?;
+        self.refill();
+        Some(self.tokens.max(0.) as u32)
+    }
+}
+
+/// The error for a request over the `/TRANSRATE` limit.
+///
+const RATE_LIMITED_MSG: &str = "The /TRANSRATE limit was reached, so the \
+                                request wasn't sent. Try again in a moment.";
+
+/// Checks the rate limit before a request to Google, without waiting.
+/// # Returns
+/// * Whether the request can be sent. It can't while the limit is used up;
+///   it fails with `RATE_LIMITED_MSG` then.
+///
+fn throttle(state: &State) -> bool {
+    state.lock().unwrap().rate_limiter.acquire()
+}
+
+/// Determines whether a message is a command to a bot, like "!weather 
+/// London" or "@bot help", rather than prose. It is if it starts with one
+/// of the sigils immediately followed by a letter or digit, so "..." and 
+/// "! " don't count.
+/// # Arguments
+/// * `msg`    - The message.
+/// * `sigils` - The characters that can start a bot command.
+///
+fn is_bot_command(msg: &str, sigils: &str) -> bool {
+    let mut chars = msg.trim_start().chars();
+    match (chars.next(), chars.next()) {
+        (Some(sigil), Some(next)) => {
+            sigils.contains(sigil) && next.is_alphanumeric()
+        },
+        _ => false,
+    }
+}
+
+/// The most translations kept in the `TranslationCache`.
+///
+const CACHE_SIZE: usize = 500;
+
+/// The key of a cached translation: (source-lang, target-lang, text).
+///
+type CacheKey = (String, String, String);
+
+/// Normalizes the text of a cache key, so texts that only differ in the 
+/// whitespace around and between their words have the same key. Case is
+/// kept, since words like "US" and "us" translate differently.
+///
+fn normalize_key((source, target, text): CacheKey) -> CacheKey {
+    (source, target, text.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// Gives the text with the case of its first character flipped, or `None`
+/// if it doesn't start with a letter. A message is often the same as 
+/// another but for a capital at the start.
+///
+fn flip_first_case(text: &str) -> Option<String> {
+    let first   = text.chars().next().filter(|c| c.is_alphabetic())?;
+    let flipped = if first.is_uppercase() { first.to_lowercase().to_string() }
+                  else                    { first.to_uppercase().to_string() };
+    if flipped == first.to_string() {
+        return None;
+    }
+    Some(fm!("{}{}", flipped, &text[first.len_utf8()..]))
+}
+
+/// Indicates whether the first letter of the text is uppercase, or `None` 
+/// if it has no letters.
+///
+fn starts_upper(text: &str) -> Option<bool> {
+    text.chars().find(|c| c.is_alphabetic()).map(|c| c.is_uppercase())
+}
+
+/// Fits a cached translation to a text that shares its cache key, or only
+/// differs from its original in the case of the first letter. The text's
+/// leading and trailing whitespace is put around the translation, and if
+/// the first letter's case differs from the original's, the translation's
+/// first letter is changed to match.
+/// # Arguments
+/// * `trans` - The cached translation.
+/// * `orig`  - The text the translation was made from.
+/// * `text`  - The text being translated.
+///
+fn match_form(trans: &str, orig: &str, text: &str) -> String {
+    if trans.is_empty() || orig == text {
+        return trans.to_string();
+    }
+    let body = trans.trim();
+    let body = match (starts_upper(text), starts_upper(body)) {
+        (Some(upper), Some(body_upper)) 
+            if upper != body_upper 
+               && starts_upper(text) != starts_upper(orig) => {
+            let pos = body.find(|c: char| c.is_alphabetic()).unwrap();
+            let ch  = body[pos..].chars().next().unwrap();
+            let new = if upper { ch.to_uppercase().to_string() } 
+                      else     { ch.to_lowercase().to_string() };
+            fm!("{}{}{}", &body[..pos], new, &body[pos + ch.len_utf8()..])
+        },
+        _ => body.to_string(),
+    };
+    let leading  = &text[..text.len() - text.trim_start().len()];
+    let trailing = &text[text.trim_end().len()..];
+    fm!("{}{}{}", leading, body, trailing)
+}
+
+/// A cache of recent translations. When it's full, the least recently used
+/// translation is evicted to make room for a new one. Texts are looked up by
+/// their normalized form, so ones that only differ in spacing share a 
+/// translation, and if that misses, with the case of their first letter 
+/// flipped. The original each translation was made from is kept with it, 
+/// so it can be fitted to the text it's looked up with.
+///
+#[derive(Debug, Default)]
+struct TranslationCache {
+    map   : HashMap<CacheKey, (String, String)>,
+    order : VecDeque<CacheKey>,
+}
+
+impl TranslationCache {
+    /// Looks up a translation, marking it as the most recently used. If it
+    /// was made from a text spaced differently, or with its first letter in
+    /// another case, it's fitted to this one with `match_form()`.
+    ///
+    fn get(&mut self, key: &CacheKey) -> Option<String> {
+        let text = &key.2;
+        let key  = normalize_key(key.clone());
+        let key  = if self.map.contains_key(&key) {
+                       key
+                   } else {
+                       let (src, tgt, text) = key;
+                       (src, tgt, flip_first_case(&text)?)
+                   };
+        let (orig, trans) = self.map.get(&key)?;
+        let trans         = match_form(trans, orig, text);
+        self.touch(&key);
+        Some(trans)
+    }
+
+    /// Adds a translation, evicting the least recently used one if the cache
+    /// is full.
+    ///
+    fn put(&mut self, key: CacheKey, trans: String) {
+        let orig = key.2.clone();
+        let key  = normalize_key(key);
+        if self.map.insert(key.clone(), (orig, trans)).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > CACHE_SIZE {
+            if let Some(old) = self.order.pop_front() {
+                self.map.remove(&old);
+            }
+        }
+    }
+
+    /// Moves a key to the most recently used end of the order.
+    ///
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            if let Some(k) = self.order.remove(pos) {
+                self.order.push_back(k);
+            }
+        }
+    }
+
+    /// Empties the cache.
+    ///
+    fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+    }
+
+    /// Removes the translations between two languages, in either direction.
+    /// # Returns
+    /// * The number of translations removed.
+    ///
+    fn remove_pair(&mut self, lang_a: &str, lang_b: &str) -> usize {
+        let matches = |(src, tgt, _): &CacheKey| {
+            (src == lang_a && tgt == lang_b) || (src == lang_b && tgt == lang_a)
+        };
+        let before = self.map.len();
+        self.map.retain(|key, _| !matches(key));
+        self.order.retain(|key| !matches(key));
+        before - self.map.len()
+    }
+}
+
+/// Counts of the sentences sent for translation between a pair of languages.
+/// Cached translations aren't counted, since they're not sent.
+///
+#[derive(Debug, Default, Clone, Copy)]
+struct TranslationStats {
+    translated : u64,
+    over_limit : u64,
+    errors     : u64,
+    chars      : u64,
+    pivoted    : u64,
+}
+
+impl TranslationStats {
+    /// Adds the counts of another set of stats to these.
+    ///
+    fn add(&mut self, other: &TranslationStats) {
+        self.translated += other.translated;
+        self.over_limit += other.over_limit;
+        self.errors     += other.errors;
+        self.chars      += other.chars;
+        self.pivoted    += other.pivoted;
+    }
+}
+
+impl fmt::Display for TranslationStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} translated ({} characters), {} over limit, {} other \
+                   errors", self.translated, self.chars, self.over_limit, 
+                            self.errors)?;
+        if self.pivoted > 0 {
+            write!(f, ", {} through English", self.pivoted)?;
+        }
+        Ok(())
+    }
+}
+
+/// A thread-safe handle to the `AddonState`.
+///
+type State = Arc<Mutex<AddonState>>;
+
+/// Called when the plugin is loaded to register it with Hexchat.
+///
+fn plugin_info() -> PluginInfo {
+    PluginInfo::new(
+        "Language Translator",
+        env!("CARGO_PKG_VERSION"),
+        "Instantly translated conversation in over 100 languages.")
+}
+
+/// Called when the plugin is loaded.
+///
+fn plugin_init(hc: &Hexchat) -> i32 {
+
+    hc.print("Language Translator loaded");
+
+    for problem in validate_language_table(&SUPPORTED_LANGUAGES,
+                                           &LANGUAGE_ALIASES,
+                                           &REGIONAL_VARIANTS) {
+        hc.print(&fm!("{ERROR_COLOR}Language table problem: {}", problem));
+    }
+    
+    // `map_udata` holds a `HashMap` that maps contexts, `(network, channel)`, 
+    // to chosen translation, `(source_lang, target_lang)`. 
+    let map_udata  = UserData::shared(HashMap::<ChanData, ChanData>::new());
+    
+    // `state` holds the rest of the addon's settings, and is accessible from
+    // the translation threads.
+    let state      = State::default();
+
+    // Translations are run on a fixed number of worker threads.
+    pool::start(pool::WORKER_COUNT);
+    
+    let lsay_udata = UserData::boxed(("SAY", map_udata.clone(), state.clone()));
+    let lme_udata  = UserData::boxed(("ME", map_udata.clone(), state.clone()));
+    
+    // Register the commands.
+    
+    hc.hook_command(
+        "LISTLANG", Priority::Norm, on_cmd_listlang, LISTLANG_HELP, 
+                                                     UserData::boxed(
+                                                         state.clone()));
+        
+    hc.hook_command(
+        "SETLANG", Priority::Norm, on_cmd_setlang,   SETLANG_HELP, 
+                                                     UserData::boxed(
+                                                         (map_udata.clone(),
+                                                          state.clone())));
+    hc.hook_command(
+        "SETNETLANG", Priority::Norm, on_cmd_setnetlang, SETNETLANG_HELP, 
+                                                         UserData::boxed(
+                                                             (map_udata.clone(),
+                                                              state.clone())));
+    hc.hook_command(
+        "OFFLANG", Priority::Norm, on_cmd_offlang,   OFFLANG_HELP, 
+                                                     UserData::boxed(
+                                                         (map_udata.clone(),
+                                                          state.clone())));
+    hc.hook_command(
+        "LSAY",    Priority::Norm, on_cmd_lsay,      LSAY_HELP,    lsay_udata);
+
+    hc.hook_command(
+        "LME",     Priority::Norm, on_cmd_lsay,      LME_HELP,     lme_udata);
+
+    hc.hook_command(
+        "TRANSWELCOME", Priority::Norm, on_cmd_transwelcome, 
+                                        TRANSWELCOME_HELP,
+                                        UserData::boxed((map_udata.clone(),
+                                                         state.clone())));
+
+    hc.hook_command(
+        "LTRANS",  Priority::Norm, on_cmd_ltrans,    LTRANS_HELP,
+                                                     UserData::boxed(
+                                                         state.clone()));
+
+    hc.hook_command(
+        "LTRANSLAST", Priority::Norm, on_cmd_ltranslast, LTRANSLAST_HELP,
+                                                         UserData::boxed(
+                                                             (map_udata.clone(),
+                                                              state.clone())));
+
+    hc.hook_command(
+        "LDEF", Priority::Norm, on_cmd_ldef, LDEF_HELP, 
+                                             UserData::boxed(
+                                                 (map_udata.clone(),
+                                                  state.clone())));
+
+    hc.hook_command(
+        "LTOPIC",  Priority::Norm, on_cmd_ltopic,    LTOPIC_HELP,
+                                                     UserData::boxed(
+                                                         (map_udata.clone(),
+                                                          state.clone())));
+
+    hc.hook_command(
+        "LEDIT",   Priority::Norm, on_cmd_ledit,     LEDIT_HELP,
+                                                     UserData::boxed(
+                                                         (map_udata.clone(),
+                                                          state.clone())));
+
+    hc.hook_command(
+        "LPREVIEW", Priority::Norm, on_cmd_lpreview, LPREVIEW_HELP,
+                                                     UserData::boxed(
+                                                         (map_udata.clone(),
+                                                          state.clone())));
+
+    hc.hook_command(
+        "LSAYBLOCK", Priority::Norm, on_cmd_lsayblock, LSAYBLOCK_HELP,
+                                                       UserData::boxed(
+                                                           (map_udata.clone(),
+                                                            state.clone())));
+
+    hc.hook_command(
+        "TRANSRETRY", Priority::Norm, on_cmd_transretry, TRANSRETRY_HELP,
+                                                         UserData::boxed(
+                                                             (map_udata.clone(),
+                                                              state.clone())));
+
+    hc.hook_command(
+        "TRANSUNDO", Priority::Norm, on_cmd_transundo, TRANSUNDO_HELP,
+                                                       UserData::boxed(
+                                                           state.clone()));
+
+    hc.hook_command(
+        "TRANSAUTOPROMPT", Priority::Norm, on_cmd_transautoprompt, 
+                                           TRANSAUTOPROMPT_HELP,
+                                           UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSCOOLDOWN", Priority::Norm, on_cmd_transcooldown, 
+                                         TRANSCOOLDOWN_HELP,
+                                         UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSPAUSE", Priority::Norm, on_cmd_transpause, TRANSPAUSE_HELP, 
+                                                         NoData);
+    hc.hook_command(
+        "TRANSRESUME", Priority::Norm, on_cmd_transresume, TRANSRESUME_HELP,
+                                                           NoData);
+
+    hc.hook_command(
+        "TRANSAUTOOFF", Priority::Norm, on_cmd_transautooff, 
+                                        TRANSAUTOOFF_HELP,
+                                        UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSSKIPSAME", Priority::Norm, on_cmd_transskipsame, 
+                                         TRANSSKIPSAME_HELP,
+                                         UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSMENICK", Priority::Norm, on_cmd_transmenick, TRANSMENICK_HELP,
+                                                           UserData::boxed(
+                                                               state.clone()));
+
+    hc.hook_command(
+        "TRANSCOLOR", Priority::Norm, on_cmd_transcolor, TRANSCOLOR_HELP,
+                                                         NoData);
+
+    hc.hook_command(
+        "TRANSVERIFY", Priority::Norm, on_cmd_transverify, TRANSVERIFY_HELP,
+                                                           UserData::boxed(
+                                                               state.clone()));
+
+    hc.hook_command(
+        "TRANSAWAYPAUSE", Priority::Norm, on_cmd_transawaypause, 
+                                          TRANSAWAYPAUSE_HELP,
+                                          UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSCONFIDENCE", Priority::Norm, on_cmd_transconfidence, 
+                                           TRANSCONFIDENCE_HELP,
+                                           UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSFORMAT", Priority::Norm, on_cmd_transformat, TRANSFORMAT_HELP,
+                                                           UserData::boxed(
+                                                               state.clone()));
+
+    hc.hook_command(
+        "TRANSORIG", Priority::Norm, on_cmd_transorig, TRANSORIG_HELP,
+                                                       UserData::boxed(
+                                                           state.clone()));
+
+    hc.hook_command(
+        "TRANSINLINE", Priority::Norm, on_cmd_transinline, TRANSINLINE_HELP,
+                                                           UserData::boxed(
+                                                               state.clone()));
+
+    hc.hook_command(
+        "TRANSERRRATE", Priority::Norm, on_cmd_transerrrate, 
+                                        TRANSERRRATE_HELP,
+                                        UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSRETRIES", Priority::Norm, on_cmd_transretries, 
+                                        TRANSRETRIES_HELP,
+                                        UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSMAXCHARS", Priority::Norm, on_cmd_transmaxchars, 
+                                         TRANSMAXCHARS_HELP,
+                                         UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSRATE", Priority::Norm, on_cmd_transrate, TRANSRATE_HELP,
+                                                       UserData::boxed(
+                                                           state.clone()));
+
+    hc.hook_command(
+        "TRANSEXPANDWARN", Priority::Norm, on_cmd_transexpandwarn, 
+                                           TRANSEXPANDWARN_HELP,
+                                           UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "SETLANGLIST", Priority::Norm, on_cmd_setlanglist, 
+                                       SETLANGLIST_HELP,
+                                       UserData::boxed((map_udata.clone(),
+                                                        state.clone())));
+
+    hc.hook_command(
+        "SETLANGRELAY", Priority::Norm, on_cmd_setlangrelay, 
+                                        SETLANGRELAY_HELP,
+                                        UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "SETUSERLANG", Priority::Norm, on_cmd_setuserlang, 
+                                       SETUSERLANG_HELP,
+                                       UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "SETDISPLAYLANG", Priority::Norm, on_cmd_setdisplaylang, 
+                                          SETDISPLAYLANG_HELP,
+                                          UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSDIR", Priority::Norm, on_cmd_transdir, TRANSDIR_HELP, 
+                                                     UserData::boxed(
+                                                         state.clone()));
+
+    hc.hook_command(
+        "TRANSDETECT", Priority::Norm, on_cmd_transdetect, TRANSDETECT_HELP,
+                                                           UserData::boxed(
+                                                               state.clone()));
+
+    hc.hook_command(
+        "TRANSTEST", Priority::Norm, on_cmd_transtest, TRANSTEST_HELP,
+                                                       UserData::boxed(
+                                                           state.clone()));
+
+    hc.hook_command(
+        "TRANSPROXY", Priority::Norm, on_cmd_transproxy, TRANSPROXY_HELP,
+                                                         UserData::boxed(
+                                                             state.clone()));
+
+    hc.hook_command(
+        "TRANSCMDSIGIL", Priority::Norm, on_cmd_transcmdsigil, 
+                                         TRANSCMDSIGIL_HELP,
+                                         UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSBLOCK", Priority::Norm, on_cmd_transblock, TRANSBLOCK_HELP,
+                                                         UserData::boxed(
+                                                             state.clone()));
+
+    hc.hook_command(
+        "TRANSKEY", Priority::Norm, on_cmd_transkey, TRANSKEY_HELP,
+                                                     UserData::boxed(
+                                                         state.clone()));
+
+    hc.hook_command(
+        "TRANSPROVIDER", Priority::Norm, on_cmd_transprovider, 
+                                         TRANSPROVIDER_HELP,
+                                         UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSWEIGHT", Priority::Norm, on_cmd_transweight, 
+                                       TRANSWEIGHT_HELP,
+                                       UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSPIVOT", Priority::Norm, on_cmd_transpivot, TRANSPIVOT_HELP,
+                                                         UserData::boxed(
+                                                             state.clone()));
+
+    hc.hook_command(
+        "TRANSLANGSYNC", Priority::Norm, on_cmd_translangsync, 
+                                         TRANSLANGSYNC_HELP,
+                                         UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSREQ", Priority::Norm, on_cmd_transreq, TRANSREQ_HELP, 
+                                                     UserData::boxed(
+                                                         state.clone()));
+
+    hc.hook_command(
+        "TRANSMUTE", Priority::Norm, on_cmd_transmute, TRANSMUTE_HELP,
+                                                       UserData::boxed(
+                                                           state.clone()));
+
+    hc.hook_command(
+        "TRANSWATCH", Priority::Norm, on_cmd_transwatch, TRANSWATCH_HELP,
+                                                         UserData::boxed(
+                                                             state.clone()));
+
+    hc.hook_command(
+        "TRANSSTATS", Priority::Norm, on_cmd_transstats, TRANSSTATS_HELP,
+                                                         UserData::boxed(
+                                                             state.clone()));
+
+    hc.hook_command(
+        "TRANSCACHE", Priority::Norm, on_cmd_transcache, TRANSCACHE_HELP,
+                                                         UserData::boxed(
+                                                             (map_udata.clone(),
+                                                              state.clone())));
+
+    hc.hook_command(
+        "TRANSHISTORY", Priority::Norm, on_cmd_transhistory, 
+                                        TRANSHISTORY_HELP,
+                                        UserData::boxed(state.clone()));
+
+    hc.hook_command(
+        "TRANSCTL", Priority::Norm, on_cmd_transctl, TRANSCTL_HELP, NoData);
+
+    hc.hook_command(
+        "TRANSEXPORT", Priority::Norm, on_cmd_transexport, 
+                                       TRANSEXPORT_HELP,
+                                       UserData::boxed((map_udata.clone(),
+                                                        state.clone())));
+    hc.hook_command(
+        "TRANSIMPORT", Priority::Norm, on_cmd_transimport, 
+                                       TRANSIMPORT_HELP,
+                                       UserData::boxed((map_udata.clone(),
+                                                        state.clone())));
+
+    hc.hook_command(
+        "TRANSSYSTEM", Priority::Norm, on_cmd_transsystem, TRANSSYSTEM_HELP,
+                                                           UserData::boxed(
+                                                               state.clone()));
+
+
+    // Register the handler for all the interesting text events.
+    
+    for event in &["Channel Message", "Channel Msg Hilight", 
+                   "Channel Action",  "Channel Action Hilight", 
+                   "Private Message", "Private Message to Dialog",
+                   "Private Action",  "Private Action to Dialog",
+                   "Notice",          "Channel Notice"] 
+    {
+        let event_udata = UserData::boxed((*event, map_udata.clone(), 
+                                           state.clone()));
+        
+        hc.hook_print(event, Priority::Norm, on_recv_message, event_udata);
+    }
+
+    // Register the handlers that suspend translation in the channels the 
+    // user leaves, and turn it back on when they're rejoined.
+
+    for event in &["You Part", "You Part with Reason", "Disconnected"] {
+        let event_udata = UserData::boxed((*event, map_udata.clone(), 
+                                           state.clone()));
+        
+        hc.hook_print(event, Priority::Norm, on_you_leave, event_udata);
+    }
+    hc.hook_print("You Join", Priority::Norm, on_you_join, 
+                  UserData::boxed((map_udata.clone(), state.clone())));
+
+    // Register the handler that completes language names with TAB.
+
+    hc.hook_print("Key Press", Priority::Norm, on_key_press, NoData);
+
+    // Register the handler for the system events with reason text, and the
+    // topic events.
+
+    for (event, _) in SYSTEM_EVENTS.iter().chain(&TOPIC_EVENTS) {
+        let event_udata = UserData::boxed((*event, map_udata.clone(), 
+                                           state.clone()));
+        
+        hc.hook_print(event, Priority::Norm, on_recv_system, event_udata);
+    }
+
+    1
+}
+
+/// Called when the plugin is unloaded.
+///
+fn plugin_deinit(hc: &Hexchat) -> i32 {
+    control::stop();
+    let dropped = pool::stop();
+    if dropped > 0 {
+        hc.print(&fm!("{ERROR_COLOR}{} outgoing messages were still being \
+                       translated, and weren't sent.", dropped));
+    }
+    ALL_PAUSED.store(false, Ordering::SeqCst);
+    ORIG_COLOR.reset();
+    ERROR_COLOR.reset();
+    hc.print("Language Translator unloaded");
+    1
+}
+
+
+/// Waits for a task sent to the main thread with `main_thread()`. Like 
+/// `control::dispatch()`, it polls rather than blocking on `get()`, and 
+/// gives up if the worker pool is being stopped, since the main thread 
+/// doing that won't run the task.
+///
+fn wait_main<T>(result: AsyncResult<T>) -> Result<T, HexchatError> 
+where
+    T: Clone + Send,
+{
+    while !result.is_done() {
+        if pool::stopping() {
+            return Err( HexchatError::ThreadSafeOperationFailed(
+                            "The addon is unloading.".to_string()) );
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    result.get()
+}
+
+/// Returns Option((sourcelang, targetlang)) for the window receiving
+/// an event. If the window has no entry in the map, the default languages 
+/// of its network are returned, unless translation was turned off in the
+/// window. If there's neither, or there's a problem accessing the map, 
+/// `None` is returned.
+/// # Arguments
+/// * `hc`        - The Hexchat interface.
+/// * `map_udata` - The user data of the invoking command.
+/// * `state`     - The addon state, which holds the windows turned off.
+/// # Returns
+/// * Returns the channel data for the current context. This is obtained from
+///   the `HashMap` that maps contexts to the source and dest languages.
+///   If a context hasn't been set up for transation, `None` is returned.
+///
+fn get_channel_langs(hc        : &Hexchat, 
+                     map_udata : &UserData,
+                     state     : &State) -> Option<ChanData> 
+{
+    let key      = get_chan_key(hc)?;
+    let opt_outs = state.lock().unwrap().net_opt_outs.clone();
+    map_udata.apply(
+        |chan_map: &ChanMap| {
+            lookup_langs(chan_map, &opt_outs, &key)
+        })
+}
+
+/// Looks up the languages of a context in the channel map: its own, or else
+/// its network's defaults, unless it's opted out of those.
+/// # Arguments
+/// * `chan_map` - The channel map.
+/// * `opt_outs` - The contexts translation was turned off in while their 
+///                network had default languages.
+/// * `key`      - The context's key.
+///
+fn lookup_langs(chan_map : &ChanMap, 
+                opt_outs : &HashSet<ChanData>,
+                key      : &ChanData
+               ) -> Option<ChanData> 
+{
+    chan_map.get(key)
+            .or_else(|| {
+                if opt_outs.contains(key) {
+                    None
+                } else {
+                    chan_map.get(&net_key(&key.0))
+                }
+            })
+            .cloned()
+}
+
+/// Builds the channel map key that a network's default languages are kept 
+/// under.
+///
+fn net_key(network: &str) -> ChanData {
+    (network.to_string(), NET_DEFAULT_CHANNEL.to_string())
+}
+
+/// The source language given to /SETLANG to have the user's language 
+/// detected from each message they send.
+///
+const AUTO_LANG: (&str, &str) = ("Auto-detected", "auto");
+
+/// The language incoming messages are translated to in a context whose 
+/// source language is `auto`, until the user's language has been detected.
+///
+const DEFAULT_AUTO_REPLY_LANG: &str = "en";
+
+/// Fills in the user's language for a context whose source language is 
+/// `auto`, so incoming messages are translated to the language last detected
+/// in the user's messages.
+/// # Arguments
+/// * `hc`         - The Hexchat interface.
+/// * `state`      - The addon state.
+/// * `chan_langs` - The languages configured for the context.
+/// # Returns
+/// * The languages to translate incoming messages with.
+///
+fn resolve_auto_lang(hc         : &Hexchat, 
+                     state      : &State, 
+                     chan_langs : ChanData
+                    ) -> ChanData 
+{
+    if chan_langs.0 != AUTO_LANG.1 {
+        return chan_langs;
+    }
+    let detected = get_chan_key(hc).and_then(|key| {
+        state.lock().unwrap().detected_langs.get(&key).cloned()
+    });
+    (detected.unwrap_or_else(|| DEFAULT_AUTO_REPLY_LANG.to_string()), 
+     chan_langs.1)
+}
+
+/// Returns the `(network, channel)` key of the current context, or `None` if
+/// that information isn't available. The channel name is case-folded so 
+/// that keys match however the server or user capitalizes the channel.
+///
+fn get_chan_key(hc: &Hexchat) -> Option<ChanData> {
+    let network = get_network(hc)?;
+    let channel = hc.get_info("channel")?;
+    Some(chan_key(&network, &channel))
+}
+
+/// Returns the name of the current context's network. Server tabs, and
+/// contexts of a connection that's still being set up, may not have one
+/// yet. For those the server's name is used instead, so they can still be
+/// keyed and translated like any other context.
+///
+fn get_network(hc: &Hexchat) -> Option<String> {
+    hc.get_info("network").or_else(|| hc.get_info("server"))
+}
+
+/// Builds the key used in the channel map, and other per-context maps, for a
+/// network and channel.
+///
+fn chan_key(network: &str, channel: &str) -> ChanData {
+    (network.to_string(), irc_casefold(channel))
+}
+
+/// Case-folds a channel or nick name using the RFC 1459 case mapping, which
+/// is the IRC default. In addition to the ASCII letters, `[]\~` are the 
+/// uppercase forms of `{}|^`.
+///
+fn irc_casefold(name: &str) -> String {
+    name.chars().map(|c| {
+        match c {
+            '[' => '{',
+            ']' => '}',
+            '\\' => '|',
+            '~' => '^',
+            _   => c.to_ascii_lowercase(),
+        }
+    }).collect()
+}
+
+/// Returns the translation direction set for the current context.
+///
+fn get_direction(hc: &Hexchat, state: &State) -> Direction {
+    if let Some(key) = get_chan_key(hc) {
+        key_direction(state, &key)
+    } else {
+        Direction::default()
+    }
+}
+
+/// Returns the translation direction set for the context with the key.
+///
+fn key_direction(state: &State, key: &ChanData) -> Direction {
+    state.lock().unwrap().directions.get(key).copied().unwrap_or_default()
+}
+
+/// Returns the relay languages (from, to) set for the current context, if
+/// any.
+///
+fn get_relay(hc: &Hexchat, state: &State) -> Option<ChanData> {
+    let key = get_chan_key(hc)?;
+    state.lock().unwrap().relays.get(&key).cloned()
+}
+
+/// Returns the language set with `/SETUSERLANG` for the sender in the 
+/// current context, if there is one.
+///
+fn get_user_lang(hc: &Hexchat, state: &State, sender: &str) -> Option<String> {
+    let sender = hc.strip(sender, StripBoth)
+                   .unwrap_or_else(|| sender.to_string());
+    let key    = get_chan_key(hc)?;
+    state.lock().unwrap()
+         .user_langs.get(&key)?.get(&irc_casefold(&sender)).cloned()
+}
+
+/// Returns the language set with `/SETDISPLAYLANG` for the current context,
+/// if there is one.
+///
+fn get_display_lang(hc: &Hexchat, state: &State) -> Option<String> {
+    let key = get_chan_key(hc)?;
+    state.lock().unwrap().display_langs.get(&key).cloned()
+}
+
+/// Swaps the display language set with `/SETDISPLAYLANG`, if there is one, 
+/// in for the language the user types in. Incoming messages, reasons, and
+/// topics are shown in the returned source language.
+///
+fn with_display_lang(hc         : &Hexchat, 
+                     state      : &State, 
+                     chan_langs : ChanData
+                    ) -> ChanData 
+{
+    match get_display_lang(hc, state) {
+        Some(lang) => (lang, chan_langs.1),
+        None       => chan_langs,
+    }
+}
+
+/// Indicates whether messages from the sender shouldn't be translated in the
+/// current context, either because the user muted them with `/TRANSMUTE`, 
+/// or because they match an entry in Hexchat's ignore list.
+///
+fn is_sender_muted(hc: &Hexchat, state: &State, sender: &str) -> bool {
+    let sender = hc.strip(sender, StripBoth)
+                   .unwrap_or_else(|| sender.to_string());
+    let muted  = get_chan_key(hc).is_some_and(|key| {
+        state.lock().unwrap()
+             .mutes.get(&key).is_some_and(|mutes| is_muted(mutes, &sender))
+    });
+    muted || is_ignored(hc, &sender)
+}
+
+/// Indicates whether messages from the sender are left untranslated in the
+/// current context because the context has a `/TRANSWATCH` list they
+/// aren't on.
+///
+fn is_sender_unwatched(hc: &Hexchat, state: &State, sender: &str) -> bool {
+    let sender = hc.strip(sender, StripBoth)
+                   .unwrap_or_else(|| sender.to_string());
+    get_chan_key(hc).is_some_and(|key| {
+        state.lock().unwrap()
+             .watches.get(&key).is_some_and(|watches| {
+                 is_unwatched(watches, &sender)
+             })
+    })
+}
+
+/// Indicates whether the sender is left out by a set of watched nicks. No
+/// one is while the set is empty. Nicks are compared like `is_muted()` 
+/// compares them.
+///
+fn is_unwatched(watches: &HashSet<String>, sender: &str) -> bool {
+    !watches.is_empty() && !is_muted(watches, sender)
+}
+
+/// Indicates whether the sender is in the set of muted nicks. Nicks are 
+/// compared the way IRC does, so "[bob]" and "{BOB}" are the same nick; the
+/// set holds nicks folded with `irc_casefold()`.
+///
+fn is_muted(mutes: &HashSet<String>, sender: &str) -> bool {
+    mutes.contains(&irc_casefold(sender))
+}
+
+/// Checks Hexchat's ignore list for an entry that ignores channel or private
+/// messages from the nick. Only the nick portion of each ignore mask is 
+/// compared, since the host of the sender isn't available to the handlers.
+///
+fn is_ignored(hc: &Hexchat, nick: &str) -> bool {
+    let ignore_flags = (IgnFlag::CHANNEL as i32) | (IgnFlag::PRIVATE as i32);
+    let nick         = nick.to_lowercase();
+    if let Some(list) = hc.list_get("ignore") {
+        for item in &list {
+            let mask  = item.get_field("mask").map(|f| f.str());
+            let flags = item.get_field("flags").map(|f| f.int());
+            if let (Ok(mask), Ok(flags)) = (mask, flags) {
+                let mask_nick = mask.split('!').next().unwrap_or_default();
+                if flags & (IgnFlag::UNIGNORE as i32) == 0 
+                    && flags & ignore_flags != 0
+                    && wildcard_match(&mask_nick.to_lowercase(), &nick) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Matches text against a pattern that can contain `*` (any run of 
+/// characters) and `?` (any single character) wildcards, like those used
+/// in IRC masks.
+///
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pat = pattern.chars().collect::<Vec<_>>();
+    let txt = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t)       = (0, 0);
+    let (mut star, mut mark) = (None, 0);
+
+    while t < txt.len() {
+        if p < pat.len() && (pat[p] == '?' || pat[p] == txt[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pat.len() && pat[p] == '*' {
+            star = Some(p);
+            mark = t;
+            p   += 1;
+        } else if let Some(sp) = star {
+            p     = sp + 1;
+            mark += 1;
+            t     = mark;
+        } else {
+            return false;
+        }
+    }
+    pat[p..].iter().all(|&c| c == '*')
+}
+
+/// Activates the current context for language translation. A `HashMap` is
+/// maintained that maps contexts (network/channel) to the desired translation
+/// (source_lang, dest_lang).
+///
+/// The map is only ever changed on Hexchat's main thread - translation 
+/// threads that need to turn a channel off do it by issuing /OFFLANG through
+/// `main_thread()` - so changes made by `activate()`, `deactivate()`, and 
+/// `deactivate_all()` are applied one at a time, in the order they're made. 
+/// The last one wins.
+/// 
+/// Error tracking left over from an earlier activation of the context is 
+/// reset.
+/// # Arguments
+/// * `hc`        - The Hexchat interface.
+/// * `map_udata` - The user data holding the channel map.
+/// * `state`     - The addon state.
+/// * `source`    - The source language to translate from.
+/// * `dest`      - The destination language to translate to.
+///
+fn activate(hc        : &Hexchat, 
+            map_udata : &UserData, 
+            state     : &State,
+            source    : &str, 
+            dest      : &str) 
+{
+    if {||{
+        let key = get_chan_key(hc)?;
+        map_udata.apply_mut(
+            |chan_map: &mut ChanMap| {
+                set_context_langs(chan_map, &mut state.lock().unwrap(), key,
+                                  (source.to_string(), dest.to_string()));
+            });
+        Some(())
+    }}().is_none() {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 Failed to get channel information during activation."));
+    }
+}
+
+/// Removes the current context's key and value from the `HashMap` that maps
+/// active contexts to translation information (source-lang, dest-lang). This
+/// effectively disables language translation in that window if it was 
+/// on before. 
+///
+/// The context's other settings, like its direction, mutes, relay, and 
+/// history, are dropped as well if `forget` is set. That's only done when 
+/// the user turns translation off, and not when the addon does.
+///
+fn deactivate(hc        : &Hexchat, 
+              map_udata : &UserData,
+              state     : &State,
+              forget    : bool) 
+{
+    if {||{
+        let key = get_chan_key(hc)?;
+        map_udata.apply_mut(
+            |chan_map: &mut ChanMap| {
+                let mut state = state.lock().unwrap();
+                if forget {
+                    state.forget_context(&key);
+                }
+                remove_context_langs(chan_map, &mut state.net_opt_outs, &key);
+            });
+        Some(())
+    }}().is_none() {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 Failed to get channel information during deactivation."));
+    }
+}
+
+/// Removes every context from the map of active contexts, turning
+/// translation off everywhere. The map is cleared in a single `apply_mut()`,
+/// so no other change to it can be interleaved with the clearing. The other
+/// settings of every context are dropped too.
+///
+fn deactivate_all(map_udata: &UserData, state: &State) -> usize {
+    map_udata.apply_mut(
+        |chan_map: &mut ChanMap| {
+            clear_contexts(chan_map, &mut state.lock().unwrap())
+        })
+}
+
+/// Takes a context's languages out of the channel map. If its network has
+/// default languages, the context is opted out of them, so translation is
+/// off in it either way.
+///
+fn remove_context_langs(chan_map : &mut ChanMap, 
+                        opt_outs : &mut HashSet<ChanData>,
+                        key      : &ChanData)
+{
+    chan_map.remove(key);
+    if chan_map.contains_key(&net_key(&key.0)) {
+        opt_outs.insert(key.clone());
+    }
+}
+
+/// Sets a context's languages in the channel map, and resets what's kept
+/// about its errors and suspension. The map and the state are changed 
+/// together, while both are held, so a clearing by `clear_contexts()` comes
+/// entirely before or after it.
+///
+fn set_context_langs(chan_map : &mut ChanMap, 
+                     state    : &mut AddonState,
+                     key      : ChanData,
+                     langs    : ChanData)
+{
+    state.over_limit_shown.remove(&key);
+    state.error_runs.remove(&key);
+    state.suspended.remove(&key);
+    state.net_opt_outs.remove(&key);
+    chan_map.insert(key, langs);
+}
+
+/// Empties the channel map and drops the settings of every context, as one
+/// change, like `set_context_langs()`.
+/// # Returns
+/// * How many contexts had translation on.
+///
+fn clear_contexts(chan_map: &mut ChanMap, state: &mut AddonState) -> usize {
+    state.forget_all_contexts();
+    let count = chan_map.len();
+    chan_map.clear();
+    count
+}
+
+/// Takes a context's languages out of the channel map and keeps them with
+/// the suspended ones, so translation is off in it until it's resumed.
+/// # Returns
+/// * Whether the context had translation on.
+///
+fn suspend_context(chan_map  : &mut ChanMap, 
+                   suspended : &mut HashMap<ChanData, ChanData>,
+                   key       : &ChanData
+                  ) -> bool 
+{
+    if let Some(langs) = chan_map.remove(key) {
+        suspended.insert(key.clone(), langs);
+        true
+    } else {
+        false
+    }
+}
+
+/// Puts a suspended context's languages back in the channel map, unless 
+/// other languages were set for it in the meantime.
+/// # Returns
+/// * Whether translation was turned back on for the context.
+///
+fn resume_context(chan_map  : &mut ChanMap, 
+                  suspended : &mut HashMap<ChanData, ChanData>,
+                  key       : &ChanData
+                 ) -> bool 
+{
+    match suspended.remove(key) {
+        Some(langs) if !chan_map.contains_key(key) => {
+            chan_map.insert(key.clone(), langs);
+            true
+        },
+        _ => false,
+    }
+}
+
+/// Indicates whether a context name is a channel's, rather than a nick's
+/// or a server's, from its prefix.
+///
+fn is_channel_name(name: &str) -> bool {
+    name.starts_with(['#', '&', '!', '+'])
+}
+
+/// Callback invoked when the user leaves a context. On "You Part" and "You
+/// Part with Reason", translation is suspended for the channel left. On 
+/// "Disconnected", which Hexchat prints in each of the network's windows, 
+/// it's suspended for each channel. Dialogs with nicks are left on, since 
+/// they aren't rejoined. The channels' languages and other settings are 
+/// kept, and translation is turned back on by `on_you_join()`.
+///
+fn on_you_leave(hc        : &Hexchat, 
+                _word     : &[String], 
+                user_data : &UserData
+               ) -> Eat 
+{
+    let (ref map_udata, ref state) = user_data.apply(
+                                    |ud: &(&str, UserData, State)| {
+                                        (ud.1.clone(), ud.2.clone())
+                                    });
+    if let Some(key) = get_chan_key(hc).filter(|k| is_channel_name(&k.1)) {
+        let mut state = state.lock().unwrap();
+        let suspended = map_udata.apply_mut(
+            |chan_map: &mut ChanMap| {
+                suspend_context(chan_map, &mut state.suspended, &key)
+            });
+        if suspended {
+            hc.print(&fm!("{ERROR_COLOR}Translation suspended until you \
+                           rejoin the channel."));
+        }
+    }
+    Eat::None
+}
+
+/// Callback invoked on "You Join". Turns translation back on in a channel 
+/// it was suspended in when the user left it, or was disconnected.
+///
+fn on_you_join(hc        : &Hexchat, 
+               _word     : &[String], 
+               user_data : &UserData
+              ) -> Eat 
+{
+    let (ref map_udata, ref state) = user_data.apply(
+                                        |ud: &(UserData, State)| {
+                                            (ud.0.clone(), ud.1.clone())
+                                        });
+    if let Some(key) = get_chan_key(hc) {
+        let mut state = state.lock().unwrap();
+        let resumed   = map_udata.apply_mut(
+            |chan_map: &mut ChanMap| {
+                resume_context(chan_map, &mut state.suspended, &key)
+            });
+        if resumed {
+            hc.print(&fm!("{ERROR_COLOR}Translation turned back on."));
+        }
+    }
+    Eat::None
+}
+
+/// Implements the /SETLANG command. Use /SETLANG to set the source and
+/// target language for translation. Issuing this command activates 
+/// the channel for translation.
+///
+fn on_cmd_setlang(hc        : &Hexchat, 
+                  word      : &[String], 
+                  _word_eol : &[String], 
+                  user_data : &UserData
+                 ) -> Eat 
+{
+    let (ref map_udata, ref state) = user_data.apply(
+                                        |ud: &(UserData, State)| {
+                                            (ud.0.clone(), ud.1.clone())
+                                        });
+    // The direction is optional. Without one, the channel keeps the one it
+    // has.
+    let direction = match word.get(3) {
+        Some(dir) => Direction::parse(dir).map(Some),
+        None      => Some(None),
+    };
+    if let (3 | 4, Some(direction)) = (word.len(), direction) {
+        let mut src_lang = word[1].as_str();
+        let mut tgt_lang = word[2].as_str();
+        
+        let mut params_good = false;
+        
+        // Verify each lang is in the list below.
+        let src_info = if src_lang.eq_ignore_ascii_case(AUTO_LANG.1) { 
+                           Some(&AUTO_LANG) 
+                       } else { 
+                           find_lang(src_lang) 
+                       };
+        if let Some(src_lang_info) = src_info /* && */ {
+        if let Some(tgt_lang_info) = find_lang(tgt_lang) {
+        
+            if src_lang_info !=  tgt_lang_info {
+                params_good = true;
+                    
+                // Make sure the language names are the abbreviation.
+                src_lang  =  src_lang_info.1;
+                tgt_lang  =  tgt_lang_info.1;
+
+                // Activate the channel.
+                activate(hc, map_udata, state, src_lang, tgt_lang);
+
+                if let (Some(dir), Some(key)) = (direction, get_chan_key(hc)) {
+                    state.lock().unwrap().directions.insert(key, dir);
+                }
+                hc.print(&fm!("{ERROR_COLOR}\
+                         TRANSLATION IS ON FOR THIS CHANNEL! \
+                         {} (you) to {} (them). Direction: {}.", 
+                         src_lang_info.0, tgt_lang_info.0, 
+                         get_direction(hc, state)));
+
+                if let Some(synced) = get_synced_langs(state) {
+                    for info in [src_lang_info, tgt_lang_info] {
+                        if *info != AUTO_LANG && !synced.contains(info.1) {
+                            hc.print(&fm!("{ERROR_COLOR}{} doesn't support \
+                                           {}.", get_provider(state), 
+                                          info.0));
+                        }
+                    }
+                }
+            } 
+        }}
+        if !params_good {
+            hc.print(&fm!("{ERROR_COLOR}\
+                     BAD LANGUAGE PARAMETERS. Use /LISTLANG to \
+                     get a list of supported languages. And don't \
+                     set translation source and target languages the \
+                     same."));
+            for msg in word[1..3].iter().filter_map(|w| {
+                           ambiguous_lang_msg(w).or_else(|| suggest_lang_msg(w))
+                       }) {
+                hc.print(&fm!("{ERROR_COLOR}{}", msg));
+            }
+        }
+    } else {
+        hc.print(&fm!("USAGE: {}", SETLANG_HELP));
+    }
+    Eat::All
+}
+
+/// Implements the /SETNETLANG command. Sets the languages used in every 
+/// channel of the current network that doesn't have its own set with 
+/// `/SETLANG`, which saves setting them in each channel of a network where
+/// one language is spoken. `/SETNETLANG off` removes them, and without 
+/// arguments, they're printed.
+///
+fn on_cmd_setnetlang(hc        : &Hexchat, 
+                     word      : &[String], 
+                     _word_eol : &[String], 
+                     user_data : &UserData
+                    ) -> Eat 
+{
+    let (map_udata, state) = user_data.apply(
+                                |ud: &(UserData, State)| {
+                                    (ud.0.clone(), ud.1.clone())
+                                });
+
+    let Some(network) = get_network(hc) else {
+        hc.print(&fm!("{ERROR_COLOR}Failed to get network information."));
+        return Eat::All;
+    };
+    let key = net_key(&network);
+    
+    match word.len() {
+        1 => {
+            let langs = map_udata.apply(|chan_map: &ChanMap| {
+                            chan_map.get(&key).cloned()
+                        });
+            if let Some((src, tgt)) = langs {
+                let name = |lang: &str| {
+                    if lang == AUTO_LANG.1 {
+                        AUTO_LANG.0.to_string()
+                    } else {
+                        find_lang(lang).map_or(lang.to_string(), 
+                                               |info| info.0.to_string())
+                    }
+                };
+                hc.print(&fm!("{ERROR_COLOR}Default languages for {}: {} \
+                               (you) to {} (them).", network, name(&src), 
+                                                              name(&tgt)));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}No default languages are set for \
+                               {}.", network));
+            }
+        },
+        2 if word[1].eq_ignore_ascii_case("off") => {
+            map_udata.apply_mut(|chan_map: &mut ChanMap| {
+                chan_map.remove(&key);
+            });
+            state.lock().unwrap().net_opt_outs.retain(|k| k.0 != key.0);
+            hc.print(&fm!("{ERROR_COLOR}Default languages for {} removed.", 
+                          network));
+        },
+        3 => {
+            let src_info = if word[1].eq_ignore_ascii_case(AUTO_LANG.1) { 
+                               Some(&AUTO_LANG) 
+                           } else { 
+                               find_lang(&word[1]) 
+                           };
+            match (src_info, find_lang(&word[2])) {
+                (Some(src), Some(tgt)) if src != tgt => {
+                    map_udata.apply_mut(|chan_map: &mut ChanMap| {
+                        chan_map.insert(key, (src.1.to_string(), 
+                                              tgt.1.to_string()));
+                    });
+                    hc.print(&fm!("{ERROR_COLOR}Default languages for {} \
+                                   set: {} (you) to {} (them). Channels \
+                                   set with /SETLANG keep their own.", 
+                                  network, src.0, tgt.0));
+                },
+                _ => {
+                    hc.print(&fm!("{ERROR_COLOR}\
+                             BAD LANGUAGE PARAMETERS. Use /LISTLANG to \
+                             get a list of supported languages. And don't \
+                             set translation source and target languages \
+                             the same."));
+                    for msg in word[1..3].iter().filter_map(|w| {
+                                   ambiguous_lang_msg(w)
+                                       .or_else(|| suggest_lang_msg(w))
+                               }) {
+                        hc.print(&fm!("{ERROR_COLOR}{}", msg));
+                    }
+                },
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", SETNETLANG_HELP));
+        },
+    }
+    Eat::All
+}
+
+/// Implements the /SETLANGRELAY command. Sets the context to translate 
+/// incoming messages from one language to another, neither of which needs
+/// to be the user's, for relaying between two groups. The translations are
+/// displayed in the window like other incoming translations. `/SETLANGRELAY
+/// off` returns the context to its normal translation.
+///
+fn on_cmd_setlangrelay(hc        : &Hexchat, 
+                       word      : &[String], 
+                       _word_eol : &[String], 
+                       user_data : &UserData
+                      ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let Some(key) = get_chan_key(hc) else {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 Failed to get channel information."));
+        return Eat::All;
+    };
+    match word.len() {
+        2 if word[1].eq_ignore_ascii_case("off") => {
+            state.lock().unwrap().relays.remove(&key);
+            hc.print(&fm!("{ERROR_COLOR}Relay translation turned OFF for this \
+                           channel."));
+        },
+        3 => {
+            match (find_lang(&word[1]), find_lang(&word[2])) {
+                (Some(from), Some(to)) if from != to => {
+                    state.lock().unwrap().relays.insert(
+                        key, (from.1.to_string(), to.1.to_string()));
+                    hc.print(&fm!("{ERROR_COLOR}RELAY TRANSLATION IS ON FOR \
+                                   THIS CHANNEL! Incoming {} is translated \
+                                   to {}.", from.0, to.0));
+                },
+                _ => {
+                    hc.print(&fm!("{ERROR_COLOR}\
+                             BAD LANGUAGE PARAMETERS. Use /LISTLANG to \
+                             get a list of supported languages. And don't \
+                             set the relay languages the same."));
+                }
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", SETLANGRELAY_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /SETUSERLANG command. Sets the language incoming messages
+/// from a nick are translated from in the current channel, for channels 
+/// where people speak different languages. `/SETUSERLANG <nick> off` 
+/// removes a nick's language, `/SETUSERLANG list` lists them, and 
+/// `/SETUSERLANG clear` removes them all.
+///
+fn on_cmd_setuserlang(hc        : &Hexchat, 
+                      word      : &[String], 
+                      _word_eol : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let Some(key) = get_chan_key(hc) else {
+        hc.print(&fm!("{ERROR_COLOR}Failed to get channel information."));
+        return Eat::All;
+    };
+    let mut state = state.lock().unwrap();
+
+    match (word.get(1).map(|w| w.to_lowercase()).as_deref(), word.get(2)) {
+        (Some("list"), None) => {
+            let mut langs = state.user_langs.get(&key)
+                                 .map(|langs| {
+                                     langs.iter()
+                                          .map(|(nick, lang)| {
+                                              fm!("{} ({})", nick, lang)
+                                          })
+                                          .collect::<Vec<_>>()
+                                 })
+                                 .unwrap_or_default();
+            langs.sort_unstable();
+            if langs.is_empty() {
+                hc.print(&fm!("{ERROR_COLOR}No nicks have a language set in \
+                               this channel."));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}Nick languages: {}", 
+                              langs.join(", ")));
+            }
+        },
+        (Some("clear"), None) => {
+            state.user_langs.remove(&key);
+            hc.print(&fm!("{ERROR_COLOR}Nick languages cleared for this \
+                           channel."));
+        },
+        (Some(_), Some(lang)) if lang.eq_ignore_ascii_case("off") => {
+            let nick    = irc_casefold(&word[1]);
+            let removed = state.user_langs.get_mut(&key)
+                               .is_some_and(|langs| {
+                                   langs.remove(&nick).is_some()
+                               });
+            if state.user_langs.get(&key).is_some_and(|l| l.is_empty()) {
+                state.user_langs.remove(&key);
+            }
+            if removed {
+                hc.print(&fm!("{ERROR_COLOR}Messages from {} will be \
+                               translated from the channel's language.", 
+                              word[1]));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}{} has no language set in this \
+                               channel.", word[1]));
+            }
+        },
+        (Some(_), Some(lang)) if word.len() == 3 => {
+            let Some(lang) = find_lang(lang) else {
+                hc.print(&fm!("{ERROR_COLOR}\
+                         BAD LANGUAGE PARAMETER. Use /LISTLANG to \
+                         get a list of supported languages."));
+                return Eat::All;
+            };
+            state.user_langs.entry(key).or_default()
+                 .insert(irc_casefold(&word[1]), lang.1.to_string());
+            hc.print(&fm!("{ERROR_COLOR}Messages from {} will be translated \
+                           from {}.", word[1], lang.0));
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", SETUSERLANG_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /SETDISPLAYLANG command. Sets the language incoming 
+/// messages are translated to in the current channel, for users who read 
+/// a language they don't type in. `off` goes back to translating them to
+/// the channel's source language. Without arguments, prints the language.
+///
+fn on_cmd_setdisplaylang(hc        : &Hexchat, 
+                         word      : &[String], 
+                         _word_eol : &[String], 
+                         user_data : &UserData
+                        ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let Some(key) = get_chan_key(hc) else {
+        hc.print(&fm!("{ERROR_COLOR}Failed to get channel information."));
+        return Eat::All;
+    };
+    let mut state = state.lock().unwrap();
+
+    match word.get(1) {
+        None => {
+            match state.display_langs.get(&key) {
+                Some(lang) => {
+                    let name = find_lang(lang).map_or(lang.as_str(), 
+                                                      |info| info.0);
+                    hc.print(&fm!("{ERROR_COLOR}Incoming messages are shown \
+                                   in {} in this channel.", name));
+                },
+                None => {
+                    hc.print(&fm!("{ERROR_COLOR}Incoming messages are shown \
+                                   in the language you type in."));
+                }
+            }
+        },
+        Some(lang) if word.len() == 2 && lang.eq_ignore_ascii_case("off") => {
+            state.display_langs.remove(&key);
+            hc.print(&fm!("{ERROR_COLOR}Incoming messages will be shown in \
+                           the language you type in."));
+        },
+        Some(lang) if word.len() == 2 => {
+            let Some(lang) = find_lang(lang) else {
+                hc.print(&fm!("{ERROR_COLOR}\
+                         BAD LANGUAGE PARAMETER. Use /LISTLANG to \
+                         get a list of supported languages."));
+                return Eat::All;
+            };
+            state.display_langs.insert(key, lang.1.to_string());
+            hc.print(&fm!("{ERROR_COLOR}Incoming messages will be shown in \
+                           {} in this channel.", lang.0));
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", SETDISPLAYLANG_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /SETLANGLIST command. Sets a list of target languages
+/// for the channel that outgoing messages take turns being translated to,
+/// so speakers of each get addressed over time. `/SETLANGLIST off` goes back
+/// to the single target language set with /SETLANG.
+///
+fn on_cmd_setlanglist(hc        : &Hexchat, 
+                      word      : &[String], 
+                      word_eol  : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let (map_udata, state) = user_data.apply(
+                                |ud: &(UserData, State)| {
+                                    (ud.0.clone(), ud.1.clone())
+                                });
+    if word.len() < 2 {
+        hc.print(&fm!("USAGE: {}", SETLANGLIST_HELP));
+        return Eat::All;
+    }
+    let Some(key) = get_chan_key(hc) else {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 Failed to get channel information."));
+        return Eat::All;
+    };
+    if word.len() == 2 && word[1].eq_ignore_ascii_case("off") {
+        state.lock().unwrap().lang_lists.remove(&key);
+        hc.print(&fm!("{ERROR_COLOR}Language list turned OFF for this \
+                       channel."));
+        return Eat::All;
+    }
+    let Some((src_lang, _)) = get_channel_langs(hc, &map_udata, &state) else {
+        hc.print(&fm!("{ERROR_COLOR}Translation isn't on for this channel. \
+                       Use /SETLANG to turn it on."));
+        return Eat::All;
+    };
+    match parse_lang_list(&word_eol[1], &src_lang) {
+        Some(langs) => {
+            let names = langs.iter().map(|info| info.0)
+                             .collect::<Vec<_>>().join(", ");
+            let codes = langs.iter().map(|info| info.1.to_string()).collect();
+            state.lock().unwrap().lang_lists.insert(key, (codes, 0));
+            hc.print(&fm!("{ERROR_COLOR}Outgoing messages will take turns \
+                           being translated to: {}", names));
+        },
+        _ => {
+            hc.print(&fm!("{ERROR_COLOR}\
+                     BAD LANGUAGE PARAMETERS. Use /LISTLANG to \
+                     get a list of supported languages. And don't \
+                     include your own language in the list."));
+        }
+    }
+    Eat::All
+}
+
+/// Parses the language list given to `/SETLANGLIST`, separated by commas or
+/// spaces.
+/// # Arguments
+/// * `list`     - The list of languages.
+/// * `src_lang` - The user's language, which can't be in the list.
+/// # Returns
+/// * The languages, or `None` if the list has none, has one that isn't 
+///   supported, or has the user's language.
+///
+fn parse_lang_list<'a>(list: &'a str, src_lang: &str) 
+    -> Option<Vec<(&'a str, &'a str)>> 
+{
+    let langs = list.split([',', ' '])
+                    .filter(|l| !l.trim().is_empty())
+                    .map(|l| find_lang(l).copied())
+                    .collect::<Option<Vec<_>>>()?;
+    if langs.is_empty() || langs.iter().any(|info| info.1 == src_lang) {
+        None
+    } else {
+        Some(langs)
+    }
+}
+
+/// Gets the target language for the next outgoing message in a context with
+/// a language list, and moves the list on to the following language.
+/// # Returns
+/// * The language code, or `None` if the context has no language list.
+///
+fn next_list_lang(state: &State, key: &ChanData) -> Option<String> {
+    let mut state = state.lock().unwrap();
+    let (langs, cursor) = state.lang_lists.get_mut(key)?;
+    round_robin(langs, cursor).map(str::to_string)
+}
+
+/// Takes the item at the cursor and advances the cursor, wrapping around to
+/// the start after the last item.
+/// # Returns
+/// * The item, or `None` if the list is empty.
+///
+fn round_robin<'a>(items: &'a [String], cursor: &mut usize) -> Option<&'a str> {
+    if items.is_empty() {
+        return None;
+    }
+    let item = &items[*cursor % items.len()];
+    *cursor  = (*cursor + 1) % items.len();
+    Some(item)
+}
+
+/// Implements the /OFFLANG command. Turns translation off in the 
+/// open window/channel, or in every channel with `/OFFLANG ALL`. The 
+/// channel's other settings are dropped, unless the addon issued the command
+/// itself with `auto_disable()`.
+///
+fn on_cmd_offlang(hc        : &Hexchat, 
+                  word      : &[String], 
+                  _word_eol : &[String], 
+                  user_data : &UserData
+                 ) -> Eat 
+{
+    let (ref map_udata, ref state) = user_data.apply(
+                                        |ud: &(UserData, State)| {
+                                            (ud.0.clone(), ud.1.clone())
+                                        });
+    if word.len() == 1 {
+        let forget = !AUTO_DISABLING.with(|a| a.get());
+        deactivate(hc, map_udata, state, forget);
+        hc.print(&fm!("{ERROR_COLOR}Translation turned OFF for this channel."));
+    } else if word.len() == 2 && word[1].eq_ignore_ascii_case("all") {
+        let count = deactivate_all(map_udata, state);
+        hc.print(&fm!("{ERROR_COLOR}Translation turned OFF for all channels \
+                       ({} were on).", count));
+    } else {
+        hc.print(&fm!("USAGE: {}", OFFLANG_HELP));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSDIR command. Sets which directions translation is
+/// performed in for the current channel. Without arguments, the current 
+/// setting is printed.
+///
+fn on_cmd_transdir(hc        : &Hexchat, 
+                   word      : &[String], 
+                   _word_eol : &[String], 
+                   user_data : &UserData
+                  ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+    
+    match word.len() {
+        1 => {
+            hc.print(&fm!("{ERROR_COLOR}Translation direction for this \
+                           channel: {}", get_direction(hc, &state)));
+        },
+        2 => {
+            if let Some(dir) = Direction::parse(&word[1]) {
+                if let Some(key) = get_chan_key(hc) {
+                    state.lock().unwrap().directions.insert(key, dir);
+                    hc.print(&fm!("{ERROR_COLOR}Translation direction for \
+                                   this channel set to: {}", dir));
+                } else {
+                    hc.print(&fm!("{ERROR_COLOR}\
+                             Failed to get channel information."));
+                }
+            } else {
+                hc.print(&fm!("USAGE: {}", TRANSDIR_HELP));
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSDIR_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSMUTE command. Maintains the set of nicks whose 
+/// messages aren't translated in the current channel.
+///
+fn on_cmd_transmute(hc        : &Hexchat, 
+                    word      : &[String], 
+                    _word_eol : &[String], 
+                    user_data : &UserData
+                   ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let Some(key) = get_chan_key(hc) else {
+        hc.print(&fm!("{ERROR_COLOR}Failed to get channel information."));
+        return Eat::All;
+    };
+    let mut state = state.lock().unwrap();
+
+    match (word.get(1).map(|w| w.to_lowercase()).as_deref(), word.get(2)) {
+        (Some("add"), Some(nick)) => {
+            state.mutes.entry(key).or_default().insert(irc_casefold(nick));
+            hc.print(&fm!("{ERROR_COLOR}Messages from {} won't be \
+                           translated in this channel.", nick));
+        },
+        (Some("del"), Some(nick)) => {
+            let removed = state.mutes.get_mut(&key)
+                               .is_some_and(|m| m.remove(&irc_casefold(nick)));
+            if state.mutes.get(&key).is_some_and(|m| m.is_empty()) {
+                state.mutes.remove(&key);
+            }
+            if removed {
+                hc.print(&fm!("{ERROR_COLOR}Messages from {} will be \
+                               translated again in this channel.", nick));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}{} isn't muted in this \
+                               channel.", nick));
+            }
+        },
+        (Some("list"), None) => {
+            let mut nicks = state.mutes.get(&key)
+                                 .map(|m| m.iter().cloned().collect::<Vec<_>>())
+                                 .unwrap_or_default();
+            nicks.sort_unstable();
+            if nicks.is_empty() {
+                hc.print(&fm!("{ERROR_COLOR}No nicks are muted in this \
+                               channel."));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}Muted nicks: {}", 
+                              nicks.join(", ")));
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSMUTE_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSWATCH command. Maintains the set of nicks whose 
+/// messages are the only ones translated in the current channel, for busy
+/// channels where only a few people need translating. While the set is 
+/// empty, everyone's messages are translated.
+///
+fn on_cmd_transwatch(hc        : &Hexchat, 
+                     word      : &[String], 
+                     _word_eol : &[String], 
+                     user_data : &UserData
+                    ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let Some(key) = get_chan_key(hc) else {
+        hc.print(&fm!("{ERROR_COLOR}Failed to get channel information."));
+        return Eat::All;
+    };
+    let mut state = state.lock().unwrap();
+
+    match (word.get(1).map(|w| w.to_lowercase()).as_deref(), word.get(2)) {
+        (Some("add"), Some(nick)) => {
+            state.watches.entry(key).or_default()
+                 .insert(irc_casefold(nick));
+            hc.print(&fm!("{ERROR_COLOR}Messages from {} will be translated \
+                           in this channel, along with the other watched \
+                           nicks'. Others' won't be.", nick));
+        },
+        (Some("remove"), Some(nick)) => {
+            let removed = state.watches.get_mut(&key)
+                               .is_some_and(|w| w.remove(&irc_casefold(nick)));
+            if state.watches.get(&key).is_some_and(|w| w.is_empty()) {
+                state.watches.remove(&key);
+                hc.print(&fm!("{ERROR_COLOR}No nicks are watched in this \
+                               channel now, so everyone's messages will be \
+                               translated."));
+            } else if removed {
+                hc.print(&fm!("{ERROR_COLOR}Messages from {} won't be \
+                               translated in this channel.", nick));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}{} isn't watched in this \
+                               channel.", nick));
+            }
+        },
+        (Some("clear"), None) => {
+            state.watches.remove(&key);
+            hc.print(&fm!("{ERROR_COLOR}Watched nicks cleared. Everyone's \
+                           messages will be translated in this channel."));
+        },
+        (Some("list"), None) => {
+            let mut nicks = state.watches.get(&key)
+                                 .map(|w| w.iter().cloned().collect::<Vec<_>>())
+                                 .unwrap_or_default();
+            nicks.sort_unstable();
+            if nicks.is_empty() {
+                hc.print(&fm!("{ERROR_COLOR}No nicks are watched in this \
+                               channel, so everyone's messages are \
+                               translated."));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}Watched nicks: {}", 
+                              nicks.join(", ")));
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSWATCH_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSCMDSIGIL command. Sets the characters that mark a
+/// message as a bot command, so it isn't translated. "none" turns the check
+/// off, "default" restores the default set, and with no argument the current
+/// set is printed.
+///
+fn on_cmd_transcmdsigil(hc        : &Hexchat, 
+                        word      : &[String], 
+                        _word_eol : &[String], 
+                        user_data : &UserData
+                       ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    match word.len() {
+        1 => {
+            let sigils = get_cmd_sigils(&state);
+            if sigils.is_empty() {
+                hc.print(&fm!("{ERROR_COLOR}Bot commands are translated."));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}Bot command characters: {}", 
+                              sigils));
+            }
+        },
+        2 => {
+            let sigils = match word[1].to_lowercase().as_str() {
+                "none"    => Some(String::new()),
+                "default" => None,
+                _         => Some(word[1].clone()),
+            };
+            state.lock().unwrap().cmd_sigils = sigils;
+            let sigils = get_cmd_sigils(&state);
+            if sigils.is_empty() {
+                hc.print(&fm!("{ERROR_COLOR}Bot commands will be translated."));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}Messages starting with any of \
+                               '{}' won't be translated.", sigils));
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSCMDSIGIL_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSBLOCK command. Maintains the list of words that 
+/// outgoing translations are checked for before they're sent.
+///
+fn on_cmd_transblock(hc        : &Hexchat, 
+                     word      : &[String], 
+                     _word_eol : &[String], 
+                     user_data : &UserData
+                    ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+    let mut state = state.lock().unwrap();
+
+    match (word.get(1).map(|w| w.to_lowercase()).as_deref(), word.get(2)) {
+        (Some("add"), Some(w)) => {
+            state.blocked_words.insert(w.to_lowercase());
+            hc.print(&fm!("{ERROR_COLOR}Translations containing '{}' won't \
+                           be sent.", w));
+        },
+        (Some("del"), Some(w)) => {
+            if state.blocked_words.remove(&w.to_lowercase()) {
+                hc.print(&fm!("{ERROR_COLOR}'{}' is no longer blocked.", w));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}'{}' isn't blocked.", w));
+            }
+        },
+        (Some("list"), None) => {
+            let mut words = state.blocked_words.iter().cloned()
+                                 .collect::<Vec<_>>();
+            words.sort_unstable();
+            if words.is_empty() {
+                hc.print(&fm!("{ERROR_COLOR}No words are blocked."));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}Blocked words: {}", 
+                              words.join(", ")));
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSBLOCK_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Looks for a blocked word in a translation. Words are compared without
+/// regard to case, and only whole words match.
+/// # Arguments
+/// * `text`    - The translated text.
+/// * `blocked` - The lowercase blocked words.
+/// # Returns
+/// * The first blocked word found in the text, if any.
+///
+fn find_blocked_word(text: &str, blocked: &HashSet<String>) -> Option<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(|w| w.to_lowercase())
+        .find(|w| blocked.contains(w))
+}
+
+/// Implements the /TRANSHISTORY command. Turns the recording of incoming
+/// translations on or off, or prints the last `n` original and translated
+/// message pairs recorded for the current channel.
+///
+fn on_cmd_transhistory(hc        : &Hexchat, 
+                       word      : &[String], 
+                       _word_eol : &[String], 
+                       user_data : &UserData
+                      ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if word.len() != 2 {
+        hc.print(&fm!("USAGE: {}", TRANSHISTORY_HELP));
+
+    } else if let Some(on) = parse_on_off(&word[1]) {
+        let mut state = state.lock().unwrap();
+        state.history_on = on;
+        if !on {
+            state.history.clear();
+        }
+        hc.print(&fm!("{ERROR_COLOR}Translation history turned {}.", 
+                      if on { "ON" } else { "OFF" }));
+
+    } else if let Ok(n) = word[1].parse::<usize>() {
+        let state = state.lock().unwrap();
+        let hist  = get_chan_key(hc).and_then(|key| state.history.get(&key));
+
+        match hist {
+            Some(hist) if !hist.is_empty() => {
+                for entry in recent_history(hist, n) {
+                    hc.print(&fm!("{ORIG_COLOR}<{}> [{}] {}", 
+                                  entry.sender, entry.lang, entry.original));
+                    hc.print(&fm!("    {}", entry.translation));
+                }
+            },
+            _ => {
+                if state.history_on {
+                    hc.print(&fm!("{ERROR_COLOR}No translation history for \
+                                   this channel."));
+                } else {
+                    hc.print(&fm!("{ERROR_COLOR}Translation history is \
+                                   off. Use /TRANSHISTORY ON to record it."));
+                }
+            }
+        }
+    } else {
+        hc.print(&fm!("USAGE: {}", TRANSHISTORY_HELP));
+    }
+    Eat::All
+}
+
+/// The header row of the CSV written by `/TRANSSTATS export`.
+///
+const STATS_CSV_HEADER: &str = "source,target,translations,failures,\
+                                over_limit,characters";
+
+/// Formats the translation counts of each language pair as CSV, with a 
+/// header row and a row for each pair in the order given. Language codes 
+/// have no commas or quotes in them, so nothing needs quoting.
+///
+fn stats_csv(pairs: &[((String, String), TranslationStats)]) -> String {
+    let mut csv = fm!("{}\n", STATS_CSV_HEADER);
+    for ((src, tgt), stats) in pairs {
+        csv.push_str(&fm!("{},{},{},{},{},{}\n", src, tgt, stats.translated,
+                          stats.errors, stats.over_limit, stats.chars));
+    }
+    csv
+}
+
+/// Implements the /TRANSSTATS command. Prints how many sentences have been
+/// translated since the addon was loaded, how many failed, and how many
+/// characters were sent, in total and for each pair of languages. The rate
+/// limit and how many requests it allows right away are printed last. With
+/// "export", the counts for each pair are written to a CSV file instead.
+///
+fn on_cmd_transstats(hc        : &Hexchat, 
+                     word      : &[String], 
+                     word_eol  : &[String], 
+                     user_data : &UserData
+                    ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let exporting = word.len() > 2 && word[1].eq_ignore_ascii_case("export");
+    if word.len() > 1 && !exporting {
+        hc.print(&fm!("USAGE: {}", TRANSSTATS_HELP));
+        return Eat::All;
+    }
+    let mut pairs = state.lock().unwrap()
+                         .stats.iter()
+                         .map(|(pair, stats)| (pair.clone(), *stats))
+                         .collect::<Vec<_>>();
+    pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    if exporting {
+        let path = word_eol[2].trim();
+        match std::fs::write(path, stats_csv(&pairs)) {
+            Ok(()) => {
+                hc.print(&fm!("{ERROR_COLOR}Exported the counts of {} \
+                               language pairs to {}.", pairs.len(), path));
+            },
+            Err(err) => {
+                hc.print(&fm!("{ERROR_COLOR}Failed to export to {}: {}", 
+                              path, err));
+            }
+        }
+        return Eat::All;
+    }
+
+    let mut total = TranslationStats::default();
+    for (_, stats) in &pairs {
+        total.add(stats);
+    }
+    hc.print(&fm!("{ERROR_COLOR}Since loading: {}", total));
+    for ((src, tgt), stats) in &pairs {
+        hc.print(&fm!("{ERROR_COLOR}  {} > {}: {}", src, tgt, stats));
+    }
+    let (rate, remaining, next) = {
+        let limiter = &mut state.lock().unwrap().rate_limiter;
+        (limiter.per_minute, limiter.remaining(), limiter.next_token())
+    };
+    if let (Some(rate), Some(0)) = (rate, remaining) {
+        hc.print(&fm!("{ERROR_COLOR}Rate limit: {} requests a minute, none \
+                       available for {:.1} seconds.", rate, 
+                      next.as_secs_f64()));
+    } else if let (Some(rate), Some(remaining)) = (rate, remaining) {
+        hc.print(&fm!("{ERROR_COLOR}Rate limit: {} requests a minute, {} \
+                       available now.", rate, remaining));
+    } else {
+        hc.print(&fm!("{ERROR_COLOR}Rate limit: off."));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSCACHE command. Empties the cache of recent 
+/// translations, or with "clearhere", removes only the translations between
+/// the current channel's languages, so fresh ones are made there.
+///
+fn on_cmd_transcache(hc        : &Hexchat, 
+                     word      : &[String], 
+                     _word_eol : &[String], 
+                     user_data : &UserData
+                    ) -> Eat 
+{
+    let (ref map_udata, ref state) = user_data.apply(
+                                        |ud: &(UserData, State)| {
+                                            (ud.0.clone(), ud.1.clone())
+                                        });
+    match (word.len(), word.get(1).map(|w| w.to_lowercase()).as_deref()) {
+        (2, Some("clear")) => {
+            state.lock().unwrap().cache.clear();
+            hc.print(&fm!("{ERROR_COLOR}Translation cache cleared."));
+        },
+        (2, Some("clearhere")) => {
+            let Some(langs) = get_channel_langs(hc, map_udata, state) else {
+                hc.print(&fm!("{ERROR_COLOR}Translation isn't on for this \
+                               channel."));
+                return Eat::All;
+            };
+            // With an `auto` source, incoming messages are translated to the
+            // language detected, so those translations go too.
+            let resolved  = resolve_auto_lang(hc, state, langs.clone());
+            let cache     = &mut state.lock().unwrap().cache;
+            let mut count = cache.remove_pair(&langs.0, &langs.1);
+            if resolved != langs {
+                count += cache.remove_pair(&resolved.0, &resolved.1);
+            }
+            hc.print(&fm!("{ERROR_COLOR}Removed {} cached translations \
+                           between {} and {}.", count, langs.0, langs.1));
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSCACHE_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSCTL command. Starts or stops the local control 
+/// socket that external scripts can use to configure translation. Without
+/// arguments, prints whether it's running.
+///
+fn on_cmd_transctl(hc        : &Hexchat, 
+                   word      : &[String], 
+                   _word_eol : &[String], 
+                   _userdata : &UserData
+                  ) -> Eat 
+{
+    let option = word.get(1).and_then(|w| parse_on_off(w));
+    let port   = word.get(2).map(|p| p.parse::<u16>().ok());
+
+    match (word.len(), option, port) {
+        (1, _, _) => {
+            if let Some((port, token)) = control::running() {
+                hc.print(&fm!("{ERROR_COLOR}The control socket is listening \
+                               on 127.0.0.1:{}, with the token {}.", 
+                              port, token));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}The control socket is off."));
+            }
+        },
+        (2, Some(true), None) | (3, Some(true), Some(Some(_))) => {
+            let port = port.flatten().unwrap_or(control::DEFAULT_CONTROL_PORT);
+            match control::start(port) {
+                Ok(token) => {
+                    hc.print(&fm!("{ERROR_COLOR}The control socket is \
+                                   listening on 127.0.0.1:{}. Send the \
+                                   token {} with each command.", 
+                                  port, token));
+                },
+                Err(err) => {
+                    hc.print(&fm!("{ERROR_COLOR}Failed to start the control \
+                                   socket on port {}: {}", port, err));
+                }
+            }
+        },
+        (2, Some(false), None) => {
+            control::stop();
+            hc.print(&fm!("{ERROR_COLOR}The control socket is off."));
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSCTL_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSEXPORT command. Writes the languages of every 
+/// channel translation is on in, and every network's default languages, to
+/// a file as JSON, so they can be brought into another Hexchat with 
+/// /TRANSIMPORT.
+///
+fn on_cmd_transexport(hc        : &Hexchat, 
+                      word      : &[String], 
+                      word_eol  : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let map_udata = user_data.apply(|ud: &(UserData, State)| ud.0.clone());
+
+    if word.len() < 2 {
+        hc.print(&fm!("USAGE: {}", TRANSEXPORT_HELP));
+        return Eat::All;
+    }
+    let path     = word_eol[1].trim();
+    let settings = map_udata.apply(|chan_map: &ChanMap| {
+                       export_settings(chan_map)
+                   });
+    let count    = settings.values()
+                           .filter_map(Value::as_array)
+                           .map(Vec::len)
+                           .sum::<usize>();
+
+    match serde_json::to_string_pretty(&Value::Object(settings))
+              .map_err(|err| err.to_string())
+              .and_then(|json| {
+                  std::fs::write(path, json).map_err(|err| err.to_string())
+              }) {
+        Ok(()) => {
+            hc.print(&fm!("{ERROR_COLOR}Exported {} entries to {}.", 
+                          count, path));
+        },
+        Err(err) => {
+            hc.print(&fm!("{ERROR_COLOR}Failed to export to {}: {}", 
+                          path, err));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSIMPORT command. Reads a file written by 
+/// /TRANSEXPORT and merges its channels and network defaults into the 
+/// channel map, replacing the languages of any that are already set. 
+/// Entries without a known language are skipped.
+///
+fn on_cmd_transimport(hc        : &Hexchat, 
+                      word      : &[String], 
+                      word_eol  : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let map_udata = user_data.apply(|ud: &(UserData, State)| ud.0.clone());
+
+    if word.len() < 2 {
+        hc.print(&fm!("USAGE: {}", TRANSIMPORT_HELP));
+        return Eat::All;
+    }
+    let path     = word_eol[1].trim();
+    let settings = std::fs::read_to_string(path)
+                       .map_err(|err| err.to_string())
+                       .and_then(|json| {
+                           serde_json::from_str::<Value>(&json)
+                               .map_err(|err| err.to_string())
+                       });
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(err) => {
+            hc.print(&fm!("{ERROR_COLOR}Failed to import from {}: {}", 
+                          path, err));
+            return Eat::All;
+        }
+    };
+    let (imported, skipped) = map_udata.apply_mut(|chan_map: &mut ChanMap| {
+                                  import_settings(chan_map, &settings)
+                              });
+    hc.print(&fm!("{ERROR_COLOR}Imported {} entries from {}. {} were \
+                   skipped as invalid.", imported, path, skipped));
+    Eat::All
+}
+
+/// Builds the JSON written by /TRANSEXPORT from the channel map. Channels 
+/// go in the "channels" array, and network defaults in "networks", each 
+/// sorted so exports of the same settings compare equal.
+///
+fn export_settings(chan_map: &ChanMap) -> serde_json::Map<String, Value> {
+    let mut entries = chan_map.iter().collect::<Vec<_>>();
+    entries.sort_unstable();
+
+    let mut channels = vec![];
+    let mut networks = vec![];
+
+    for ((network, channel), (source, target)) in entries {
+        if channel == NET_DEFAULT_CHANNEL {
+            networks.push(serde_json::json!({
+                "network" : network,
+                "source"  : source,
+                "target"  : target,
+            }));
+        } else {
+            channels.push(serde_json::json!({
+                "network" : network,
+                "channel" : channel,
+                "source"  : source,
+                "target"  : target,
+            }));
+        }
+    }
+    let mut settings = serde_json::Map::new();
+    settings.insert("channels".to_string(), Value::Array(channels));
+    settings.insert("networks".to_string(), Value::Array(networks));
+    settings
+}
+
+/// Merges the entries of JSON written by /TRANSEXPORT into the channel map.
+/// An entry is skipped if it's missing a field, or its languages aren't 
+/// found by `find_lang()` or are the same.
+/// # Returns
+/// * The number of entries imported, and the number skipped.
+///
+fn import_settings(chan_map: &mut ChanMap, settings: &Value) -> (usize, usize) 
+{
+    let mut imported = 0;
+    let mut skipped  = 0;
+
+    for (field, has_channel) in [("channels", true), ("networks", false)] {
+        let entries = settings[field].as_array().cloned().unwrap_or_default();
+
+        for entry in &entries {
+            let field = |name| entry[name].as_str().filter(|v| !v.is_empty());
+            if {||{
+                let network = field("network")?;
+                let channel = if has_channel { 
+                                  field("channel")? 
+                              } else { 
+                                  NET_DEFAULT_CHANNEL 
+                              };
+                let source  = field("source")?;
+                let source  = if source.eq_ignore_ascii_case(AUTO_LANG.1) {
+                                  AUTO_LANG.1
+                              } else {
+                                  find_lang(source)?.1
+                              };
+                let target  = find_lang(field("target")?)?.1;
+                if source == target {
+                    return None;
+                }
+                chan_map.insert(chan_key(network, channel), 
+                                (source.to_string(), target.to_string()));
+                Some(())
+            }}().is_some() {
+                imported += 1;
+            } else {
+                skipped += 1;
+            }
+        }
+    }
+    (imported, skipped)
+}
+
+/// Implements the /TRANSSYSTEM command. Turns translation of the reason
+/// text in part, quit, and kick messages on or off. Off by default.
+///
+fn on_cmd_transsystem(hc        : &Hexchat, 
+                      word      : &[String], 
+                      _word_eol : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+    
+    if let Some(on) = word.get(1).and_then(|w| parse_on_off(w)) {
+        state.lock().unwrap().translate_system = on;
+        hc.print(&fm!("{ERROR_COLOR}Translation of part, quit, and kick \
+                       messages turned {}.", if on { "ON" } else { "OFF" }));
+    } else {
+        hc.print(&fm!("USAGE: {}", TRANSSYSTEM_HELP));
+    }
+    Eat::All
+}
+
+/// Parses the "on" and "off" options accepted by the addon's toggle 
+/// commands.
+///
+fn parse_on_off(option: &str) -> Option<bool> {
+    match option.to_lowercase().as_str() {
+        "on"  => Some(true),
+        "off" => Some(false),
+        _     => None,
+    }
+}
+
+/// Implements the /LSAY and /LME commands. Use /LSAY or /LME followed 
+/// by whatever text you want. The text will be translated and posted to 
+/// the channel. Other users will only see the translated message.
+///
+fn on_cmd_lsay(hc        : &Hexchat, 
+               _word     : &[String], 
+               word_eol  : &[String], 
+               user_data : &UserData
+              ) -> Eat 
+{
+    // Unpackage the user data to get which command this is for (LSAY/LME),
+    // the `UserData` with the `HashMap` in it, and the addon state.
+    let (cmd, ref map_udata, ref state) = user_data.apply(
+                                    |ud: &(&str, UserData, State)| {
+                                        (ud.0, ud.1.clone(), ud.2.clone())
+                                    });
+
+    if let Some(chan_langs) = get_channel_langs(hc, map_udata, state) {
+        let bot_cmd = is_bot_command(&word_eol[1], &get_cmd_sigils(state));
+        match check_outgoing(hc, state, &word_eol[1], bot_cmd) {
+            Outgoing::TooLong => return Eat::All,
+            Outgoing::AsIs    => {
+                hc.command(&fm!("{} {}", cmd, word_eol[1]));
+                return Eat::All;
+            },
+            Outgoing::Translate => {},
+        }
+        let listed = get_chan_key(hc).and_then(|key| {
+                         next_list_lang(state, &key)
+                     });
+        if try_on_cmd_lsay(hc, &word_eol[1], cmd, chan_langs, listed, 
+                           false, state).is_none() {
+            // If we get here, either `strip()` or `get_info()` returned None.
+            hc.print(&fm!("{ERROR_COLOR}\
+                     Translator Error: Basic failure retrieving channel \
+                     information, or unable to strip original message."));
+        }
+        Eat::All
+    } else if state.lock().unwrap().autoprompt {
+        // Hold on to the message so it can be sent with /TRANSRETRY once
+        // translation is set up for the channel.
+        if let Some(key) = get_chan_key(hc) {
+            hold_message(state, key, cmd, &word_eol[1]);
+        }
+        hc.print(&fm!("{ERROR_COLOR}Translation isn't on for this channel. \
+                       Use /SETLANG to set the languages, then /TRANSRETRY \
+                       to send your message."));
+        Eat::All
+    } else {
+        Eat::None
+    }
+}
+
+/// Implements the /LPREVIEW command. Translates the message the way /LSAY 
+/// would, and prints the translation and the original to the window 
+/// without sending anything, so an important message can be checked first.
+///
+fn on_cmd_lpreview(hc        : &Hexchat, 
+                   word      : &[String], 
+                   word_eol  : &[String], 
+                   user_data : &UserData
+                  ) -> Eat 
+{
+    let (ref map_udata, ref state) = user_data.apply(
+                                        |ud: &(UserData, State)| {
+                                            (ud.0.clone(), ud.1.clone())
+                                        });
+    if word.len() < 2 {
+        hc.print(&fm!("USAGE: {}", LPREVIEW_HELP));
+        return Eat::All;
+    }
+    if let Some(chan_langs) = get_channel_langs(hc, map_udata, state) {
+        if try_on_cmd_lsay(hc, &word_eol[1], "SAY", chan_langs, None, 
+                           true, state).is_none() {
+            hc.print(&fm!("{ERROR_COLOR}\
+                     Translator Error: Basic failure retrieving channel \
+                     information, or unable to strip original message."));
+        }
+    } else {
+        hc.print(&fm!("{ERROR_COLOR}Translation isn't on for this channel. \
+                       Use /SETLANG to turn it on."));
+    }
+    Eat::All
+}
+
+/// Implements the /LTRANS command. Translates a single message with the 
+/// given languages and sends it to the channel, without turning translation
+/// on for the channel.
+///
+fn on_cmd_ltrans(hc        : &Hexchat, 
+                 word      : &[String], 
+                 word_eol  : &[String], 
+                 user_data : &UserData
+                ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if word.len() < 4 {
+        hc.print(&fm!("USAGE: {}", LTRANS_HELP));
+        return Eat::All;
+    }
+    match (find_lang(&word[1]), find_lang(&word[2])) {
+        (Some(src), Some(tgt)) if src != tgt => {
+            let chan_langs = (src.1.to_string(), tgt.1.to_string());
+            if try_on_cmd_lsay(hc, &word_eol[3], "SAY", chan_langs, None, 
+                               false, &state).is_none() {
+                hc.print(&fm!("{ERROR_COLOR}\
+                         Translator Error: Basic failure retrieving channel \
+                         information, or unable to strip original message."));
+            }
+        },
+        _ => {
+            hc.print(&fm!("{ERROR_COLOR}\
+                     BAD LANGUAGE PARAMETERS. Use /LISTLANG to \
+                     get a list of supported languages. And don't \
+                     set translation source and target languages the \
+                     same."));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /LTRANSLAST command. Translates the last message received
+/// in the channel and prints the translation, for the odd message that 
+/// needs it in a channel that doesn't have translation on. The message's 
+/// language is detected. It's translated to the given language, or the
+/// channel's display language or the user's language if none is given.
+///
+fn on_cmd_ltranslast(hc        : &Hexchat, 
+                     word      : &[String], 
+                     _word_eol : &[String], 
+                     user_data : &UserData
+                    ) -> Eat 
+{
+    let (ref map_udata, ref state) = user_data.apply(
+                                        |ud: &(UserData, State)| {
+                                            (ud.0.clone(), ud.1.clone())
+                                        });
+    if word.len() > 2 {
+        hc.print(&fm!("USAGE: {}", LTRANSLAST_HELP));
+        return Eat::All;
+    }
+    let tgt_lang = if let Some(lang) = word.get(1) {
+        let Some(info) = find_lang(lang) else {
+            hc.print(&fm!("{ERROR_COLOR}Unsupported language: {}. Use \
+                           /LISTLANG to get a list of supported languages.",
+                          lang));
+            return Eat::All;
+        };
+        info.1.to_string()
+    } else if let Some(langs) = get_channel_langs(hc, map_udata, state) {
+        let langs = resolve_auto_lang(hc, state, langs);
+        with_display_lang(hc, state, langs).0
+    } else {
+        hc.print(&fm!("{ERROR_COLOR}Translation isn't on for this channel, \
+                       so give the language to translate to."));
+        return Eat::All;
+    };
+    if {||{
+        let key     = get_chan_key(hc)?;
+        let network = get_network(hc)?;
+        let channel = hc.get_info("channel")?;
+        let last    = state.lock().unwrap().last_received.get(&key).cloned();
+        
+        let Some((sender, message)) = last else {
+            hc.print(&fm!("{ERROR_COLOR}No message has been received in \
+                           this channel yet."));
+            return Some(());
+        };
+        let sender  = hc.strip(&sender, StripBoth)?;
+        let text    = hc.strip(&message, StripBoth)?;
+        let nicks   = channel_nicks(hc);
+        let state   = state.clone();
+
+        pool::execute(move || {
+            let (trans, emsg) = match translate_text(&text, AUTO_LANG.1, 
+                                                     &tgt_lang, &nicks, 
+                                                     &state) {
+                Ok(trans) => (trans, None),
+                Err(err)  => (err.get_partial_trans().to_string(), 
+                              Some(error_report(&err))),
+            };
+            main_thread(move |hc| {
+                let report = fm!("{}\t{}{}", sender, INLINE_MARKER, trans);
+                if let Some(ctx) = hc.find_context(&network, &channel) {
+                    if ctx.print(&report).is_ok() {
+                        if let Some(emsg) = &emsg {
+                            let _ = ctx.print(emsg);
+                        }
+                        return;
+                    }
+                }
+                hc.print(&orphaned_translation(&network, &channel, &trans));
+            });
+        });
+        Some(())
+    }}().is_none() {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 Translator Error: Basic failure retrieving channel \
+                 information, or unable to strip the message."));
+    }
+    Eat::All
+}
+
+/// Implements the /LDEF command. Looks up a word in Google's dictionary for
+/// the channel's pair of languages, from the user's language to the 
+/// channel's, and prints its translation and the alternatives for each 
+/// part of speech.
+///
+fn on_cmd_ldef(hc        : &Hexchat, 
+               word      : &[String], 
+               _word_eol : &[String], 
+               user_data : &UserData
+              ) -> Eat 
+{
+    let (ref map_udata, ref state) = user_data.apply(
+                                        |ud: &(UserData, State)| {
+                                            (ud.0.clone(), ud.1.clone())
+                                        });
+    if word.len() != 2 {
+        hc.print(&fm!("USAGE: {}", LDEF_HELP));
+        return Eat::All;
+    }
+    let Some(langs) = get_channel_langs(hc, map_udata, state) else {
+        hc.print(&fm!("{ERROR_COLOR}Translation isn't on for this channel. \
+                       Use /SETLANG to set its languages."));
+        return Eat::All;
+    };
+    let (src_lang, tgt_lang) = resolve_auto_lang(hc, state, langs);
+    
+    if {||{
+        let text    = hc.strip(&word[1], StripBoth)?;
+        let network = get_network(hc)?;
+        let channel = hc.get_info("channel")?;
+        let state   = state.clone();
+
+        pool::execute(move || {
+            let agent   = get_agent(&state);
+            let result  = if throttle(&state) {
+                              dictionary_single(&text, &agent, &src_lang, 
+                                                &tgt_lang)
+                          } else {
+                              Err( SingleTranslationError::StaticError(
+                                       ErrorKind::Quota, RATE_LIMITED_MSG) )
+                          };
+            let reports = match result {
+                Ok((trans, entries)) => {
+                    let mut reports = vec![
+                        fm!("{ERROR_COLOR}{} ({} > {}): {}", 
+                            text, src_lang, tgt_lang, trans)
+                    ];
+                    if entries.is_empty() {
+                        reports.push(fm!("{ERROR_COLOR}  No alternatives \
+                                          found."));
+                    }
+                    for (pos, terms) in entries {
+                        reports.push(fm!("{ERROR_COLOR}  {}: {}", 
+                                         pos, terms.join(", ")));
+                    }
+                    reports
+                },
+                Err(err) => {
+                    vec![fm!("{ERROR_COLOR}Dictionary lookup failed: {}", 
+                             String::from(&err))]
+                }
+            };
+            main_thread(move |hc| {
+                if let Some(ctx) = hc.find_context(&network, &channel) {
+                    if reports.iter().all(|r| ctx.print(r).is_ok()) {
+                        return;
+                    }
+                }
+                for report in &reports {
+                    hc.print(report);
+                }
+            });
+        });
+        Some(())
+    }}().is_none() {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 Translator Error: Basic failure retrieving channel \
+                 information, or unable to strip the word."));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSRETRY command. Sends the message held back when 
+/// `/LSAY` or `/LME` was used in the channel before translation was turned
+/// on for it. Only done when `/TRANSAUTOPROMPT` is on.
+///
+fn on_cmd_transretry(hc        : &Hexchat, 
+                     word      : &[String], 
+                     _word_eol : &[String], 
+                     user_data : &UserData
+                    ) -> Eat 
+{
+    let (map_udata, state) = user_data.apply(
+                                |ud: &(UserData, State)| {
+                                    (ud.0.clone(), ud.1.clone())
+                                });
+    if word.len() != 1 {
+        hc.print(&fm!("USAGE: {}", TRANSRETRY_HELP));
+        return Eat::All;
+    }
+    let Some(key) = get_chan_key(hc) else {
+        hc.print(&fm!("{ERROR_COLOR}Failed to get channel information."));
+        return Eat::All;
+    };
+    if get_channel_langs(hc, &map_udata, &state).is_none() {
+        hc.print(&fm!("{ERROR_COLOR}Translation still isn't on for this \
+                       channel. Use /SETLANG first."));
+        return Eat::All;
+    }
+    if let Some((cmd, message)) = take_pending(&state, &key) {
+        hc.command(&fm!("L{} {}", cmd, message));
+    } else {
+        hc.print(&fm!("{ERROR_COLOR}No message is waiting to be sent in \
+                       this channel."));
+    }
+    Eat::All
+}
+
+/// Holds a message sent with `/LSAY` or `/LME` in a context without 
+/// translation, for `/TRANSRETRY`. It replaces any message already held.
+/// # Arguments
+/// * `state`   - The addon state.
+/// * `key`     - The key of the context.
+/// * `cmd`     - The command to send the message with, "SAY" or "ME".
+/// * `message` - The message.
+///
+fn hold_message(state   : &State, 
+                key     : ChanData, 
+                cmd     : &'static str, 
+                message : &str) 
+{
+    state.lock().unwrap().pending.insert(key, (cmd, message.to_string()));
+}
+
+/// Takes the message held in a context for `/TRANSRETRY`, if there is one.
+///
+fn take_pending(state : &State, 
+                key   : &ChanData
+               ) -> Option<(&'static str, String)> 
+{
+    state.lock().unwrap().pending.remove(key)
+}
+
+/// Prints a notice in the context if an outgoing translation hasn't come 
+/// back after `SLOW_TRANSLATION_NOTICE`, so a slow server or retries don't
+/// look like the plugin has frozen. Printed lines can't be taken back, so 
+/// the notice is only printed for translations that are slow, rather than 
+/// for every one.
+/// # Arguments
+/// * `hc`      - The Hexchat interface.
+/// * `network` - The network of the context.
+/// * `channel` - The channel of the context.
+/// * `done`    - Set by the translation's worker when it's finished.
+///
+fn notify_if_slow(hc      : &Hexchat, 
+                  network : &str, 
+                  channel : &str, 
+                  done    : Arc<AtomicBool>) 
+{
+    let network = network.to_string();
+    let channel = channel.to_string();
+    hc.hook_timer(
+        SLOW_TRANSLATION_NOTICE,
+        move |hc, _| {
+            if !done.load(Ordering::SeqCst) {
+                if let Some(ctx) = hc.find_context(&network, &channel) {
+                    let _ = ctx.print(&fm!("{ERROR_COLOR}\
+                                            Translating\u{2026}"));
+                }
+            }
+            // Run once.
+            0
+        },
+        NoData);
+}
+
+/// Records the original of a translation sent to a context, for 
+/// `/TRANSUNDO`, evicting the oldest one if the context has too many.
+///
+fn push_sent_orig(state: &State, network: &str, channel: &str, orig: &str) {
+    let mut state = state.lock().unwrap();
+    let origs = state.sent_origs.entry(chan_key(network, channel))
+                                .or_default();
+    if origs.len() >= SENT_ORIGS_SIZE {
+        origs.pop_front();
+    }
+    origs.push_back(orig.to_string());
+}
+
+/// Implements the /TRANSUNDO command. IRC messages can't be taken back, so 
+/// this resends the original of the last message translated and sent in 
+/// the channel, untranslated and marked as a correction. Using it again 
+/// resends the one before that, and so on.
+///
+fn on_cmd_transundo(hc        : &Hexchat, 
+                    word      : &[String], 
+                    _word_eol : &[String], 
+                    user_data : &UserData
+                   ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+    
+    if word.len() != 1 {
+        hc.print(&fm!("USAGE: {}", TRANSUNDO_HELP));
+        return Eat::All;
+    }
+    let Some(key) = get_chan_key(hc) else {
+        hc.print(&fm!("{ERROR_COLOR}Failed to get channel information."));
+        return Eat::All;
+    };
+    let orig = state.lock().unwrap().sent_origs.get_mut(&key)
+                                    .and_then(|origs| origs.pop_back());
+    if let Some(orig) = orig {
+        hc.command(&fm!("SAY * correction: {}", orig));
+    } else {
+        hc.print(&fm!("{ERROR_COLOR}No translated message has been sent in \
+                       this channel to correct."));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSAUTOPROMPT command. When on, using `/LSAY` or `/LME`
+/// in a channel without translation prompts the user to set it up and holds
+/// the message for `/TRANSRETRY`, instead of passing the command through.
+///
+fn on_cmd_transautoprompt(hc        : &Hexchat, 
+                          word      : &[String], 
+                          _word_eol : &[String], 
+                          user_data : &UserData
+                         ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+    
+    if let Some(on) = word.get(1).and_then(|w| parse_on_off(w)) {
+        let mut state = state.lock().unwrap();
+        state.autoprompt = on;
+        if !on {
+            state.pending.clear();
+        }
+        hc.print(&fm!("{ERROR_COLOR}Prompting for languages on /LSAY turned \
+                       {}.", if on { "ON" } else { "OFF" }));
+    } else {
+        hc.print(&fm!("USAGE: {}", TRANSAUTOPROMPT_HELP));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSFORMAT command. Turns on or off putting the 
+/// formatting at the start and end of a message - colors, bold, and the 
+/// like - back around its translation. Formatting in the middle of a message
+/// can't be mapped onto the translation, so it's always dropped.
+///
+fn on_cmd_transformat(hc        : &Hexchat, 
+                      word      : &[String], 
+                      _word_eol : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if let Some(on) = word.get(1).and_then(|w| parse_on_off(w)) {
+        state.lock().unwrap().keep_format = on;
+        hc.print(&fm!("{ERROR_COLOR}Keeping message formatting turned {}.", 
+                      if on { "ON" } else { "OFF" }));
+    } else {
+        hc.print(&fm!("USAGE: {}", TRANSFORMAT_HELP));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSSKIPSAME command. Turns on or off detecting the
+/// language of each incoming message before translating it, and leaving it
+/// as is if it's already in the user's language. Translating such a message
+/// can only reword it, but detecting takes a request of its own, so this is
+/// off by default.
+///
+fn on_cmd_transskipsame(hc        : &Hexchat, 
+                        word      : &[String], 
+                        _word_eol : &[String], 
+                        user_data : &UserData
+                       ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if let Some(on) = word.get(1).and_then(|w| parse_on_off(w)) {
+        state.lock().unwrap().skip_same = on;
+        hc.print(&fm!("{ERROR_COLOR}Skipping messages already in your \
+                       language turned {}.", if on { "ON" } else { "OFF" }));
+    } else {
+        hc.print(&fm!("USAGE: {}", TRANSSKIPSAME_HELP));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSMENICK command. Turns on or off translating `/LME`
+/// actions with the user's nick in front, as they're read in the channel. 
+/// Without a subject, languages that conjugate verbs by person can pick the
+/// wrong form, but the nick can also throw off translation into others, so
+/// it's off by default.
+///
+fn on_cmd_transmenick(hc        : &Hexchat, 
+                      word      : &[String], 
+                      _word_eol : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if let Some(on) = word.get(1).and_then(|w| parse_on_off(w)) {
+        state.lock().unwrap().me_nick = on;
+        hc.print(&fm!("{ERROR_COLOR}Translating actions with your nick \
+                       turned {}.", if on { "ON" } else { "OFF" }));
+    } else {
+        hc.print(&fm!("USAGE: {}", TRANSMENICK_HELP));
+    }
+    Eat::All
+}
+
+/// Removes the user's nick from the start of an action's translation, where
+/// it was put by `/TRANSMENICK` to give the action a subject.
+/// # Returns
+/// * The translation without the nick, or `None` if it doesn't start with 
+///   the nick followed by a space, like when the translation moved it or 
+///   attached something to it.
+///
+fn strip_leading_nick(trans: &str, nick: &str) -> Option<String> {
+    let rest = trans.trim_start().strip_prefix(nick)?;
+    if rest.starts_with(char::is_whitespace) {
+        Some(rest.trim_start().to_string())
+    } else {
+        None
+    }
+}
+
+/// Splits a mention of a user in the channel, like "bob: " or "bob, ", off 
+/// the start of a message. The first word only counts as a mention if it's
+/// the nick of someone in the channel, so sentences that happen to start 
+/// with a word and a colon are left alone.
+/// # Arguments
+/// * `text`    - The message.
+/// * `nicks`   - The case-folded nicks of the users in the channel.
+/// # Returns
+/// * The mention, with its punctuation and the spaces after it, and the
+///   rest of the message; or `None` if the message doesn't start with one,
+///   or has nothing after it.
+///
+fn split_mention(text  : &str, 
+                 nicks : &HashSet<String>
+                ) -> Option<(String, String)> 
+{
+    let end  = text.find([':', ','])?;
+    let nick = text[..end].trim_start();
+    if nick.is_empty() || nick.contains(char::is_whitespace) 
+        || !nicks.contains(&irc_casefold(nick)) {
+        return None;
+    }
+    let rest = text[end + 1..].trim_start();
+    if rest.is_empty() {
+        return None;
+    }
+    let split = text.len() - rest.len();
+    Some((text[..split].to_string(), rest.to_string()))
+}
+
+/// Implements the /TRANSCOLOR command. Sets the mIRC color, 0 to 15, that 
+/// the originals of translated messages or the addon's notices and errors
+/// are printed in, for themes the default colors are hard to read on. 
+/// Without arguments, the current colors are printed.
+///
+fn on_cmd_transcolor(hc        : &Hexchat, 
+                     word      : &[String], 
+                     _word_eol : &[String], 
+                     _userdata : &UserData
+                    ) -> Eat 
+{
+    let color = word.get(2).and_then(|c| c.parse::<u8>().ok())
+                           .filter(|&c| c <= MAX_IRC_COLOR);
+    match (word.len(), color) {
+        (1, _) => {
+            hc.print(&fm!("{ERROR_COLOR}Colors: {ORIG_COLOR}orig {}\
+                           {ERROR_COLOR}, error {}.", ORIG_COLOR.get(), 
+                                                       ERROR_COLOR.get()));
+        },
+        (3, Some(color)) if word[1].eq_ignore_ascii_case("orig") => {
+            ORIG_COLOR.set(color);
+            hc.print(&fm!("{ERROR_COLOR}Color of originals set to \
+                           {ORIG_COLOR}{}{ERROR_COLOR}.", color));
+        },
+        (3, Some(color)) if word[1].eq_ignore_ascii_case("error") => {
+            ERROR_COLOR.set(color);
+            hc.print(&fm!("{ERROR_COLOR}Color of notices and errors set to \
+                           {}.", color));
+        },
+        (3, None) => {
+            hc.print(&fm!("{ERROR_COLOR}The color must be a number from 0 \
+                           to {}.", MAX_IRC_COLOR));
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSCOLOR_HELP));
+        },
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSVERIFY command. Turns on or off translating each 
+/// outgoing translation back to the user's language and showing it below
+/// the message, as a check of how well it was translated. It doubles the
+/// requests made for outgoing messages, so it's off by default.
+///
+fn on_cmd_transverify(hc        : &Hexchat, 
+                      word      : &[String], 
+                      _word_eol : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if let Some(on) = word.get(1).and_then(|w| parse_on_off(w)) {
+        state.lock().unwrap().verify = on;
+        hc.print(&fm!("{ERROR_COLOR}Back-translation of outgoing messages \
+                       turned {}.", if on { "ON" } else { "OFF" }));
+    } else {
+        hc.print(&fm!("USAGE: {}", TRANSVERIFY_HELP));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSAWAYPAUSE command. Turns on or off sending `/LSAY` 
+/// and `/LME` messages untranslated while the user is marked away, so 
+/// translations aren't used up then. Incoming messages are still 
+/// translated. Off by default.
+///
+fn on_cmd_transawaypause(hc        : &Hexchat, 
+                         word      : &[String], 
+                         _word_eol : &[String], 
+                         user_data : &UserData
+                        ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if let Some(on) = word.get(1).and_then(|w| parse_on_off(w)) {
+        state.lock().unwrap().away_pause = on;
+        hc.print(&fm!("{ERROR_COLOR}Pausing outgoing translation while away \
+                       turned {}.", if on { "ON" } else { "OFF" }));
+    } else {
+        hc.print(&fm!("USAGE: {}", TRANSAWAYPAUSE_HELP));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSCONFIDENCE command. Sets the confidence, as a 
+/// percentage, below which the language detected for an outgoing message 
+/// in a channel with an `auto` source is flagged as low confidence. `on` 
+/// uses `LOW_DETECTION_CONFIDENCE`, and `off`, the default, flags none, 
+/// since the confidence is read from undocumented parts of the response.
+/// Without arguments, prints the setting.
+///
+fn on_cmd_transconfidence(hc        : &Hexchat, 
+                          word      : &[String], 
+                          _word_eol : &[String], 
+                          user_data : &UserData
+                         ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let setting = word.get(1).and_then(|w| {
+        match parse_on_off(w) {
+            Some(true)  => Some(Some(LOW_DETECTION_CONFIDENCE)),
+            Some(false) => Some(None),
+            None => {
+                w.trim_end_matches('%').parse::<u8>().ok()
+                 .filter(|pct| (1..=100).contains(pct))
+                 .map(|pct| Some(pct as f64 / 100.))
+            }
+        }
+    });
+    match (word.len(), setting) {
+        (1, _) => {
+            match state.lock().unwrap().min_confidence {
+                Some(conf) => {
+                    hc.print(&fm!("{ERROR_COLOR}Detected languages with a \
+                                   confidence below {:.0}% are flagged.", 
+                                  conf * 100.));
+                },
+                None => {
+                    hc.print(&fm!("{ERROR_COLOR}Detected languages aren't \
+                                   flagged for low confidence."));
+                }
+            }
+        },
+        (2, Some(conf)) => {
+            state.lock().unwrap().min_confidence = conf;
+            match conf {
+                Some(conf) => {
+                    hc.print(&fm!("{ERROR_COLOR}Detected languages with a \
+                                   confidence below {:.0}% will be \
+                                   flagged.", conf * 100.));
+                },
+                None => {
+                    hc.print(&fm!("{ERROR_COLOR}Detected languages won't be \
+                                   flagged for low confidence."));
+                }
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSCONFIDENCE_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Gets the formatting codes at the start and end of a message, if keeping
+/// formatting is turned on.
+/// # Returns
+/// * A tuple of the leading and trailing formatting codes. Both are empty if
+///   `/TRANSFORMAT` is off.
+///
+fn get_edge_format(state: &State, message: &str) -> (String, String) {
+    if state.lock().unwrap().keep_format {
+        edge_format(message)
+    } else {
+        (String::new(), String::new())
+    }
+}
+
+/// Finds the runs of IRC formatting codes at the start and end of a message.
+/// A message that's formatted the same throughout, like one all in one 
+/// color, has its formatting at the start.
+/// # Returns
+/// * A tuple of the leading and trailing runs of codes.
+///
+fn edge_format(message: &str) -> (String, String) {
+    // Single character codes, colors with optional foreground and 
+    // background, and hex colors.
+    const CODE: &str = concat!(
+        r"(?:[\x02\x0F\x11\x16\x1D\x1E\x1F]",
+        r"|\x03(?:\d{1,2}(?:,\d{1,2})?)?",
+        r"|\x04(?:[0-9A-Fa-f]{6}(?:,[0-9A-Fa-f]{6})?)?)+");
+    let leading  = Regex::new(&fm!("^{}", CODE)).unwrap();
+    let trailing = Regex::new(&fm!("{}$", CODE)).unwrap();
+
+    let lead = leading.find(message).map_or("", |m| m.as_str());
+    let rest = &message[lead.len()..];
+    let tail = trailing.find(rest).map_or("", |m| m.as_str());
+    (lead.to_string(), tail.to_string())
+}
+
+/// Implements the /TRANSINLINE command. Sets whether incoming translations
+/// replace the original message in the window, or are shown with the 
+/// original on a separate line below them. Hexchat can't edit a line once 
+/// it's printed, so "replace" works by showing only the translation, with a 
+/// marker, in place of the original.
+///
+fn on_cmd_transinline(hc        : &Hexchat, 
+                      word      : &[String], 
+                      _word_eol : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let replace = match word.get(1).map(|w| w.to_lowercase()).as_deref() {
+        Some("replace")  => true,
+        Some("separate") => false,
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSINLINE_HELP));
+            return Eat::All;
+        }
+    };
+    state.lock().unwrap().inline_replace = replace;
+    hc.print(&fm!("{ERROR_COLOR}Incoming translations will {}.",
+                  if replace { "replace the original message" } 
+                  else       { "be shown above the original message" }));
+    Eat::All
+}
+
+/// Put before incoming translations shown in place of the original message
+/// with `/TRANSINLINE replace`.
+///
+const INLINE_MARKER: &str = "[tr] ";
+
+/// Implements the /TRANSORIG command. Sets whether the original of each 
+/// translated message is printed below the translation in the current 
+/// channel, coming and going.
+///
+fn on_cmd_transorig(hc        : &Hexchat, 
+                    word      : &[String], 
+                    _word_eol : &[String], 
+                    user_data : &UserData
+                   ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let Some(on) = word.get(1).and_then(|w| parse_on_off(w)) else {
+        hc.print(&fm!("USAGE: {}", TRANSORIG_HELP));
+        return Eat::All;
+    };
+    let Some(key) = get_chan_key(hc) else {
+        hc.print(&fm!("{ERROR_COLOR}Failed to get channel information."));
+        return Eat::All;
+    };
+    let mut state = state.lock().unwrap();
+    if on {
+        state.hidden_origs.remove(&key);
+    } else {
+        state.hidden_origs.insert(key);
+    }
+    hc.print(&fm!("{ERROR_COLOR}Original messages will {} in this channel.",
+                  if on { "be shown" } else { "not be shown" }));
+    Eat::All
+}
+
+/// Indicates whether the originals of translated messages are printed in a
+/// context. They are unless `/TRANSORIG off` was given for it.
+///
+fn shows_orig(state: &State, network: &str, channel: &str) -> bool {
+    !state.lock().unwrap().hidden_origs.contains(&chan_key(network, channel))
+}
+
+/// Implements the /TRANSERRRATE command. Sets whether over-limit errors are
+/// shown for every message, or only once per context within a short window.
+///
+fn on_cmd_transerrrate(hc        : &Hexchat, 
+                       word      : &[String], 
+                       _word_eol : &[String], 
+                       user_data : &UserData
+                      ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+    
+    let once = match word.get(1).map(|w| w.to_lowercase()).as_deref() {
+        Some("once")   => true,
+        Some("always") => false,
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSERRRATE_HELP));
+            return Eat::All;
+        }
+    };
+    let mut state = state.lock().unwrap();
+    state.over_limit_once = once;
+    state.over_limit_shown.clear();
+    hc.print(&fm!("{ERROR_COLOR}Over-limit errors will be shown {}.", 
+                  if once { "once per channel" } else { "for every message" }));
+    Eat::All
+}
+
+/// Implements the /TRANSRETRIES command. Sets how many times a translation 
+/// request that failed with a network or server error is retried, or prints
+/// the current count.
+///
+fn on_cmd_transretries(hc        : &Hexchat, 
+                       word      : &[String], 
+                       _word_eol : &[String], 
+                       user_data : &UserData
+                      ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    match word.get(1).map(|w| w.parse::<u32>()) {
+        None => {
+            hc.print(&fm!("{ERROR_COLOR}Failed requests are retried up to \
+                           {} times.", get_max_retries(&state)));
+        },
+        Some(Ok(count)) if count <= MAX_RETRIES_LIMIT => {
+            state.lock().unwrap().max_retries = Some(count);
+            hc.print(&fm!("{ERROR_COLOR}Failed requests will be retried up \
+                           to {} times.", count));
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSRETRIES_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSMAXCHARS command. Sets the most characters a text 
+/// can have to be translated. Longer ones are refused rather than sent.
+/// Without arguments, prints the limit.
+///
+fn on_cmd_transmaxchars(hc        : &Hexchat, 
+                        word      : &[String], 
+                        _word_eol : &[String], 
+                        user_data : &UserData
+                       ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    match word.get(1).map(|w| w.parse::<usize>()) {
+        None => {
+            hc.print(&fm!("{ERROR_COLOR}Texts of up to {} characters are \
+                           translated.", get_max_chars(&state)));
+        },
+        Some(Ok(count)) if (1..=MAX_CHARS_LIMIT).contains(&count) => {
+            state.lock().unwrap().max_chars = Some(count);
+            hc.print(&fm!("{ERROR_COLOR}Texts of up to {} characters will be \
+                           translated.", count));
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSMAXCHARS_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSRATE command. Sets how many requests a minute are 
+/// sent to Google, or turns the limit off. Requests over the limit wait 
+/// their turn instead of being sent. Without arguments, prints the rate.
+///
+fn on_cmd_transrate(hc        : &Hexchat, 
+                    word      : &[String], 
+                    _word_eol : &[String], 
+                    user_data : &UserData
+                   ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let rate = word.get(1).map(|w| {
+                   if parse_on_off(w) == Some(false) {
+                       Some(None)
+                   } else {
+                       w.parse::<u32>().ok().filter(|&n| n > 0).map(Some)
+                   }
+               });
+    match (word.len(), rate) {
+        (1, _) => {
+            match state.lock().unwrap().rate_limiter.per_minute {
+                Some(rate) => {
+                    hc.print(&fm!("{ERROR_COLOR}Up to {} requests a minute \
+                                   are sent to Google.", rate));
+                },
+                None => {
+                    hc.print(&fm!("{ERROR_COLOR}Requests to Google aren't \
+                                   rate limited."));
+                }
+            }
+        },
+        (2, Some(Some(rate))) => {
+            state.lock().unwrap().rate_limiter.set_rate(rate);
+            match rate {
+                Some(rate) => {
+                    hc.print(&fm!("{ERROR_COLOR}Up to {} requests a minute \
+                                   will be sent to Google.", rate));
+                },
+                None => {
+                    hc.print(&fm!("{ERROR_COLOR}Requests to Google won't be \
+                                   rate limited."));
+                }
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSRATE_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// How long a shown over-limit error keeps further ones in the same context
+/// from being shown when `/TRANSERRRATE once` is set.
+///
+const OVER_LIMIT_ERR_WINDOW: Duration = Duration::from_secs(60);
+
+/// Decides whether an over-limit error should be shown in a context, and
+/// records the time if it is.
+/// # Arguments
+/// * `state`   - The addon state.
+/// * `network` - The network of the context.
+/// * `channel` - The channel of the context.
+/// # Returns
+/// * `true` if the error should be printed.
+///
+fn show_over_limit(state: &State, network: &str, channel: &str) -> bool {
+    let mut state = state.lock().unwrap();
+    if !state.over_limit_once {
+        return true;
+    }
+    let now  = Instant::now();
+    let key  = chan_key(network, channel);
+    let last = state.over_limit_shown.get(&key).copied();
+    if is_repeat_error(last, now) {
+        false
+    } else {
+        state.over_limit_shown.insert(key, now);
+        true
+    }
+}
+
+/// Determines whether an error is a repeat of one shown at `last`, within
+/// `OVER_LIMIT_ERR_WINDOW`.
+///
+fn is_repeat_error(last: Option<Instant>, now: Instant) -> bool {
+    last.is_some_and(|last| now.duration_since(last) < OVER_LIMIT_ERR_WINDOW)
+}
+
+/// Implements the /TRANSAUTOOFF command. Sets how many consecutive failed
+/// translations within a number of seconds turn a channel's translation off.
+/// A count of 0 turns the feature off.
+///
+fn on_cmd_transautooff(hc        : &Hexchat, 
+                       word      : &[String], 
+                       _word_eol : &[String], 
+                       user_data : &UserData
+                      ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let count = word.get(1).and_then(|w| w.parse::<u32>().ok());
+    let secs  = word.get(2).and_then(|w| w.parse::<u64>().ok());
+
+    match (word.len(), count, secs) {
+        (2, Some(0), _) | (3, Some(0), Some(_)) => {
+            let mut state = state.lock().unwrap();
+            state.auto_off = None;
+            state.error_runs.clear();
+            hc.print(&fm!("{ERROR_COLOR}Turning translation off after \
+                           repeated errors is OFF."));
+        },
+        (3, Some(count), Some(secs)) if secs > 0 => {
+            let mut state = state.lock().unwrap();
+            state.auto_off = Some((count, Duration::from_secs(secs)));
+            state.error_runs.clear();
+            hc.print(&fm!("{ERROR_COLOR}Translation will be turned off in a \
+                           channel after {} errors in a row within {} \
+                           seconds.", count, secs));
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSAUTOOFF_HELP));
+        }
+    }
+    Eat::All
+}
+
+thread_local! {
+    /// Set while `auto_disable()` issues /OFFLANG, so the command keeps the
+    /// context's other settings, like its mutes and history. Hexchat runs 
+    /// the command before `command()` returns, on the same thread.
+    ///
+    static AUTO_DISABLING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Turns translation off in a context that the addon has given up on, with
+/// a notice in a fixed format that stands out from ordinary errors and can
+/// be matched by scripts: `*** TRANSLATION AUTO-DISABLED (<reason>) ***`.
+/// Only the context's entry in the channel map is removed; its other 
+/// settings are kept for when the user turns translation back on.
+/// # Arguments
+/// * `ctx`    - The context to turn translation off in.
+/// * `reason` - Why it's being turned off.
+/// * `hint`   - What the user can do about it.
+///
+fn auto_disable(ctx: &Context, reason: &str, hint: &str) 
+    -> Result<(), HexchatError> 
+{
+    ctx.print(&fm!("{ERROR_COLOR}*** TRANSLATION AUTO-DISABLED ({}) *** {}",
+                   reason, hint))?;
+    let prior  = AUTO_DISABLING.with(|a| a.replace(true));
+    let result = ctx.command("OFFLANG");
+    AUTO_DISABLING.with(|a| a.set(prior));
+    result
+}
+
+/// Handles an over-limit error in a context. Translation is paused for the
+/// cooldown, a minute unless another is set with `/TRANSCOOLDOWN`, and a 
+/// notice is printed the first time, along with a timer that ends the pause
+/// with `end_pause()`. With the cooldown turned off, translation is turned 
+/// off instead.
+/// # Arguments
+/// * `hc`      - The Hexchat interface.
+/// * `ctx`     - The context the error happened in.
+/// * `state`   - The addon state.
+/// * `network` - The network of the context.
+/// * `channel` - The channel of the context.
+///
+fn pause_or_disable(hc      : &Hexchat,
+                    ctx     : &Context, 
+                    state   : &State, 
+                    network : &str, 
+                    channel : &str
+                   ) -> Result<(), HexchatError> 
+{
+    let mut guard = state.lock().unwrap();
+    let Cooldown(Some(cooldown)) = guard.cooldown else {
+        drop(guard);
+        return auto_disable(ctx, "over limit", OVER_LIMIT_COOLDOWN_HINT);
+    };
+    let key    = chan_key(network, channel);
+    let paused = guard.paused.contains_key(&key);
+    guard.paused.insert(key.clone(), Instant::now() + cooldown);
+    drop(guard);
+    
+    if !paused {
+        ctx.print(&fm!("{ERROR_COLOR}Translation paused for {} seconds \
+                        (over limit). Messages aren't translated until \
+                        then.", cooldown.as_secs()))?;
+
+        // A pause that's extended by another error before the timer goes 
+        // off is checked again after another cooldown.
+        let state = state.clone();
+        hc.hook_timer(
+            cooldown.as_millis() as i64,
+            move |hc, _| i32::from(!end_pause(hc, &state, &key)),
+            NoData);
+    }
+    Ok(())
+}
+
+/// Ends a context's over-limit pause once it's over, printing a notice in
+/// the context. Called from the timer `pause_or_disable()` sets.
+/// # Returns
+/// * Whether the pause is done with, either ended here or already ended by
+///   `/TRANSRESUME` or turning translation off; `false` if it has been 
+///   extended and isn't over yet.
+///
+fn end_pause(hc: &Hexchat, state: &State, key: &ChanData) -> bool {
+    let mut guard = state.lock().unwrap();
+    match guard.paused.get(key) {
+        Some(until) if Instant::now() < *until => false,
+        Some(_) => {
+            guard.paused.remove(key);
+            drop(guard);
+            if let Some(ctx) = hc.find_context(&key.0, &key.1) {
+                let _ = ctx.print(&fm!("{ERROR_COLOR}Translation resumed."));
+            }
+            true
+        },
+        None => true,
+    }
+}
+
+/// Whether translation is paused in every context with `/TRANSPAUSE`. It's
+/// kept in a static so pausing doesn't touch the channel map, and resuming
+/// picks up where things were.
+///
+static ALL_PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Indicates whether translation in the current context is paused, either
+/// everywhere with `/TRANSPAUSE`, or in the context after an over-limit 
+/// error. A pause that's over counts as ended even before `end_pause()` 
+/// clears it.
+///
+fn is_paused(hc: &Hexchat, state: &State) -> bool {
+    if ALL_PAUSED.load(Ordering::SeqCst) {
+        return true;
+    }
+    get_chan_key(hc).is_some_and(|key| {
+        state.lock().unwrap()
+             .paused.get(&key).is_some_and(|until| Instant::now() < *until)
+    })
+}
+
+/// Implements the /TRANSPAUSE command. Pauses translation in every channel
+/// without turning it off, so messages pass through untranslated and 
+/// `/LSAY` sends like `/SAY` until /TRANSRESUME.
+///
+fn on_cmd_transpause(hc        : &Hexchat, 
+                     word      : &[String], 
+                     _word_eol : &[String], 
+                     _userdata : &UserData
+                    ) -> Eat 
+{
+    if word.len() > 1 {
+        hc.print(&fm!("USAGE: {}", TRANSPAUSE_HELP));
+    } else if ALL_PAUSED.swap(true, Ordering::SeqCst) {
+        hc.print(&fm!("{ERROR_COLOR}Translation is already paused in all \
+                       channels. Use /TRANSRESUME to resume it."));
+    } else {
+        hc.print(&fm!("{ERROR_COLOR}TRANSLATION IS PAUSED IN ALL CHANNELS. \
+                       Messages will be shown and sent untranslated until \
+                       /TRANSRESUME."));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSRESUME command. Resumes translation paused with 
+/// /TRANSPAUSE in every channel it's on in.
+///
+fn on_cmd_transresume(hc        : &Hexchat, 
+                      word      : &[String], 
+                      _word_eol : &[String], 
+                      _userdata : &UserData
+                     ) -> Eat 
+{
+    if word.len() > 1 {
+        hc.print(&fm!("USAGE: {}", TRANSRESUME_HELP));
+    } else if ALL_PAUSED.swap(false, Ordering::SeqCst) {
+        hc.print(&fm!("{ERROR_COLOR}Translation resumed in all channels."));
+    } else {
+        hc.print(&fm!("{ERROR_COLOR}Translation isn't paused."));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSCOOLDOWN command. Sets how long translation is 
+/// paused in a context after an over-limit error, or turns pausing off so
+/// translation is turned off instead. Prints the setting with no arguments.
+///
+fn on_cmd_transcooldown(hc        : &Hexchat, 
+                        word      : &[String], 
+                        _word_eol : &[String], 
+                        user_data : &UserData
+                       ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    match word.get(1).map(|w| w.to_lowercase()).as_deref() {
+        None => {
+            match state.lock().unwrap().cooldown.0 {
+                Some(cd) => hc.print(&fm!("{ERROR_COLOR}Translation pauses \
+                                           for {} seconds when over the \
+                                           limit.", cd.as_secs())),
+                None     => hc.print(&fm!("{ERROR_COLOR}Translation is \
+                                           turned off when over the \
+                                           limit.")),
+            }
+        },
+        Some("off") => {
+            let mut state = state.lock().unwrap();
+            state.cooldown = Cooldown(None);
+            state.paused.clear();
+            hc.print(&fm!("{ERROR_COLOR}Translation will be turned off when \
+                           over the limit."));
+        },
+        Some(arg) => {
+            match arg.parse::<u64>() {
+                Ok(secs) if secs > 0 => {
+                    state.lock().unwrap().cooldown = 
+                        Cooldown(Some(Duration::from_secs(secs)));
+                    hc.print(&fm!("{ERROR_COLOR}Translation will pause for \
+                                   {} seconds when over the limit.", secs));
+                },
+                _ => {
+                    hc.print(&fm!("USAGE: {}", TRANSCOOLDOWN_HELP));
+                }
+            }
+        }
+    }
+    Eat::All
+}
+
+/// Printed with the notice when translation is turned off because the 
+/// server's limit was reached. Google's limit can take up to an hour to 
+/// clear.
+///
+const OVER_LIMIT_COOLDOWN_HINT: &str = "The translation server's limit was \
+                                        reached. Wait about an hour before \
+                                        turning translation back on with \
+                                        /SETLANG.";
+
+/// Printed with the notice when translation is turned off after too many
+/// errors in a row.
+///
+const ERROR_RUN_HINT: &str = "There were too many translation errors in a \
+                              row. Check /TRANSPROVIDER, then turn \
+                              translation back on with /SETLANG.";
+
+/// Records whether a translation in a context failed, and decides whether
+/// the context has had enough consecutive failures to be turned off.
+/// # Arguments
+/// * `state`   - The addon state.
+/// * `network` - The network of the context.
+/// * `channel` - The channel of the context.
+/// * `failed`  - Whether the translation failed. Over-limit errors aren't 
+///               counted, since they turn translation off on their own.
+/// # Returns
+/// * `true` if translation should be turned off for the context.
+///
+fn note_result(state: &State, network: &str, channel: &str, failed: bool) 
+    -> bool 
+{
+    let mut state = state.lock().unwrap();
+    let key       = chan_key(network, channel);
+    let Some(limit) = state.auto_off else {
+        return false;
+    };
+    if !failed {
+        state.error_runs.remove(&key);
+        return false;
+    }
+    let run = next_error_run(state.error_runs.get(&key).copied(), 
+                             Instant::now(), limit.1);
+    if run.0 >= limit.0 {
+        state.error_runs.remove(&key);
+        true
+    } else {
+        state.error_runs.insert(key, run);
+        false
+    }
+}
+
+/// Extends a run of consecutive errors with another one. A run that started
+/// longer than `window` ago is begun again.
+/// # Arguments
+/// * `run`    - The current run of errors, its count and start time.
+/// * `now`    - The time of the new error.
+/// * `window` - The period a run must fall within.
+/// # Returns
+/// * The run including the new error.
+///
+fn next_error_run(run    : Option<(u32, Instant)>, 
+                  now    : Instant, 
+                  window : Duration
+                 ) -> (u32, Instant) 
+{
+    match run {
+        Some((count, start)) if now.duration_since(start) <= window => {
+            (count + 1, start)
+        },
+        _ => (1, now),
+    }
+}
+
+/// What's done with an outgoing message before it's translated.
+/// # Variants
+/// * `TooLong`   - It's over the `/TRANSMAXCHARS` limit, so it isn't sent at
+///                 all.
+/// * `AsIs`      - It's sent untranslated, since translation is paused or 
+///                 off for outgoing messages in the context, or it's a bot
+///                 command.
+/// * `Translate` - It's translated and sent.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outgoing {
+    TooLong,
+    AsIs,
+    Translate,
+}
+
+/// Decides what's done with an outgoing message. The length limit comes 
+/// first, so an accidental paste isn't sent untranslated while translation
+/// is paused.
+/// # Arguments
+/// * `message`   - The message, with its formatting stripped.
+/// * `max_chars` - The most characters a message can have to be sent.
+/// * `as_is`     - Whether the message would be sent untranslated.
+///
+fn outgoing_action(message: &str, max_chars: usize, as_is: bool) -> Outgoing {
+    if message.chars().count() > max_chars {
+        Outgoing::TooLong
+    } else if as_is {
+        Outgoing::AsIs
+    } else {
+        Outgoing::Translate
+    }
+}
+
+/// Checks an outgoing message of the current context before anything is 
+/// sent, printing a notice if it's too long to send. Every command that sends
+/// translated text checks its text with this first, and its translation with
+/// `blocked_word()` before it's sent, so pausing, the outgoing direction, the
+/// length limit, and the blocklist hold for all of them.
+/// # Arguments
+/// * `hc`      - The Hexchat interface.
+/// * `state`   - The addon state.
+/// * `message` - The message, as typed.
+/// * `as_is`   - Whether the caller sends the message untranslated for a 
+///               reason of its own, like being a bot command.
+///
+fn check_outgoing(hc      : &Hexchat, 
+                  state   : &State, 
+                  message : &str, 
+                  as_is   : bool
+                 ) -> Outgoing 
+{
+    let as_is     = as_is
+                    || !get_direction(hc, state).outgoing() 
+                    || is_paused(hc, state);
+    let max_chars = get_max_chars(state);
+    let stripped  = hc.strip(message, StripBoth)
+                      .unwrap_or_else(|| message.to_string());
+    let action    = outgoing_action(&stripped, max_chars, as_is);
+    if action == Outgoing::TooLong {
+        report_too_long(hc, max_chars);
+    }
+    action
+}
+
+/// Looks for a word blocked with `/TRANSBLOCK` in a translation about to be
+/// sent.
+/// # Returns
+/// * The first blocked word in the translation, if any.
+///
+fn blocked_word(state: &State, trans: &str) -> Option<String> {
+    find_blocked_word(trans, &state.lock().unwrap().blocked_words)
+}
+
+/// Prints the notice for an outgoing message over the `/TRANSMAXCHARS` 
+/// limit.
+///
+fn report_too_long(hc: &Hexchat, max_chars: usize) {
+    hc.print(&fm!("{ERROR_COLOR}The message is over {} characters long, and \
+                   wasn't translated or sent. Split it up, or raise the \
+                   limit with /TRANSMAXCHARS.", max_chars));
+}
+
+/// Does the work of `on_cmd_lsay()` for a context that's active for 
+/// translation, and of `/LTRANS`. The message is translated on a separate 
+/// thread, and the result is sent to the channel when it's ready.
+/// While translation is paused with `/TRANSPAUSE`, or the user is away with
+/// `/TRANSAWAYPAUSE` on, the message is sent untranslated instead. A message
+/// over the `/TRANSMAXCHARS` limit isn't sent either way.
+/// # Arguments
+/// * `hc`          - The Hexchat interface.
+/// * `message`     - The message to translate and send.
+/// * `cmd`         - The command to send the translation with, "SAY" or "ME".
+/// * `chan_langs`  - The languages to translate with.
+/// * `listed`      - The target language taken from the context's
+///                   `/SETLANGLIST` list, if it has one. It's used instead of
+///                   the target in `chan_langs`.
+/// * `preview`     - For `/LPREVIEW`. The translation and the original are
+///                   printed to the window, and nothing is sent.
+/// * `state`       - The addon state.
+/// # Returns
+/// * `None` if the channel information couldn't be retrieved or the message
+///   couldn't be stripped.
+///
+fn try_on_cmd_lsay(hc         : &Hexchat,
+                   message    : &str,
+                   cmd        : &'static str,
+                   chan_langs : ChanData,
+                   listed     : Option<String>,
+                   preview    : bool,
+                   state      : &State
+                  ) -> Option<()>
+{
+    let strip_msg = hc.strip(message, StripBoth)?;
+
+    // An oversized message, like an accidental paste, isn't translated, and
+    // it isn't sent untranslated either, even while translation is paused.
+    let max_chars = get_max_chars(state);
+    if outgoing_action(&strip_msg, max_chars, false) == Outgoing::TooLong {
+        report_too_long(hc, max_chars);
+        return Some(());
+    }
+    // Hexchat only has away info while the user is marked away.
+    let away = state.lock().unwrap().away_pause 
+               && hc.get_info("away").is_some();
+
+    if ALL_PAUSED.load(Ordering::SeqCst) || away {
+        // With translation paused, `/LTRANS` sends like `/SAY`, and there's
+        // nothing to preview.
+        if preview && away {
+            hc.print(&fm!("{ERROR_COLOR}You're away, and /TRANSAWAYPAUSE is \
+                           on, so messages are sent untranslated."));
+        } else if preview {
+            hc.print(&fm!("{ERROR_COLOR}Translation is paused. Use \
+                           /TRANSRESUME to resume it."));
+        } else {
+            hc.command(&fm!("{} {}", cmd, message));
+        }
+        return Some(());
+    }
+    let src_lang  = chan_langs.0;
+    let message   = message.to_string();
+    let network   = get_network(hc)?;                              
+    let channel   = hc.get_info("channel")?;
+    let tgt_lang  = listed.clone().unwrap_or(chan_langs.1);
+    let ratio     = state.lock().unwrap().expand_warn_ratio;
+    let format    = get_edge_format(state, &message);
+    let nicks     = channel_nicks(hc);
+    let show_orig = preview || shows_orig(state, &network, &channel);
+    let verify    = state.lock().unwrap().verify;
+    let me_nick   = state.lock().unwrap().me_nick;
+    let min_conf  = state.lock().unwrap().min_confidence;
+    let nick      = hc.get_info("nick").unwrap_or_default();
+    let budget    = message_budget(cmd, &nick, &channel)
+                        .saturating_sub(format.0.len() + format.1.len());
+    let state     = state.clone();
+    let done      = Arc::new(AtomicBool::new(false));
+
+    notify_if_slow(hc, &network, &channel, done.clone());
+
+    pool::execute_as(!preview, move || {
+        let msg;
+        let mut emsg = None;
+        let mut is_over_limit = false;
+
+        // A leading mention, like "bob: ", is sent as it was typed, since 
+        // the translation can move or change it and lose the highlight.
+        let (mention, body) = split_mention(&strip_msg, &nicks)
+                                  .unwrap_or_else(|| {
+                                      (String::new(), strip_msg.clone())
+                                  });
+        // The lines of each paragraph of a paste are translated as one, 
+        // and each paragraph is sent as a message of its own.
+        let body = if body.contains('\n') { 
+                       join_paragraphs(&body) 
+                   } else { 
+                       body 
+                   };
+
+        // An action is read with the nick in front of it, so with 
+        // `/TRANSMENICK` on, it's translated that way and the nick taken 
+        // back off.
+        let with_nick = cmd == "ME" && me_nick && !nick.is_empty();
+        let text      = if with_nick { 
+                            fm!("{} {}", nick, body) 
+                        } else { 
+                            body.clone() 
+                        };
+        // Google reports the language it detected along with the 
+        // translation, and it's remembered for replies.
+        let mut detection = None;
+        let mut result = translate_detect(&text, &src_lang, &tgt_lang, &nicks,
+                                          &state)
+                             .map(|(trans, found)| {
+                                 detection = found;
+                                 trans
+                             });
+        if with_nick {
+            result = match result {
+                Ok(trans) => match strip_leading_nick(&trans, &nick) {
+                    Some(trans) => Ok(trans),
+                    // The nick can't be taken off cleanly, so the action is
+                    // translated on its own instead.
+                    None => translate_text(&body, &src_lang, &tgt_lang, 
+                                           &nicks, &state),
+                },
+                err => err,
+            };
+        }
+        let detected = detection.as_ref().map(|(lang, _)| lang.clone());
+        let low_conf = is_low_confidence(detection.as_ref(), min_conf);
+        if let Some(lang) = &detected {
+            state.lock().unwrap()
+                 .detected_langs.insert(chan_key(&network, &channel), 
+                                        lang.clone());
+        }
+        let src_lang = detected.clone().unwrap_or(src_lang);
+
+        match result {
+            Ok(trans) => { 
+                msg  = trans;
+            },
+            Err(err)  => { 
+                let partial = err.get_partial_trans();
+                msg  = if with_nick {
+                           strip_leading_nick(partial, &nick)
+                               .unwrap_or_else(|| partial.to_string())
+                       } else {
+                           partial.to_string()
+                       };
+                emsg = Some(error_report(&err));
+                is_over_limit = err.is_over_limit();
+            }
+        }
+        let msg = mention + &msg;
+        // The back-translation is only worth the extra request if the 
+        // forward one went through, and there's a language to go back to.
+        let back = if verify && emsg.is_none() && src_lang != AUTO_LANG.1 {
+            translate_text(&msg, &tgt_lang, &src_lang, &nicks, &state).ok()
+        } else {
+            None
+        };
+        let expanded = ratio.is_some_and(|r| {
+            is_over_expanded(strip_msg.len(), msg.len(), r)
+        });
+        // A translation can be too long for one IRC message, so it's sent in
+        // pieces, each with the formatting of the original around it. Each
+        // line of a multi-line message is sent as a message of its own.
+        let sends = msg.trim()
+                       .lines()
+                       .flat_map(|line| split_by_length(line.trim(), budget,
+                                                        char::len_utf8))
+                       .map(|s| s.trim())
+                       .filter(|s| !s.is_empty())
+                       .map(|s| fm!("{}{}{}", format.0, s, format.1))
+                       .collect::<Vec<_>>();
+        let msg = format.0 + &msg + &format.1;
+        let block_hit = blocked_word(&state, &msg);
+        done.store(true, Ordering::SeqCst);
+        if let Err(err) = wait_main(main_thread(
+            move |hc| -> Result<(), HexchatError> {
+                if let Some(ctx) = hc.find_context(&network, &channel) {
+                    if expanded {
+                        ctx.print(&fm!("{ERROR_COLOR}Warning: the translation \
+                                        is {} bytes, up from {} in the \
+                                        original.", msg.len(), 
+                                                    strip_msg.len()))?;
+                    }
+                    if preview {
+                        ctx.print(&fm!("{ERROR_COLOR}Preview, not sent. Use \
+                                        /LSAY to send it if it looks \
+                                        good:"))?;
+                        ctx.print(&msg)?;
+                        if let Some(word) = &block_hit {
+                            ctx.print(&fm!("{ERROR_COLOR}It has the blocked \
+                                            word '{}' in it, so /LSAY won't \
+                                            send it.", word))?;
+                        }
+                    } else if let Some(word) = &block_hit {
+                        // Hold the translation in the input box rather than
+                        // sending it, so it can be reworded.
+                        ctx.print(&fm!("{ERROR_COLOR}The translation has the \
+                                        blocked word '{}' in it, and wasn't \
+                                        sent.", word))?;
+                        if !set_input_box(&ctx, &msg)? {
+                            ctx.print(&msg)?;
+                        }
+                    } else if expanded {
+                        // Likewise, it's held so it can be checked and sent
+                        // with Enter, or shortened first.
+                        ctx.print(&fm!("{ERROR_COLOR}It wasn't sent, and is \
+                                        in the input box to send or edit."))?;
+                        let held = if cmd == "ME" { 
+                                       fm!("/me {}", msg) 
+                                   } else { 
+                                       msg.clone() 
+                                   };
+                        if !set_input_box(&ctx, &held)? {
+                            ctx.print(&msg)?;
+                        }
+                    } else if sends.iter().any(|send| {
+                        ctx.command(&fm!("{} {}", cmd, send)).is_err()
+                    }) {
+                        hc.print(&orphaned_translation(&network, 
+                                                       &channel, 
+                                                       &msg));
+                    } else {
+                        push_sent_orig(&state, &network, &channel, &message);
+                    }
+                    // With the original hidden, the detected language is
+                    // still worth showing on its own.
+                    let detected = detected.as_ref().map(|lang| {
+                        let name = find_lang(lang).map_or(lang.as_str(), 
+                                                          |info| info.0);
+                        if low_conf {
+                            fm!("{}, low confidence", name)
+                        } else {
+                            name.to_string()
+                        }
+                    });
+                    match (show_orig, detected) {
+                        (true, Some(name)) => {
+                            ctx.print(&fm!("{ORIG_COLOR}{} {ERROR_COLOR}\
+                                            (detected: {})", message, 
+                                                             name))?;
+                        },
+                        (true, None) => {
+                            ctx.print(&fm!("{ORIG_COLOR}{}", message))?;
+                        },
+                        (false, Some(name)) => {
+                            ctx.print(&fm!("{ERROR_COLOR}(detected: {})", 
+                                           name))?;
+                        },
+                        (false, None) => {},
+                    }
+                    if let Some(back) = &back {
+                        ctx.print(&fm!("{IRC_GRAY}(back: {})", back.trim()))?;
+                    }
+                    if let Some(lang) = &listed {
+                        let name = find_lang(lang).map_or(lang.as_str(), 
+                                                          |info| info.0);
+                        ctx.print(&fm!("{ERROR_COLOR}(sent in {})", name))?;
+                    }
+                       
+                    if let Some(emsg) = &emsg {
+                        if !is_over_limit 
+                            || show_over_limit(&state, &network, &channel) {
+                            ctx.print(emsg)?;
+                        }
+                        if is_over_limit {
+                            pause_or_disable(hc, &ctx, &state, &network, 
+                                             &channel)?;
+                        }
+                    }
+                    if note_result(&state, &network, &channel, 
+                                   emsg.is_some() && !is_over_limit) {
+                        auto_disable(&ctx, "too many errors", 
+                                     ERROR_RUN_HINT)?;
+                    }
+                } else {
+                    hc.print(&fm!("{ERROR_COLOR}\
+                             Failed to get context."));
+                    hc.print(&orphaned_translation(&network, 
+                                                   &channel, 
+                                                   &msg));
+                }
+                Ok(())
+            }
+        )) {
+            hc_print_th!("{ERROR_COLOR}{}", err);
+        }
+    });
+    Some(())
+}
+
+/// Translations longer than this many bytes always get an expansion warning
+/// when `/TRANSEXPANDWARN` is on, whatever their ratio to the original.
+///
+const EXPAND_WARN_BYTES: usize = 400;
+
+/// Decides whether a translation has grown enough over the original to
+/// warn the user about it.
+/// # Arguments
+/// * `orig_len`  - The length of the original message in bytes.
+/// * `trans_len` - The length of the translation in bytes.
+/// * `ratio`     - The largest allowed ratio of translated to original length.
+///
+fn is_over_expanded(orig_len: usize, trans_len: usize, ratio: f64) -> bool {
+    trans_len > EXPAND_WARN_BYTES 
+        || trans_len as f64 > orig_len.max(1) as f64 * ratio
+}
+
+/// Implements the /TRANSEXPANDWARN command. Sets the ratio of translated to
+/// original length above which an outgoing translation is held in the input
+/// box with a warning instead of being sent, or turns the warning off.
+///
+fn on_cmd_transexpandwarn(hc        : &Hexchat, 
+                          word      : &[String], 
+                          _word_eol : &[String], 
+                          user_data : &UserData
+                         ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    match word.get(1).map(|w| w.to_lowercase()).as_deref() {
+        None => {
+            match state.lock().unwrap().expand_warn_ratio {
+                Some(r) => hc.print(&fm!("{ERROR_COLOR}Expansion warning \
+                                          ratio: {}", r)),
+                None    => hc.print(&fm!("{ERROR_COLOR}Expansion warning is \
+                                          off.")),
+            }
+        },
+        Some("off") => {
+            state.lock().unwrap().expand_warn_ratio = None;
+            hc.print(&fm!("{ERROR_COLOR}Expansion warning turned OFF."));
+        },
+        Some(arg) => {
+            match arg.parse::<f64>() {
+                Ok(r) if r >= 1.0 && r.is_finite() => {
+                    state.lock().unwrap().expand_warn_ratio = Some(r);
+                    hc.print(&fm!("{ERROR_COLOR}Outgoing translations more \
+                                   than {} times longer than the original, \
+                                   or over {} bytes, will be held in the \
+                                   input box.",
+                                  r, EXPAND_WARN_BYTES));
+                },
+                _ => {
+                    hc.print(&fm!("USAGE: {}", TRANSEXPANDWARN_HELP));
+                }
+            }
+        }
+    }
+    Eat::All
+}
+
+/// Stores the greeting sent by `/TRANSWELCOME`, replacing the one before.
+///
+fn set_welcome(state: &State, text: &str) {
+    state.lock().unwrap().welcome_template = Some(text.to_string());
+}
+
+/// Returns the greeting sent by `/TRANSWELCOME`, if one is set.
+///
+fn get_welcome(state: &State) -> Option<String> {
+    state.lock().unwrap().welcome_template.clone()
+}
+
+/// Translates the greeting for a newcomer. It's written in the user's 
+/// language, the channel's source, and sent in the channel's target 
+/// language, like an outgoing message.
+///
+fn translate_welcome(template : &str, 
+                     src_lang : &str, 
+                     tgt_lang : &str, 
+                     state    : &State
+                    ) -> Result<String, TranslationError> 
+{
+    translate_text(template, src_lang, tgt_lang, &HashSet::new(), state)
+}
+
+/// Implements the /TRANSWELCOME command. `/TRANSWELCOME set <text>` stores
+/// a greeting, and `/TRANSWELCOME <nick>` translates the greeting into the
+/// channel's target language and sends it to the nick in a private message.
+///
+fn on_cmd_transwelcome(hc        : &Hexchat, 
+                       word      : &[String], 
+                       word_eol  : &[String], 
+                       user_data : &UserData
+                      ) -> Eat 
+{
+    let (map_udata, state) = user_data.apply(
+                                |ud: &(UserData, State)| {
+                                    (ud.0.clone(), ud.1.clone())
+                                });
+    if word.len() >= 3 && word[1].eq_ignore_ascii_case("set") {
+        set_welcome(&state, &word_eol[2]);
+        hc.print(&fm!("{ERROR_COLOR}Welcome message set."));
+        return Eat::All;
+    }
+    if word.len() != 2 || word[1].eq_ignore_ascii_case("set") {
+        hc.print(&fm!("USAGE: {}", TRANSWELCOME_HELP));
+        return Eat::All;
+    }
+    let nick = word[1].clone();
+    let Some(template) = get_welcome(&state) else {
+        hc.print(&fm!("{ERROR_COLOR}No welcome message is set. Set one with \
+                       /TRANSWELCOME set <text>."));
+        return Eat::All;
+    };
+    let Some((src_lang, tgt_lang)) = get_channel_langs(hc, &map_udata, &state) else {
+        hc.print(&fm!("{ERROR_COLOR}Translation isn't on for this channel. \
+                       Use /SETLANG to turn it on."));
+        return Eat::All;
+    };
+    if !has_user(hc, &nick) {
+        hc.print(&fm!("{ERROR_COLOR}{} isn't in this channel.", nick));
+        return Eat::All;
+    }
+    match check_outgoing(hc, &state, &template, false) {
+        Outgoing::TooLong => return Eat::All,
+        Outgoing::AsIs    => {
+            hc.command(&fm!("MSG {} {}", nick, template));
+            return Eat::All;
+        },
+        Outgoing::Translate => {},
+    }
+    if {||{
+        let network = get_network(hc)?;
+        let channel = hc.get_info("channel")?;
+
+        pool::execute_as(true, move || {
+            let (msg, emsg) = match translate_welcome(&template, 
+                                                      &src_lang, 
+                                                      &tgt_lang,
+                                                      &state) {
+                Ok(trans) => (trans, None),
+                Err(err)  => (err.get_partial_trans().to_string(),
+                              Some(error_report(&err))),
+            };
+            let block_hit = blocked_word(&state, &msg);
+            if let Err(err) = wait_main(main_thread(
+                move |hc| -> Result<(), HexchatError> {
+                    if let Some(ctx) = hc.find_context(&network, &channel) {
+                        if let Some(word) = &block_hit {
+                            ctx.print(&fm!("{ERROR_COLOR}The translation \
+                                            has the blocked word '{}' in \
+                                            it, and wasn't sent.", word))?;
+                        } else if let Some(emsg) = &emsg {
+                            // Don't greet the newcomer with a half
+                            // translated message.
+                            ctx.print(emsg)?;
+                            ctx.print(&fm!("{ERROR_COLOR}The welcome \
+                                            message wasn't sent."))?;
+                        } else {
+                            ctx.command(&fm!("MSG {} {}", nick, msg))?;
+                            ctx.print(&fm!("{ORIG_COLOR}{}", template))?;
+                        }
+                    } else {
+                        hc.print(&fm!("{ERROR_COLOR}\
+                                 Failed to get context."));
+                    }
+                    Ok(())
+                }
+            )) {
+                hc_print_th!("{ERROR_COLOR}{}", err);
+            }
+        });
+        Some(())
+    }}().is_none() {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 Translator Error: Basic failure retrieving channel \
+                 information."));
+    }
+    Eat::All
+}
+
+/// Checks whether the nick is in the current channel's user list.
+///
+fn has_user(hc: &Hexchat, nick: &str) -> bool {
+    channel_nicks(hc).contains(&irc_casefold(nick))
+}
+
+/// Returns the case-folded nicks of the users in the current channel.
+///
+fn channel_nicks(hc: &Hexchat) -> HashSet<String> {
+    hc.list_get("users").map_or_else(HashSet::new, |list| {
+        (&list).into_iter()
+               .filter_map(|item| item.get_field("nick").ok())
+               .map(|f| irc_casefold(&f.str()))
+               .collect()
+    })
+}
+
+/// Implements the /LEDIT command. Translates the message with the channel's
+/// languages and puts the translation in Hexchat's input box rather than 
+/// sending it, so it can be touched up before it's sent. The original is
+/// printed to the window for reference, since the input box only holds one
+/// line.
+///
+fn on_cmd_ledit(hc        : &Hexchat, 
+                word      : &[String], 
+                word_eol  : &[String], 
+                user_data : &UserData
+               ) -> Eat 
+{
+    let (map_udata, state) = user_data.apply(
+                                |ud: &(UserData, State)| {
+                                    (ud.0.clone(), ud.1.clone())
+                                });
+    if word.len() < 2 {
+        hc.print(&fm!("USAGE: {}", LEDIT_HELP));
+        return Eat::All;
+    }
+    if let Some((src_lang, tgt_lang)) = get_channel_langs(hc, &map_udata, &state) {
+        if {||{
+            let message   = word_eol[1].clone();
+            let strip_msg = hc.strip(&message, StripBoth)?;
+            let network   = get_network(hc)?;
+            let channel   = hc.get_info("channel")?;
+            let nicks     = channel_nicks(hc);
+
+            pool::execute(move || {
+                let (msg, emsg) = match translate_text(&strip_msg, 
+                                                       &src_lang, 
+                                                       &tgt_lang,
+                                                       &nicks,
+                                                       &state) {
+                    Ok(trans) => (trans, None),
+                    Err(err)  => (err.get_partial_trans().to_string(),
+                                  Some(error_report(&err))),
+                };
+                if let Err(err) = wait_main(main_thread(
+                    move |hc| -> Result<(), HexchatError> {
+                        if let Some(ctx) = hc.find_context(&network, &channel) {
+                            ctx.print(&fm!("{ORIG_COLOR}{}", message))?;
+                            if !set_input_box(&ctx, &msg)? {
+                                ctx.print(&fm!("{ERROR_COLOR}Couldn't set \
+                                                the input box. The \
+                                                translation is:"))?;
+                                ctx.print(&msg)?;
+                            }
+                            if let Some(emsg) = &emsg {
+                                ctx.print(emsg)?;
+                            }
+                        } else {
+                            hc.print(&fm!("{ERROR_COLOR}\
+                                     Failed to get context."));
+                            hc.print(&orphaned_translation(&network, 
+                                                           &channel, 
+                                                           &msg));
+                        }
+                        Ok(())
+                    }
+                )) {
+                    hc_print_th!("{ERROR_COLOR}{}", err);
+                }
+            });
+            Some(())
+        }}().is_none() {
+            hc.print(&fm!("{ERROR_COLOR}\
+                     Translator Error: Basic failure retrieving channel \
+                     information, or unable to strip original message."));
+        }
+    } else {
+        hc.print(&fm!("{ERROR_COLOR}Translation isn't on for this channel. \
+                       Use /SETLANG to turn it on."));
+    }
+    Eat::All
+}
+
+/// Implements the /LTOPIC command. Translates the new topic with the 
+/// channel's languages and sets it. The topic is only set if the whole of it
+/// was translated, since a topic stays up far longer than a message.
+///
+fn on_cmd_ltopic(hc        : &Hexchat, 
+                 word      : &[String], 
+                 word_eol  : &[String], 
+                 user_data : &UserData
+                ) -> Eat 
+{
+    let (map_udata, state) = user_data.apply(
+                                |ud: &(UserData, State)| {
+                                    (ud.0.clone(), ud.1.clone())
+                                });
+    if word.len() < 2 {
+        hc.print(&fm!("USAGE: {}", LTOPIC_HELP));
+        return Eat::All;
+    }
+    if let Some((src_lang, tgt_lang)) = get_channel_langs(hc, &map_udata, &state) {
+        match check_outgoing(hc, &state, &word_eol[1], false) {
+            Outgoing::TooLong => return Eat::All,
+            Outgoing::AsIs    => {
+                hc.command(&fm!("TOPIC {}", word_eol[1]));
+                return Eat::All;
+            },
+            Outgoing::Translate => {},
+        }
+        if {||{
+            let topic     = word_eol[1].clone();
+            let strip_msg = hc.strip(&topic, StripBoth)?;
+            let network   = get_network(hc)?;
+            let channel   = hc.get_info("channel")?;
+            let nicks     = channel_nicks(hc);
+
+            pool::execute_as(true, move || {
+                let result = translate_text(&strip_msg, &src_lang, &tgt_lang,
+                                            &nicks, &state);
+                let block_hit = result.as_ref().ok().and_then(|trans| {
+                                    blocked_word(&state, trans)
+                                });
+                if let Err(err) = wait_main(main_thread(
+                    move |hc| -> Result<(), HexchatError> {
+                        let Some(ctx) = hc.find_context(&network, &channel) 
+                        else {
+                            hc.print(&fm!("{ERROR_COLOR}\
+                                     Failed to get context."));
+                            return Ok(());
+                        };
+                        match &result {
+                            Ok(_) if block_hit.is_some() => {
+                                ctx.print(&fm!("{ERROR_COLOR}The translation \
+                                                has the blocked word '{}' in \
+                                                it. The topic wasn't \
+                                                changed.", 
+                                               block_hit.as_ref().unwrap()))?;
+                            },
+                            Ok(trans) => {
+                                ctx.command(&fm!("TOPIC {}", trans))?;
+                                ctx.print(&fm!("{ORIG_COLOR}{}", topic))?;
+                            },
+                            Err(err) => {
+                                ctx.print(&fm!("{ERROR_COLOR}{} The topic \
+                                                wasn't changed.", err))?;
+                            }
+                        }
+                        Ok(())
+                    }
+                )) {
+                    hc_print_th!("{ERROR_COLOR}{}", err);
+                }
+            });
+            Some(())
+        }}().is_none() {
+            hc.print(&fm!("{ERROR_COLOR}\
+                     Translator Error: Basic failure retrieving channel \
+                     information, or unable to strip original message."));
+        }
+    } else {
+        hc.print(&fm!("{ERROR_COLOR}Translation isn't on for this channel. \
+                       Use /SETLANG to turn it on."));
+    }
+    Eat::All
+}
+
+/// Implements the /LSAYBLOCK command. Sends a multi-line block of text to the
+/// channel, translating only the lines that hold words, and passing lines of
+/// ASCII art, tables borders, and the like through untouched. Consecutive
+/// lines of prose are translated together as a paragraph, and blank lines
+/// between paragraphs are kept.
+///
+fn on_cmd_lsayblock(hc        : &Hexchat, 
+                    word      : &[String], 
+                    word_eol  : &[String], 
+                    user_data : &UserData
+                   ) -> Eat 
+{
+    let (map_udata, state) = user_data.apply(
+                                |ud: &(UserData, State)| {
+                                    (ud.0.clone(), ud.1.clone())
+                                });
+    if word.len() < 2 {
+        hc.print(&fm!("USAGE: {}", LSAYBLOCK_HELP));
+        return Eat::All;
+    }
+    if let Some(chan_langs) = get_channel_langs(hc, &map_udata, &state) {
+        match check_outgoing(hc, &state, &word_eol[1], false) {
+            Outgoing::TooLong => return Eat::All,
+            Outgoing::AsIs    => {
+                for line in word_eol[1].lines().filter(|l| !l.is_empty()) {
+                    hc.command(&fm!("SAY {}", line));
+                }
+                return Eat::All;
+            },
+            Outgoing::Translate => {},
+        }
+        if {||{
+            let (src_lang, tgt_lang) = chan_langs;
+            // Pairs of each raw line, with its formatting, and the line
+            // stripped for translation.
+            let block   = word_eol[1].lines()
+                                     .map(|l| Some((l.to_string(), 
+                                                    hc.strip(l, StripBoth)?)))
+                                     .collect::<Option<Vec<_>>>()?;
+            let network = get_network(hc)?;
+            let channel = hc.get_info("channel")?;
+            let nicks   = channel_nicks(hc);
+            let nick    = hc.get_info("nick").unwrap_or_default();
+            let budget  = message_budget("SAY", &nick, &channel);
+            let sigils  = get_cmd_sigils(&state);
+
+            pool::execute_as(true, move || {
+                let mut lines  = vec![];
+                let mut errors = vec![];
+
+                for part in split_paragraphs(block, &sigils) {
+                    match part {
+                        BlockPart::Prose(raws, text) => {
+                            let trans = match translate_text(&text, 
+                                                             &src_lang, 
+                                                             &tgt_lang,
+                                                             &nicks,
+                                                             &state) {
+                                Ok(trans) => trans,
+                                Err(err)  => {
+                                    errors.push(error_report(&err));
+                                    err.get_partial_trans().to_string()
+                                }
+                            };
+                            let sends = block_sends(&trans, budget);
+                            lines.push((raws, sends));
+                        },
+                        BlockPart::Verbatim(raw) if raw.trim().is_empty() => {
+                            // A blank line inside a code fence.
+                            lines.push((vec![raw], vec![]));
+                        },
+                        BlockPart::Verbatim(raw) => {
+                            lines.push((vec![raw.clone()], vec![raw]));
+                        },
+                        BlockPart::Blank => {
+                            lines.push((vec![String::new()], vec![]));
+                        },
+                    }
+                }
+                // The same error can come back for paragraphs that aren't
+                // next to each other.
+                errors.sort();
+                errors.dedup();
+
+                let block_hit = lines.iter()
+                                     .flat_map(|l| &l.1)
+                                     .find_map(|l| blocked_word(&state, l));
+                
+                if let Err(err) = wait_main(main_thread(
+                    move |hc| -> Result<(), HexchatError> {
+                        if let Some(ctx) = hc.find_context(&network, &channel) {
+                            if let Some(word) = &block_hit {
+                                ctx.print(&fm!("{ERROR_COLOR}The translation \
+                                                has the blocked word '{}' in \
+                                                it, and the block wasn't \
+                                                sent.", word))?;
+                                return Ok(());
+                            }
+                            // IRC can't send an empty message, so a blank
+                            // line between paragraphs only shows in the echo
+                            // of the original.
+                            for line in lines.iter().flat_map(|l| &l.1) {
+                                ctx.command(&fm!("SAY {}", line))?;
+                            }
+                            for orig in lines.iter().flat_map(|l| &l.0) {
+                                ctx.print(&fm!("{ORIG_COLOR}{}", orig))?;
+                            }
+                            for emsg in &errors {
+                                ctx.print(emsg)?;
+                            }
+                        } else {
+                            hc.print(&fm!("{ERROR_COLOR}\
+                                     Failed to get context."));
+                        }
+                        Ok(())
+                    }
+                )) {
+                    hc_print_th!("{ERROR_COLOR}{}", err);
+                }
+            });
+            Some(())
+        }}().is_none() {
+            hc.print(&fm!("{ERROR_COLOR}\
+                     Translator Error: Basic failure retrieving channel \
+                     information, or unable to strip original message."));
+        }
+    } else {
+        hc.print(&fm!("{ERROR_COLOR}Translation isn't on for this channel. \
+                       Use /SETLANG to turn it on."));
+    }
+    Eat::All
+}
+
+/// The longest line, in bytes, an IRC server accepts, including the CRLF.
+///
+const IRC_LINE_BYTES: usize = 512;
+
+/// The room left in each line for the user and host in the prefix the 
+/// server adds when it relays a message to the channel. Servers allow up to
+/// 10 bytes for the user and 63 for the host.
+///
+const USER_HOST_ALLOWANCE: usize = 80;
+
+/// Works out how many bytes of message text fit in one line sent to a 
+/// channel, leaving room for the command, the channel name, and the prefix 
+/// the server adds when relaying it.
+/// # Arguments
+/// * `cmd`     - The command the message is sent with, "SAY" or "ME".
+/// * `nick`    - The user's nick.
+/// * `channel` - The channel the message is sent to.
+///
+fn message_budget(cmd: &str, nick: &str, channel: &str) -> usize {
+    let mut overhead = fm!(":{}! PRIVMSG {} :\r\n", nick, channel).len() 
+                       + USER_HOST_ALLOWANCE;
+    if cmd == "ME" {
+        overhead += "\x01ACTION \x01".len();
+    }
+    IRC_LINE_BYTES.saturating_sub(overhead).max(MIN_MESSAGE_BUDGET)
+}
+
+/// The fewest bytes `message_budget()` gives, so a very long channel name 
+/// doesn't break a message into tiny pieces.
+///
+const MIN_MESSAGE_BUDGET: usize = 100;
+
+/// Breaks a paragraph translated by /LSAYBLOCK into the messages it's sent
+/// as, since it can be too long for one.
+/// # Arguments
+/// * `trans`  - The translated paragraph.
+/// * `budget` - The most bytes of text that fit in one message; see
+///              `message_budget()`.
+///
+fn block_sends(trans: &str, budget: usize) -> Vec<String> {
+    split_by_length(trans.trim(), budget, char::len_utf8)
+        .into_iter()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// A piece of a block of text given to /LSAYBLOCK.
+/// # Variants
+/// * `Prose`    - A paragraph of translatable lines. Holds the raw lines and
+///                the stripped lines joined into one string for translation.
+/// * `Verbatim` - A raw line of ASCII art or symbols to send as it is.
+/// * `Blank`    - One or more blank lines between paragraphs.
+///
+#[derive(Debug, PartialEq)]
+enum BlockPart {
+    Prose(Vec<String>, String),
+    Verbatim(String),
+    Blank,
+}
+
+/// Groups the lines of a block into paragraphs, lines to pass through, and
+/// the blank lines separating them. Consecutive translatable lines make up a
+/// paragraph; a blank line or a line that isn't translatable ends it. Runs of
+/// blank lines collapse into one, and blank lines at the start or end of the
+/// block are dropped. Code - lines fenced in by triple backticks, or 
+/// indented by four spaces or a tab - is passed through as it is, and so are
+/// bot commands.
+/// # Arguments
+/// * `block`  - Pairs of each raw line and the line stripped of formatting.
+/// * `sigils` - The characters that can start a bot command.
+/// # Returns
+/// * The parts of the block in order.
+///
+fn split_paragraphs(block  : Vec<(String, String)>, 
+                    sigils : &str
+                   ) -> Vec<BlockPart> 
+{
+    let mut parts = vec![];
+    let mut raws  = vec![];
+    let mut text  = String::new();
+
+    let end_prose = |parts: &mut Vec<BlockPart>, 
+                     raws : &mut Vec<String>, 
+                     text : &mut String| {
+        if !raws.is_empty() {
+            parts.push(BlockPart::Prose(std::mem::take(raws), 
+                                        std::mem::take(text)));
+        }
+    };
+    let mut fenced = false;
+
+    for (raw, line) in block {
+        let is_fence = line.trim_start().starts_with("```");
+        if fenced || is_fence {
+            if is_fence && line.trim_start()[3..].contains("```") {
+                // The fence opens and closes on the same line.
+            } else if is_fence {
+                fenced = !fenced;
+            }
+            end_prose(&mut parts, &mut raws, &mut text);
+            parts.push(BlockPart::Verbatim(raw));
+        } else if line.trim().is_empty() {
+            end_prose(&mut parts, &mut raws, &mut text);
+            if !matches!(parts.last(), None | Some(BlockPart::Blank)) {
+                parts.push(BlockPart::Blank);
+            }
+        } else if is_translatable(&line) && !is_indented_code(&line)
+                  && !is_bot_command(&line, sigils) {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(line.trim());
+            raws.push(raw);
+        } else {
+            end_prose(&mut parts, &mut raws, &mut text);
+            parts.push(BlockPart::Verbatim(raw));
+        }
+    }
+    end_prose(&mut parts, &mut raws, &mut text);
+    if parts.last() == Some(&BlockPart::Blank) {
+        parts.pop();
+    }
+    parts
+}
+
+/// Joins the lines of each paragraph of a multi-line message into one, so 
+/// `/LSAY` translates a paragraph as a unit, which reads better than lines 
+/// broken mid-sentence translated on their own. Paragraphs are grouped by
+/// `split_paragraphs()`, so code and other lines that aren't prose stay on
+/// lines of their own, and a blank line is kept between paragraphs.
+///
+fn join_paragraphs(text: &str) -> String {
+    let block = text.lines()
+                    .map(|l| (l.to_string(), l.to_string()))
+                    .collect();
+    split_paragraphs(block, "").into_iter()
+                           .map(|part| match part {
+                               BlockPart::Prose(_, text) => text,
+                               BlockPart::Verbatim(raw)  => raw,
+                               BlockPart::Blank          => String::new(),
+                           })
+                           .collect::<Vec<_>>()
+                           .join("\n")
+}
+
+/// Decides whether a line of text holds words worth translating, as opposed
+/// to ASCII art, table borders, or other symbols. A line is translatable if
+/// it has a word of at least two letters, and letters make up at least half
+/// of its non-whitespace characters.
+///
+fn is_translatable(line: &str) -> bool {
+    let has_word = line.split(|c: char| !c.is_alphabetic())
+                       .any(|w| w.chars().count() >= 2);
+    let letters  = line.chars().filter(|c| c.is_alphabetic()).count();
+    let visible  = line.chars().filter(|c| !c.is_whitespace()).count();
+    has_word && letters * 2 >= visible
+}
+
+/// Returns the index of the message in the words of an incoming message
+/// event. It follows the sender, except in "Channel Notice", where the 
+/// channel comes between them.
+///
+fn message_index(event: &str) -> usize {
+    if event == "Channel Notice" { 2 } else { 1 }
+}
+
+thread_local! {
+    /// Set while the addon emits an event of its own, so its event handlers
+    /// can tell it apart from the events Hexchat emits and don't translate 
+    /// it again. Hexchat invokes the handlers from within `emit_print()`, 
+    /// on the same thread, so the flag is only ever seen set by them.
+    ///
+    static EMITTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with `EMITTING` set, restoring its previous value afterward.
+///
+fn with_emitting<R>(f: impl FnOnce() -> R) -> R {
+    let prior  = EMITTING.with(|e| e.replace(true));
+    let result = f();
+    EMITTING.with(|e| e.set(prior));
+    result
+}
+
+/// Checks whether the event being handled was emitted by the addon itself.
+///
+fn is_emitting() -> bool {
+    EMITTING.with(|e| e.get())
+}
+
+/// Emits `event` in the window of `ctx` as the addon's own, so its handlers
+/// pass it through instead of translating it again.
+/// # Arguments
+/// * `ctx`     - The context to emit the event in.
+/// * `event`   - The name of the event.
+/// * `words`   - The event's words.
+/// # Returns
+/// * The result of `emit_print()`.
+///
+fn emit_own(ctx   : &Context, 
+            event : &str, 
+            words : &[String]
+           ) -> Result<(), HexchatError> 
+{
+    let args = words.iter().map(|w| w.as_str()).collect::<Vec<_>>();
+    with_emitting(|| ctx.emit_print(event, &args))
+}
+
+/// Checks whether an incoming message event should be passed through as is,
+/// either because the addon emitted it or it has no message.
+///
+fn is_passthrough_event(word: &[String], msg_idx: usize) -> bool {
+    word.len() <= msg_idx || is_emitting()
+}
+
+/// Builds the words to emit an incoming message event with. They're the 
+/// event's words up to the one after the message, like the mode character,
+/// with the message replaced by `text`.
+///
+fn emit_words(word: &[String], msg_idx: usize, text: &str) -> Vec<String> {
+    let mut words = word.iter().take(msg_idx + 2).cloned().collect::<Vec<_>>();
+    words[msg_idx] = text.to_string();
+    words
+}
+
+/// Checks whether the sender of an incoming event is the user, like with a
+/// notice the user sent to a channel they're in.
+///
+fn is_own_message(hc: &Hexchat, sender: &str) -> bool {
+    let sender = hc.strip(sender, StripBoth)
+                   .unwrap_or_else(|| sender.to_string());
+    hc.get_info("nick").is_some_and(|nick| {
+        irc_casefold(&nick) == irc_casefold(&sender)
+    })
+}
+
+/// Callback invoked when channel events like 'Channel Message' occur. 
+/// If translation is on for the channel, this callback will have it 
+/// translated and update the context window with translated message text.
+/// Notices are handled the same way.
+///
+fn on_recv_message(hc        : &Hexchat, 
+                   word      : &[String], 
+                   user_data : &UserData
+                  ) -> Eat 
+{
+    let (event, ref map_udata, ref state) = user_data.apply(
+                                    |ud: &(&str, UserData, State)| {
+                                        (ud.0, ud.1.clone(), ud.2.clone())
+                                    });
+    let msg_idx = message_index(event);
+    
+    if is_passthrough_event(word, msg_idx) {
+        // To avoid recursion, the events this handler emits are passed
+        // through.
+        return Eat::None;
+    }
+    if let Some(key) = get_chan_key(hc) {
+        state.lock().unwrap()
+             .last_received.insert(key, (word[0].clone(), 
+                                         word[msg_idx].clone()));
+    }
+    // A relay translates from its first language to its second. Since 
+    // incoming messages are translated from the target language to the
+    // source, the relay's languages are swapped to fit.
+    let chan_langs = get_relay(hc, state).map(|(from, to)| (to, from))
+                     .or_else(|| get_channel_langs(hc, map_udata, state))
+                     .map(|langs| resolve_auto_lang(hc, state, langs));
+
+    if let Some(mut chan_langs) = chan_langs {
+        if !get_direction(hc, state).incoming() 
+            || is_sender_muted(hc, state, &word[0])
+            || is_sender_unwatched(hc, state, &word[0])
+            || is_own_message(hc, &word[0])
+            || is_bot_command(&word[msg_idx], &get_cmd_sigils(state))
+            || is_paused(hc, state) {
+            return Eat::None;
+        }
+        // Incoming messages are shown in the display language, if one is 
+        // set, rather than the one the user types in. A relay has its own
+        // languages.
+        if get_relay(hc, state).is_none() {
+            chan_langs = with_display_lang(hc, state, chan_langs);
+        }
+        // A sender with a language of their own set is translated from it
+        // instead of the channel's. There's nothing to do if they speak the
+        // language being translated to.
+        if let Some(lang) = get_user_lang(hc, state, &word[0]) {
+            if lang == chan_langs.0 {
+                return Eat::None;
+            }
+            chan_langs.1 = lang;
+        }
+        try_on_recv_message(hc, word, event, msg_idx, chan_langs, state)
+            .unwrap_or_else(|| {
+            // If we get here, either `strip()` or `get_info()` returned None.
+            hc.print(&fm!("{ERROR_COLOR}\
+                     Translator Error: Basic failure retrieving channel \
+                     information, or unable to strip original message."));
+            Eat::Hexchat
+        })
+    } else {
+        Eat::None
+    }
+}
+
+/// Indicates whether a stripped incoming message is empty or only 
+/// whitespace, so there's nothing to translate, and the original is shown
+/// as it is.
+///
+fn is_blank(strip_msg: &str) -> bool {
+    strip_msg.trim().is_empty()
+}
+
+/// Selects how an incoming translation is shown with `/TRANSINLINE`. In 
+/// replace mode, the original is left out and the translation is marked so
+/// it can be told apart from messages that weren't translated. If the 
+/// translation failed, it's shown separately, with the original.
+/// # Arguments
+/// * `replace` - Whether `/TRANSINLINE replace` is set.
+/// * `failed`  - Whether the translation failed.
+/// * `msg`     - The translation.
+/// # Returns
+/// * The text to show in place of the message, and whether the original is 
+///   replaced by it.
+///
+fn inline_display(replace: bool, failed: bool, msg: &str) -> (String, bool) {
+    if replace && !failed {
+        (fm!("{}{}", INLINE_MARKER, msg), true)
+    } else {
+        (msg.to_string(), false)
+    }
+}
+
+/// Determines whether a translation was made from the user's own language,
+/// going by the language detected with it.
+/// # Arguments
+/// * `result`   - The result of `translate_detect()`.
+/// * `src_lang` - The user's language.
+///
+fn is_same_lang(result   : &Result<(String, Option<Detection>), 
+                                   TranslationError>,
+                src_lang : &str
+               ) -> bool 
+{
+    matches!(result, Ok((_, Some((lang, _)))) 
+                     if lang.eq_ignore_ascii_case(src_lang))
+}
+
+/// Does the work of `on_recv_message()` for a context that's active for
+/// translation. The message is queued for translation on a worker, and
+/// the result is emitted to the context's window when it's ready.
+/// # Arguments
+/// * `hc`          - The Hexchat interface.
+/// * `word`        - The event's words. The first is the sender.
+/// * `event`       - The name of the event being handled.
+/// * `msg_idx`     - The index of the message in `word`.
+/// * `chan_langs`  - The languages configured for the context.
+/// * `state`       - The addon state.
+/// # Returns
+/// * `None` if the channel information couldn't be retrieved or the message
+///   couldn't be stripped; otherwise the `Eat` value for the event.
+///
+fn try_on_recv_message(hc         : &Hexchat,
+                       word       : &[String],
+                       event      : &'static str,
+                       msg_idx    : usize,
+                       chan_langs : ChanData,
+                       state      : &State
+                      ) -> Option<Eat>
+{
+    let sender    = word[0].clone();
+    let message   = word[msg_idx].clone();
+    let msg_type  = event;
+    let words     = word.to_vec();
+    let src_lang  = chan_langs.0;
+    let tgt_lang  = chan_langs.1;
+    
+    let strip_msg = hc.strip(&message, StripBoth)?;
+
+    if is_blank(&strip_msg) {
+        // Nothing to translate - let Hexchat display the original as is.
+        return Some(Eat::None);
+    }
+    let network   = get_network(hc)?;
+    let channel   = hc.get_info("channel")?;
+    let replace   = state.lock().unwrap().inline_replace;
+    let skip_same = state.lock().unwrap().skip_same;
+    let show_orig = shows_orig(state, &network, &channel);
+    let format    = get_edge_format(state, &message);
+    let nicks     = channel_nicks(hc);
+    let state     = state.clone();
+    let strip_nick = hc.strip(&sender, StripBoth)
+                       .unwrap_or_else(|| sender.clone());
+    
+    pool::execute(move || {
+        let msg;
+        let mut emsg = None;
+        let mut is_over_limit = false;
+
+        // With `/TRANSSKIPSAME` on, the language is detected as the message
+        // is translated, and a message already in the user's language is 
+        // shown as it came in. If nothing was detected, the translation is
+        // shown as usual.
+        let source = if skip_same { AUTO_LANG.1 } else { tgt_lang.as_str() };
+        let result = translate_detect(&strip_msg, source, &src_lang, &nicks, 
+                                      &state);
+        if skip_same && is_same_lang(&result, &src_lang) {
+            main_thread(move |hc| {
+                let Some(ctx) = hc.find_context(&network, &channel) else {
+                    return;
+                };
+                let words = emit_words(&words, msg_idx, &message);
+                let _ = emit_own(&ctx, msg_type, &words);
+            });
+            return;
+        }
+        let mut detection = None;
+        match result {
+            Ok((trans, found)) => { 
+                msg = trans;
+                detection = found;
+            },
+            Err(err)  => { 
+                msg  = err.get_partial_trans().to_string();
+                emsg = Some(error_report(&err));
+                is_over_limit = err.is_over_limit();
+            }
+        }
+        let lang = history_lang(source, detection);
+        let msg = fm!("{}{}{}", format.0, msg, format.1);
+        {
+            let mut state = state.lock().unwrap();
+            if state.history_on {
+                let entry = HistoryEntry {
+                    sender      : strip_nick,
+                    original    : strip_msg.clone(),
+                    translation : msg.clone(),
+                    lang,
+                };
+                push_history(state.history
+                                  .entry(chan_key(&network, &channel))
+                                  .or_default(),
+                             entry);
+            }
+        }
+        if let Err(err) = wait_main(main_thread(
+            move |hc| -> Result<(), HexchatError> {
+                if let Some(ctx) = hc.find_context(&network, &channel) {
+                    let (shown, 
+                         replace) = inline_display(replace, emsg.is_some(), 
+                                                   &msg);
+                    let words     = emit_words(&words, msg_idx, &shown);
+                    let emitted = emit_own(&ctx, msg_type, &words);
+                    // If the event can't be emitted, fall back on a
+                    // plain print, and as a last resort, print to
+                    // whatever window is active.
+                    if emitted.or_else(
+                        |_| ctx.print(&fm!("{}\t{}", sender, shown))
+                    ).is_err() {
+                        hc.print(&orphaned_translation(&network, 
+                                                       &channel, 
+                                                       &msg));
+                    }
+                    // Like in replace mode, a hidden original is shown 
+                    // anyway when the translation failed.
+                    if !replace && (show_orig || emsg.is_some()) {
+                        ctx.print(&fm!("{ORIG_COLOR}{}", message))?;
+                    }
+                    if let Some(emsg) = &emsg { 
+                        if !is_over_limit 
+                            || show_over_limit(&state, &network, &channel) {
+                            ctx.print(emsg)?;
+                        }
+                        if is_over_limit {
+                            pause_or_disable(hc, &ctx, &state, &network, 
+                                             &channel)?;
+                        }
+                    }
+                    if note_result(&state, &network, &channel, 
+                                   emsg.is_some() && !is_over_limit) {
+                        auto_disable(&ctx, "too many errors", 
+                                     ERROR_RUN_HINT)?;
+                    }
+                } else {
+                    hc.print("Failed to get context.");
+                    hc.print(&orphaned_translation(&network, 
+                                                   &channel, 
+                                                   &msg));
+                }
+                Ok(())
+            }
+        )) {
+            hc_print_th!("{ERROR_COLOR}{}", err);
+        }
+    });
+    Some(Eat::Hexchat)
+}
+
+/// The system events whose reason text can be translated, paired with the
+/// index of the reason in each event's word list.
+///
+const SYSTEM_EVENTS: [(&str, usize); 4] = [("Part with Reason", 3), 
+                                           ("Quit",             1),
+                                           ("Kick",             3),
+                                           ("You Kicked",       3)];
+
+/// The topic events, paired with the index of the topic in each event's word
+/// list. They're handled like the system events, but are always translated
+/// in active channels.
+///
+const TOPIC_EVENTS: [(&str, usize); 2] = [("Topic",        1), 
+                                          ("Topic Change", 1)];
+
+/// Returns the index of the reason text or topic in the words of `event`, if
+/// it's one of the system or topic events.
+///
+fn reason_index(event: &str) -> Option<usize> {
+    SYSTEM_EVENTS.iter().chain(&TOPIC_EVENTS)
+                 .find(|(e, _)| *e == event)
+                 .map(|&(_, idx)| idx)
+}
+
+/// Builds the words to emit a system or topic event with. Only the reason 
+/// text or topic at `reason_idx` is replaced by `text`.
+///
+fn reason_words(word       : &[String], 
+                reason_idx : usize, 
+                text       : &str
+               ) -> Vec<String> 
+{
+    let mut words = word.to_vec();
+    words[reason_idx] = text.to_string();
+    words
+}
+
+/// Callback invoked for the part, quit, kick, and topic events. If the 
+/// channel is active, only the reason text or topic of the event is 
+/// translated. The nicks, host, and channel are re-emitted unchanged. Part,
+/// quit, and kick reasons are only translated if system message translation
+/// is turned on with `/TRANSSYSTEM`.
+///
+fn on_recv_system(hc        : &Hexchat, 
+                  word      : &[String], 
+                  user_data : &UserData
+                 ) -> Eat 
+{
+    let (event, ref map_udata, ref state) = user_data.apply(
+                                    |ud: &(&str, UserData, State)| {
+                                        (ud.0, ud.1.clone(), ud.2.clone())
+                                    });
+    if reason_index(event).is_none_or(|idx| is_passthrough_event(word, idx)) {
+        // Same recursion guard as `on_recv_message()`.
+        return Eat::None;
+    }
+    let is_topic = TOPIC_EVENTS.iter().any(|(e, _)| *e == event);
+    
+    if !(is_topic || state.lock().unwrap().translate_system)
+        || !get_direction(hc, state).incoming() 
+        || is_paused(hc, state) {
+        return Eat::None;
+    }
+    if let Some(chan_langs) = get_channel_langs(hc, map_udata, state) {
+        let chan_langs = resolve_auto_lang(hc, state, chan_langs);
+        let chan_langs = with_display_lang(hc, state, chan_langs);
+        try_on_recv_system(hc, word, event, chan_langs, state.clone())
+            .unwrap_or(Eat::None)
+    } else {
+        Eat::None
+    }
+}
+
+/// Does the work of `on_recv_system()`, translating the reason text or topic
+/// of the event on a worker thread and re-emitting the event when it's 
+/// done.
+/// # Returns
+/// * `None` if the event has no reason text, or the channel information 
+///   couldn't be retrieved; otherwise, the `Eat` value for the event.
+///
+fn try_on_recv_system(hc         : &Hexchat,
+                      word       : &[String],
+                      event      : &'static str,
+                      chan_langs : ChanData,
+                      state      : State
+                     ) -> Option<Eat>
+{
+    let reason_idx = reason_index(event)?;
+    let reason    = word.get(reason_idx)?.clone();
+    let strip_msg = hc.strip(&reason, StripBoth)?;
+    
+    if is_blank(&strip_msg) {
+        return Some(Eat::None);
+    }
+    let network   = get_network(hc)?;
+    let channel   = hc.get_info("channel")?;
+    let word      = word.to_vec();
+    let src_lang  = chan_langs.0;
+    let tgt_lang  = chan_langs.1;
+
+    pool::execute(move || {
+        let (trans, emsg) = match translate_text(&strip_msg, 
+                                                 &tgt_lang, 
+                                                 &src_lang,
+                                                 &HashSet::new(),
+                                                 &state) {
+            Ok(trans) => {
+                (trans, None)
+            },
+            Err(err) => {
+                (err.get_partial_trans().to_string(), 
+                 Some(error_report(&err)))
+            }
+        };
+        let words = reason_words(&word, reason_idx, &trans);
+
+        if let Err(err) = wait_main(main_thread(
+            move |hc| -> Result<(), HexchatError> {
+                if let Some(ctx) = hc.find_context(&network, &channel) {
+                    emit_own(&ctx, event, &words)?;
+                    ctx.print(&fm!("{ORIG_COLOR}{}", reason))?;
+                    if let Some(emsg) = &emsg {
+                        ctx.print(emsg)?;
+                    }
+                } else {
+                    hc.print("Failed to get context.");
+                }
+                Ok(())
+            }
+        )) {
+            hc_print_th!("{ERROR_COLOR}{}", err);
+        }
+    });
+    Some(Eat::Hexchat)
+}
+
+/// Puts text in a context's input box with `SETTEXT`. The command succeeds
+/// whether or not the box was set, so the box is read back to check.
+/// # Arguments
+/// * `ctx`  - The context whose input box is set.
+/// * `text` - The text to put in it.
+/// # Returns
+/// * Whether the input box holds the text, or an error if the context is 
+///   gone.
+///
+fn set_input_box(ctx: &Context, text: &str) -> Result<bool, HexchatError> {
+    ctx.command(&fm!("SETTEXT {}", text))?;
+    Ok(ctx.get_info("inputbox")? == text)
+}
+
+/// Formats a translated message that couldn't be displayed in the context it
+/// belongs to so it can be printed to the active window instead of being 
+/// silently dropped.
+/// # Arguments
+/// * `network` - The network of the context the message was meant for.
+/// * `channel` - The channel of the context the message was meant for.
+/// * `msg`     - The translated message.
+///
+fn orphaned_translation(network: &str, channel: &str, msg: &str) -> String {
+    fm!("{ERROR_COLOR}[orphaned translation for {}/{}]{IRC_ORIG_ATTRIBS} {}", 
+        network, channel, msg)
+}
+
+/// Translates a chat text message to the desired target language using the
+/// chosen translation service - by default, the free translation web service
+/// provided by Google. Each sentence is sent to the services in the chain
+/// in turn until one of them translates it, so errors are only reported for
+/// sentences none of them could translate.
+/// # Arguments
+/// * `text`     - The text to translate.
+/// * `source`   - The source language of the text.
+/// * `target`   - The language to translate the text to.
+/// * `nicks`    - The case-folded nicks of the channel's users. They're left
+///                untranslated wherever they appear in the text.
+/// * `state`    - The addon state, which holds the translation services to 
+///                use and the cache of recent translations.
+/// # Returns
+/// * A result where `Ok()` contains the translated text, and `Err()` indicates
+///   the translation failed. The error will contain an aggregate of 
+///   descriptions for each problem encountered during translation.
+///
+fn translate_text(text     : &str, 
+                  source   : &str, 
+                  target   : &str,
+                  nicks    : &HashSet<String>,
+                  state    : &State
+                 ) -> Result<String, TranslationError> 
+{
+    translate_detect(text, source, target, nicks, state).map(|(trans, _)| {
+        trans
+    })
+}
+
+/// Does the work of `translate_text()`, and also gives the language Google
+/// detected when `source` is "auto", read from the same responses that 
+/// carry the translation so no separate request is needed to detect it.
+/// It's the detection of the first sentence Google translated; there's 
+/// none if another service translated the text, or it came from the cache.
+/// # Returns
+/// * A result where `Ok()` contains the translated text and the detection,
+///   and `Err()` is as for `translate_text()`.
+///
+fn translate_detect(text     : &str, 
+                    source   : &str, 
+                    target   : &str,
+                    nicks    : &HashSet<String>,
+                    state    : &State
+                   ) -> Result<(String, Option<Detection>), TranslationError> 
+{
+    let max_chars = get_max_chars(state);
+    if text.chars().count() > max_chars {
+        return Err( TranslationError::new(
+                        text.to_string(),
+                        fm!("The text is over {} characters long, and wasn't \
+                             translated.", max_chars),
+                        false, None, ErrorKind::TooLong) );
+    }
+    if text.contains('\n') {
+        return translate_lines(text, source, target, nicks, state);
+    }
+    let key = (source.to_string(), target.to_string(), text.to_string());
+    
+    if let Some(trans) = state.lock().unwrap().cache.get(&key) {
+        return Ok((trans, None));
+    }
+    let providers = get_providers(state);
+    let retries   = get_max_retries(state);
+    let deepl_key = state.lock().unwrap().deepl_key.clone();
+    let pivot     = state.lock().unwrap().pivot;
+    // The agent runs no threads of its own, so unlike the worker pool it 
+    // needn't be stopped by `plugin_deinit()`, and it's kept in the addon
+    // state, to be dropped with the rest of it when the plugin is unloaded.
+    let agent = get_agent(state);
+                     
+    let mut translated = String::new();
+    let mut errors     = vec![];
+    let mut over_limit = false;
+    let mut failed     = None;
+    let mut kind       = None;
+    let mut stats      = TranslationStats::default();
+    let mut detection  = None;
+
+    // Code, URLs, and nicks in the message are swapped for placeholders, so
+    // the sentences around them are translated whole, and they're put back
+    // as they were afterwards. DeepL is told to leave them alone; with the
+    // other services, `translate_masked()` makes sure they come back.
+    let (masked, protected) = mask_protected(text, nicks);
+    let prose    = masked.trim();
+    let leading  = &masked[..masked.len() - masked.trim_start().len()];
+    let trailing = &masked[leading.len() + prose.len()..];
+    translated.push_str(leading);
+
+    // The translation service won't translate past certain punctuation,
+    // so we break the prose up into parts terminated by such punctuation
+    // and treat each one as a separate translation while piecing the 
+    // results together.
+    for sentence in split_sentences(prose, source) {
+        if !has_prose(sentence) {
+            // Nothing but placeholders and punctuation.
+            translated.push_str(sentence);
+            continue;
+        }
+        let mut result = Err( SingleTranslationError::StaticError(
+                                  ErrorKind::Config,
+                                  "No translation service is set.") );
+        for provider in &weighted_providers(&providers, state) {
+            let translate_one = |text: &str, source: &str, target: &str| {
+                match provider {
+                    Provider::Google if !throttle(state) => {
+                        Err( SingleTranslationError::StaticError(
+                                 ErrorKind::Quota, RATE_LIMITED_MSG) )
+                    },
+                    Provider::Google => {
+                        translate_single(text, &agent, source, target, 
+                                         retries)
+                    },
+                    Provider::LibreTranslate(url) => {
+                        libretranslate_single(text, &agent, url, 
+                                              source, target)
+                            .map(|trans| (trans, None))
+                    },
+                    Provider::DeepL => {
+                        deepl_single(text, &agent, deepl_key.as_deref(),
+                                     source, target)
+                            .map(|trans| (trans, None))
+                    },
+                }
+            };
+            let translate = |text: &str, source: &str, target: &str| {
+                translate_masked(text, |piece| {
+                    translate_one(piece, source, target)
+                })
+            };
+            let pivoted;
+            (result, pivoted) = pivot_translate(sentence, source, target, 
+                                                pivot, translate);
+            if pivoted && result.is_ok() {
+                stats.pivoted += 1;
+            }
+            if matches!(result, Ok(_) 
+                              | Err(SingleTranslationError::LossyText(_))) {
+                break;
+            }
+            if matches!(result, Err(SingleTranslationError::OverLimit(_))) {
+                lower_weight(provider, state);
+            }
+            // Only the last service's error is reported if they all
+            // fail.
+            failed = Some(provider.clone());
+        }
+        match result {
+            Ok((trans, found)) => {
+                translated.push_str(&trans);
+                detection = detection.or(found);
+                stats.translated += 1;
+                stats.chars      += sentence.chars().count() as u64;
+            },
+            Err(SingleTranslationError::LossyText(trans)) => {
+                // The translation is usable, but may have a few
+                // replacement characters in it.
+                translated.push_str(&trans);
+                errors.push(LOSSY_TEXT_MSG.to_string());
+                kind = kind.or(Some(ErrorKind::Parse));
+                stats.translated += 1;
+                stats.chars      += sentence.chars().count() as u64;
+            },
+            Err(err)  => {
+                use SingleTranslationError as STE;
+
+                if matches!(err, STE::OverLimit(_)) {
+                    stats.over_limit += 1;
+                } else {
+                    stats.errors += 1;
+                }
+                kind = Some(err.kind());
+                let emsg = match err {
+                    STE::StaticError(_, s) => {
+                        s.to_string()
+                    },
+                    STE::DynamicError(_, s) => {
+                        s
+                    },
+                    STE::OverLimit(s) => {
+                        over_limit = true;
+                        s.to_string()
+                    },
+                    STE::LossyText(_) => {
+                        LOSSY_TEXT_MSG.to_string()
+                    }
+                };
+                errors.push(emsg);
+                translated.push_str(sentence);
+            },
+        }
+    }
+    translated.push_str(trailing);
+    let translated = unmask_protected(&translated, &protected);
+
+    state.lock().unwrap()
+         .stats.entry((source.to_string(), target.to_string()))
+         .or_default()
+         .add(&stats);
+
+    if !errors.is_empty() {
+        // Error will contain the partially translated text, deduplicated
+        // error messages, and indicate if the translation limit was reached.
+        errors.sort_unstable();
+        errors.dedup();
+        // The service that failed is only worth naming if there was more
+        // than one to try.
+        let failed = failed.filter(|_| providers.len() > 1);
+        let kind   = if over_limit { Some(ErrorKind::Quota) } else { kind };
+        Err( TranslationError::new(translated, errors.join(" "), over_limit,
+                                   failed, kind.unwrap_or(ErrorKind::Parse)) )
+        
+    } else {
+        // Each sentence translated went successfully.
+        state.lock().unwrap().cache.put(key, translated.clone());
+        Ok( (translated, detection) )
+    }
+}
+
+/// The language text is translated through when a service can't translate 
+/// between two languages directly.
+///
+const PIVOT_LANG: &str = "en";
+
+/// Translates text with one service. If the service can't translate between
+/// the languages and pivoting is on, the text is translated to English and
+/// the English to the target instead, which most services can do.
+/// # Arguments
+/// * `text`      - The text to translate.
+/// * `source`    - The source language, which may be "auto".
+/// * `target`    - The target language.
+/// * `pivot`     - Whether to go through English, set with `/TRANSPIVOT`.
+/// * `translate` - Translates text with the service, given the text and the
+///                 languages to translate it between.
+/// # Returns
+/// * The result of the translation, with the language detected on the way
+///   to English if it went through English, and whether it did.
+///
+fn pivot_translate<F>(text          : &str,
+                      source        : &str,
+                      target        : &str,
+                      pivot         : bool,
+                      mut translate : F
+                     ) -> (Result<(String, Option<Detection>), 
+                                  SingleTranslationError>, bool)
+where
+    F: FnMut(&str, &str, &str) 
+       -> Result<(String, Option<Detection>), SingleTranslationError>
+{
+    let result = translate(text, source, target);
+    let pivots = pivot && source != PIVOT_LANG && target != PIVOT_LANG
+                 && matches!(&result, Err(err) 
+                                 if err.kind() == ErrorKind::Unsupported);
+    if !pivots {
+        return (result, false);
+    }
+    let result = translate(text, source, PIVOT_LANG).and_then(
+                     |(english, detection)| {
+                         translate(&english, PIVOT_LANG, target)
+                             .map(|(trans, _)| (trans, detection))
+                     });
+    (result, true)
+}
+
+/// Translates text of more than one line, like a multi-line paste, a line 
+/// at a time with `translate_text()`, so the sentence splitting doesn't run
+/// lines together and the line breaks survive. Empty lines are kept as 
+/// they are.
+/// # Returns
+/// * The translated lines joined with line breaks, and the first line's 
+///   detected language. If any line failed, the error holds the lines with
+///   the failed ones partially translated, and the errors of all of them.
+///
+fn translate_lines(text     : &str, 
+                   source   : &str, 
+                   target   : &str,
+                   nicks    : &HashSet<String>,
+                   state    : &State
+                  ) -> Result<(String, Option<Detection>), TranslationError> 
+{
+    let mut lines      = vec![];
+    let mut errors     = vec![];
+    let mut over_limit = false;
+    let mut failed     = None;
+    let mut kind       = None;
+    let mut detection  = None;
+
+    for line in text.split('\n') {
+        if line.trim().is_empty() {
+            lines.push(line.to_string());
+            continue;
+        }
+        match translate_detect(line, source, target, nicks, state) {
+            Ok((trans, found)) => {
+                lines.push(trans);
+                detection = detection.or(found);
+            },
+            Err(err) => {
+                lines.push(err.partial_trans);
+                errors.push(err.error_msg);
+                over_limit |= err.over_limit;
+                failed      = err.provider.or(failed);
+                kind        = Some(err.kind);
+            }
+        }
+    }
+    let translated = lines.join("\n");
+
+    if errors.is_empty() {
+        Ok( (translated, detection) )
+    } else {
+        errors.sort_unstable();
+        errors.dedup();
+        let kind = if over_limit { Some(ErrorKind::Quota) } else { kind };
+        Err( TranslationError::new(translated, errors.join(" "), over_limit,
+                                   failed, kind.unwrap_or(ErrorKind::Parse)) )
+    }
+}
+
+/// Separates the parts of a message that shouldn't be translated - code, 
+/// URLs, and the nicks of users in the channel - from the prose around them.
+/// A nick is only protected where it appears as a word of its own, possibly
+/// with a leading "@".
+/// # Arguments
+/// * `text`  - The message to separate.
+/// * `nicks` - The case-folded nicks to protect.
+/// # Returns
+/// * The pieces of the message in order, each paired with `true` if it's
+///   protected. Joined together, they make up the original message.
+///
+fn split_protected<'a>(text  : &'a str, 
+                       nicks : &HashSet<String>
+                      ) -> Vec<(bool, &'a str)> 
+{
+    // URLs, and words made of the characters allowed in nicks.
+    let expr     = Regex::new(concat!(r"(?:https?|ftp)://\S+|\bwww\.\S+",
+                                      r"|@?[\w\[\]\\`^{}|-]+")).unwrap();
+    let mut segs = vec![];
+
+    for (is_code, segment) in split_code(text) {
+        if is_code {
+            segs.push((true, segment));
+            continue;
+        }
+        let mut last = 0;
+        for m in expr.find_iter(segment) {
+            let token   = m.as_str();
+            let is_url  = token.contains("://") || token.starts_with("www.");
+            let nick    = token.trim_start_matches('@');
+            let is_nick = nick.chars().count() > 1 
+                          && nicks.contains(&irc_casefold(nick));
+            if !is_url && !is_nick {
+                continue;
+            }
+            if m.start() > last {
+                segs.push((false, &segment[last..m.start()]));
+            }
+            segs.push((true, token));
+            last = m.end();
+        }
+        if last < segment.len() {
+            segs.push((false, &segment[last..]));
+        }
+    }
+    segs
+}
+
+/// Swaps the parts of a message found by `split_protected()` for numbered
+/// placeholders, like "\x000\x00", that the translation services pass 
+/// through as they are.
+/// # Arguments
+/// * `text`  - The message to mask.
+/// * `nicks` - The case-folded nicks to protect.
+/// # Returns
+/// * The masked message, and the protected parts in the order they're 
+///   numbered.
+///
+fn mask_protected<'a>(text  : &'a str, 
+                      nicks : &HashSet<String>
+                     ) -> (String, Vec<&'a str>) 
+{
+    let mut masked    = String::new();
+    let mut protected = vec![];
+    for (is_protected, segment) in split_protected(text, nicks) {
+        if is_protected {
+            masked.push_str(&fm!("\x00{}\x00", protected.len()));
+            protected.push(segment);
+        } else {
+            masked.push_str(segment);
+        }
+    }
+    (masked, protected)
+}
+
+/// Puts the parts taken out by `mask_protected()` back in a translation. 
+/// The service can put spaces inside a placeholder, so they're allowed. A 
+/// part whose placeholder was lost is added at the end, so nothing is 
+/// dropped.
+/// # Arguments
+/// * `trans`     - The translation of the masked message.
+/// * `protected` - The protected parts.
+///
+fn unmask_protected(trans: &str, protected: &[&str]) -> String {
+    if protected.is_empty() {
+        return trans.to_string();
+    }
+    let mut used = vec![false; protected.len()];
+    let mut text = placeholder_regex().replace_all(trans, 
+                                                   |caps: &regex::Captures| {
+        match caps[1].parse::<usize>().ok().filter(|&i| i < protected.len()) {
+            Some(i) => {
+                used[i] = true;
+                protected[i].to_string()
+            },
+            None => String::new(),
+        }
+    }).into_owned();
+    for (part, _) in protected.iter().zip(used).filter(|(_, used)| !used) {
+        if !text.is_empty() && !text.ends_with(char::is_whitespace) {
+            text.push(' ');
+        }
+        text.push_str(part);
+    }
+    text
+}
+
+/// Matches the placeholders `mask_protected()` puts in a message, allowing 
+/// for the spaces a service can put inside one. The number is captured.
+/// Compiled on first use.
+///
+static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+
+/// Returns the `PLACEHOLDER` expression, compiling it the first time.
+///
+fn placeholder_regex() -> &'static Regex {
+    PLACEHOLDER.get_or_init(|| Regex::new(r"\x00\s*(\d+)\s*\x00").unwrap())
+}
+
+/// Determines whether a piece of a masked message has anything to translate,
+/// rather than only placeholders, punctuation, and spaces.
+///
+fn has_prose(text: &str) -> bool {
+    placeholder_regex().replace_all(text, "")
+                       .chars().any(char::is_alphanumeric)
+}
+
+/// Indicates whether a translation has every placeholder of the masked text
+/// it was translated from, so `unmask_protected()` can put each part back
+/// where it belongs.
+///
+fn placeholders_kept(masked: &str, trans: &str) -> bool {
+    let numbers = |text| {
+        placeholder_regex().captures_iter(text)
+                           .map(|caps| caps[1].to_string())
+                           .collect::<HashSet<_>>()
+    };
+    numbers(masked).is_subset(&numbers(trans))
+}
+
+/// Translates a masked sentence whole, so the words around its placeholders
+/// are translated in context. Google's free endpoint and LibreTranslate's
+/// plain text mode have no markup to keep a part untranslated, so if the
+/// service loses or mangles a placeholder, the pieces of prose between the
+/// placeholders are translated on their own instead, and the placeholders
+/// kept as they are.
+/// # Arguments
+/// * `sentence`  - The masked sentence.
+/// * `translate` - Translates a piece of text with the service.
+/// # Returns
+/// * The translation, with the language the first request detected.
+///
+fn translate_masked<F>(sentence      : &str,
+                       mut translate : F
+                      ) -> Result<(String, Option<Detection>), 
+                                  SingleTranslationError>
+where
+    F: FnMut(&str) 
+       -> Result<(String, Option<Detection>), SingleTranslationError>
+{
+    let (trans, detection) = translate(sentence)?;
+    if placeholders_kept(sentence, &trans) {
+        return Ok((trans, detection));
+    }
+    let mut pieces = vec![];
+    let mut last   = 0;
+    for m in placeholder_regex().find_iter(sentence) {
+        pieces.push((false, &sentence[last..m.start()]));
+        pieces.push((true, m.as_str()));
+        last = m.end();
+    }
+    pieces.push((false, &sentence[last..]));
+
+    let mut text = String::new();
+    for (is_placeholder, piece) in pieces {
+        if is_placeholder || !has_prose(piece) {
+            text.push_str(piece);
+            continue;
+        }
+        // The service drops the spaces around a piece.
+        let prose = piece.trim();
+        text.push_str(&piece[..piece.len() - piece.trim_start().len()]);
+        text.push_str(translate(prose)?.0.trim());
+        text.push_str(&piece[piece.trim_end().len()..]);
+    }
+    Ok((text, detection))
+}
+
+/// Separates code from prose in a message so the code can be left 
+/// untranslated. Code is anything between triple backticks, or the whole
+/// message if it's indented by four spaces or a tab like a code block.
+/// An unclosed fence runs to the end of the message.
+/// # Arguments
+/// * `text` - The message to separate.
+/// # Returns
+/// * The pieces of the message in order, each paired with `true` if it's
+///   code. Joined together, they make up the original message.
+///
+fn split_code(text: &str) -> Vec<(bool, &str)> {
+    if is_indented_code(text) {
+        return vec![(true, text)];
+    }
+    let expr     = Regex::new(r"(?s)```.*?(?:```|$)").unwrap();
+    let mut segs = vec![];
+    let mut last = 0;
+    for m in expr.find_iter(text) {
+        if m.start() > last {
+            segs.push((false, &text[last..m.start()]));
+        }
+        segs.push((true, m.as_str()));
+        last = m.end();
+    }
+    if last < text.len() {
+        segs.push((false, &text[last..]));
+    }
+    segs
+}
+
+/// Determines whether a line is indented like a code block, by four spaces
+/// or a tab.
+///
+fn is_indented_code(line: &str) -> bool {
+    line.starts_with("    ") || line.starts_with('\t')
+}
+
+/// The strategies available for breaking a message up into segments that can
+/// each be sent to the translation server.
+/// # Variants
+/// * `Punctuation` - Split after runs of stop punctuation followed by
+///                   whitespace, or after CJK full stops and marks, which
+///                   needn't be followed by any. Works for languages that 
+///                   put spaces between words, like those written in Latin,
+///                   Arabic, or Devanagari script.
+/// * `Length`      - Split into segments of at most the given number of
+///                   characters, preferring to break after punctuation or
+///                   whitespace. Used for scripts that don't put spaces
+///                   between words or sentences.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SplitStrategy {
+    Punctuation,
+    Length(usize),
+}
+
+/// Languages written in scripts that don't separate words or sentences with
+/// spaces. Punctuation based splitting tends to treat whole messages in these
+/// languages as a single segment, so they get chunked by length instead.
+///
+const SPACELESS_LANGUAGES: [&str; 6] = ["zh", "ja", "th", "lo", "km", "my"];
+
+/// The maximum number of characters in a segment produced by the
+/// `SplitStrategy::Length` strategy.
+///
+const SPACELESS_CHUNK_CHARS: usize = 200;
+
+/// Selects the splitting strategy to use for text written in the given 
+/// language.
+/// # Arguments
+/// * `lang` - The language code of the text that will be split.
+///
+fn split_strategy(lang: &str) -> SplitStrategy {
+    // Regional variants, like "zh-TW", are written like the language.
+    let lang = lang.split('-').next().unwrap_or(lang);
+    if SPACELESS_LANGUAGES.contains(&lang) {
+        SplitStrategy::Length(SPACELESS_CHUNK_CHARS)
+    } else {
+        SplitStrategy::Punctuation
+    }
+}
+
+/// Breaks a message up into the segments that will be individually sent to
+/// the translation server. The segments, concatenated in order, reproduce the
+/// original text.
+/// # Arguments
+/// * `text` - The text to split.
+/// * `lang` - The language the text is written in. This selects the
+///            `SplitStrategy` used.
+///
+fn split_sentences<'a>(text: &'a str, lang: &str) -> Vec<&'a str> {
+    match split_strategy(lang) {
+        SplitStrategy::Punctuation => {
+            // Besides the ASCII stops, the Arabic question mark, Urdu full
+            // stop, Devanagari dandas, and Armenian and Ethiopic full stops
+            // end sentences. `\s` matches the ideographic space too.
+            let expr = Regex::new(concat!(r".+?(?:[.?!;|؟۔।॥։።]+\s+",
+                                          r"|[。！？｡．]+\s*|$)")).unwrap();
+
+            // A period after an abbreviation, like "U.S." or "Dr.", doesn't
+            // end the sentence, so the match is joined to the next one.
+            // Decimals, like "3.14", aren't followed by whitespace, so they
+            // never match as a stop.
+            let mut segments = vec![];
+            let mut start    = 0;
+            let mut end      = 0;
+            for m in expr.find_iter(text) {
+                end = m.end();
+                if !ends_with_abbreviation(&text[start..end]) {
+                    segments.push(&text[start..end]);
+                    start = end;
+                }
+            }
+            if start < end {
+                segments.push(&text[start..end]);
+            }
+            segments
+        },
+        SplitStrategy::Length(max_chars) => {
+            split_by_length(text, max_chars, char_count)
+        },
+    }
+}
+
+/// Common abbreviations, in lowercase, that end with a period but rarely end
+/// a sentence. Dotted initialisms, like "U.S." and "e.g.", are recognized
+/// without being listed.
+///
+const ABBREVIATIONS: [&str; 10] = ["mr.", "mrs.", "ms.", "dr.", "prof.", 
+                                   "sr.", "jr.", "st.", "vs.", "approx."];
+
+/// Indicates whether the segment's last word is an abbreviation ending in a
+/// period, so the period doesn't mark the end of a sentence. The word is
+/// either one of the `ABBREVIATIONS`, or a dotted initialism of two or more
+/// single letters, like "U.S.".
+///
+fn ends_with_abbreviation(segment: &str) -> bool {
+    let Some(word) = segment.split_whitespace().last() else {
+        return false;
+    };
+    let word = word.trim_start_matches(|c: char| !c.is_alphanumeric());
+    if !word.ends_with('.') {
+        return false;
+    }
+    if ABBREVIATIONS.contains(&word.to_lowercase().as_str()) {
+        return true;
+    }
+    let letters = word.trim_end_matches('.').split('.').collect::<Vec<_>>();
+    letters.len() >= 2
+        && letters.iter().all(|l| {
+            let mut chars = l.chars();
+            chars.next().is_some_and(char::is_alphabetic) 
+                && chars.next().is_none()
+        })
+}
+
+/// Chunks text into segments no longer than `max`, as measured by adding up
+/// `measure` over their characters. Each chunk is broken after the last 
+/// punctuation or whitespace character within the limit if there is one;
+/// otherwise it's broken at the limit itself. Breaks always fall on character
+/// boundaries, and each chunk has at least one character, however small the
+/// limit.
+/// # Arguments
+/// * `text`    - The text to chunk.
+/// * `max`     - The most each chunk can measure.
+/// * `measure` - The length of a character, like `char_count` or 
+///               `char::len_utf8`.
+///
+fn split_by_length(text    : &str, 
+                   max     : usize, 
+                   measure : fn(char) -> usize
+                  ) -> Vec<&str> 
+{
+    let mut chunks = vec![];
+    let mut rest   = text;
+
+    while !rest.is_empty() {
+        let mut end   = rest.len();
+        let mut brk   = None;
+        let mut len   = 0;
+        for (i, c) in rest.char_indices() {
+            len += measure(c);
+            if len > max {
+                end = if i == 0 { c.len_utf8() } else { i };
+                break;
+            }
+            if c.is_whitespace() || "。！？、，.!?;,".contains(c) {
+                brk = Some(i + c.len_utf8());
+            }
+        }
+        if end < rest.len() {
+            if let Some(b) = brk {
+                end = b;
+            }
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    chunks
+}
+
+/// Measures a character as one, for `split_by_length()` to count characters.
+///
+fn char_count(_c: char) -> usize {
+    1
+}
+
+/// The kinds of errors a translation can fail with, so the advice printed 
+/// with an error, and anything scripting the addon, can tell them apart.
+/// # Variants
+/// * `Network`    - The server couldn't be reached, or its response couldn't
+///                  be read.
+/// * `HttpStatus` - The server answered with an error status.
+/// * `Quota`      - The server's translation limit was reached.
+/// * `Parse`      - The response wasn't in the expected format, or wasn't 
+///                  valid UTF-8.
+/// * `Escape`     - The text couldn't be put in the request URL.
+/// * `Config`     - The service isn't set up to translate the text, like
+///                  DeepL without an API key.
+/// * `TooLong`    - The text is over the `/TRANSMAXCHARS` limit, so it 
+///                  wasn't sent.
+/// * `Unsupported` - The service can't translate between the languages.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorKind {
+    Network,
+    HttpStatus (u16),
+    Quota,
+    Parse,
+    Escape,
+    Config,
+    TooLong,
+    Unsupported,
+}
+
+impl ErrorKind {
+    /// Returns the advice printed along with an error of this kind, if 
+    /// there's any worth giving. Over-limit errors are followed by a notice
+    /// that translation was paused or turned off, so they get none.
+    ///
+    fn hint(self) -> Option<&'static str> {
+        use ErrorKind::*;
+        match self {
+            Network => {
+                Some("Check your connection, or the proxy set with \
+                      /TRANSPROXY.")
+            },
+            HttpStatus(code) if code >= 500 => {
+                Some("The server is having trouble. Wait a bit and try \
+                      again.")
+            },
+            Config => {
+                Some("Check the services set with /TRANSPROVIDER and \
+                      /TRANSKEY.")
+            },
+            TooLong => {
+                Some("Split it up, or raise the limit with /TRANSMAXCHARS.")
+            },
+            Unsupported => {
+                Some("Go through English with /TRANSPIVOT on, or use \
+                      another service with /TRANSPROVIDER.")
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Represents errors encountered when doing a single translation. This
+/// error is generated by `translate_single()`.
+/// # Variants
+/// * `StaticError`  - A predicted error with a static error message.
+/// * `DynamicError` - A freeform text error for unexpected errors.
+/// * `OverLimit`    - Indicates that the translation server sent a response
+///                    saying the user has used up all their translations
+///                    in some amount of time.
+/// * `LossyText`    - The response body wasn't valid UTF-8 and had to be
+///                    decoded lossily. Holds the translation, which may have
+///                    some replacement characters in it.
+///
+#[derive(Debug, Clone)]
+enum SingleTranslationError {
+    StaticError  (ErrorKind, &'static str),
+    DynamicError (ErrorKind, String),
+    OverLimit    (&'static str),
+    LossyText    (String),
+}
+
+impl SingleTranslationError {
+    /// Returns the kind of the error.
+    ///
+    fn kind(&self) -> ErrorKind {
+        use SingleTranslationError::*;
+        match self {
+            StaticError(kind, _) | DynamicError(kind, _) => *kind,
+            OverLimit(_)                                 => ErrorKind::Quota,
+            LossyText(_)                                 => ErrorKind::Parse,
+        }
+    }
+}
+
+/// The error message reported for `SingleTranslationError::LossyText`.
+///
+const LOSSY_TEXT_MSG: &str = "Server response had invalid UTF-8; some \
+                              characters may have been replaced.";
+
+/// The largest response body read from the translation server.
+///
+const MAX_RESPONSE_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Reads the body of an HTTP response as text. Invalid UTF-8 sequences in the
+/// body are replaced with the Unicode replacement character rather than
+/// failing the whole read.
+/// # Returns
+/// * The body text, and `true` if any bytes had to be replaced.
+///
+fn read_body_lossy(rsp: ureq::Response) -> std::io::Result<(String, bool)> {
+    decode_body_lossy(rsp.into_reader())
+}
+
+/// Does the work of `read_body_lossy()`, reading the body from any reader
+/// so recorded responses can be decoded the same way.
+///
+fn decode_body_lossy(body: impl Read) -> std::io::Result<(String, bool)> {
+    let mut bytes = vec![];
+    body.take(MAX_RESPONSE_BYTES).read_to_end(&mut bytes)?;
+    match String::from_utf8(bytes) {
+        Ok(text) => Ok((text, false)),
+        Err(err) => {
+            Ok((String::from_utf8_lossy(err.as_bytes()).into_owned(), true))
+        }
+    }
+}
+impl From<&SingleTranslationError> for SingleTranslationError {
+    fn from(item: &SingleTranslationError) -> Self {
+        item.clone()
+    }
+}
+
+impl From<&SingleTranslationError> for String {
+    fn from(err: &SingleTranslationError) -> Self {
+        use SingleTranslationError::*;
+        match err {
+            StaticError(_, s) | OverLimit(s) => s.to_string(),
+            DynamicError(_, s)               => s.clone(),
+            LossyText(_)                     => LOSSY_TEXT_MSG.to_string(),
+        }
+    }
+}
+
+/// Builds the URL of the HTTPS GET sent to the translation server.
+/// # Arguments
+/// * `text`    - The text to translate.
+/// * `source`  - The source language, or "auto" to have it detected.
+/// * `target`  - The target language.
+/// # Returns
+/// * The URL, or `None` if the text couldn't be escaped.
+///
+fn translation_url(text: &str, source: &str, target: &str) -> Option<String> {
+    let escaped = urlparse::quote(text, b"").ok()?;
+    Some(fm!("https://translate.googleapis.com/\
+              translate_a/single\
+              ?client=gtx\
+              &sl={source_lang}\
+              &tl={target_lang}\
+              &dt=t&q={source_text}",
+              source_lang = source,
+              target_lang = target,
+              source_text = escaped))
+}
+
+/// Translates a single phrase, or sentence - one without multiple clauses 
+/// separated by stop punctuation like a period.
+/// # Arguments
+/// * `sentence`    - The phrase to translate.
+/// * `agent`       - The network agent that will send the HTTPS GET.
+/// * `source`      - The source language to translate from.
+/// * `target`      - The target language to translate to.
+/// * `retries`     - How many times to retry the request if it fails with a
+///                   network error or a 5xx status. The delay before each
+///                   retry doubles, starting at `RETRY_BASE_DELAY`.
+/// # Returns
+/// * A `Result` with either the translation, and the language the server
+///   detected if `source` is "auto", if the translation was successful; or
+///   a `SingleTranslationError` if not.
+///
+fn translate_single(sentence : &str, 
+                    agent    : &ureq::Agent,
+                    source   : &str,
+                    target   : &str,
+                    retries  : u32
+                   ) -> Result<(String, Option<Detection>), 
+                               SingleTranslationError>
+{
+    use SingleTranslationError::*;
+    use ErrorKind::*;
+    use serde_json::Result as SResult;
+    #[inline]
+    fn parse_json(s: &str) -> SResult<Value> {
+        serde_json::from_str::<Value>(s)
+    }
+    static ERRORS: [SingleTranslationError; 4] = [
+        StaticError(Escape, "URL message escaping failed."),
+        StaticError(Network, "Failed to get response from translation server."),
+        StaticError(Network, "Failed to get text for HTTP response body."),
+        StaticError(Parse, "Received invalid response format from server."),
+    ];
+
+    let url     = translation_url(sentence, source, target)
+                                                .ok_or(&ERRORS[0])?;
+                                    
+    // We're on a translation thread, so sleeping between retries doesn't 
+    // hold up Hexchat. An over-limit 403 isn't retried, since it won't
+    // clear up for a long while.
+    let mut attempt = 0;
+    let tr_rsp = loop {
+        let retry = attempt < retries;
+        match agent.get(&url).call() {
+            Ok(rsp) => break rsp,
+            Err(ureq::Error::Status(403, _)) => {
+                return Err( OverLimit("Server translation limit reached.") );
+            },
+            Err(ureq::Error::Status(code, _)) if code >= 500 && retry => {},
+            Err(ureq::Error::Status(code, rsp)) => {
+                return Err( DynamicError(HttpStatus(code), 
+                                         rsp.status_text().to_string()) );
+            },
+            Err(ureq::Error::Transport(_)) if retry => {},
+            Err(_) => return Err( ERRORS[1].clone() ),
+        }
+        thread::sleep(RETRY_BASE_DELAY * 2u32.pow(attempt));
+        attempt += 1;
+    };
+    
+    if tr_rsp.status_text() == "OK" {
+    
+        let (rsp_txt, 
+             lossy) = read_body_lossy(tr_rsp)   .map_err(|_| &ERRORS[2])?;
+        let tr_json = parse_json(&rsp_txt)      .map_err(|_| &ERRORS[3])?;
+        let trans   = tr_json[0][0][0].as_str() .ok_or  (    &ERRORS[3])?;
+        
+        let mut trans = trans.to_string();
+        
+        if sentence.ends_with(' ') {
+            trans.push(' ');
+        }
+        if lossy {
+            Err( LossyText(trans) )
+        } else if source == AUTO_LANG.1 {
+            Ok((trans, parse_detection(&tr_json)))
+        } else {
+            Ok((trans, None))
+        }
+        
+    } else {
+        Err( DynamicError(HttpStatus(tr_rsp.status()), 
+                          tr_rsp.status_text().to_string()) )
+    }
+}
+
+/// Translates a single phrase, or sentence, with a LibreTranslate server. 
+/// The phrase is POSTed as JSON to the server's `/translate` endpoint.
+/// # Arguments
+/// * `sentence`    - The phrase to translate.
+/// * `agent`       - The network agent that will send the HTTP POST.
+/// * `base_url`    - The base URL of the LibreTranslate server.
+/// * `source`      - The source language to translate from.
+/// * `target`      - The target language to translate to.
+/// # Returns
+/// * A `Result` with either a `String` if the translation was successful; or
+///   a `SingleTranslationError` if not.
+///
+fn libretranslate_single(sentence : &str, 
+                         agent    : &ureq::Agent,
+                         base_url : &str,
+                         source   : &str,
+                         target   : &str
+                        ) -> Result<String, SingleTranslationError>
+{
+    use SingleTranslationError::*;
+    use ErrorKind::*;
+    static ERRORS: [SingleTranslationError; 3] = [
+        StaticError(Network, "Failed to get response from translation server."),
+        StaticError(Network, "Failed to get text for HTTP response body."),
+        StaticError(Parse, "Received invalid response format from server."),
+    ];
+
+    let (url, body) = libretranslate_request(base_url, sentence, 
+                                             source, target);
+    let tr_rsp = match agent.post(&url)
+                            .set("Content-Type", "application/json")
+                            .send_string(&body) {
+        Ok(rsp) => rsp,
+        Err(ureq::Error::Status(429, _)) => {
+            return Err( OverLimit("Server translation limit reached.") );
+        },
+        Err(ureq::Error::Status(code, rsp)) => {
+            // LibreTranslate describes what went wrong in an "error" field,
+            // like "ga is not supported" for a language it doesn't have.
+            let emsg = read_body_lossy(rsp).ok()
+                           .and_then(|(txt, _)| {
+                               serde_json::from_str::<Value>(&txt).ok()
+                           })
+                           .and_then(|json| {
+                               json["error"].as_str().map(str::to_string)
+                           });
+            return Err( match emsg {
+                Some(emsg) if code == 400 && emsg.contains("not supported") => {
+                    DynamicError(Unsupported, emsg)
+                },
+                Some(emsg) => DynamicError(HttpStatus(code), emsg),
+                None       => ERRORS[0].clone(),
+            });
+        },
+        Err(_) => return Err( ERRORS[0].clone() ),
+    };
+    let (rsp_txt, 
+         lossy) = read_body_lossy(tr_rsp)       .map_err(|_| &ERRORS[1])?;
+    let tr_json = serde_json::from_str::<Value>(&rsp_txt)
+                                                .map_err(|_| &ERRORS[2])?;
+    let trans   = tr_json["translatedText"].as_str()
+                                                .ok_or(&ERRORS[2])?;
+    let mut trans = trans.to_string();
+    
+    if sentence.ends_with(' ') {
+        trans.push(' ');
+    }
+    if lossy {
+        Err( LossyText(trans) )
+    } else {
+        Ok(trans)
+    }
+}
+
+/// Translates a single phrase, or sentence, with DeepL's API. The phrase is
+/// POSTed as JSON with the API key in the Authorization header.
+/// # Arguments
+/// * `sentence`    - The phrase to translate.
+/// * `agent`       - The network agent that will send the HTTPS POST.
+/// * `key`         - The DeepL API key, if one is set.
+/// * `source`      - The source language to translate from, or "auto".
+/// * `target`      - The target language to translate to.
+/// # Returns
+/// * A `Result` with either a `String` if the translation was successful; or
+///   a `SingleTranslationError` if not.
+///
+fn deepl_single(sentence : &str, 
+                agent    : &ureq::Agent,
+                key      : Option<&str>,
+                source   : &str,
+                target   : &str
+               ) -> Result<String, SingleTranslationError>
+{
+    use SingleTranslationError::*;
+    use ErrorKind::*;
+    static ERRORS: [SingleTranslationError; 5] = [
+        StaticError(Network, "Failed to get response from translation server."),
+        StaticError(Network, "Failed to get text for HTTP response body."),
+        StaticError(Parse, "Received invalid response format from server."),
+        StaticError(Config, 
+                    "No DeepL API key is set. Use /TRANSKEY deepl <key>."),
+        StaticError(Unsupported, DEEPL_UNSUPPORTED_MSG),
+    ];
+
+    let key         = key.ok_or(&ERRORS[3])?;
+    let (url, body) = deepl_request(key, sentence, source, target)
+                                                .ok_or(&ERRORS[4])?;
+    let tr_rsp = match agent.post(&url)
+                            .set("Authorization", 
+                                 &fm!("DeepL-Auth-Key {}", key))
+                            .set("Content-Type", "application/json")
+                            .send_string(&body) {
+        Ok(rsp) => rsp,
+        // DeepL uses 456 when the character quota is used up.
+        Err(ureq::Error::Status(429 | 456, _)) => {
+            return Err( OverLimit("Server translation limit reached.") );
+        },
+        Err(ureq::Error::Status(403, _)) => {
+            return Err( StaticError(Config, "DeepL rejected the API key.") );
+        },
+        Err(ureq::Error::Status(code, rsp)) => {
+            // DeepL describes what went wrong in a "message" field.
+            let emsg = read_body_lossy(rsp).ok()
+                           .and_then(|(txt, _)| {
+                               serde_json::from_str::<Value>(&txt).ok()
+                           })
+                           .and_then(|json| {
+                               json["message"].as_str().map(str::to_string)
+                           });
+            return Err( match emsg {
+                Some(emsg) => DynamicError(HttpStatus(code), emsg),
+                None       => ERRORS[0].clone(),
+            });
+        },
+        Err(_) => return Err( ERRORS[0].clone() ),
+    };
+    let (rsp_txt, 
+         lossy) = read_body_lossy(tr_rsp)       .map_err(|_| &ERRORS[1])?;
+    let tr_json = serde_json::from_str::<Value>(&rsp_txt)
+                                                .map_err(|_| &ERRORS[2])?;
+    let trans   = tr_json["translations"][0]["text"].as_str()
+                                                .ok_or(&ERRORS[2])?;
+    let mut trans = if placeholder_regex().is_match(sentence) {
+                        xml_to_placeholders(trans)
+                    } else {
+                        trans.to_string()
+                    };
+    
+    if sentence.ends_with(' ') {
+        trans.push(' ');
+    }
+    if lossy {
+        Err( LossyText(trans) )
+    } else {
+        Ok(trans)
+    }
+}
+
+/// The tag protected parts are wrapped in for DeepL, which it's told to
+/// leave untranslated with its `ignore_tags` option.
+///
+const DEEPL_IGNORE_TAG: &str = "x";
+
+/// Turns a masked sentence into XML for DeepL, with each placeholder as an
+/// empty tag DeepL ignores, like `<x i="0"/>`, and the rest of the text 
+/// escaped.
+///
+fn placeholders_to_xml(text: &str) -> String {
+    let escaped = text.replace('&', "&amp;")
+                      .replace('<', "&lt;")
+                      .replace('>', "&gt;");
+    placeholder_regex().replace_all(&escaped, |caps: &regex::Captures| {
+        fm!("<{} i=\"{}\"/>", DEEPL_IGNORE_TAG, &caps[1])
+    }).into_owned()
+}
+
+/// Reverses `placeholders_to_xml()` on DeepL's translation, putting the 
+/// placeholders back and unescaping the text.
+///
+fn xml_to_placeholders(xml: &str) -> String {
+    let expr = Regex::new(&fm!(r#"<{}\s+i\s*=\s*"(\d+)"\s*/>"#, 
+                               DEEPL_IGNORE_TAG)).unwrap();
+    expr.replace_all(xml, "\x00$1\x00")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The error given when DeepL can't translate one of the languages.
+///
+const DEEPL_UNSUPPORTED_MSG: &str = "DeepL doesn't support one of the \
+                                     languages.";
+
+/// The languages DeepL translates, by our codes, paired with DeepL's codes 
+/// for them as a source and as a target. DeepL wants a regional variant for
+/// English and Portuguese targets. "zh", Chinese's code before it was split,
+/// is kept for channels that were set to it.
+///
+const DEEPL_LANGUAGES: [(&str, &str, &str); 34] = [
+    ("ar", "AR", "AR"),    ("bg", "BG", "BG"),    ("cs", "CS", "CS"),
+    ("da", "DA", "DA"),    ("de", "DE", "DE"),    ("el", "EL", "EL"),
+    ("en", "EN", "EN-US"), ("es", "ES", "ES"),    ("et", "ET", "ET"),
+    ("fi", "FI", "FI"),    ("fr", "FR", "FR"),    ("hu", "HU", "HU"),
+    ("id", "ID", "ID"),    ("it", "IT", "IT"),    ("ja", "JA", "JA"),
+    ("ko", "KO", "KO"),    ("lt", "LT", "LT"),    ("lv", "LV", "LV"),
+    ("no", "NB", "NB"),    ("nl", "NL", "NL"),    ("pl", "PL", "PL"),
+    ("pt", "PT", "PT-BR"), ("ro", "RO", "RO"),    ("ru", "RU", "RU"),
+    ("sk", "SK", "SK"),    ("sl", "SL", "SL"),    ("sv", "SV", "SV"),
+    ("tr", "TR", "TR"),    ("uk", "UK", "UK"),    ("zh", "ZH", "ZH-HANS"),
+    ("zh-CN", "ZH", "ZH-HANS"), ("zh-TW", "ZH", "ZH-HANT"),
+    ("pt-BR", "PT", "PT-BR"),   ("pt-PT", "PT", "PT-PT"),
+];
+
+/// Builds the URL and JSON body of the HTTPS POST sent to DeepL. Keys for
+/// DeepL's free plan end in ":fx" and go to its free endpoint.
+/// # Arguments
+/// * `key`      - The DeepL API key.
+/// * `text`     - The text to translate.
+/// * `source`   - The source language, or "auto" to have DeepL detect it.
+/// * `target`   - The target language.
+/// # Returns
+/// * A tuple with the URL and the body, or `None` if DeepL doesn't support
+///   one of the languages.
+///
+fn deepl_request(key    : &str, 
+                 text   : &str, 
+                 source : &str, 
+                 target : &str
+                ) -> Option<(String, String)> 
+{
+    let lookup   = |code: &str| DEEPL_LANGUAGES.iter().find(|l| l.0 == code);
+    let target   = lookup(target)?.2;
+    let url      = fm!("https://{}/v2/translate", deepl_host(key));
+    let masked   = placeholder_regex().is_match(text);
+    let text     = if masked { placeholders_to_xml(text) } 
+                   else      { text.to_string()          };
+    let mut body = serde_json::json!({
+        "text"        : [text],
+        "target_lang" : target,
+    });
+    if source != AUTO_LANG.1 {
+        body["source_lang"] = lookup(source)?.1.into();
+    }
+    if masked {
+        // DeepL's documented way to keep parts of the text untranslated.
+        body["tag_handling"] = "xml".into();
+        body["ignore_tags"]  = serde_json::json!([DEEPL_IGNORE_TAG]);
+    }
+    Some((url, body.to_string()))
+}
+
+/// Implements the /TRANSKEY command. Sets the API key of a translation 
+/// service that needs one, or forgets it with "off". The key is only kept in
+/// memory, and isn't printed.
+///
+fn on_cmd_transkey(hc        : &Hexchat, 
+                   word      : &[String], 
+                   _word_eol : &[String], 
+                   user_data : &UserData
+                  ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    match (word.get(1).map(|w| w.to_lowercase()).as_deref(), word.get(2)) {
+        (Some("deepl"), Some(key)) if word.len() == 3 => {
+            let mut state = state.lock().unwrap();
+            if key.eq_ignore_ascii_case("off") {
+                state.deepl_key = None;
+                state.providers.retain(|p| *p != Provider::DeepL);
+                hc.print(&fm!("{ERROR_COLOR}DeepL API key cleared."));
+            } else {
+                state.deepl_key = Some(key.clone());
+                hc.print(&fm!("{ERROR_COLOR}DeepL API key set. Use \
+                               /TRANSPROVIDER deepl to translate with \
+                               DeepL."));
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSKEY_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Builds the URL and JSON body of the HTTP POST sent to a LibreTranslate
+/// server.
+/// # Arguments
+/// * `base_url` - The base URL of the server.
+/// * `text`     - The text to translate.
+/// * `source`   - The source language.
+/// * `target`   - The target language.
+/// # Returns
+/// * A tuple with the URL and the body.
+///
+fn libretranslate_request(base_url : &str, 
+                          text     : &str, 
+                          source   : &str, 
+                          target   : &str
+                         ) -> (String, String) 
+{
+    let url  = fm!("{}/translate", base_url.trim_end_matches('/'));
+    let body = serde_json::json!({
+        "q"      : text,
+        "source" : libretranslate_code(source),
+        "target" : libretranslate_code(target),
+        "format" : "text",
+    });
+    (url, body.to_string())
+}
+
+/// Returns the host DeepL requests are sent to. Keys for DeepL's free plan
+/// end in ":fx" and go to its free endpoint.
+///
+fn deepl_host(key: &str) -> &'static str {
+    if key.ends_with(":fx") { "api-free.deepl.com" } 
+    else                    { "api.deepl.com"      }
+}
+
+/// Finds our code for a language by its code alone, without the aliases and
+/// name matching of `find_lang()`, which would read too much into codes we 
+/// don't know.
+///
+fn lang_by_code(code: &str) -> Option<&'static str> {
+    SUPPORTED_LANGUAGES.iter().chain(&REGIONAL_VARIANTS)
+                       .find(|(name, c)| !name.is_empty() 
+                                         && c.eq_ignore_ascii_case(code))
+                       .map(|(_, c)| *c)
+}
+
+/// Reads the languages a LibreTranslate server supports from the response
+/// to a request to its `/languages` endpoint.
+/// # Returns
+/// * Our codes of the languages, or `None` if the response isn't a list of
+///   languages. Ones we don't have are left out.
+///
+fn parse_libretranslate_languages(json: &str) -> Option<HashSet<String>> {
+    let langs = serde_json::from_str::<Value>(json).ok()?;
+    Some(langs.as_array()?.iter()
+              .filter_map(|lang| lang["code"].as_str())
+              .filter_map(|code| {
+                  // The reverse of `libretranslate_code()`, and the code
+                  // LibreTranslate has for Norwegian.
+                  match code {
+                      "zh" => Some("zh-CN"),
+                      "zt" => Some("zh-TW"),
+                      "nb" => Some("no"),
+                      _    => lang_by_code(code),
+                  }
+              })
+              .map(str::to_string)
+              .collect())
+}
+
+/// Reads the languages DeepL translates to from the response to a request
+/// to its `/v2/languages` endpoint.
+/// # Returns
+/// * Our codes of the languages, or `None` if the response isn't a list of
+///   languages. Ones we don't have are left out.
+///
+fn parse_deepl_languages(json: &str) -> Option<HashSet<String>> {
+    let langs = serde_json::from_str::<Value>(json).ok()?;
+    let codes = langs.as_array()?.iter()
+                     .filter_map(|lang| lang["language"].as_str())
+                     .collect::<Vec<_>>();
+    Some(DEEPL_LANGUAGES.iter()
+                        .filter(|(_, src, tgt)| {
+                            codes.iter().any(|c| {
+                                c.eq_ignore_ascii_case(src) 
+                                || c.eq_ignore_ascii_case(tgt)
+                            })
+                        })
+                        .filter_map(|(ours, _, _)| lang_by_code(ours))
+                        .map(str::to_string)
+                        .collect())
+}
+
+/// Asks a translation service which languages it supports.
+/// # Returns
+/// * Our codes of the languages, `None` if the service has no way to list 
+///   them, like Google's free endpoint, or a description of what went 
+///   wrong.
+///
+fn fetch_languages(provider  : &Provider,
+                   agent     : &ureq::Agent,
+                   deepl_key : Option<&str>
+                  ) -> Result<Option<HashSet<String>>, String>
+{
+    let body = match provider {
+        Provider::Google => return Ok(None),
+        Provider::LibreTranslate(url) => {
+            agent.get(&fm!("{}/languages", url.trim_end_matches('/')))
+                 .call()
+        },
+        Provider::DeepL => {
+            let key = deepl_key.ok_or("No DeepL API key is set.")?;
+            agent.get(&fm!("https://{}/v2/languages", deepl_host(key)))
+                 .query("type", "target")
+                 .set("Authorization", &fm!("DeepL-Auth-Key {}", key))
+                 .call()
+        },
+    };
+    let (body, _) = body.map_err(|err| err.to_string())
+                        .and_then(|rsp| {
+                            read_body_lossy(rsp).map_err(|e| e.to_string())
+                        })?;
+    let langs = match provider {
+        Provider::DeepL => parse_deepl_languages(&body),
+        _               => parse_libretranslate_languages(&body),
+    };
+    langs.map(Some)
+         .ok_or_else(|| "Received invalid response format from server."
+                             .to_string())
+}
+
+/// Returns the languages the primary translation service supports, if
+/// they were fetched with `/TRANSLANGSYNC` while it was the primary.
+///
+fn get_synced_langs(state: &State) -> Option<HashSet<String>> {
+    let provider = get_provider(state);
+    match &state.lock().unwrap().synced_langs {
+        Some((synced, langs)) if *synced == provider => Some(langs.clone()),
+        _ => None,
+    }
+}
+
+/// Returns LibreTranslate's code for a language. It has its own codes for
+/// the two kinds of Chinese; the rest are the same as ours.
+///
+fn libretranslate_code(lang: &str) -> &str {
+    match lang {
+        "zh-CN" => "zh",
+        "zh-TW" => "zt",
+        _       => lang,
+    }
+}
+
+/// Returns the network agent that requests to the translation server are
+/// sent with. It's built the first time it's needed and then shared, so 
+/// its connections are kept alive and reused from one request to the next.
+/// It goes through the proxy set with `/TRANSPROXY`, if there is one.
+///
+fn get_agent(state: &State) -> ureq::Agent {
+    let mut state = state.lock().unwrap();
+    if let Some(agent) = &state.agent {
+        return agent.clone();
+    }
+    let mut builder = ureq::AgentBuilder::new()
+                            .timeout_read(
+                                 Duration::from_secs(
+                                     TRANSLATION_SERVER_TIMEOUT));
+    // The URL was checked when it was set, so it won't fail to parse here.
+    if let Some(proxy) = state.proxy.as_ref()
+                              .and_then(|url| ureq::Proxy::new(url).ok()) {
+        builder = builder.proxy(proxy);
+    }
+    let agent = builder.build();
+    state.agent = Some(agent.clone());
+    agent
+}
+
+/// The URL schemes of the proxies `/TRANSPROXY` accepts.
+///
+const PROXY_SCHEMES: [&str; 2] = ["http://", "socks5://"];
+
+/// Checks a proxy URL given to `/TRANSPROXY`.
+/// # Returns
+/// * The URL with its scheme lowercased, which is the only way ureq 
+///   recognizes it; or a description of what's wrong with the URL.
+///
+fn check_proxy_url(url: &str) -> Result<String, String> {
+    let url   = url.trim_end_matches('/');
+    let lower = url.to_lowercase();
+    let Some(scheme) = PROXY_SCHEMES.iter().find(|s| lower.starts_with(*s))
+    else {
+        return Err(fm!("The proxy URL must start with {}.", 
+                       PROXY_SCHEMES.join(" or ")));
+    };
+    let rest = &url[scheme.len()..];
+    if rest.is_empty() {
+        return Err("The proxy URL has no host.".to_string());
+    }
+    let url = fm!("{}{}", scheme, rest);
+    ureq::Proxy::new(&url)
+        .map(|_| url)
+        .map_err(|err| fm!("The proxy URL is malformed: {}", err))
+}
+
+/// Implements the /TRANSPROXY command. Sets the HTTP or SOCKS5 proxy that
+/// requests to the translation server are sent through, for networks that
+/// can't reach it directly. `/TRANSPROXY off` sends them directly again, 
+/// and without arguments, the proxy is printed.
+///
+fn on_cmd_transproxy(hc        : &Hexchat, 
+                     word      : &[String], 
+                     _word_eol : &[String], 
+                     user_data : &UserData
+                    ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    match word.len() {
+        1 => {
+            if let Some(url) = &state.lock().unwrap().proxy {
+                hc.print(&fm!("{ERROR_COLOR}Translation proxy: {}", url));
+            } else {
+                hc.print(&fm!("{ERROR_COLOR}No translation proxy is set."));
+            }
+        },
+        2 if word[1].eq_ignore_ascii_case("off") => {
+            let mut state = state.lock().unwrap();
+            state.proxy = None;
+            state.agent = None;
+            hc.print(&fm!("{ERROR_COLOR}Translation proxy turned OFF."));
+        },
+        2 => {
+            match check_proxy_url(&word[1]) {
+                Ok(url) => {
+                    hc.print(&fm!("{ERROR_COLOR}Translation proxy set to {}.",
+                                  url));
+                    let mut state = state.lock().unwrap();
+                    state.proxy = Some(url);
+                    state.agent = None;
+                },
+                Err(err) => {
+                    hc.print(&fm!("{ERROR_COLOR}{}", err));
+                },
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSPROXY_HELP));
+        },
+    }
+    Eat::All
+}
+
+/// Asks the translation server to detect the language of the given text.
+/// The request counts against the `/TRANSRATE` limit like a translation.
+/// # Arguments
+/// * `text`    - The text to detect the language of.
+/// * `state`   - The addon state, which holds the rate limit and the agent
+///               that will send the HTTPS GET.
+/// # Returns
+/// * A `Result` with the detected language code and the server's confidence 
+///   in it, if given; or a `SingleTranslationError` if detection failed.
+///
+fn detect_single(text  : &str,
+                 state : &State
+                ) -> Result<Detection, SingleTranslationError>
+{
+    use SingleTranslationError::*;
+    use ErrorKind::*;
+    static ERRORS: [SingleTranslationError; 5] = [
+        StaticError(Escape, "URL message escaping failed."),
+        StaticError(Network, "Failed to get response from translation server."),
+        StaticError(Network, "Failed to get text for HTTP response body."),
+        StaticError(Parse, "Received invalid response format from server."),
+        StaticError(Quota, RATE_LIMITED_MSG),
+    ];
+
+    let url     = translation_url(text, "auto", "en").ok_or(&ERRORS[0])?;
+
+    if !throttle(state) {
+        return Err( ERRORS[4].clone() );
+    }
+    let agent  = get_agent(state);
+
+    let tr_rsp = agent.get(&url).call()         .map_err(|_| &ERRORS[1])?;
+    
+    if tr_rsp.status_text() == "OK" {
+        let (rsp_txt, _) = read_body_lossy(tr_rsp)
+                                                .map_err(|_| &ERRORS[2])?;
+        let tr_json = serde_json::from_str::<Value>(&rsp_txt)
+                                                .map_err(|_| &ERRORS[3])?;
+        parse_detection(&tr_json).ok_or(&ERRORS[3]).map_err(From::from)
+
+    } else if tr_rsp.status() == 403 {
+        Err( OverLimit("Server translation limit reached.") )
+        
+    } else {
+        Err( DynamicError(HttpStatus(tr_rsp.status()), 
+                          tr_rsp.status_text().to_string()) )
+    }
+}
+
+/// A language detected by the translation server, and its confidence in the
+/// detection, if given.
+///
+type Detection = (String, Option<f64>);
+
+/// Extracts the detected source language and the confidence of the detection
+/// from a response of the translation server to a request made with
+/// `sl=auto`.
+/// # Arguments
+/// * `tr_json` - The parsed JSON response.
+/// # Returns
+/// * The detected language code and the confidence, a value between 0 and 1,
+///   if the server included it. `None` if there's no detected language in the
+///   response.
+///
+fn parse_detection(tr_json: &Value) -> Option<Detection> {
+    let lang = tr_json[2].as_str()?.to_string();
+    let conf = tr_json[6].as_f64()
+                         .or_else(|| tr_json[8][2][0].as_f64());
+    Some((lang, conf))
+}
+
+/// Determines whether a detection read from a translation response should be
+/// flagged as uncertain, since the translation may be off too.
+/// # Arguments
+/// * `detection` - The detection, if the server made one.
+/// * `min_conf`  - The confidence set with `/TRANSCONFIDENCE`, if any.
+///
+fn is_low_confidence(detection: Option<&Detection>, 
+                     min_conf : Option<f64>
+                    ) -> bool 
+{
+    detection.and_then(|(_, conf)| *conf)
+             .zip(min_conf)
+             .is_some_and(|(conf, min)| conf < min)
+}
+
+/// Detections with a confidence below this value are reported as uncertain.
+///
+const LOW_DETECTION_CONFIDENCE: f64 = 0.5;
+
+/// The most alternatives `/LDEF` prints for each part of speech.
+///
+const MAX_DICTIONARY_TERMS: usize = 8;
+
+/// The alternatives of a word for each part of speech, as parsed by 
+/// `parse_dictionary()`.
+///
+type DictEntries = Vec<(String, Vec<String>)>;
+
+/// Looks up a word in the translation server's dictionary. The request is
+/// the one `translate_single()` sends, with dictionary data (`dt=bd`) asked
+/// for as well.
+/// # Arguments
+/// * `word`    - The word to look up.
+/// * `agent`   - The network agent that will send the HTTPS GET.
+/// * `source`  - The language of the word.
+/// * `target`  - The language to give the alternatives in.
+/// # Returns
+/// * A `Result` with the word's translation and its alternatives for each
+///   part of speech; or a `SingleTranslationError` if the lookup failed.
+///
+fn dictionary_single(word   : &str,
+                     agent  : &ureq::Agent,
+                     source : &str,
+                     target : &str
+                    ) -> Result<(String, DictEntries), SingleTranslationError>
+{
+    use SingleTranslationError::*;
+    use ErrorKind::*;
+    static ERRORS: [SingleTranslationError; 4] = [
+        StaticError(Escape, "URL message escaping failed."),
+        StaticError(Network, "Failed to get response from translation server."),
+        StaticError(Network, "Failed to get text for HTTP response body."),
+        StaticError(Parse, "Received invalid response format from server."),
+    ];
+
+    let url     = translation_url(word, source, target)
+                      .map(|url| url + "&dt=bd")    .ok_or(&ERRORS[0])?;
+
+    let tr_rsp  = match agent.get(&url).call() {
+        Ok(rsp) => rsp,
+        Err(ureq::Error::Status(403, _)) => {
+            return Err( OverLimit("Server translation limit reached.") );
+        },
+        Err(_) => return Err( ERRORS[1].clone() ),
+    };
+    if tr_rsp.status_text() == "OK" {
+        let (rsp_txt, _) = read_body_lossy(tr_rsp)
+                                                .map_err(|_| &ERRORS[2])?;
+        let tr_json = serde_json::from_str::<Value>(&rsp_txt)
+                                                .map_err(|_| &ERRORS[3])?;
+        let trans   = tr_json[0][0][0].as_str() .ok_or  (    &ERRORS[3])?;
+        
+        Ok((trans.to_string(), parse_dictionary(&tr_json)))
+    } else {
+        Err( DynamicError(HttpStatus(tr_rsp.status()), 
+                          tr_rsp.status_text().to_string()) )
+    }
+}
+
+/// Extracts the alternative translations from a response of the translation
+/// server to a request made with `dt=bd`. The dictionary is the second 
+/// element of the response, with an entry for each part of speech holding
+/// its name and a list of terms, best first.
+/// # Arguments
+/// * `tr_json` - The parsed JSON response.
+/// # Returns
+/// * The parts of speech and up to `MAX_DICTIONARY_TERMS` terms for each. 
+///   Empty if the response has no dictionary, as for phrases.
+///
+fn parse_dictionary(tr_json: &Value) -> DictEntries {
+    let Some(entries) = tr_json[1].as_array() else {
+        return vec![];
+    };
+    entries.iter().filter_map(|entry| {
+        let pos   = entry[0].as_str()?;
+        let terms = entry[1].as_array()?
+                            .iter()
+                            .filter_map(Value::as_str)
+                            .take(MAX_DICTIONARY_TERMS)
+                            .map(str::to_string)
+                            .collect::<Vec<_>>();
+        if terms.is_empty() {
+            None
+        } else {
+            Some((if pos.is_empty() { "other" } else { pos }.to_string(), 
+                  terms))
+        }
+    }).collect()
+}
+
+/// Implements the /TRANSPROVIDER command. Switches the translation service
+/// between Google's free endpoint and a LibreTranslate server, adds a service
+/// to fall back on when the ones before it fail, or prints the chain in use.
+///
+fn on_cmd_transprovider(hc        : &Hexchat, 
+                        word      : &[String], 
+                        _word_eol : &[String], 
+                        user_data : &UserData
+                       ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    let adding = word.get(1).is_some_and(|w| w.eq_ignore_ascii_case("add"));
+    let word   = if adding { &word[1..] } else { word };
+
+    let provider = match (word.get(1).map(|w| w.to_lowercase()).as_deref(),
+                          word.get(2)) {
+        (None, _) if !adding => {
+            hc.print(&fm!("{ERROR_COLOR}Translation provider: {}", 
+                          providers_str(&get_providers(&state))));
+            return Eat::All;
+        },
+        (Some("google"), None) => {
+            Provider::Google
+        },
+        (Some("libretranslate"), Some(url)) 
+            if url.starts_with("http://") || url.starts_with("https://") => {
+            Provider::LibreTranslate(url.trim_end_matches('/').to_string())
+        },
+        (Some("deepl"), None) => {
+            if state.lock().unwrap().deepl_key.is_none() {
+                hc.print(&fm!("{ERROR_COLOR}Set a DeepL API key with \
+                               /TRANSKEY deepl <key> first."));
+                return Eat::All;
+            }
+            Provider::DeepL
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSPROVIDER_HELP));
+            return Eat::All;
+        }
+    };
+    let providers = if adding {
+        let mut providers = get_providers(&state);
+        if providers.contains(&provider) {
+            hc.print(&fm!("{ERROR_COLOR}{} is already in the chain.", 
+                          provider));
+            return Eat::All;
+        }
+        providers.push(provider);
+        providers
+    } else {
+        vec![provider]
+    };
+    hc.print(&fm!("{ERROR_COLOR}Translation provider set to: {}", 
+                  providers_str(&providers)));
+    let mut state = state.lock().unwrap();
+    state.providers = providers;
+    // Translations from the old provider shouldn't be served from the cache.
+    state.cache.clear();
+    Eat::All
+}
+
+/// Implements the /TRANSWEIGHT command. Sets the weight of a service in the
+/// chain, so the first one tried for each request is picked at random by 
+/// weight, spreading requests across them. The service is named like it is
+/// for `/TRANSPROVIDER`, or by just "libretranslate" if there's one 
+/// LibreTranslate server in the chain. "off" goes back to trying the chain 
+/// in order, and the weights are printed with no arguments.
+///
+fn on_cmd_transweight(hc        : &Hexchat, 
+                      word      : &[String], 
+                      _word_eol : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let state     = user_data.apply(|state: &State| state.clone());
+    let providers = get_providers(&state);
+
+    match (word.get(1).map(|w| w.to_lowercase()).as_deref(), word.len()) {
+        (None, _) => {
+            let state = state.lock().unwrap();
+            if state.weights.is_empty() {
+                hc.print(&fm!("{ERROR_COLOR}No weights are set; services are \
+                               tried in chain order."));
+                return Eat::All;
+            }
+            let weights = providers.iter().map(|p| {
+                              let w = state.weights.get(p).copied()
+                                           .unwrap_or(DEFAULT_WEIGHT);
+                              fm!("{} {}", p, w)
+                          }).collect::<Vec<_>>();
+            hc.print(&fm!("{ERROR_COLOR}Service weights: {}", 
+                          weights.join(", ")));
+        },
+        (Some("off"), 2) => {
+            let mut state = state.lock().unwrap();
+            state.weights.clear();
+            state.lowered_weights.clear();
+            hc.print(&fm!("{ERROR_COLOR}Weights cleared; services will be \
+                           tried in chain order."));
+        },
+        (Some(name), 3) => {
+            let Ok(weight) = word[2].parse::<u32>() else {
+                hc.print(&fm!("USAGE: {}", TRANSWEIGHT_HELP));
+                return Eat::All;
+            };
+            let matches = providers.iter().filter(|p| {
+                              match p {
+                                  Provider::Google => name == "google",
+                                  Provider::DeepL  => name == "deepl",
+                                  Provider::LibreTranslate(url) => {
+                                      name == "libretranslate" 
+                                      || word[1].trim_end_matches('/') == url
+                                  },
+                              }
+                          }).collect::<Vec<_>>();
+            match matches[..] {
+                [provider] => {
+                    state.lock().unwrap()
+                         .weights.insert(provider.clone(), weight);
+                    hc.print(&fm!("{ERROR_COLOR}Weight of {} set to {}.", 
+                                  provider, weight));
+                },
+                [] => {
+                    hc.print(&fm!("{ERROR_COLOR}{} isn't in the chain. Add \
+                                   it with /TRANSPROVIDER add first.", 
+                                  word[1]));
+                },
+                _ => {
+                    hc.print(&fm!("{ERROR_COLOR}More than one \
+                                   LibreTranslate server is in the chain. \
+                                   Give the URL of the one to weight."));
+                },
+            }
+        },
+        _ => {
+            hc.print(&fm!("USAGE: {}", TRANSWEIGHT_HELP));
+        }
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSPIVOT command. Turns on or off translating through
+/// English when a service can't translate between two languages directly.
+/// Off by default.
+///
+fn on_cmd_transpivot(hc        : &Hexchat, 
+                     word      : &[String], 
+                     _word_eol : &[String], 
+                     user_data : &UserData
+                    ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if let Some(on) = word.get(1).and_then(|w| parse_on_off(w)) {
+        state.lock().unwrap().pivot = on;
+        hc.print(&fm!("{ERROR_COLOR}Translation through English turned {}.",
+                      if on { "ON" } else { "OFF" }));
+    } else {
+        hc.print(&fm!("USAGE: {}", TRANSPIVOT_HELP));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSLANGSYNC command. Asks the primary translation 
+/// service which languages it supports, so `/LISTLANG` and `/SETLANG` can
+/// point out the ones it doesn't have. Google's free endpoint has no way to
+/// list them, so there's nothing to do for it.
+///
+fn on_cmd_translangsync(hc        : &Hexchat, 
+                        word      : &[String], 
+                        _word_eol : &[String], 
+                        user_data : &UserData
+                       ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if word.len() != 1 {
+        hc.print(&fm!("USAGE: {}", TRANSLANGSYNC_HELP));
+        return Eat::All;
+    }
+    let provider = get_provider(&state);
+    if provider == Provider::Google {
+        hc.print(&fm!("{ERROR_COLOR}Google's free endpoint can't list its \
+                       languages, so there's nothing to sync. /LISTLANG \
+                       lists the ones it's known to support."));
+        return Eat::All;
+    }
+    if {||{
+        let network = get_network(hc)?;
+        let channel = hc.get_info("channel")?;
+        
+        hc.print(&fm!("{ERROR_COLOR}Asking {} for its languages...", 
+                      provider));
+
+        pool::execute(move || {
+            let agent     = get_agent(&state);
+            let deepl_key = state.lock().unwrap().deepl_key.clone();
+            let result    = fetch_languages(&provider, &agent, 
+                                            deepl_key.as_deref());
+            let report = match result {
+                Ok(Some(langs)) => {
+                    let count   = langs.len();
+                    let missing = SUPPORTED_LANGUAGES.iter()
+                                      .chain(&REGIONAL_VARIANTS)
+                                      .filter(|(name, code)| {
+                                          !name.is_empty() 
+                                          && !langs.contains(*code)
+                                      })
+                                      .count();
+                    state.lock().unwrap().synced_langs = 
+                        Some((provider.clone(), langs));
+                    fm!("{ERROR_COLOR}{} supports {} of our languages; {} \
+                         aren't supported. See /LISTLANG.", 
+                        provider, count, missing)
+                },
+                Ok(None) => {
+                    fm!("{ERROR_COLOR}{} can't list its languages.", provider)
+                },
+                Err(err) => {
+                    fm!("{ERROR_COLOR}Couldn't get the languages of {}: {}", 
+                        provider, err)
+                },
+            };
+            main_thread(move |hc| {
+                if let Some(ctx) = hc.find_context(&network, &channel) {
+                    if ctx.print(&report).is_ok() {
+                        return;
+                    }
+                }
+                hc.print(&report);
+            });
+        });
+        Some(())
+    }}().is_none() {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 Translator Error: Basic failure retrieving channel \
+                 information."));
+    }
+    Eat::All
+}
+
+/// Implements the /TRANSREQ command. Prints the requests that would be sent
+/// to the translation server to translate the text, without sending them. 
+/// The text is split up the same way `translate_text()` splits it, so
+/// there's one request for each piece that would be translated.
+///
+fn on_cmd_transreq(hc        : &Hexchat, 
+                   word      : &[String], 
+                   word_eol  : &[String], 
+                   user_data : &UserData
+                  ) -> Eat 
+{
+    let (provider, deepl_key) = user_data.apply(|state: &State| {
+                                    (get_provider(state),
+                                     state.lock().unwrap().deepl_key.clone())
+                                });
+    if word.len() < 4 {
+        hc.print(&fm!("USAGE: {}", TRANSREQ_HELP));
+        return Eat::All;
+    }
+    let (Some(src), Some(tgt)) = (find_lang(&word[1]), find_lang(&word[2])) 
+    else {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 BAD LANGUAGE PARAMETERS. Use /LISTLANG to \
+                 get a list of supported languages."));
+        return Eat::All;
+    };
+    let Some(text) = hc.strip(&word_eol[3], StripBoth) else {
+        hc.print(&fm!("{ERROR_COLOR}Unable to strip the text."));
+        return Eat::All;
+    };
+    match &provider {
+        Provider::Google => {
+            hc.print(&fm!("{ERROR_COLOR}The requests are HTTPS GETs with no \
+                           API key and ureq's default headers. {} second \
+                           read timeout.", TRANSLATION_SERVER_TIMEOUT));
+        },
+        Provider::LibreTranslate(_) => {
+            hc.print(&fm!("{ERROR_COLOR}The requests are HTTP POSTs with a \
+                           JSON body, no API key, and ureq's default \
+                           headers. {} second read timeout.", 
+                          TRANSLATION_SERVER_TIMEOUT));
+        },
+        Provider::DeepL => {
+            hc.print(&fm!("{ERROR_COLOR}The requests are HTTPS POSTs with a \
+                           JSON body, the API key in an Authorization \
+                           header, and ureq's default headers. {} second \
+                           read timeout.", TRANSLATION_SERVER_TIMEOUT));
+        },
+    }
+
+    // The sentences are masked and split as `translate_text()` does it.
+    let (masked, _) = mask_protected(&text, &HashSet::new());
+    for sentence in split_sentences(masked.trim(), src.1) {
+        if !has_prose(sentence) {
+            continue;
+        }
+        match request_preview(&provider, deepl_key.as_deref(), sentence,
+                              src.1, tgt.1) {
+            Ok(request) => {
+                hc.print(&request);
+            },
+            Err(emsg) => {
+                hc.print(&fm!("{ERROR_COLOR}{}", emsg));
+                if provider == Provider::DeepL {
+                    return Eat::All;
+                }
+            }
+        }
+    }
+    Eat::All
+}
+
+/// What `request_preview()` shows in place of an API key.
+///
+const REDACTED_KEY: &str = "<redacted>";
+
+/// Builds the request line `/TRANSREQ` prints for translating a sentence. 
+/// An API key is never part of it; the header that would carry one shows
+/// `REDACTED_KEY` instead.
+/// # Arguments
+/// * `provider`  - The translation service the request is for.
+/// * `deepl_key` - The DeepL API key, if one is set. It only decides which
+///                 of DeepL's servers the request goes to.
+/// * `sentence`  - The sentence to translate.
+/// * `source`    - The source language code.
+/// * `target`    - The target language code.
+/// # Returns
+/// * The method, URL, headers, and body of the request, or an error message
+///   if it can't be built.
+///
+fn request_preview(provider  : &Provider, 
+                   deepl_key : Option<&str>,
+                   sentence  : &str, 
+                   source    : &str, 
+                   target    : &str
+                  ) -> Result<String, String> 
+{
+    match provider {
+        Provider::Google => {
+            translation_url(sentence, source, target)
+                .map(|url| fm!("GET {}", url))
+                .ok_or_else(|| fm!("URL message escaping failed for: {}", 
+                                   sentence))
+        },
+        Provider::LibreTranslate(base_url) => {
+            let (url, body) = libretranslate_request(base_url, sentence,
+                                                     source, target);
+            Ok( fm!("POST {} {}", url, body) )
+        },
+        Provider::DeepL => {
+            let key = deepl_key.unwrap_or_default();
+            deepl_request(key, sentence, source, target)
+                .map(|(url, body)| {
+                    fm!("POST {} Authorization: DeepL-Auth-Key {} {}", 
+                        url, REDACTED_KEY, body)
+                })
+                .ok_or_else(|| DEEPL_UNSUPPORTED_MSG.to_string())
+        },
+    }
+}
+
+/// Implements the /TRANSDETECT command. Detects the language of the given
+/// text and prints the result without translating anything.
+///
+fn on_cmd_transdetect(hc        : &Hexchat, 
+                      word      : &[String], 
+                      word_eol  : &[String], 
+                      user_data : &UserData
+                     ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if word.len() < 2 {
+        hc.print(&fm!("USAGE: {}", TRANSDETECT_HELP));
+        return Eat::All;
+    }
+    if {||{
+        let text    = hc.strip(&word_eol[1], StripBoth)?;
+        let network = get_network(hc)?;
+        let channel = hc.get_info("channel")?;
+
+        pool::execute(move || {
+            let report = match detect_single(&text, &state) {
+                Ok((code, conf)) => {
+                    let name = find_lang(&code).map_or(code.as_str(), 
+                                                       |info| info.0);
+                    match conf {
+                        Some(c) if c < LOW_DETECTION_CONFIDENCE => {
+                            fm!("{ERROR_COLOR}Detected language: {} ({}), \
+                                 but with low confidence ({:.0}%) - the \
+                                 result is uncertain.", name, code, c * 100.)
+                        },
+                        Some(c) => {
+                            fm!("{ERROR_COLOR}Detected language: {} ({}), \
+                                 confidence {:.0}%.", name, code, c * 100.)
+                        },
+                        None => {
+                            fm!("{ERROR_COLOR}Detected language: {} ({}).",
+                                name, code)
+                        }
+                    }
+                },
+                Err(err) => {
+                    fm!("{ERROR_COLOR}Language detection failed: {}", 
+                        String::from(&err))
+                }
+            };
+            main_thread(move |hc| {
+                if let Some(ctx) = hc.find_context(&network, &channel) {
+                    if ctx.print(&report).is_ok() {
+                        return;
+                    }
+                }
+                hc.print(&report);
+            });
+        });
+        Some(())
+    }}().is_none() {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 Translator Error: Basic failure retrieving channel \
+                 information, or unable to strip the text."));
+    }
+    Eat::All
+}
+
+/// The phrase and languages `/TRANSTEST` translates with.
+///
+const TEST_PHRASE: (&str, &str, &str) = ("hello", "en", "es");
+
+/// Implements the /TRANSTEST command. Translates a fixed phrase with 
+/// Google's server, without retrying or using the cache, and prints whether
+/// it worked and how long the request took. It's a quick way to tell a 
+/// network or server problem from a problem with the channel's settings.
+///
+fn on_cmd_transtest(hc        : &Hexchat, 
+                    word      : &[String], 
+                    _word_eol : &[String], 
+                    user_data : &UserData
+                   ) -> Eat 
+{
+    use SingleTranslationError::*;
+    
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if word.len() != 1 {
+        hc.print(&fm!("USAGE: {}", TRANSTEST_HELP));
+        return Eat::All;
+    }
+    if {||{
+        let network = get_network(hc)?;
+        let channel = hc.get_info("channel")?;
+        
+        hc.print(&fm!("{ERROR_COLOR}Testing translation of '{}' from {} to \
+                       {}...", TEST_PHRASE.0, TEST_PHRASE.1, TEST_PHRASE.2));
+
+        pool::execute(move || {
+            let agent = get_agent(&state);
+            let (text, src, tgt) = TEST_PHRASE;
+            let start  = Instant::now();
+            let result = translate_single(text, &agent, src, tgt, 0);
+            let millis = start.elapsed().as_millis();
+            
+            let report = match result {
+                Ok((trans, _)) => {
+                    fm!("{ERROR_COLOR}Translation test succeeded in {} ms \
+                         (HTTP 200): '{}'.", millis, trans.trim())
+                },
+                Err(OverLimit(msg)) => {
+                    fm!("{ERROR_COLOR}Translation test failed in {} ms \
+                         (HTTP 403): {}", millis, msg)
+                },
+                Err(err) => {
+                    fm!("{ERROR_COLOR}Translation test failed in {} ms: {}",
+                        millis, String::from(&err))
+                },
+            };
+            main_thread(move |hc| {
+                if let Some(ctx) = hc.find_context(&network, &channel) {
+                    if ctx.print(&report).is_ok() {
+                        return;
+                    }
+                }
+                hc.print(&report);
+            });
+        });
+        Some(())
+    }}().is_none() {
+        hc.print(&fm!("{ERROR_COLOR}\
+                 Translator Error: Basic failure retrieving channel \
+                 information."));
+    }
+    Eat::All
+}
+
+/// The commands whose first two arguments are languages, which are 
+/// completed by `on_key_press()`.
+///
+const LANG_ARG_COMMANDS: [&str; 6] = ["/setlang", "/setlangrelay", 
+                                      "/setnetlang", "/setdisplaylang",
+                                      "/ltrans", "/transreq"];
+
+/// The key value Hexchat gives the TAB key in the "Key Press" event.
+///
+const TAB_KEY: &str = "65289";
+
+/// Callback invoked for every key pressed in the input box. When TAB is 
+/// pressed at the end of the first or second argument of a command that takes
+/// languages, like `/SETLANG spa`, the partial language name or code is 
+/// completed. If it could be more than one language, they're printed 
+/// instead.
+///
+fn on_key_press(hc         : &Hexchat, 
+                word       : &[String], 
+                _user_data : &UserData
+               ) -> Eat 
+{
+    // Only a plain TAB, without modifiers, is handled.
+    if word.first().is_none_or(|k| k != TAB_KEY) 
+        || word.get(1).is_some_and(|m| m != "0") {
+        return Eat::None;
+    }
+    let Some(text) = hc.get_info("inputbox") else {
+        return Eat::None;
+    };
+    let cursor = match hc.get_prefs("state_cursor") {
+        Some(PrefValue::IntegerVal(pos)) => pos as usize,
+        _ => return Eat::None,
+    };
+    if cursor != text.chars().count() {
+        return Eat::None;
+    }
+    let words = text.split(' ').collect::<Vec<_>>();
+    if !(2..=3).contains(&words.len()) 
+        || !LANG_ARG_COMMANDS.contains(&words[0].to_lowercase().as_str()) {
+        return Eat::None;
+    }
+    let last    = words[words.len() - 1];
+    let partial = last.to_lowercase();
+    if partial.is_empty() {
+        return Eat::None;
+    }
+    let mut matches = SUPPORTED_LANGUAGES.iter()
+                          .filter(|info| !info.1.is_empty())
+                          .filter(|info| {
+                              info.0.to_lowercase().starts_with(&partial)
+                                  || info.1.to_lowercase()
+                                           .starts_with(&partial)
+                          })
+                          .collect::<Vec<_>>();
+    if words.len() == 2 && AUTO_LANG.1.starts_with(&partial) {
+        matches.push(&AUTO_LANG);
+    }
+    match matches[..] {
+        [] => Eat::None,
+        [info] => {
+            // Complete the name if that's what's being typed, or the code.
+            let done = if info.0.to_lowercase().starts_with(&partial) 
+                            && info != &AUTO_LANG { 
+                           info.0 
+                       } else { 
+                           info.1 
+                       };
+            let head = &text[..text.len() - last.len()];
+            let line = fm!("{}{} ", head, done);
+            hc.command(&fm!("SETTEXT {}", line));
+            hc.command(&fm!("SETCURSOR {}", line.chars().count()));
+            Eat::All
+        },
+        _ => {
+            let names = matches.iter()
+                               .map(|info| fm!("{} ({})", info.0, info.1))
+                               .collect::<Vec<_>>();
+            hc.print(&fm!("{ERROR_COLOR}{}", names.join(", ")));
+            Eat::All
+        }
+    }
+}
+
+/// The number of languages in each row printed by /LISTLANG.
+///
+const LISTLANG_COLUMNS: usize = 3;
+
+/// Formats a table of languages into the rows printed by /LISTLANG, with
+/// `LISTLANG_COLUMNS` languages to a row. The table doesn't need to fill the
+/// last row; it's printed with the languages there are.
+///
+fn listlang_rows(langs: &[(&str, &str)]) -> Vec<String> {
+    langs.chunks(LISTLANG_COLUMNS)
+         .map(|row| {
+             row.iter()
+                .map(|(name, code)| fm!("{:-20}{:5}", name, code))
+                .collect::<Vec<_>>()
+                .join("   ")
+                .trim_end()
+                .to_string()
+         })
+         .collect()
+}
+
+/// Implements the /LISTLANG command - prints out a list of all languages 
+/// that the translation web services support.
+///
+fn on_cmd_listlang(hc        : &Hexchat, 
+                   word      : &[String], 
+                   _word_eol : &[String], 
+                   user_data : &UserData
+                  ) -> Eat 
+{
+    let state = user_data.apply(|state: &State| state.clone());
+
+    if word.len() == 1 {
+        hc.print("");
+        hc.print(&fm!("{ORIG_COLOR}\
+                  ------------------------ Supported Languages \
+                  ------------------------"));
+        for row in listlang_rows(&SUPPORTED_LANGUAGES) {
+            hc.print(&fm!("{ORIG_COLOR}{}", row));
+        }
+        let variants = REGIONAL_VARIANTS.iter()
+                                        .map(|(name, code)| {
+                                            fm!("{} ({})", name, code)
+                                        })
+                                        .collect::<Vec<_>>();
+        hc.print(&fm!("{ORIG_COLOR}Regional variants: {}", 
+                      variants.join(", ")));
+        if let Some(synced) = get_synced_langs(&state) {
+            let missing = SUPPORTED_LANGUAGES.iter()
+                                             .chain(&REGIONAL_VARIANTS)
+                                             .filter(|(name, code)| {
+                                                 !name.is_empty() 
+                                                 && !synced.contains(*code)
+                                             })
+                                             .map(|(name, _)| *name)
+                                             .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                hc.print(&fm!("{ERROR_COLOR}Not supported by {}: {}", 
+                              get_provider(&state), missing.join(", ")));
+            }
+        }
+        hc.print("");
+    } else {
+        hc.print("USAGE: ");
+    }
+    Eat::All
+}
+
+/// Finds and gives back a tuple (<long-name>, <abbrev>) from the supported 
+/// languages list. This can be used to verify the languages the user requested
+/// to see if they exist and can be used to interact with translation services.
+/// # Arguments
+/// * `lang` - This can be the name of the langauge, or the two character code
+///            for the language. Case is ignored, as is any punctuation or 
+///            whitespace surrounding it, like in "EN," or " Fr.". If it 
+///            doesn't match a name, code, or alias exactly, it can be the 
+///            start of just one language name, like "port" for Portuguese.
+///            Regional variants, like "pt-BR", are matched by their codes.
+/// # Returns
+/// * If a match is found, a tuple is returned from the `SUPPORTED_LANGUAGES`
+///   or `REGIONAL_VARIANTS` array. It will have the long name for the 
+///   language and its code. 
+///
+fn find_lang(lang: &str) -> Option<&(&str, &str)> {
+    let lang = lang.trim_matches(|c: char| !c.is_alphanumeric())
+                   .to_lowercase();
+    if lang.is_empty() {
+        return None;
+    }
+    let lang = LANGUAGE_ALIASES.iter()
+                               .find(|(alias, _)| *alias == lang)
+                               .map_or(lang.clone(), |(_, code)| {
+                                   code.to_lowercase()
+                               });
+    if let Some(lang_info) = lang_index().get(&lang) {
+        return Some(lang_info);
+    }
+    match prefix_matches(&lang)[..] {
+        [lang_info] => Some(lang_info),
+        _ => None,
+    }
+}
+
+/// The entries of `SUPPORTED_LANGUAGES` and `REGIONAL_VARIANTS` keyed by 
+/// lowercase name and code, so `find_lang()` doesn't have to scan the 
+/// tables. Built on first use.
+///
+static LANG_INDEX: OnceLock<HashMap<String, 
+                                    &'static (&'static str, &'static str)>> 
+                   = OnceLock::new();
+
+/// Returns the index of `SUPPORTED_LANGUAGES` used by `find_lang()`, building
+/// it the first time it's needed. Where a key would match more than one 
+/// entry, the earlier one in the table wins.
+///
+fn lang_index() -> &'static HashMap<String, 
+                                    &'static (&'static str, &'static str)> {
+    LANG_INDEX.get_or_init(|| {
+        let mut index = HashMap::new();
+        // The empty entries at the end of the table only pad out the 
+        // /LISTLANG grid, and must never be handed out as a language.
+        for lang_info in SUPPORTED_LANGUAGES.iter()
+                                            .filter(|info| !info.1.is_empty())
+                                            .chain(REGIONAL_VARIANTS.iter()) {
+            index.entry(lang_info.0.to_lowercase()).or_insert(lang_info);
+            index.entry(lang_info.1.to_lowercase()).or_insert(lang_info);
+        }
+        index
+    })
+}
+
+/// The fewest characters a language name prefix can have to be matched by
+/// `find_lang()`.
+///
+const MIN_LANG_PREFIX: usize = 3;
+
+/// Finds the supported languages whose names start with the given prefix.
+/// # Arguments
+/// * `prefix` - The start of a language name. Case and surrounding 
+///              punctuation are ignored.
+/// # Returns
+/// * The matching entries of `SUPPORTED_LANGUAGES`. The list is empty if the
+///   prefix is shorter than `MIN_LANG_PREFIX`.
+///
+fn prefix_matches(prefix: &str) -> Vec<&'static (&'static str, &'static str)> {
+    let prefix = prefix.trim_matches(|c: char| !c.is_alphanumeric())
+                       .to_lowercase();
+    if prefix.chars().count() < MIN_LANG_PREFIX {
+        return vec![];
+    }
+    SUPPORTED_LANGUAGES.iter()
+                       .filter(|info| !info.0.is_empty() 
+                                      && info.0.to_lowercase()
+                                               .starts_with(&prefix))
+                       .collect()
+}
+
+/// Describes why a language couldn't be found when it's the start of more 
+/// than one language name.
+/// # Returns
+/// * A message listing the candidates, or `None` if the language isn't 
+///   ambiguous.
+///
+fn ambiguous_lang_msg(lang: &str) -> Option<String> {
+    let matches = prefix_matches(lang);
+    if matches.len() > 1 {
+        let names = matches.iter().map(|info| info.0)
+                           .collect::<Vec<_>>().join(", ");
+        Some(fm!("'{}' could be any of: {}.", lang, names))
+    } else {
+        None
+    }
+}
+
+/// The largest edit distance between a misspelled language name and a 
+/// supported one for `suggest_lang_msg()` to suggest it.
+///
+const MAX_SUGGEST_DISTANCE: usize = 2;
+
+/// Suggests a language when a name isn't found, but is within a couple of
+/// typos of a supported language's name, like "englsh".
+/// # Returns
+/// * A message naming the closest language, or `None` if the name was found
+///   or isn't close to any.
+///
+fn suggest_lang_msg(lang: &str) -> Option<String> {
+    if lang.eq_ignore_ascii_case(AUTO_LANG.1) || find_lang(lang).is_some() {
+        return None;
+    }
+    let lang = lang.trim_matches(|c: char| !c.is_alphanumeric())
+                   .to_lowercase();
+    let (dist, info) = SUPPORTED_LANGUAGES.iter()
+                           .filter(|info| !info.1.is_empty())
+                           .map(|info| {
+                               (edit_distance(&lang, &info.0.to_lowercase()), 
+                                info)
+                           })
+                           .min_by_key(|(dist, _)| *dist)?;
+    if dist <= MAX_SUGGEST_DISTANCE {
+        Some(fm!("Did you mean '{}' ({})?", info.0, info.1))
+    } else {
+        None
+    }
+}
+
+/// Calculates the Levenshtein distance between two strings: the fewest 
+/// single character insertions, deletions, and substitutions that turn one
+/// into the other.
+///
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b       = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                             diag
+                         } else {
+                             1 + diag.min(above).min(row[j])
+                         };
+            diag = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Translation error. The error object will contain either a mix of translated
+/// and untranslated messages - if some succeeded and some didn't. Or, just
+/// untranslated text accessible from `get_partial_trans()`. The display
+/// of the error will be an accumulated set of each unique error that occurred
+/// during the translation. If the server indicated the user is over their
+/// translation limit, `is_over-limit()` will reflect that. The kind of the
+/// error, from `kind()`, is that of the last sentence that failed, or 
+/// `ErrorKind::Quota` if the limit was reached.
+///
+#[derive(Debug)]
+struct TranslationError {
+    partial_trans : String,
+    error_msg     : String,
+    over_limit    : bool,
+    provider      : Option<Provider>,
+    kind          : ErrorKind,
+}
+
+impl TranslationError {
+    /// Constructs the translation error.
+    /// # Arguments
+    /// * `partial_trans`   - Translated and untranslated portions of the 
+    ///                       original text.
+    /// * `error_msg`       - The aggregate of error messages that occurred
+    ///                       during the translation.
+    /// * `over_limit`      - A bool indicating whether the server responded
+    ///                       with a 403 error.
+    /// * `provider`        - The last translation service tried when a 
+    ///                       chain of them all failed. `None` if there was
+    ///                       only one to try.
+    /// * `kind`            - The kind of error.
+    ///
+    fn new(partial_trans : String, 
+           error_msg     : String, 
+           over_limit    : bool,
+           provider      : Option<Provider>,
+           kind          : ErrorKind
+          ) -> Self 
+    {
+        TranslationError { partial_trans, error_msg, over_limit, provider, 
+                           kind }
+    }
+    
+    /// Returns the parts of translated and untranslated text - in the same
+    /// order as the original text.
+    ///
+    fn get_partial_trans(&self) -> &str {
+        &self.partial_trans
+    }
+    
+    /// Indicates whether the translator server responded with a 403 error
+    /// which means the number of translations per given span of time has 
+    /// been exceeded.
+    ///
+    fn is_over_limit(&self) -> bool {
+        self.over_limit
+    }
+
+    /// Returns the kind of the error.
+    ///
+    fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+/// Formats a translation error to be printed, with the advice for its kind
+/// after it, if there is any.
+///
+fn error_report(err: &TranslationError) -> String {
+    match err.kind().hint() {
+        Some(hint) => fm!("{ERROR_COLOR}{} {}", err, hint),
+        None       => fm!("{ERROR_COLOR}{}", err),
+    }
+}
+
+impl Error for TranslationError {
+    /*
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        if let Some(err) = &self.source_error {
+            Some(err.as_ref())
+        } else { None }
+    }
+    */
+}
+
+impl fmt::Display for TranslationError {
+
+    /// Displays the aggregate of error messages that occurred during the 
+    /// translation, and the last service tried if there was a chain.
+    ///
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Translation Error: {}", self.error_msg)?;
+        if let Some(provider) = &self.provider {
+            write!(f, " (last tried: {})", provider)?;
+        }
+        Ok(())
+    }
+}
+
+
+// Help strings printed when the user requests /HELP on any of the commands 
+// this addon provides.
+
+const LISTLANG_HELP: &str = "/LISTLANG - Lists languages supported and \
+                             their abbrevations. This command takes no \
+                             parameters.";
+                             
+const SETLANG_HELP : &str = "/SETLANG <src> <tgt> [both|in|out] - Sets \
+                             source and target languages for the channel. \
+                             <src> can be 'auto' to detect the language you \
+                             write in. The direction, like /TRANSDIR, sets \
+                             whether incoming messages, outgoing messages, \
+                             or both are translated.";
+                             
+const SETNETLANG_HELP : &str = "/SETNETLANG [<src> <tgt>|off] - Sets the \
+                                source and target languages for every \
+                                channel of the network that doesn't have its \
+                                own set with /SETLANG. 'off' removes them, \
+                                and without arguments they're printed.";
+
+const OFFLANG_HELP : &str = "/OFFLANG [ALL] - Deactivates translation on \
+                             the channel, or on every channel if ALL is \
+                             given.";
                              
 const LSAY_HELP    : &str = "/LSAY <message> - Sends a translated message \
                              to the channel.";
@@ -725,44 +8685,1638 @@ const LSAY_HELP    : &str = "/LSAY <message> - Sends a translated message \
 const LME_HELP     : &str = "/LME <message> - Sends a channel action \
                              message translated.";
 
-/// A listing of all the supported langauges.
+const TRANSPROVIDER_HELP: &str = "/TRANSPROVIDER [add] [google|deepl|\
+                                  libretranslate <url>] - Sets the \
+                                  translation service to Google's free \
+                                  endpoint, the default, DeepL, or the \
+                                  LibreTranslate server at <url>. With add, \
+                                  the service is tried when the ones before \
+                                  it fail.";
+
+const TRANSWEIGHT_HELP: &str = "/TRANSWEIGHT [<service> <weight>|off] - Sets \
+                                the weight of a service in the chain. The \
+                                first service tried for each request is \
+                                picked at random by weight, and ones over \
+                                the limit are picked less for a while. off \
+                                tries the chain in order again.";
+
+const TRANSPIVOT_HELP: &str = "/TRANSPIVOT on|off - When on, text a service \
+                               can't translate between two languages is \
+                               translated to English and then from \
+                               English. It takes two requests. Off by \
+                               default.";
+
+const TRANSLANGSYNC_HELP: &str = "/TRANSLANGSYNC - Asks the translation \
+                                  service which languages it supports, so \
+                                  /LISTLANG and /SETLANG can point out the \
+                                  ones it doesn't have. Google can't be \
+                                  asked.";
+
+const TRANSKEY_HELP: &str = "/TRANSKEY deepl <key>|off - Sets the DeepL API \
+                             key, or clears it. The key is only kept until \
+                             the addon is unloaded.";
+
+const TRANSREQ_HELP: &str = "/TRANSREQ <src-lang> <tgt-lang> <text> - Prints \
+                             the requests that would be sent to the \
+                             translation server for the text, without \
+                             sending them.";
+
+const TRANSMUTE_HELP: &str = "/TRANSMUTE add|del <nick>, /TRANSMUTE list - \
+                              Stops or resumes translating a nick's \
+                              messages in the channel, or lists the muted \
+                              nicks. Nicks on Hexchat's ignore list are \
+                              never translated.";
+
+const TRANSWATCH_HELP: &str = "/TRANSWATCH add|remove <nick>, /TRANSWATCH \
+                               clear|list - Adds or removes a nick from the \
+                               ones whose messages are the only ones \
+                               translated in the channel, or clears or lists \
+                               them. With none, everyone's messages are \
+                               translated.";
+
+const TRANSCMDSIGIL_HELP: &str = "/TRANSCMDSIGIL [<chars>|none|default] - \
+                                  Sets the characters that start bot \
+                                  commands, like '!weather', which aren't \
+                                  translated. Defaults to '!'.";
+
+const TRANSBLOCK_HELP: &str = "/TRANSBLOCK add|del <word>, /TRANSBLOCK list \
+                               - Blocks or unblocks a word in outgoing \
+                               translations, or lists the blocked words. A \
+                               translation with a blocked word in it is put \
+                               in the input box instead of being sent.";
+
+const TRANSSTATS_HELP: &str = "/TRANSSTATS [export <path>] - Prints how many \
+                               sentences have been translated since \
+                               loading, how many failed, and how many \
+                               characters were sent, for each pair of \
+                               languages, and the rate limit. With export, \
+                               writes the counts to <path> as CSV.";
+
+const TRANSCACHE_HELP: &str = "/TRANSCACHE clear|clearhere - Empties the \
+                               cache of recent translations, or removes \
+                               only the ones between the channel's \
+                               languages.";
+
+const TRANSHISTORY_HELP: &str = "/TRANSHISTORY on|off|<n> - Turns recording \
+                                 of incoming translations on or off, or \
+                                 prints the last <n> original and \
+                                 translated messages of the channel.";
+
+const TRANSCTL_HELP: &str = "/TRANSCTL [on [<port>]|off] - Starts or stops \
+                             a control socket on 127.0.0.1 that scripts can \
+                             send JSON setlang and offlang commands to, with \
+                             the token it prints. Off by default.";
+
+const TRANSEXPORT_HELP: &str = "/TRANSEXPORT <path> - Writes the languages \
+                                of the channels translation is on in, and \
+                                the network defaults, to a JSON file.";
+
+const TRANSIMPORT_HELP: &str = "/TRANSIMPORT <path> - Merges the channel and \
+                                network languages of a file written by \
+                                /TRANSEXPORT into the current settings.";
+
+const TRANSDETECT_HELP: &str = "/TRANSDETECT <text> - Detects and prints \
+                                the language of the text without \
+                                translating it.";
+
+const TRANSTEST_HELP: &str = "/TRANSTEST - Translates 'hello' from English to \
+                              Spanish with Google's server and prints \
+                              whether it worked and how many milliseconds \
+                              it took.";
+
+const TRANSPROXY_HELP: &str = "/TRANSPROXY [<url>|off] - Sends requests to \
+                               the translation server through the HTTP or \
+                               SOCKS5 proxy at the URL, like \
+                               http://proxy:8080 or socks5://127.0.0.1:1080. \
+                               'off' sends them directly, and without \
+                               arguments the proxy is printed.";
+
+const TRANSSYSTEM_HELP: &str = "/TRANSSYSTEM on|off - Turns translation of \
+                                the reason given in part, quit, and kick \
+                                messages on or off. Off by default.";
+
+const LSAYBLOCK_HELP: &str = "/LSAYBLOCK <text> - Sends a multi-line block to \
+                              the channel, translating the lines with words \
+                              and passing lines of ASCII art or symbols \
+                              through as they are. Lines of prose are \
+                              translated together as paragraphs.";
+
+const TRANSWELCOME_HELP: &str = "/TRANSWELCOME <nick>|set <text> - Sends \
+                                 the welcome message, translated into the \
+                                 channel's language, to <nick> privately. \
+                                 'set' stores the welcome message.";
+
+const LTRANS_HELP: &str = "/LTRANS <src> <tgt> <message> - Translates one \
+                           message and sends it to the channel, without \
+                           turning on translation for the channel.";
+
+const LTOPIC_HELP: &str = "/LTOPIC <topic> - Translates the topic and sets it \
+                           for the channel.";
+
+const LEDIT_HELP: &str = "/LEDIT <message> - Translates the message and puts \
+                          the translation in the input box to edit before \
+                          sending.";
+
+const LPREVIEW_HELP: &str = "/LPREVIEW <message> - Translates the message like \
+                             /LSAY, and prints the translation and the \
+                             original without sending anything.";
+
+const LTRANSLAST_HELP: &str = "/LTRANSLAST [<language>] - Translates the last \
+                               message received in the channel to the \
+                               language, or to your display language or \
+                               yours if translation is on in the channel, \
+                               and prints it. Translation \
+                               doesn't need to be on.";
+
+const LDEF_HELP: &str = "/LDEF <word> - Looks up the word in your language \
+                         and prints its translations into the channel's \
+                         language, for each part of speech. Translation \
+                         must be on in the channel.";
+
+const TRANSRETRY_HELP: &str = "/TRANSRETRY - Sends the message held back by \
+                               /LSAY or /LME before translation was turned \
+                               on for the channel.";
+
+const TRANSUNDO_HELP: &str = "/TRANSUNDO - Resends the original of the last \
+                              message translated by /LSAY or /LME in the \
+                              channel, untranslated and starting with \
+                              \"* correction:\". Using it again resends the \
+                              one before it, up to 5 back.";
+
+const TRANSAUTOPROMPT_HELP: &str = "/TRANSAUTOPROMPT on|off - When on, /LSAY \
+                                    in a channel without translation prompts \
+                                    for languages and holds the message for \
+                                    /TRANSRETRY. Off by default.";
+
+const TRANSCOOLDOWN_HELP: &str = "/TRANSCOOLDOWN [<seconds>|off] - Pauses \
+                                  translation in a channel for <seconds> \
+                                  when the server's limit is reached, then \
+                                  resumes it. Defaults to 60. With off, \
+                                  translation is turned off instead.";
+
+const TRANSPAUSE_HELP: &str = "/TRANSPAUSE - Pauses translation in all \
+                               channels without turning it off. Messages are \
+                               shown and sent untranslated until \
+                               /TRANSRESUME.";
+
+const TRANSRESUME_HELP: &str = "/TRANSRESUME - Resumes translation paused \
+                                with /TRANSPAUSE.";
+
+const TRANSAUTOOFF_HELP: &str = "/TRANSAUTOOFF <count> <seconds> - Turns \
+                                 translation off in a channel after <count> \
+                                 errors in a row within <seconds>. A count of \
+                                 0 turns this off, which is the default.";
+
+const TRANSFORMAT_HELP: &str = "/TRANSFORMAT on|off - When on, colors, bold, \
+                                and other formatting at the start and end of \
+                                a message are kept around its translation. \
+                                Off by default.";
+
+const TRANSSKIPSAME_HELP: &str = "/TRANSSKIPSAME on|off - When on, the \
+                                  language of each incoming message is \
+                                  detected first, and messages already in \
+                                  your language aren't translated. Detecting \
+                                  takes an extra request per message. Off by \
+                                  default.";
+
+const TRANSMENICK_HELP: &str = "/TRANSMENICK on|off - When on, /LME actions \
+                                are translated with your nick in front of \
+                                them, so verbs are conjugated for you as the \
+                                subject. It can make translation into some \
+                                languages worse. Off by default.";
+
+const TRANSCOLOR_HELP: &str = "/TRANSCOLOR [orig|error <color>] - Sets the \
+                               mIRC color, 0 to 15, of the originals of \
+                               translated messages, or of the translator's \
+                               notices and errors. Without arguments, the \
+                               colors are printed. Defaults to 11 (cyan) and \
+                               13 (magenta).";
+
+const TRANSCONFIDENCE_HELP: &str = "/TRANSCONFIDENCE [on|off|<percent>] - \
+                                    Flags the language detected for your \
+                                    messages with an 'auto' source as low \
+                                    confidence when the server's confidence \
+                                    is below <percent>, 50 with 'on'. Off by \
+                                    default.";
+
+const TRANSAWAYPAUSE_HELP: &str = "/TRANSAWAYPAUSE on|off - When on, /LSAY \
+                                   and /LME send messages untranslated \
+                                   while you're marked away. Incoming \
+                                   messages are still translated. Off by \
+                                   default.";
+
+const TRANSVERIFY_HELP: &str = "/TRANSVERIFY on|off - When on, each message \
+                                translated by /LSAY or /LME is translated \
+                                back to your language and shown below it, \
+                                so you can check the translation. It takes \
+                                an extra request per message. Off by \
+                                default.";
+
+const TRANSORIG_HELP: &str = "/TRANSORIG on|off - Sets whether the original \
+                              of each translated message is shown below the \
+                              translation in the channel. On by default.";
+
+const TRANSINLINE_HELP: &str = "/TRANSINLINE replace|separate - Sets whether \
+                                incoming translations are shown in place of \
+                                the original message, or with the original \
+                                on its own line. Defaults to separate.";
+
+const TRANSRETRIES_HELP: &str = "/TRANSRETRIES [<count>] - Sets how many \
+                                 times a translation request that failed \
+                                 with a network or server error is retried, \
+                                 from 0 to 6. Defaults to 3.";
+
+const TRANSMAXCHARS_HELP: &str = "/TRANSMAXCHARS [<count>] - Sets the most \
+                                  characters a text can have to be \
+                                  translated, up to 20000. Longer ones are \
+                                  refused, and /LSAY doesn't send them. \
+                                  Defaults to 2000.";
+
+const TRANSRATE_HELP: &str = "/TRANSRATE [<per-minute>|off] - Sets how many \
+                              requests a minute are sent to Google. Requests \
+                              over the limit fail with an error. Defaults to \
+                              60.";
+
+const TRANSERRRATE_HELP: &str = "/TRANSERRRATE once|always - Sets whether \
+                                 over-limit errors are shown for every \
+                                 message, or only once a minute per channel. \
+                                 Defaults to always.";
+
+const TRANSEXPANDWARN_HELP: &str = "/TRANSEXPANDWARN [<ratio>|off] - Holds \
+                                    a translation that's more than <ratio> \
+                                    times longer than the original, or over \
+                                    400 bytes, in the input box instead of \
+                                    sending it. Off by default.";
+
+const SETLANGLIST_HELP: &str = "/SETLANGLIST <lang1,lang2,...>|off - Has \
+                                outgoing messages in the channel take turns \
+                                being translated to each of the languages. \
+                                'off' goes back to the /SETLANG target.";
+
+const SETDISPLAYLANG_HELP: &str = "/SETDISPLAYLANG [<lang>|off] - Sets the \
+                                   language incoming messages, reasons, and \
+                                   topics are translated to in the channel, \
+                                   if it isn't the one you type in. 'off' \
+                                   goes back to that one.";
+
+const SETUSERLANG_HELP: &str = "/SETUSERLANG <nick> <lang>|off, /SETUSERLANG \
+                                list|clear - Sets the language a nick's \
+                                messages are translated from in the \
+                                channel, instead of the channel's language. \
+                                'off' removes it, 'list' lists them, and \
+                                'clear' removes them all.";
+
+const SETLANGRELAY_HELP: &str = "/SETLANGRELAY <from-lang> <to-lang>|off - \
+                                 Translates incoming messages in the channel \
+                                 from one language to another, for relaying \
+                                 between two groups. 'off' goes back to \
+                                 normal translation.";
+
+const TRANSDIR_HELP: &str = "/TRANSDIR [both|in|out] - Sets whether \
+                             incoming messages, outgoing messages, or both \
+                             are translated in the channel. Prints the \
+                             current setting if no option is given.";
+
+/// Checks a table of languages for data entry mistakes: duplicate names or
+/// codes, and names without codes or codes without names. Entries with both
+/// empty are padding and are skipped. Aliases are checked to make sure they
+/// refer to a language in the table or one of its regional variants.
+/// # Arguments
+/// * `table`    - The (name, code) pairs to check.
+/// * `aliases`  - The (alias, code) pairs to check, like `LANGUAGE_ALIASES`.
+/// * `variants` - The (name, code) pairs of the regional variants, like 
+///                `REGIONAL_VARIANTS`.
+/// # Returns
+/// * A description of each problem found. The list is empty if there are 
+///   none.
+///
+fn validate_language_table(table    : &[(&str, &str)],
+                           aliases  : &[(&str, &str)],
+                           variants : &[(&str, &str)]
+                          ) -> Vec<String> 
+{
+    let mut problems = vec![];
+    let mut names    = HashSet::new();
+    let mut codes    = HashSet::new();
+
+    for &(name, code) in table {
+        match (name.is_empty(), code.is_empty()) {
+            (true, true)  => continue,
+            (true, false) => {
+                problems.push(fm!("code '{}' has no name.", code));
+            },
+            (false, true) => {
+                problems.push(fm!("'{}' has no code.", name));
+            },
+            (false, false) => {},
+        }
+        if !name.is_empty() && !names.insert(name.to_lowercase()) {
+            problems.push(fm!("the name '{}' is listed more than once.", 
+                              name));
+        }
+        if !code.is_empty() && !codes.insert(code) {
+            problems.push(fm!("the code '{}' is listed more than once.", 
+                              code));
+        }
+    }
+    let variants = variants.iter().map(|v| v.1).collect::<Vec<_>>();
+    for (alias, code) in aliases {
+        if !codes.contains(code) && !variants.contains(code) {
+            problems.push(fm!("the alias '{}' refers to the unknown code \
+                               '{}'.", alias, code));
+        }
+        if codes.contains(alias) {
+            problems.push(fm!("the alias '{}' is also a language's code.", 
+                              alias));
+        }
+    }
+    problems
+}
+
+/// Other names `find_lang()` accepts for a language, in lowercase, paired
+/// with the language's code. "azeerbaijani" is the old misspelling from
+/// `SUPPORTED_LANGUAGES` that may still be in users' scripts, and "chinese"
+/// and "zh" were Chinese's name and code before it was split into 
+/// Simplified and Traditional. The rest are country codes people use for 
+/// languages, older codes, and ISO 639-2 codes.
+///
+const LANGUAGE_ALIASES: [(&str, &str); 29] = [
+    ("azeerbaijani", "az"), ("chinese", "zh-CN"), ("zh", "zh-CN"),
+    ("jp",  "ja"), ("cn",  "zh-CN"), ("gr",  "el"), ("kr",  "ko"), 
+    ("ua",  "uk"), ("dk",  "da"), ("cz",  "cs"), ("iw",  "he"), 
+    ("jv",  "jw"), ("in",  "id"), ("eng", "en"), ("spa", "es"), 
+    ("fra", "fr"), ("fre", "fr"), ("deu", "de"), ("ger", "de"), 
+    ("ita", "it"), ("por", "pt"), ("rus", "ru"), ("jpn", "ja"), 
+    ("kor", "ko"), ("zho", "zh-CN"), ("chi", "zh-CN"), ("ara", "ar"),
+    ("tw",  "zh-TW"), ("br",  "pt-BR"),
+];
+
+/// Regional variants of languages in `SUPPORTED_LANGUAGES` that Google 
+/// translates differently. `find_lang()` accepts them by name or code, and 
+/// the codes are passed to the translation server as they are.
+///
+const REGIONAL_VARIANTS: [(&str, &str); 2] = [
+    ("Portuguese_Brazil", "pt-BR"), ("Portuguese_Portugal", "pt-PT"),
+];
 
+/// A listing of all the supported langauges.
+///
 const SUPPORTED_LANGUAGES: [(&str, &str); 105] = [
     
-    ("Afrikaans",      "af"), ("Hmong",        "hmn"), ("Polish",       "pl"),
-    ("Albanian",       "sq"), ("Hungarian",     "hu"), ("Portuguese",   "pt"),
-    ("Amharic",        "am"), ("Icelandic",     "is"), ("Punjabi",      "pa"),
-    ("Arabic",         "ar"), ("Igbo",          "ig"), ("Romanian",     "ro"),
-    ("Armenian",       "hy"), ("Indonesian",    "id"), ("Russian",      "ru"),
-    ("Azeerbaijani",   "az"), ("Irish",         "ga"), ("Samoan",       "sm"),
-    ("Basque",         "eu"), ("Italian",       "it"), ("Scots_Gaelic", "gd"),
-    ("Belarusian",     "be"), ("Japanese",      "ja"), ("Serbian",      "sr"),
-    ("Bengali",        "bn"), ("Javanese",      "jw"), ("Sesotho",      "st"),
-    ("Bosnian",        "bs"), ("Kannada",       "kn"), ("Shona",        "sn"),
-    ("Bulgarian",      "bg"), ("Kazakh",        "kk"), ("Sindhi",       "sd"),
-    ("Catalan",        "ca"), ("Khmer",         "km"), ("Sinhala",      "si"),
-    ("Cebuano",       "ceb"), ("Korean",        "ko"), ("Slovak",       "sk"),
-    ("Chinese",        "zh"), ("Kurdish",       "ku"), ("Slovenian",    "sl"),
-    ("Corsican",       "co"), ("Kyrgyz",        "ky"), ("Somali",       "so"),
-    ("Croatian",       "hr"), ("Lao",           "lo"), ("Spanish",      "es"),
-    ("Czech",          "cs"), ("Latin",         "la"), ("Sundanese",    "su"),
-    ("Danish",         "da"), ("Latvian",       "lv"), ("Swahili",      "sw"),
-    ("Dutch",          "nl"), ("Lithuanian",    "lt"), ("Swedish",      "sv"),
-    ("English",        "en"), ("Luxembourgish", "lb"), ("Tagalog",      "tl"),
-    ("Esperanto",      "eo"), ("Macedonian",    "mk"), ("Tajik",        "tg"),
-    ("Estonian",       "et"), ("Malagasy",      "mg"), ("Tamil",        "ta"),
-    ("Finnish",        "fi"), ("Malay",         "ms"), ("Telugu",       "te"),
-    ("French",         "fr"), ("Malayalam",     "ml"), ("Thai",         "th"),
-    ("Frisian",        "fy"), ("Maltese",       "mt"), ("Turkish",      "tr"),
-    ("Galician",       "gl"), ("Maori",         "mi"), ("Ukrainian",    "uk"),
-    ("Georgian",       "ka"), ("Marathi",       "mr"), ("Urdu",         "ur"),
-    ("German",         "de"), ("Mongolian",     "mn"), ("Uzbek",        "uz"),
-    ("Greek",          "el"), ("Myanmar",       "my"), ("Vietnamese",   "vi"),
-    ("Gujarati",       "gu"), ("Nepali",        "ne"), ("Welsh",        "cy"),
-    ("Haitian_Creole", "ht"), ("Norwegian",     "no"), ("Xhosa",        "xh"),
-    ("Hausa",          "ha"), ("Nyanja",        "ny"), ("Yiddish",      "yi"),
-    ("Hawaiian",      "haw"), ("Pashto",        "ps"), ("Yoruba",       "yo"),
-    ("Hebrew",         "he"), ("Persian",       "fa"), ("Zulu",         "zu"),
-    ("Hindi",          "hi"), ("",              ""  ), ("",             ""  )];		
+    ("Afrikaans",      "af"), ("Hindi",         "hi"), ("Polish",       "pl"),
+    ("Albanian",       "sq"), ("Hmong",        "hmn"), ("Portuguese",   "pt"),
+    ("Amharic",        "am"), ("Hungarian",     "hu"), ("Punjabi",      "pa"),
+    ("Arabic",         "ar"), ("Icelandic",     "is"), ("Romanian",     "ro"),
+    ("Armenian",       "hy"), ("Igbo",          "ig"), ("Russian",      "ru"),
+    ("Azerbaijani",    "az"), ("Indonesian",    "id"), ("Samoan",       "sm"),
+    ("Basque",         "eu"), ("Irish",         "ga"), ("Scots_Gaelic", "gd"),
+    ("Belarusian",     "be"), ("Italian",       "it"), ("Serbian",      "sr"),
+    ("Bengali",        "bn"), ("Japanese",      "ja"), ("Sesotho",      "st"),
+    ("Bosnian",        "bs"), ("Javanese",      "jw"), ("Shona",        "sn"),
+    ("Bulgarian",      "bg"), ("Kannada",       "kn"), ("Sindhi",       "sd"),
+    ("Catalan",        "ca"), ("Kazakh",        "kk"), ("Sinhala",      "si"),
+    ("Cebuano",       "ceb"), ("Khmer",         "km"), ("Slovak",       "sk"),
+    ("Chinese_Simplified",
+                    "zh-CN"), ("Korean",        "ko"), ("Slovenian",    "sl"),
+    ("Chinese_Traditional",
+                    "zh-TW"), ("Kurdish",       "ku"), ("Somali",       "so"),
+    ("Corsican",       "co"), ("Kyrgyz",        "ky"), ("Spanish",      "es"),
+    ("Croatian",       "hr"), ("Lao",           "lo"), ("Sundanese",    "su"),
+    ("Czech",          "cs"), ("Latin",         "la"), ("Swahili",      "sw"),
+    ("Danish",         "da"), ("Latvian",       "lv"), ("Swedish",      "sv"),
+    ("Dutch",          "nl"), ("Lithuanian",    "lt"), ("Tagalog",      "tl"),
+    ("English",        "en"), ("Luxembourgish", "lb"), ("Tajik",        "tg"),
+    ("Esperanto",      "eo"), ("Macedonian",    "mk"), ("Tamil",        "ta"),
+    ("Estonian",       "et"), ("Malagasy",      "mg"), ("Telugu",       "te"),
+    ("Finnish",        "fi"), ("Malay",         "ms"), ("Thai",         "th"),
+    ("French",         "fr"), ("Malayalam",     "ml"), ("Turkish",      "tr"),
+    ("Frisian",        "fy"), ("Maltese",       "mt"), ("Ukrainian",    "uk"),
+    ("Galician",       "gl"), ("Maori",         "mi"), ("Urdu",         "ur"),
+    ("Georgian",       "ka"), ("Marathi",       "mr"), ("Uzbek",        "uz"),
+    ("German",         "de"), ("Mongolian",     "mn"), ("Vietnamese",   "vi"),
+    ("Greek",          "el"), ("Myanmar",       "my"), ("Welsh",        "cy"),
+    ("Gujarati",       "gu"), ("Nepali",        "ne"), ("Xhosa",        "xh"),
+    ("Haitian_Creole", "ht"), ("Norwegian",     "no"), ("Yiddish",      "yi"),
+    ("Hausa",          "ha"), ("Nyanja",        "ny"), ("Yoruba",       "yo"),
+    ("Hawaiian",      "haw"), ("Pashto",        "ps"), ("Zulu",         "zu"),
+    ("Hebrew",         "he"), ("Persian",       "fa"), ("",             ""  )];
 
     
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(ws: &[&str]) -> Vec<String> {
+        ws.iter().map(|w| w.to_string()).collect()
+    }
+
+    /// Builds an addon state whose cache holds the translations of `pairs`
+    /// from English to Spanish, standing in for the translation service.
+    /// Texts that aren't cached would be sent to it.
+    fn cached_state(pairs: &[(&str, &str)]) -> State {
+        let state = State::default();
+        for (text, trans) in pairs {
+            state.lock().unwrap().cache.put(("en".into(), "es".into(), 
+                                             text.to_string()),
+                                            trans.to_string());
+        }
+        state
+    }
+
+    #[test]
+    fn message_ending_in_tilde_is_translated() {
+        let word = words(&["bob", "see you ~"]);
+        assert!(!is_passthrough_event(&word, 1));
+        let word = words(&["bob", "hola", "@", "~"]);
+        assert!(!is_passthrough_event(&word, 1));
+    }
+
+    #[test]
+    fn own_emits_are_passed_through() {
+        let word = words(&["bob", "see you ~"]);
+        assert!(with_emitting(|| is_passthrough_event(&word, 1)));
+        assert!(!is_passthrough_event(&word, 1));
+        assert!(is_passthrough_event(&words(&["bob"]), 1));
+    }
+
+    #[test]
+    fn own_system_emits_are_passed_through() {
+        let part = words(&["bob", "bob@host", "#chan", "hasta luego"]);
+        let idx  = reason_index("Part with Reason").unwrap();
+        assert!(!is_passthrough_event(&part, idx));
+        assert!(with_emitting(|| is_passthrough_event(&part, idx)));
+
+        let quit = words(&["bob", "me voy", "host"]);
+        let idx  = reason_index("Quit").unwrap();
+        assert!(!is_passthrough_event(&quit, idx));
+        assert!(with_emitting(|| is_passthrough_event(&quit, idx)));
+
+        assert!(reason_index("Join").is_none());
+    }
+
+    #[test]
+    fn only_the_reason_is_translated() {
+        let part = words(&["bob", "bob@host", "#chan", "hasta luego"]);
+        let idx  = reason_index("Part with Reason").unwrap();
+        assert_eq!(reason_words(&part, idx, "see you later"),
+                   words(&["bob", "bob@host", "#chan", "see you later"]));
+
+        let quit = words(&["bob", "me voy", "host"]);
+        let idx  = reason_index("Quit").unwrap();
+        assert_eq!(reason_words(&quit, idx, "I'm leaving"),
+                   words(&["bob", "I'm leaving", "host"]));
+    }
+
+    #[test]
+    fn own_kick_emits_are_passed_through() {
+        let kick = words(&["alice", "bob", "#chan", "fuera de aquí"]);
+        for event in ["Kick", "You Kicked"] {
+            let idx = reason_index(event).unwrap();
+            assert!(!is_passthrough_event(&kick, idx));
+            assert!(with_emitting(|| is_passthrough_event(&kick, idx)));
+            assert_eq!(reason_words(&kick, idx, "get out of here"),
+                       words(&["alice", "bob", "#chan", "get out of here"]));
+        }
+    }
+
+    #[test]
+    fn reasons_are_within_emitted_words() {
+        // Hexchat passes at most four words of an emitted event on.
+        for (_, idx) in SYSTEM_EVENTS.iter().chain(&TOPIC_EVENTS) {
+            assert!(*idx < 4);
+        }
+    }
+
+    #[test]
+    fn forget_context_drops_every_setting() {
+        let key   = chan_key("net", "#Chan");
+        let other = chan_key("net", "#other");
+        let mut state = AddonState::default();
+        for k in [&key, &other] {
+            state.directions.insert(k.clone(), Direction::default());
+            state.relays.insert(k.clone(), ("es".into(), "fr".into()));
+            state.lang_lists.insert(k.clone(), (vec!["es".into()], 0));
+            state.detected_langs.insert(k.clone(), "es".into());
+            state.mutes.insert(k.clone(), HashSet::from(["bob".into()]));
+            state.watches.insert(k.clone(), HashSet::from(["al".into()]));
+            state.user_langs.insert(k.clone(), HashMap::new());
+            state.display_langs.insert(k.clone(), "fr".into());
+            state.hidden_origs.insert(k.clone());
+            state.over_limit_shown.insert(k.clone(), Instant::now());
+            state.error_runs.insert(k.clone(), (1, Instant::now()));
+            state.paused.insert(k.clone(), Instant::now());
+            state.history.insert(k.clone(), VecDeque::new());
+            state.sent_origs.insert(k.clone(), VecDeque::new());
+        }
+        state.forget_context(&key);
+
+        assert!(!state.directions.contains_key(&key));
+        assert!(!state.relays.contains_key(&key));
+        assert!(!state.lang_lists.contains_key(&key));
+        assert!(!state.detected_langs.contains_key(&key));
+        assert!(!state.mutes.contains_key(&key));
+        assert!(!state.watches.contains_key(&key));
+        assert!(!state.user_langs.contains_key(&key));
+        assert!(!state.display_langs.contains_key(&key));
+        assert!(!state.hidden_origs.contains(&key));
+        assert!(!state.over_limit_shown.contains_key(&key));
+        assert!(!state.error_runs.contains_key(&key));
+        assert!(!state.paused.contains_key(&key));
+        assert!(!state.history.contains_key(&key));
+        assert!(!state.sent_origs.contains_key(&key));
+
+        assert!(state.mutes.contains_key(&other));
+        assert!(state.history.contains_key(&other));
+    }
+
+    #[test]
+    fn suspended_channels_are_resumed() {
+        let langs = ("en".to_string(), "es".to_string());
+        let chan  = chan_key("net", "#chan");
+        let other = chan_key("other", "#chan");
+        let mut chan_map  = ChanMap::new();
+        let mut suspended = HashMap::new();
+        chan_map.insert(chan.clone(), langs.clone());
+        chan_map.insert(other.clone(), langs.clone());
+
+        assert!(suspend_context(&mut chan_map, &mut suspended, &chan));
+        assert!(!suspend_context(&mut chan_map, &mut suspended, &chan));
+        assert!(!chan_map.contains_key(&chan));
+        assert!(chan_map.contains_key(&other));
+
+        assert!(resume_context(&mut chan_map, &mut suspended, &chan));
+        assert_eq!(chan_map.get(&chan), Some(&langs));
+        assert!(suspended.is_empty());
+        assert!(!resume_context(&mut chan_map, &mut suspended, &chan));
+    }
+
+    #[test]
+    fn resuming_keeps_newer_languages() {
+        let newer = ("en".to_string(), "fr".to_string());
+        let chan  = chan_key("net", "#chan");
+        let mut chan_map  = ChanMap::new();
+        let mut suspended = HashMap::new();
+        chan_map.insert(chan.clone(), ("en".into(), "es".into()));
+        suspend_context(&mut chan_map, &mut suspended, &chan);
+        chan_map.insert(chan.clone(), newer.clone());
+
+        assert!(!resume_context(&mut chan_map, &mut suspended, &chan));
+        assert_eq!(chan_map.get(&chan), Some(&newer));
+        assert!(suspended.is_empty());
+    }
+
+    #[test]
+    fn only_channels_are_suspended() {
+        assert!(is_channel_name("#rust"));
+        assert!(is_channel_name("&local"));
+        assert!(!is_channel_name("bob"));
+        assert!(!is_channel_name(""));
+    }
+
+    #[test]
+    fn emit_words_replaces_only_the_message() {
+        let word = words(&["#chan", "bob", "hola", "x", "y"]);
+        assert_eq!(emit_words(&word, 2, "hello"), 
+                   words(&["#chan", "bob", "hello", "x"]));
+    }
+
+    #[test]
+    fn listlang_rows_with_partial_last_row() {
+        let langs = [("One", "a"), ("Two", "b"), ("Three", "c"), 
+                     ("Four", "d"), ("Five", "e"), ("Six", "f"), 
+                     ("Seven", "g"), ("Eight", "h")];
+        let rows = listlang_rows(&langs[..7]);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[2], fm!("{:-20}{}", "Seven", "g"));
+
+        let rows = listlang_rows(&langs);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[2], fm!("{:-20}{:5}   {:-20}{}", 
+                                "Seven", "g", "Eight", "h"));
+        assert!(rows[0].contains("Three"));
+    }
+
+    #[test]
+    fn listlang_rows_of_the_language_table() {
+        let rows = listlang_rows(&SUPPORTED_LANGUAGES);
+        assert_eq!(rows.len(), SUPPORTED_LANGUAGES.len().div_ceil(3));
+    }
+
+    #[test]
+    fn decimals_dont_split_sentences() {
+        assert_eq!(split_sentences("pi is 3.14 exactly", "en"), 
+                   vec!["pi is 3.14 exactly"]);
+        assert_eq!(split_sentences("It's 3.14. Or so.", "en"), 
+                   vec!["It's 3.14. ", "Or so."]);
+    }
+
+    #[test]
+    fn abbreviations_dont_split_sentences() {
+        assert_eq!(split_sentences("I live in the U.S. now", "en"), 
+                   vec!["I live in the U.S. now"]);
+        assert_eq!(split_sentences("Ask Dr. Smith. He knows.", "en"), 
+                   vec!["Ask Dr. Smith. ", "He knows."]);
+    }
+
+    #[test]
+    fn ends_with_abbreviation_words() {
+        assert!(ends_with_abbreviation("I live in the U.S. "));
+        assert!(ends_with_abbreviation("see (e.g. "));
+        assert!(ends_with_abbreviation("Hi Mr. "));
+        assert!(!ends_with_abbreviation("pi is 3.14. "));
+        assert!(!ends_with_abbreviation("The end. "));
+        assert!(!ends_with_abbreviation(""));
+    }
+
+    #[test]
+    fn multi_line_text_keeps_its_lines() {
+        let state = cached_state(&[("Hello.", "Hola."), 
+                                   ("How are you?", "¿Cómo estás?"),
+                                   ("Bye.", "Adiós.")]);
+        let text  = "Hello.\nHow are you?\n\nBye.";
+        let trans = translate_text(text, "en", "es", &HashSet::new(), &state);
+        assert_eq!(trans.unwrap(), "Hola.\n¿Cómo estás?\n\nAdiós.");
+    }
+
+    #[test]
+    fn texts_over_the_cap_are_refused() {
+        let state = cached_state(&[("Hello.", "Hola.")]);
+        state.lock().unwrap().max_chars = Some(6);
+
+        let trans = translate_text("Hello.", "en", "es", &HashSet::new(), 
+                                   &state);
+        assert_eq!(trans.unwrap(), "Hola.");
+
+        for text in ["Hello there.", "Hello.\nHello."] {
+            let err = translate_text(text, "en", "es", &HashSet::new(), 
+                                     &state).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TooLong);
+            assert_eq!(err.get_partial_trans(), text);
+            assert!(!err.is_over_limit());
+        }
+    }
+
+    #[test]
+    fn cap_defaults_to_default_max_chars() {
+        let state = State::default();
+        assert_eq!(get_max_chars(&state), DEFAULT_MAX_CHARS);
+        let text  = "a".repeat(DEFAULT_MAX_CHARS + 1);
+        let err   = translate_text(&text, "en", "es", &HashSet::new(), 
+                                   &state).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TooLong);
+    }
+
+    #[test]
+    fn language_table_is_valid() {
+        assert!(validate_language_table(&SUPPORTED_LANGUAGES, 
+                                        &LANGUAGE_ALIASES,
+                                        &REGIONAL_VARIANTS).is_empty());
+    }
+
+    #[test]
+    fn broken_language_tables_are_reported() {
+        let table = [("English", "en"), ("english", "eo"), ("Spanish", "en"),
+                     ("", "fr"), ("German", ""), ("", "")];
+        let problems = validate_language_table(&table, &[], &[]);
+        assert_eq!(problems.len(), 4);
+        assert!(problems.iter().any(|p| p.contains("'english'")));
+        assert!(problems.iter().any(|p| p.contains("code 'en' is listed")));
+        assert!(problems.iter().any(|p| p.contains("'fr' has no name")));
+        assert!(problems.iter().any(|p| p.contains("'German' has no code")));
+    }
+
+    #[test]
+    fn broken_aliases_are_reported() {
+        let table    = [("English", "en"), ("Portuguese", "pt")];
+        let variants = [("Portuguese_Brazil", "pt-BR")];
+        let aliases  = [("eng", "en"), ("br", "pt-BR"), ("xx", "zz"), 
+                        ("en", "pt")];
+        let problems = validate_language_table(&table, &aliases, &variants);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("unknown code 'zz'"));
+        assert!(problems[1].contains("'en' is also a language's code"));
+    }
+
+    #[test]
+    fn orphaned_translations_name_their_context() {
+        let line = orphaned_translation("Libera", "#rust", "hello there");
+        assert!(line.starts_with(&ERROR_COLOR.to_string()));
+        assert!(line.contains("[orphaned translation for Libera/#rust]"));
+        assert!(line.ends_with(" hello there"));
+    }
+
+    #[test]
+    fn whitespace_only_messages_are_not_translated() {
+        for text in ["", "   ", "\t", " \u{3000} "] {
+            assert!(is_blank(text));
+        }
+        assert!(!is_blank(" hi "));
+
+        // Nothing is sent to the translation service for them either.
+        let state = State::default();
+        let trans = translate_text("   ", "en", "es", &HashSet::new(), 
+                                   &state);
+        assert_eq!(trans.unwrap(), "   ");
+        let state = state.lock().unwrap();
+        let stats = &state.stats[&("en".to_string(), "es".to_string())];
+        assert_eq!(stats.translated + stats.errors + stats.over_limit, 0);
+    }
+
+    #[test]
+    fn directions_limit_translation() {
+        let state = State::default();
+        let keys  = ["#both", "#in", "#out", "#unset"].map(|c| {
+                        chan_key("net", c)
+                    });
+        for (key, dir) in keys.iter().zip(["both", "IN", "Out"]) {
+            state.lock().unwrap()
+                 .directions.insert(key.clone(), 
+                                    Direction::parse(dir).unwrap());
+        }
+        let modes = keys.iter().map(|key| {
+                        let dir = key_direction(&state, key);
+                        (dir.incoming(), dir.outgoing())
+                    }).collect::<Vec<_>>();
+        assert_eq!(modes, [(true, true), (true, false), (false, true), 
+                           (true, true)]);
+        assert!(Direction::parse("sideways").is_none());
+    }
+
+    #[test]
+    fn muted_senders_are_skipped() {
+        let mutes = HashSet::from(["bob".to_string(), "spambot".to_string()]);
+        assert!(is_muted(&mutes, "bob"));
+        assert!(is_muted(&mutes, "SpamBot"));
+        assert!(!is_muted(&mutes, "alice"));
+        assert!(!is_muted(&HashSet::new(), "bob"));
+
+        // Brackets fold like letters on IRC.
+        let mutes = HashSet::from([irc_casefold("[Bob]"), 
+                                   irc_casefold("ann~")]);
+        assert!(is_muted(&mutes, "{bob}"));
+        assert!(is_muted(&mutes, "[BOB]"));
+        assert!(is_muted(&mutes, "ANN^"));
+        assert!(!is_muted(&mutes, "bob"));
+    }
+
+    #[test]
+    fn ignore_masks_match_nicks() {
+        assert!(wildcard_match("bob", "bob"));
+        assert!(wildcard_match("spam*", "spambot"));
+        assert!(wildcard_match("b?b", "bob"));
+        assert!(wildcard_match("*", "anyone"));
+        assert!(!wildcard_match("bob", "bobby"));
+        assert!(!wildcard_match("b?b", "bb"));
+    }
+
+    #[test]
+    fn find_lang_ignores_case_and_punctuation() {
+        assert_eq!(find_lang("EN,"), Some(&("English", "en")));
+        assert_eq!(find_lang(" es "), Some(&("Spanish", "es")));
+        assert_eq!(find_lang("Fr."), Some(&("French", "fr")));
+        assert_eq!(find_lang("GERMAN"), Some(&("German", "de")));
+        assert_eq!(find_lang("xx"), None);
+    }
+
+    #[test]
+    fn channel_keys_ignore_case() {
+        assert_eq!(chan_key("net", "#Foo"), chan_key("net", "#foo"));
+        assert_eq!(chan_key("net", "#[Rust]"), chan_key("net", "#{rust}"));
+        assert_eq!(irc_casefold("A\\B~"), "a|b^");
+        assert_ne!(chan_key("Net", "#foo"), chan_key("net", "#foo"));
+
+        let mut chan_map = ChanMap::new();
+        chan_map.insert(chan_key("net", "#foo"), ("en".into(), "es".into()));
+        assert!(chan_map.contains_key(&chan_key("net", "#Foo")));
+    }
+
+    #[test]
+    fn azerbaijani_resolves_by_name_code_and_old_name() {
+        let lang = find_lang("az");
+        assert_eq!(lang, Some(&("Azerbaijani", "az")));
+        assert_eq!(find_lang("Azerbaijani"), lang);
+        assert_eq!(find_lang("Azeerbaijani"), lang);
+    }
+
+    #[test]
+    fn unique_name_prefixes_resolve() {
+        assert_eq!(find_lang("port"), Some(&("Portuguese", "pt")));
+        assert_eq!(find_lang("Swah"), Some(&("Swahili", "sw")));
+    }
+
+    #[test]
+    fn ambiguous_prefixes_list_the_candidates() {
+        assert_eq!(find_lang("mal"), None);
+        let names = prefix_matches("mal").iter()
+                                         .map(|info| info.0)
+                                         .collect::<Vec<_>>();
+        assert_eq!(names, ["Malagasy", "Malay", "Malayalam", "Maltese"]);
+    }
+
+    #[test]
+    fn short_prefixes_are_rejected() {
+        assert!(prefix_matches("po").is_empty());
+        assert_eq!(find_lang("po"), None);
+    }
+
+    #[test]
+    fn find_lang_skips_the_padding_entries() {
+        assert_eq!(find_lang(""), None);
+        assert_eq!(find_lang("  "), None);
+        assert_eq!(find_lang(",."), None);
+    }
+
+    #[test]
+    fn repeated_over_limit_errors_are_suppressed() {
+        let now = Instant::now();
+        assert!(!is_repeat_error(None, now));
+        assert!(is_repeat_error(Some(now), now + Duration::from_secs(5)));
+        assert!(!is_repeat_error(Some(now), now + OVER_LIMIT_ERR_WINDOW));
+
+        let state = State::default();
+        assert!(show_over_limit(&state, "net", "#chan"));
+        assert!(show_over_limit(&state, "net", "#chan"));
+        state.lock().unwrap().over_limit_once = true;
+        assert!(show_over_limit(&state, "net", "#chan"));
+        assert!(!show_over_limit(&state, "net", "#Chan"));
+        assert!(show_over_limit(&state, "net", "#other"));
+    }
+
+    #[test]
+    fn consecutive_errors_turn_translation_off() {
+        let state = State::default();
+        assert!(!note_result(&state, "net", "#chan", true));
+
+        state.lock().unwrap().auto_off = Some((3, Duration::from_secs(60)));
+        let results = [true, true, false, true, true, true, true]
+                          .map(|failed| {
+                              note_result(&state, "net", "#chan", failed)
+                          });
+        assert_eq!(results, [false, false, false, false, false, true, false]);
+    }
+
+    #[test]
+    fn error_runs_restart_after_the_window() {
+        let start  = Instant::now();
+        let window = Duration::from_secs(10);
+        assert_eq!(next_error_run(None, start, window), (1, start));
+        let later = start + Duration::from_secs(5);
+        assert_eq!(next_error_run(Some((1, start)), later, window), 
+                   (2, start));
+        let late  = start + Duration::from_secs(11);
+        assert_eq!(next_error_run(Some((2, start)), late, window), (1, late));
+    }
+
+    #[test]
+    fn blocked_words_hold_translations() {
+        let blocked = HashSet::from(["darn".to_string(), "heck".to_string()]);
+        assert_eq!(find_blocked_word("Oh, DARN it!", &blocked), 
+                   Some("darn".to_string()));
+        assert_eq!(find_blocked_word("what the heck", &blocked), 
+                   Some("heck".to_string()));
+        assert_eq!(find_blocked_word("darned shoes", &blocked), None);
+        assert_eq!(find_blocked_word("all fine", &HashSet::new()), None);
+    }
+
+    #[test]
+    fn japanese_full_stops_split_sentences() {
+        assert_eq!(split_sentences("こんにちは。元気ですか？はい！", "en"), 
+                   vec!["こんにちは。", "元気ですか？", "はい！"]);
+        assert_eq!(split_sentences("行きます。\u{3000}またね", "en"), 
+                   vec!["行きます。\u{3000}", "またね"]);
+    }
+
+    #[test]
+    fn arabic_question_marks_split_sentences() {
+        assert_eq!(split_sentences("كيف حالك؟ أنا بخير.", "ar"), 
+                   vec!["كيف حالك؟ ", "أنا بخير."]);
+        assert_eq!(split_sentences("¿Qué tal? Bien.", "es"), 
+                   vec!["¿Qué tal? ", "Bien."]);
+    }
+
+    #[test]
+    fn invalid_utf8_responses_are_decoded_lossily() {
+        let body: &[u8] = b"[[[\"Hola \xff mundo\",\"Hello\"]],null,\"en\"]";
+        let (text, lossy) = decode_body_lossy(body).unwrap();
+        assert!(lossy);
+        let json = serde_json::from_str::<Value>(&text).unwrap();
+        assert_eq!(json[0][0][0].as_str(), Some("Hola \u{fffd} mundo"));
+
+        let body: &[u8] = "[[[\"¡Hola!\"]]]".as_bytes();
+        assert_eq!(decode_body_lossy(body).unwrap(), 
+                   ("[[[\"¡Hola!\"]]]".to_string(), false));
+    }
+
+    #[test]
+    fn held_messages_survive_until_retried() {
+        let state = State::default();
+        let key   = chan_key("net", "#chan");
+        hold_message(&state, key.clone(), "SAY", "first");
+        hold_message(&state, key.clone(), "ME", "waves");
+
+        // Turning translation on or off doesn't drop the held message.
+        state.lock().unwrap().forget_context(&key);
+        assert_eq!(take_pending(&state, &chan_key("net", "#Chan")), 
+                   Some(("ME", "waves".to_string())));
+        assert_eq!(take_pending(&state, &key), None);
+    }
+
+    #[test]
+    fn welcome_is_stored_and_sent_in_the_target_language() {
+        let state = cached_state(&[("Welcome!", "¡Bienvenido!")]);
+        assert_eq!(get_welcome(&state), None);
+        set_welcome(&state, "Hi");
+        set_welcome(&state, "Welcome!");
+        let template = get_welcome(&state).unwrap();
+        assert_eq!(template, "Welcome!");
+
+        // With `/SETLANG en es`, the greeting goes out in Spanish.
+        let trans = translate_welcome(&template, "en", "es", &state);
+        assert_eq!(trans.unwrap(), "¡Bienvenido!");
+    }
+
+    #[test]
+    fn inline_display_modes() {
+        assert_eq!(inline_display(true, false, "hello"), 
+                   (fm!("{}hello", INLINE_MARKER), true));
+        assert_eq!(inline_display(false, false, "hello"), 
+                   ("hello".to_string(), false));
+        // A failed translation falls back on separate mode.
+        assert_eq!(inline_display(true, true, "hola"), 
+                   ("hola".to_string(), false));
+    }
+
+    #[test]
+    fn request_previews_match_the_requests() {
+        let google = request_preview(&Provider::Google, None, "a b&c", 
+                                     "en", "es").unwrap();
+        assert_eq!(google, "GET https://translate.googleapis.com/translate_a/\
+                            single?client=gtx&sl=en&tl=es&dt=t&q=a%20b%26c");
+
+        let libre = Provider::LibreTranslate("http://lt.local/".into());
+        assert_eq!(request_preview(&libre, None, "hi", "en", "zh-TW"),
+                   Ok("POST http://lt.local/translate {\"format\":\"text\",\
+                       \"q\":\"hi\",\"source\":\"en\",\"target\":\"zt\"}"
+                      .to_string()));
+    }
+
+    #[test]
+    fn request_previews_redact_the_key() {
+        let key     = "0123abcd-secret:fx";
+        let request = request_preview(&Provider::DeepL, Some(key), "hi", 
+                                      "en", "de").unwrap();
+        assert!(!request.contains("0123abcd"));
+        assert!(!request.contains("secret"));
+        assert!(request.starts_with("POST https://api-free.deepl.com/"));
+        assert!(request.contains(&fm!("DeepL-Auth-Key {}", REDACTED_KEY)));
+    }
+
+    #[test]
+    fn lang_lists_must_have_languages() {
+        let langs = parse_lang_list("es, FR,de", "en").unwrap();
+        assert_eq!(langs, [("Spanish", "es"), ("French", "fr"), 
+                           ("German", "de")]);
+        assert_eq!(parse_lang_list(",", "en"), None);
+        assert_eq!(parse_lang_list(" , ", "en"), None);
+        assert_eq!(parse_lang_list("es,xx", "en"), None);
+        assert_eq!(parse_lang_list("es,en", "en"), None);
+    }
+
+    #[test]
+    fn round_robin_advances_and_wraps() {
+        let langs  = vec!["es".to_string(), "fr".to_string(), "de".into()];
+        let mut cursor = 0;
+        let picks = (0..7).map(|_| round_robin(&langs, &mut cursor).unwrap())
+                          .collect::<Vec<_>>();
+        assert_eq!(picks, ["es", "fr", "de", "es", "fr", "de", "es"]);
+        assert_eq!(cursor, 1);
+        assert_eq!(round_robin(&[], &mut cursor), None);
+
+        let state = State::default();
+        let key   = chan_key("net", "#chan");
+        state.lock().unwrap().lang_lists.insert(key.clone(), (langs, 2));
+        assert_eq!(next_list_lang(&state, &key).as_deref(), Some("de"));
+        assert_eq!(next_list_lang(&state, &key).as_deref(), Some("es"));
+        assert_eq!(next_list_lang(&state, &chan_key("net", "#x")), None);
+    }
+
+    #[test]
+    fn only_bang_is_a_bot_command_by_default() {
+        let state   = State::default();
+        let sigils  = get_cmd_sigils(&state);
+        assert!(is_bot_command("!weather London", &sigils));
+        assert!(is_bot_command("  !seen bob", &sigils));
+        assert!(!is_bot_command("@bob are you there?", &sigils));
+        assert!(!is_bot_command(".5 is a half", &sigils));
+        assert!(!is_bot_command("! not a command", &sigils));
+        assert!(!is_bot_command("...", &sigils));
+        assert!(!is_bot_command("", &sigils));
+
+        state.lock().unwrap().cmd_sigils = Some("!@.".into());
+        let sigils = get_cmd_sigils(&state);
+        assert!(is_bot_command("@bot help", &sigils));
+        assert!(is_bot_command(".help", &sigils));
+        assert!(!is_bot_command("... anyway", &sigils));
+
+        state.lock().unwrap().cmd_sigils = Some(String::new());
+        assert!(!is_bot_command("!weather", &get_cmd_sigils(&state)));
+    }
+
+    #[test]
+    fn split_by_length_measures_chars_or_bytes() {
+        // Japanese characters are three bytes each in UTF-8.
+        let text = "今日は良い天気です。明日も晴れるでしょう。";
+        let by_chars = split_by_length(text, 12, char_count);
+        assert_eq!(by_chars, ["今日は良い天気です。", 
+                              "明日も晴れるでしょう。"]);
+
+        let by_bytes = split_by_length(text, 16, char::len_utf8);
+        assert!(by_bytes.iter().all(|c| c.len() <= 16));
+        assert_eq!(by_bytes.concat(), text);
+        assert_eq!(by_bytes[0], "今日は良い");
+
+        // Each chunk gets a character, even if it's over the limit.
+        assert_eq!(split_by_length("日本", 2, char::len_utf8), ["日", "本"]);
+        assert_eq!(split_by_length("ab", 0, char_count), ["a", "b"]);
+        assert_eq!(split_by_length("one two", 5, char_count), 
+                   ["one ", "two"]);
+    }
+
+    #[test]
+    fn block_sends_fit_the_byte_budget() {
+        let budget = message_budget("SAY", "nick", "#channel");
+        // 3 bytes a character, so a character limit of `budget` would send
+        // lines three times too long.
+        let trans  = "これは長い段落です。".repeat(40);
+        let sends  = block_sends(&trans, budget);
+        assert!(sends.len() > 1);
+        assert!(sends.iter().all(|s| s.len() <= budget));
+        assert!(sends.iter().all(|s| s.ends_with('。')));
+        assert_eq!(sends.concat(), trans);
+
+        assert_eq!(block_sends("  short  ", budget), ["short"]);
+        assert!(block_sends("   ", budget).is_empty());
+    }
+
+    #[test]
+    fn detection_is_read_from_a_translation_response() {
+        let rsp = serde_json::json!([
+            [["Hola, mundo", "Hello, world", null, null, 10]],
+            null, "en", null, null, null, 0.93, [], 
+            [["en"], null, [0.93], ["en"]]
+        ]);
+        assert_eq!(parse_detection(&rsp), Some(("en".to_string(), 
+                                                Some(0.93))));
+        let rsp = serde_json::json!([[["Hola", "Hello", null, null, 10]],
+                                     null, "en"]);
+        assert_eq!(parse_detection(&rsp), Some(("en".to_string(), None)));
+        assert_eq!(parse_detection(&serde_json::json!([[]])), None);
+    }
+
+    #[test]
+    fn cached_translations_have_no_detection() {
+        let state = cached_state(&[("hello", "hola")]);
+        let nicks = HashSet::new();
+        assert_eq!(translate_detect("hello", "en", "es", &nicks, &state)
+                       .unwrap(),
+                   ("hola".to_string(), None));
+        assert_eq!(translate_text("hello", "en", "es", &nicks, &state)
+                       .unwrap(),
+                   "hola");
+    }
+
+    #[test]
+    fn low_confidence_comes_from_the_translation_response() {
+        let rsp = serde_json::json!([[["Hola", "Hallo", null, null, 10]],
+                                     null, "de", null, null, null, 0.42]);
+        let detection = parse_detection(&rsp);
+        assert!(is_low_confidence(detection.as_ref(), Some(0.5)));
+        assert!(!is_low_confidence(detection.as_ref(), Some(0.4)));
+        assert!(!is_low_confidence(detection.as_ref(), None));
+
+        let sure = Some(("de".to_string(), None));
+        assert!(!is_low_confidence(sure.as_ref(), Some(0.5)));
+        assert!(!is_low_confidence(None, Some(0.5)));
+    }
+
+    #[test]
+    fn skip_same_uses_the_detection_from_the_translation() {
+        let detected = |lang: &str| -> Result<(String, Option<Detection>), 
+                                              TranslationError> {
+            Ok(("hello".into(), Some((lang.into(), Some(0.9)))))
+        };
+        assert!(is_same_lang(&detected("en"), "en"));
+        assert!(is_same_lang(&detected("EN"), "en"));
+        assert!(!is_same_lang(&detected("es"), "en"));
+        assert!(!is_same_lang(&Ok(("hello".into(), None)), "en"));
+        let err = TranslationError::new("hello".into(), "failed".into(), 
+                                        false, None, ErrorKind::Network);
+        assert!(!is_same_lang(&Err(err), "en"));
+    }
+
+    #[test]
+    fn history_keeps_the_latest_entries_with_their_languages() {
+        let entry = |i: usize| HistoryEntry {
+            sender      : "bob".into(),
+            original    : fm!("hola {}", i),
+            translation : fm!("hello {}", i),
+            lang        : history_lang("auto", Some(("es".into(), None))),
+        };
+        let mut history = VecDeque::new();
+        for i in 0..HISTORY_SIZE + 3 {
+            push_history(&mut history, entry(i));
+        }
+        assert_eq!(history.len(), HISTORY_SIZE);
+        assert_eq!(history[0].original, "hola 3");
+        assert!(history.iter().all(|e| e.lang == "es"));
+
+        let last = recent_history(&history, 2).map(|e| e.original.as_str())
+                                              .collect::<Vec<_>>();
+        assert_eq!(last, [fm!("hola {}", HISTORY_SIZE + 1), 
+                          fm!("hola {}", HISTORY_SIZE + 2)]);
+        assert_eq!(recent_history(&history, 1000).count(), HISTORY_SIZE);
+
+        assert_eq!(history_lang("fr", None), "fr");
+        assert_eq!(history_lang("fr", Some(("de".into(), Some(0.8)))), "de");
+    }
+
+    #[test]
+    fn expansion_is_measured_by_ratio_or_size() {
+        assert!(!is_over_expanded(10, 20, 2.0));
+        assert!(is_over_expanded(10, 21, 2.0));
+        assert!(is_over_expanded(300, 401, 2.0));
+        assert!(!is_over_expanded(300, 400, 2.0));
+        // An empty original counts as one byte, so any translation of it
+        // isn't infinitely longer.
+        assert!(!is_over_expanded(0, 2, 2.0));
+        assert!(is_over_expanded(0, 3, 2.0));
+    }
+
+    #[test]
+    fn paragraphs_are_translated_as_units() {
+        let text = "Hello there,\nhow are you?\n\n\nI'm fine.\nThanks.";
+        let body = join_paragraphs(text);
+        assert_eq!(body, "Hello there, how are you?\n\nI'm fine. Thanks.");
+
+        let state = cached_state(&[("Hello there, how are you?", 
+                                    "Hola, ¿cómo estás?"),
+                                   ("I'm fine. Thanks.", 
+                                    "Estoy bien. Gracias.")]);
+        let trans = translate_text(&body, "en", "es", &HashSet::new(), 
+                                   &state).unwrap();
+        assert_eq!(trans, "Hola, ¿cómo estás?\n\nEstoy bien. Gracias.");
+        let paras = trans.split("\n\n").collect::<Vec<_>>();
+        assert_eq!(paras, ["Hola, ¿cómo estás?", "Estoy bien. Gracias."]);
+
+        // Code stays on lines of its own.
+        assert_eq!(join_paragraphs("Run this:\n    make all\nthen wait."),
+                   "Run this:\n    make all\nthen wait.");
+    }
+
+    #[test]
+    fn concurrent_activations_and_clears_apply_in_order() {
+        use std::sync::mpsc;
+
+        // Like translation threads queueing /SETLANG and /OFFLANG ALL on 
+        // the main thread, which applies them one at a time.
+        let (tx, rx) = mpsc::channel::<Option<(ChanData, ChanData)>>();
+        let workers  = (0..8).map(|t| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for i in 0..250 {
+                    let change = if i % 50 == 49 {
+                        None
+                    } else {
+                        Some((chan_key("net", &fm!("#c{}", i % 7)), 
+                              ("en".to_string(), fm!("t{}", t))))
+                    };
+                    tx.send(change).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        drop(tx);
+
+        let state    = State::default();
+        let mut map  = ChanMap::new();
+        let mut log  = vec![];
+        for change in rx {
+            let mut st = state.lock().unwrap();
+            match change.clone() {
+                Some((key, langs)) => {
+                    st.suspended.insert(key.clone(), langs.clone());
+                    set_context_langs(&mut map, &mut st, key, langs);
+                },
+                None => {
+                    clear_contexts(&mut map, &mut st);
+                },
+            }
+            log.push(change);
+        }
+        workers.into_iter().for_each(|w| w.join().unwrap());
+        assert_eq!(log.len(), 8 * 250);
+
+        // The map ends as the changes, replayed in the order they were
+        // applied, say: after the last clear, the last writer of each key.
+        let mut expected = ChanMap::new();
+        for change in &log {
+            match change {
+                Some((key, langs)) => { expected.insert(key.clone(), 
+                                                        langs.clone()); },
+                None               => expected.clear(),
+            }
+        }
+        assert_eq!(map, expected);
+        let st = state.lock().unwrap();
+        assert!(map.keys().all(|key| !st.suspended.contains_key(key)));
+        drop(st);
+
+        assert_eq!(clear_contexts(&mut map, &mut state.lock().unwrap()), 
+                   expected.len());
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn urls_and_nicks_are_masked_and_restored() {
+        let nicks = HashSet::from(["bob".to_string()]);
+        let text  = "check out https://example.com @bob";
+        let (masked, protected) = mask_protected(text, &nicks);
+        assert_eq!(masked, "check out \x000\x00 \x001\x00");
+        assert_eq!(protected, ["https://example.com", "@bob"]);
+        assert!(has_prose(&masked));
+        assert!(!has_prose("\x000\x00, \x001\x00!"));
+
+        // As it might come back, with a space inside a placeholder.
+        let trans = "mira \x00 0 \x00 \x001\x00";
+        assert_eq!(unmask_protected(trans, &protected), 
+                   "mira https://example.com @bob");
+        // A lost placeholder's part goes at the end.
+        assert_eq!(unmask_protected("mira \x001\x00", &protected),
+                   "mira @bob https://example.com");
+        assert_eq!(unmask_protected("hola", &[]), "hola");
+    }
+
+    #[test]
+    fn masked_sentences_are_translated_whole() {
+        let nicks = HashSet::from(["bob".to_string()]);
+        let (masked, protected) = mask_protected("ask bob about it.", &nicks);
+        assert_eq!(split_sentences(&masked, "en"), 
+                   ["ask \x000\x00 about it."]);
+
+        // A service that keeps the placeholder gets the sentence whole.
+        let mut sent = vec![];
+        let trans = translate_masked(&masked, |text| {
+                        sent.push(text.to_string());
+                        Ok((text.replace("ask", "pregunta a")
+                                .replace("about it", "sobre eso"), None))
+                    }).unwrap().0;
+        assert_eq!(sent, [masked.as_str()]);
+        assert_eq!(unmask_protected(&trans, &protected), 
+                   "pregunta a bob sobre eso.");
+
+        // One that strips it, like a server that drops control characters,
+        // gets the prose around it on its own.
+        let mut sent = vec![];
+        let trans = translate_masked(&masked, |text| {
+                        sent.push(text.to_string());
+                        Ok((text.replace('\x00', "")
+                                .replace("ask", "pregunta a")
+                                .replace("about it.", "sobre eso."), None))
+                    }).unwrap().0;
+        assert_eq!(sent, [masked.as_str(), "ask", "about it."]);
+        assert_eq!(unmask_protected(&trans, &protected), 
+                   "pregunta a bob sobre eso.");
+    }
+
+    #[test]
+    fn deepl_ignores_the_protected_parts() {
+        let (masked, protected) = mask_protected(
+                                      "see https://a.io?x=1&y=<2> now", 
+                                      &HashSet::new());
+        let (_, body) = deepl_request("k:fx", &masked, "en", "de").unwrap();
+        let body      = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(body["text"][0], "see <x i=\"0\"/> now");
+        assert_eq!(body["tag_handling"], "xml");
+        assert_eq!(body["ignore_tags"][0], "x");
+
+        // Shaped like DeepL's response: the ignored tag comes back as it 
+        // was sent, and the text is escaped.
+        let rsp   = r##"{"translations":[{"detected_source_language":"EN",
+                        "text":"siehe <x i=\"0\"/> jetzt &amp; dann"}]}"##;
+        let json  = serde_json::from_str::<Value>(rsp).unwrap();
+        let trans = xml_to_placeholders(json["translations"][0]["text"]
+                                            .as_str().unwrap());
+        assert_eq!(unmask_protected(&trans, &protected), 
+                   "siehe https://a.io?x=1&y=<2> jetzt & dann");
+
+        // Text with nothing protected goes as plain text.
+        let (_, body) = deepl_request("k:fx", "a < b", "en", "de").unwrap();
+        assert!(!body.contains("tag_handling"));
+        assert!(body.contains("a < b"));
+    }
+
+    #[test]
+    fn fenced_code_survives_verbatim() {
+        let text = "Try this: ```let x = y. z();``` and tell me. Thanks!";
+        let (masked, protected) = mask_protected(text, &HashSet::new());
+        assert_eq!(protected, ["```let x = y. z();```"]);
+        // The period in the code doesn't split the sentence around it.
+        assert_eq!(split_sentences(&masked, "en"), 
+                   ["Try this: \x000\x00 and tell me. ", "Thanks!"]);
+        let trans = "Prueba esto: \x000\x00 y dime. ¡Gracias!";
+        assert_eq!(unmask_protected(trans, &protected),
+                   "Prueba esto: ```let x = y. z();``` y dime. ¡Gracias!");
+
+        let (masked, protected) = mask_protected("    fn main() {}", 
+                                                 &HashSet::new());
+        assert!(!has_prose(&masked));
+        assert_eq!(unmask_protected(&masked, &protected), "    fn main() {}");
+    }
+
+    #[test]
+    fn rate_limit_fails_fast() {
+        let mut limiter = RateLimiter::default();
+        limiter.set_rate(Some(60));
+        for _ in 0..60 {
+            assert!(limiter.acquire());
+        }
+        // Requests over the limit fail rather than wait, and don't run up
+        // a debt for the ones after them.
+        assert!(!limiter.acquire());
+        assert!(!limiter.acquire());
+        assert_eq!(limiter.remaining(), Some(0));
+
+        // A token a second comes in.
+        let wait   = limiter.next_token();
+        let second = Duration::from_secs(1);
+        assert!((second.mul_f64(0.9)..=second).contains(&wait));
+
+        limiter.set_rate(None);
+        assert!(limiter.acquire());
+        assert_eq!(limiter.next_token(), Duration::ZERO);
+        assert_eq!(limiter.remaining(), None);
+    }
+
+    #[test]
+    fn offlang_opts_a_channel_out_of_network_defaults() {
+        let mut chan_map = ChanMap::new();
+        let mut opt_outs = HashSet::new();
+        let quiet = chan_key("net", "#quiet");
+        let other = chan_key("net", "#other");
+        let own   = chan_key("net", "#own");
+        let langs = ("en".to_string(), "es".to_string());
+        chan_map.insert(net_key("net"), langs.clone());
+        chan_map.insert(own.clone(), ("en".into(), "fr".into()));
+
+        assert_eq!(lookup_langs(&chan_map, &opt_outs, &quiet), 
+                   Some(langs.clone()));
+        remove_context_langs(&mut chan_map, &mut opt_outs, &quiet);
+        assert_eq!(lookup_langs(&chan_map, &opt_outs, &quiet), None);
+        assert_eq!(lookup_langs(&chan_map, &opt_outs, &other), 
+                   Some(langs.clone()));
+
+        // A channel with its own languages falls back on nothing.
+        remove_context_langs(&mut chan_map, &mut opt_outs, &own);
+        assert_eq!(lookup_langs(&chan_map, &opt_outs, &own), None);
+
+        // /SETLANG turns it back on.
+        let mut state = AddonState { net_opt_outs: opt_outs, 
+                                     ..Default::default() };
+        set_context_langs(&mut chan_map, &mut state, quiet.clone(), 
+                          ("en".into(), "de".into()));
+        assert!(!state.net_opt_outs.contains(&quiet));
+        assert_eq!(lookup_langs(&chan_map, &state.net_opt_outs, &quiet), 
+                   Some(("en".into(), "de".into())));
+
+        // Without network defaults, nothing is opted out.
+        let mut opt_outs = HashSet::new();
+        chan_map.remove(&net_key("net"));
+        remove_context_langs(&mut chan_map, &mut opt_outs, &quiet);
+        assert!(opt_outs.is_empty());
+    }
+
+    #[test]
+    fn cooldown_is_on_by_default() {
+        let state = AddonState::default();
+        assert_eq!(state.cooldown, Cooldown(Some(Duration::from_secs(60))));
+    }
+
+    #[test]
+    fn deepl_takes_the_old_chinese_code() {
+        let (_, body) = deepl_request("k:fx", "hi", "en", "zh").unwrap();
+        assert!(body.contains("ZH-HANS"));
+        let (_, body) = deepl_request("k:fx", "hi", "zh", "en").unwrap();
+        assert!(body.contains("\"source_lang\":\"ZH\""));
+    }
+
+    #[test]
+    fn weighted_picks_follow_the_weights() {
+        let mut rng    = WeightRng::new(42);
+        let mut counts = [0; 3];
+        for _ in 0..40_000 {
+            counts[pick_weighted(&[3, 1, 0], &mut rng).unwrap()] += 1;
+        }
+        assert!((29_000..31_000).contains(&counts[0]), "{:?}", counts);
+        assert!((9_000..11_000).contains(&counts[1]),  "{:?}", counts);
+        assert_eq!(counts[2], 0);
+        assert_eq!(pick_weighted(&[0, 0], &mut rng), None);
+    }
+
+    #[test]
+    fn over_limit_lowers_a_weight() {
+        let state     = State::default();
+        let providers = [Provider::Google, Provider::DeepL];
+        assert_eq!(weighted_providers(&providers, &state), providers);
+
+        state.lock().unwrap().weights.insert(Provider::Google, 2);
+        lower_weight(&Provider::Google, &state);
+        // Google's weight of 2 is lowered to 0, so DeepL always goes first.
+        for _ in 0..20 {
+            assert_eq!(weighted_providers(&providers, &state)[0], 
+                       Provider::DeepL);
+        }
+    }
+
+    #[test]
+    fn remove_pair_leaves_other_pairs() {
+        let mut cache = TranslationCache::default();
+        for (src, tgt, text) in [("en", "es", "hi"), ("es", "en", "hola"),
+                                 ("en", "fr", "hi"), ("de", "es", "hallo")] {
+            cache.put((src.into(), tgt.into(), text.into()), "x".into());
+        }
+        assert_eq!(cache.remove_pair("en", "es"), 2);
+        assert_eq!(cache.map.len(), 2);
+        assert_eq!(cache.order.len(), 2);
+        assert!(cache.get(&("en".into(), "fr".into(), "hi".into())).is_some());
+        assert!(cache.get(&("de".into(), "es".into(), "hallo".into()))
+                     .is_some());
+        assert_eq!(cache.remove_pair("en", "es"), 0);
+    }
+
+    #[test]
+    fn cache_ignores_case_and_spacing() {
+        let mut cache = TranslationCache::default();
+        cache.put(("en".into(), "es".into(), "Hello".into()), "Hola".into());
+        let key = |text: &str| ("en".to_string(), "es".to_string(), 
+                                text.to_string());
+        assert_eq!(cache.get(&key("Hello")).as_deref(),  Some("Hola"));
+        assert_eq!(cache.get(&key("hello ")).as_deref(), Some("hola "));
+        assert_eq!(cache.get(&key("  Hello")).as_deref(), Some("  Hola"));
+        assert_eq!(cache.map.len(), 1);
+        assert_eq!(cache.get(&key("Hello!")), None);
+        // Words cased differently past the first letter can mean something
+        // else, so they miss.
+        assert_eq!(cache.get(&key("HELLO")), None);
+        cache.put(("en".into(), "es".into(), "US".into()), "EE. UU.".into());
+        assert_eq!(cache.get(&key("us")), None);
+        assert_eq!(cache.get(&key("Us")), None);
+    }
+
+    #[test]
+    fn match_form_only_changes_the_first_letter() {
+        assert_eq!(match_form("¿Cómo estás?", "How are you?", "how are you?"),
+                   "¿cómo estás?");
+        // The case of words past the first is left as translated.
+        assert_eq!(match_form("Ich mag Hunde", "I like dogs", "I like  dogs"),
+                   "Ich mag Hunde");
+    }
+
+    #[test]
+    fn stats_csv_rows() {
+        let pairs = [(("en".to_string(), "es".to_string()), 
+                      TranslationStats { translated: 3, over_limit: 1, 
+                                         errors: 2, chars: 40, pivoted: 1 }),
+                     (("es".to_string(), "en".to_string()), 
+                      TranslationStats::default())];
+        assert_eq!(stats_csv(&pairs), 
+                   "source,target,translations,failures,over_limit,\
+                    characters\nen,es,3,2,1,40\nes,en,0,0,0,0\n");
+        assert_eq!(stats_csv(&[]), fm!("{}\n", STATS_CSV_HEADER));
+    }
+
+    #[test]
+    fn pivot_goes_through_english() {
+        use SingleTranslationError as STE;
+        // Translates "ga" to and from English, but not to "ja" directly.
+        let mock = |text: &str, source: &str, target: &str| {
+            match (source, target) {
+                ("ga", "en") => Ok(("hello ".to_string(), 
+                                    Some(("ga".to_string(), Some(0.9))))),
+                ("en", "ja") => Ok((fm!("ja:{}", text), None)),
+                _            => Err(STE::StaticError(ErrorKind::Unsupported,
+                                                     DEEPL_UNSUPPORTED_MSG)),
+            }
+        };
+        let (result, pivoted) = pivot_translate("dia duit ", "ga", "ja", 
+                                                true, mock);
+        let (trans, detection) = result.unwrap();
+        assert!(pivoted);
+        assert_eq!(trans, "ja:hello ");
+        assert_eq!(detection.unwrap().0, "ga");
+
+        let (result, pivoted) = pivot_translate("dia duit", "ga", "ja", 
+                                                false, mock);
+        assert!(!pivoted);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn pivot_only_on_unsupported_pairs() {
+        use SingleTranslationError as STE;
+        let mut calls = 0;
+        let (result, pivoted) = pivot_translate("hi", "ga", "ja", true, 
+                                                |_, _, _| {
+            calls += 1;
+            Err(STE::OverLimit("Server translation limit reached."))
+        });
+        assert!(result.is_err());
+        assert!(!pivoted);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn long_messages_arent_sent_while_paused() {
+        let paste = "x".repeat(DEFAULT_MAX_CHARS + 1);
+        assert_eq!(outgoing_action(&paste, DEFAULT_MAX_CHARS, true), 
+                   Outgoing::TooLong);
+        assert_eq!(outgoing_action(&paste, DEFAULT_MAX_CHARS, false), 
+                   Outgoing::TooLong);
+        assert_eq!(outgoing_action("hi", DEFAULT_MAX_CHARS, true), 
+                   Outgoing::AsIs);
+        assert_eq!(outgoing_action("hi", DEFAULT_MAX_CHARS, false), 
+                   Outgoing::Translate);
+    }
+
+    #[test]
+    fn libretranslate_languages_are_read() {
+        // Trimmed response of libretranslate.com's /languages endpoint.
+        let json = r##"[
+            {"code":"en","name":"English","targets":["de","zh","zt"]},
+            {"code":"de","name":"German","targets":["en"]},
+            {"code":"nb","name":"Norwegian","targets":["en"]},
+            {"code":"zh","name":"Chinese","targets":["en"]},
+            {"code":"zt","name":"Chinese (traditional)","targets":["en"]},
+            {"code":"xx","name":"Unknown","targets":["en"]}
+        ]"##;
+        let langs = parse_libretranslate_languages(json).unwrap();
+        let mut langs = langs.into_iter().collect::<Vec<_>>();
+        langs.sort();
+        assert_eq!(langs, vec!["de", "en", "no", "zh-CN", "zh-TW"]);
+        assert!(parse_libretranslate_languages("{\"error\":1}").is_none());
+    }
+
+    #[test]
+    fn deepl_languages_are_read() {
+        let json = r##"[
+            {"language":"DE","name":"German","supports_formality":true},
+            {"language":"EN-US","name":"English (American)"},
+            {"language":"ZH-HANS","name":"Chinese (simplified)"}
+        ]"##;
+        let langs = parse_deepl_languages(json).unwrap();
+        assert!(langs.contains("de"));
+        assert!(langs.contains("en"));
+        assert!(!langs.contains("fr"));
+    }
+
+    #[test]
+    fn bot_commands_in_blocks_are_sent_as_is() {
+        let block = ["Hello there", "!weather London", "How are you"]
+                        .iter()
+                        .map(|l| (l.to_string(), l.to_string()))
+                        .collect();
+        let parts = split_paragraphs(block, "!");
+        assert_eq!(parts, vec![
+            BlockPart::Prose(vec!["Hello there".into()], 
+                             "Hello there".into()),
+            BlockPart::Verbatim("!weather London".into()),
+            BlockPart::Prose(vec!["How are you".into()], 
+                             "How are you".into()),
+        ]);
+    }
+
+    #[test]
+    fn blocked_words_are_found_through_the_state() {
+        let state = State::default();
+        state.lock().unwrap().blocked_words.insert("darn".into());
+        assert_eq!(blocked_word(&state, "Oh darn."), Some("darn".into()));
+        assert_eq!(blocked_word(&state, "All fine."), None);
+    }
+
+    #[test]
+    fn watched_nicks_fold_like_irc() {
+        let watches = HashSet::from([irc_casefold("[Al]")]);
+        assert!(!is_unwatched(&watches, "{al}"));
+        assert!(!is_unwatched(&watches, "[AL]"));
+        assert!(is_unwatched(&watches, "bob"));
+        assert!(!is_unwatched(&HashSet::new(), "bob"));
+    }
+
+    #[test]
+    fn colors_reset_to_their_defaults() {
+        let color = ColorSetting::new(11);
+        color.set(4);
+        assert_eq!(color.to_string(), "\x0304");
+        color.reset();
+        assert_eq!(color.get(), 11);
+    }
+}
@@ -0,0 +1,217 @@
+//! A small pool of worker threads that translation jobs are run on. Jobs
+//! are queued and taken up by a fixed number of workers, so a burst of
+//! messages in a busy channel doesn't start a thread for each one.
+//!
+//! The pool is started in `plugin_init()` and stopped in `plugin_deinit()`,
+//! which waits for the workers to finish, since they run code from the 
+//! plugin's library and it's unloaded after.
+//!
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The number of worker threads in the pool.
+///
+pub(crate) const WORKER_COUNT: usize = 4;
+
+/// How long stopping the pool waits for the workers to finish the jobs 
+/// they're on.
+///
+const STOP_WAIT: Duration = Duration::from_secs(2);
+
+/// How often stopping the pool checks whether the workers have finished.
+///
+const STOP_POLL: Duration = Duration::from_millis(10);
+
+/// A job run by one of the workers, and whether it sends a message for the
+/// user, so it can be reported if it's dropped.
+///
+struct Job {
+    run      : Box<dyn FnOnce() + Send + 'static>,
+    outgoing : bool,
+}
+
+thread_local! {
+    /// The stop flag of the pool the current thread is a worker of.
+    ///
+    static WORKER_STOP: RefCell<Option<Arc<AtomicBool>>> = 
+        const { RefCell::new(None) };
+}
+
+/// The running pool, if there is one. Its workers run the plugin's code, so
+/// it's kept in a static rather than the addon state: `plugin_deinit()` gets
+/// no user data to reach the state with, and has to stop the workers before
+/// the plugin's library is unloaded. It also lets jobs be queued from code
+/// that has no state at hand.
+///
+static POOL: Mutex<Option<WorkerPool>> = Mutex::new(None);
+
+/// The queue jobs are sent to the workers on, and the workers.
+///
+struct WorkerPool {
+    sender   : Sender<Job>,
+    receiver : Arc<Mutex<Receiver<Job>>>,
+    stop     : Arc<AtomicBool>,
+    dropped  : Arc<AtomicUsize>,
+    workers  : Vec<JoinHandle<()>>,
+}
+
+/// Starts the pool's workers. Any pool that's already running is stopped
+/// first.
+/// # Arguments
+/// * `workers` - The number of worker threads to start.
+///
+pub(crate) fn start(workers: usize) {
+    stop();
+    let (sender, receiver) = mpsc::channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let stop     = Arc::new(AtomicBool::new(false));
+    let dropped  = Arc::new(AtomicUsize::new(0));
+
+    let workers = (0..workers).map(|_| {
+                      let receiver = receiver.clone();
+                      let stop     = stop.clone();
+                      let dropped  = dropped.clone();
+                      thread::spawn(move || work(receiver, stop, dropped))
+                  }).collect();
+
+    *POOL.lock().unwrap() = Some(WorkerPool { 
+        sender, receiver, stop, dropped, workers 
+    });
+}
+
+/// Stops the pool. Jobs still in the queue are dropped, and the workers are
+/// waited for, up to `STOP_WAIT`, to finish the jobs they're on. Jobs 
+/// waiting on the main thread, which is the one stopping the pool, give up
+/// when they see the pool stopping; see `stopping()`.
+/// # Returns
+/// * The number of outgoing jobs that were dropped, so the user can be told
+///   their messages weren't sent.
+///
+pub(crate) fn stop() -> usize {
+    let Some(pool) = POOL.lock().unwrap().take() else {
+        return 0;
+    };
+    let WorkerPool { sender, receiver, stop, dropped, workers } = pool;
+    stop.store(true, Ordering::SeqCst);
+    // With the sender gone, idle workers wake up and exit.
+    drop(sender);
+
+    // Jobs no worker has taken yet. A worker waiting for one holds the 
+    // lock, but it's let go as soon as the sender's dropped.
+    if let Ok(receiver) = receiver.lock() {
+        let queued = receiver.try_iter().filter(|job| job.outgoing).count();
+        dropped.fetch_add(queued, Ordering::SeqCst);
+    }
+    let deadline = Instant::now() + STOP_WAIT;
+    while workers.iter().any(|w| !w.is_finished()) 
+          && Instant::now() < deadline {
+        thread::sleep(STOP_POLL);
+    }
+    for worker in workers.into_iter().filter(|w| w.is_finished()) {
+        let _ = worker.join();
+    }
+    dropped.load(Ordering::SeqCst)
+}
+
+/// Indicates whether the pool the current thread works for is being 
+/// stopped. Jobs waiting on the main thread check it so they don't hold up
+/// the unload. It's always `false` off the pool's threads.
+///
+pub(crate) fn stopping() -> bool {
+    WORKER_STOP.with(|stop| {
+        stop.borrow().as_ref().is_some_and(|s| s.load(Ordering::SeqCst))
+    })
+}
+
+/// Queues a job to be run by the next free worker. If the pool isn't
+/// running, the job is run on a thread of its own instead.
+///
+pub(crate) fn execute<F>(job: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    queue(Job { run: Box::new(job), outgoing: false });
+}
+
+/// Queues a job like `execute()`. If `outgoing` is set, the job sends a 
+/// message for the user, like a translation for `/LSAY`, and it's counted
+/// if the pool is stopped before it runs.
+///
+pub(crate) fn execute_as<F>(outgoing: bool, job: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    queue(Job { run: Box::new(job), outgoing });
+}
+
+/// Does the work of `execute()` and `execute_as()`.
+///
+fn queue(job: Job) {
+    let job = match POOL.lock().unwrap().as_ref() {
+        Some(pool) => match pool.sender.send(job) {
+            Ok(()) => return,
+            Err(err) => err.0,
+        },
+        None => job,
+    };
+    thread::spawn(job.run);
+}
+
+/// Runs jobs from the queue until the pool is stopped, or the queue is
+/// dropped along with the pool.
+///
+fn work(receiver : Arc<Mutex<Receiver<Job>>>, 
+        stop     : Arc<AtomicBool>,
+        dropped  : Arc<AtomicUsize>) 
+{
+    WORKER_STOP.with(|s| *s.borrow_mut() = Some(stop.clone()));
+    loop {
+        // The lock is only held while waiting for a job, so the other
+        // workers can take the next one while this one runs.
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_)       => break,
+        };
+        match job {
+            Ok(job) if stop.load(Ordering::SeqCst) => {
+                if job.outgoing {
+                    dropped.fetch_add(1, Ordering::SeqCst);
+                }
+                break;
+            },
+            Ok(job) => (job.run)(),
+            Err(_)  => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_waits_and_counts_dropped_jobs() {
+        let ran  = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        start(1);
+        execute(move || {
+            thread::sleep(Duration::from_millis(100));
+            flag.store(true, Ordering::SeqCst);
+        });
+        execute_as(true, || panic!("should have been dropped"));
+        execute_as(true, || panic!("should have been dropped"));
+        execute(|| panic!("should have been dropped"));
+        thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(stop(), 2);
+        // The job that was running was waited for.
+        assert!(ran.load(Ordering::SeqCst));
+        assert!(!stopping());
+        assert_eq!(stop(), 0);
+    }
+}